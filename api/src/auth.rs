@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+use crate::Error;
+use crate::db::DB;
+
+// How long a login lasts
+pub const SESSION_TTL_DAYS: i32 = 30;
+
+pub const SESSION_COOKIE: &str = "session";
+
+const MIN_PASSWORD_LENGTH: usize = 8;
+
+// How long a successful password check is remembered
+const PASSWORD_CACHE_TTL: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct User {
+    pub id: i32,
+    pub username: String,
+    pub is_admin: bool,
+}
+
+impl From<&tokio_postgres::Row> for User {
+    fn from(row: &tokio_postgres::Row) -> User {
+        User {
+            id: row.get(0),
+            username: row.get(1),
+            is_admin: row.get(2),
+        }
+    }
+}
+
+// Hashing is slow by design, so it's done off the executor
+pub async fn hash_password(password: &str) -> Result<String, Error> {
+    let password = password.to_owned();
+    let mut salt = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut salt);
+    tokio::task::spawn_blocking(move || argon2::hash_encoded(password.as_bytes(), &salt, &argon2::Config::default()))
+        .await
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?
+        .map_err(Error::from)
+}
+
+pub fn verify_password(hash: &str, password: &str) -> bool {
+    argon2::verify_encoded(hash, password.as_bytes()).unwrap_or(false)
+}
+
+// Successful password checks, so Subsonic clients, which send the password
+// with every request, don't pay for a hash each time. Entries are keyed on
+// the stored hash too, so changing the password invalidates them.
+#[derive(Clone, Default)]
+pub struct PasswordCache {
+    verified: Arc<Mutex<HashMap<String, Instant>>>,
+}
+
+impl PasswordCache {
+    fn key(hash: &str, password: &str) -> String {
+        token_hash(&format!("{}\0{}", hash, password))
+    }
+
+    fn contains(&self, key: &str) -> bool {
+        let now = Instant::now();
+        let mut verified = self.verified.lock().unwrap();
+        verified.retain(|_, at| now.duration_since(*at) < PASSWORD_CACHE_TTL);
+        verified.contains_key(key)
+    }
+
+    fn insert(&self, key: String) {
+        self.verified.lock().unwrap().insert(key, Instant::now());
+    }
+}
+
+pub fn is_valid_password(password: &str) -> bool {
+    password.chars().count() >= MIN_PASSWORD_LENGTH
+}
+
+// A random session token, and the hash it is stored under
+pub fn new_token() -> (String, String) {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let token: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    let hash = token_hash(&token);
+    (token, hash)
+}
+
+pub fn token_hash(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+// Takes the session token from a bearer `Authorization` header, falling back
+// to the session cookie, which is all an <audio> element can send. ListenBrainz
+// clients send theirs as `Token <token>`.
+pub fn session_token(authorization: Option<String>, cookie: Option<String>) -> Option<String> {
+    authorization
+        .and_then(|h| h.strip_prefix("Bearer ").or_else(|| h.strip_prefix("Token ")).map(|t| t.trim().to_owned()))
+        .or(cookie)
+        .filter(|t| !t.is_empty())
+}
+
+// The user for a session token or an API token, which doesn't expire
+pub async fn user_for_token(token: &str, client: &deadpool_postgres::Client) -> Result<Option<User>, Error> {
+    let stmt = client.prepare("
+        SELECT U.id, U.username, U.is_admin
+        FROM session S
+        INNER JOIN \"user\" U ON U.id = S.user_id
+        WHERE S.token_hash = $1 AND S.expires_at > now()
+        UNION ALL
+        SELECT U.id, U.username, U.is_admin
+        FROM api_token A
+        INNER JOIN \"user\" U ON U.id = A.user_id
+        WHERE A.token_hash = $1
+    ").await?;
+    let row = client.query_opt(&stmt, &[&token_hash(token)]).await?;
+    Ok(row.as_ref().map(User::from))
+}
+
+// The user with this username and password, for logins and Subsonic clients.
+// Hashing is slow by design, so it's done off the executor.
+pub async fn user_for_password(username: &str, password: &str, cache: &PasswordCache, client: &deadpool_postgres::Client) -> Result<Option<User>, Error> {
+    let stmt = client.prepare("
+        SELECT id, username, is_admin, password_hash
+        FROM \"user\"
+        WHERE username = $1
+    ").await?;
+    let row = match client.query_opt(&stmt, &[&username]).await? {
+        Some(row) => row,
+        None => return Ok(None),
+    };
+
+    let hash: String = row.get(3);
+    let key = PasswordCache::key(&hash, password);
+    if !cache.contains(&key) {
+        let password = password.to_owned();
+        let valid = tokio::task::spawn_blocking(move || verify_password(&hash, &password)).await.unwrap_or(false);
+        if !valid {
+            return Ok(None);
+        }
+        cache.insert(key);
+    }
+    Ok(Some(User::from(&row)))
+}
+
+// Creates an admin from ADMIN_USERNAME and ADMIN_PASSWORD when there are no
+// users yet, so a fresh instance can be logged in to. Playlists from before
+// accounts existed are given to them.
+pub async fn create_initial_admin(db: &DB) -> Result<(), Error> {
+    let mut client = db.get().await?;
+    if db.count_rows("\"user\"", &client).await?.unwrap_or(0) > 0 {
+        return Ok(());
+    }
+
+    let (username, password) = match (env::var("ADMIN_USERNAME"), env::var("ADMIN_PASSWORD")) {
+        (Ok(u), Ok(p)) => (u, p),
+        _ => {
+            eprintln!("No users exist. Set ADMIN_USERNAME and ADMIN_PASSWORD to create an admin.");
+            return Ok(());
+        }
+    };
+    if !is_valid_password(&password) {
+        eprintln!("ADMIN_PASSWORD must be at least {} characters", MIN_PASSWORD_LENGTH);
+        return Ok(());
+    }
+
+    let tx = client.transaction().await?;
+    let stmt = tx.prepare("
+        INSERT INTO \"user\" (username, password_hash, is_admin)
+        VALUES ($1, $2, true)
+        RETURNING id
+    ").await?;
+    let id: i32 = tx.query_one(&stmt, &[&username, &hash_password(&password).await?]).await?.get(0);
+    tx.execute("UPDATE playlist SET owner_id = $1 WHERE owner_id IS NULL", &[&id]).await?;
+    tx.commit().await?;
+
+    println!("Created admin {}", username);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn password_round_trip() {
+        let hash = hash_password("correct horse").await.unwrap();
+        assert!(verify_password(&hash, "correct horse"));
+        assert!(!verify_password(&hash, "battery staple"));
+        assert!(!verify_password("not a hash", "correct horse"));
+    }
+
+    #[test]
+    fn password_cache_keyed_on_hash() {
+        let cache = PasswordCache::default();
+        cache.insert(PasswordCache::key("old hash", "correct horse"));
+        assert!(cache.contains(&PasswordCache::key("old hash", "correct horse")));
+        assert!(!cache.contains(&PasswordCache::key("new hash", "correct horse")));
+        assert!(!cache.contains(&PasswordCache::key("old hash", "battery staple")));
+    }
+
+    #[test]
+    fn token_from_header_or_cookie() {
+        assert_eq!(session_token(Some("Bearer abc".to_owned()), Some("def".to_owned())).as_deref(), Some("abc"));
+        assert_eq!(session_token(Some("Token abc".to_owned()), None).as_deref(), Some("abc"));
+        assert_eq!(session_token(Some("Basic abc".to_owned()), Some("def".to_owned())).as_deref(), Some("def"));
+        assert_eq!(session_token(None, Some("".to_owned())), None);
+    }
+
+    #[test]
+    fn tokens_are_unique() {
+        let (a, a_hash) = new_token();
+        let (b, _) = new_token();
+        assert_ne!(a, b);
+        assert_eq!(a.len(), 64);
+        assert_eq!(a_hash, token_hash(&a));
+    }
+}
@@ -0,0 +1,49 @@
+use std::env;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone)]
+pub struct Config {
+    music_root: PathBuf,
+    pub transcode_cache_dir: PathBuf,
+    pub transcode_cache_max_bytes: u64,
+    // Where listens are forwarded to
+    pub listenbrainz_url: String,
+    // Where the importer stores artwork. Without it, artwork is served from
+    // where providers host it.
+    pub image_store_dir: Option<PathBuf>,
+}
+
+impl Config {
+    pub fn from_env() -> Config {
+        let music_root = env::var("MUSIC_DIR_ROOT").expect("MUSIC_DIR_ROOT environment variable not set");
+        let transcode_cache_dir = env::var("TRANSCODE_CACHE_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| env::temp_dir().join("doplr-transcodes"));
+        let transcode_cache_max_bytes = env::var("TRANSCODE_CACHE_SIZE_MB")
+            .map(|s| u64::from_str_radix(&s, 10).expect("Failed to parse transcode cache size"))
+            .unwrap_or(2048) * 1024 * 1024;
+        let listenbrainz_url = env::var("LISTENBRAINZ_URL")
+            .unwrap_or_else(|_| "https://api.listenbrainz.org".to_owned());
+        let image_store_dir = env::var("IMAGE_STORE_DIR").ok().map(PathBuf::from);
+
+        Config {
+            music_root: PathBuf::from(music_root),
+            transcode_cache_dir,
+            transcode_cache_max_bytes,
+            listenbrainz_url,
+            image_store_dir,
+        }
+    }
+
+    // Track file locations are stored relative to the music root, with a
+    // leading slash.
+    pub fn music_path(&self, file_location: &str) -> PathBuf {
+        self.music_root.join(file_location.trim_start_matches('/'))
+    }
+
+    // The reverse of music_path, for paths under the music root
+    pub fn file_location(&self, path: &Path) -> Option<String> {
+        let relative = path.strip_prefix(&self.music_root).ok()?;
+        Some(format!("/{}", relative.to_str()?))
+    }
+}
@@ -4,10 +4,12 @@ use deadpool_postgres::{Config, Pool};
 use tokio_postgres::NoTls;
 
 use crate::Error;
+use crate::auth::PasswordCache;
 
 #[derive(Clone)]
 pub struct DB {
     pool: Pool,
+    passwords: PasswordCache,
 }
 
 impl DB {
@@ -35,9 +37,14 @@ impl DB {
 
         Ok(DB {
             pool,
+            passwords: PasswordCache::default(),
         })
     }
 
+    pub fn passwords(&self) -> &PasswordCache {
+        &self.passwords
+    }
+
     pub async fn get(&self) -> Result<deadpool_postgres::Client, Error> {
         self.pool.get().await.map_err(Error::from)
     }
@@ -53,3 +60,20 @@ impl DB {
         }
     }
 }
+
+// Matches `s` literally in a LIKE pattern
+pub fn escape_like(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn like_wildcards_escaped() {
+        assert_eq!(escape_like("100% pure_"), "100\\% pure\\_");
+        assert_eq!(escape_like("a\\b"), "a\\\\b");
+        assert_eq!(escape_like("plain"), "plain");
+    }
+}
@@ -1,10 +1,14 @@
 use deadpool_postgres::PoolError;
 use warp::Rejection;
+use warp::http::StatusCode;
 
 #[derive(Debug)]
 pub enum Error {
     DBError(tokio_postgres::Error),
     DBPoolError(PoolError),
+    IOError(std::io::Error),
+    HashError(argon2::Error),
+    ImageError(image::ImageError),
 }
 
 impl warp::reject::Reject for Error {}
@@ -15,6 +19,9 @@ impl std::fmt::Display for Error {
         match self {
             DBError(_) => write!(fmt, "postgres database error"),
             DBPoolError(_) => write!(fmt, "postgres pool error"),
+            IOError(_) => write!(fmt, "io error"),
+            HashError(_) => write!(fmt, "password hashing error"),
+            ImageError(_) => write!(fmt, "image error"),
         }
     }
 }
@@ -25,6 +32,9 @@ impl std::error::Error for Error {
         match self {
             DBError(e) => Some(e),
             DBPoolError(e) => Some(e),
+            IOError(e) => Some(e),
+            HashError(e) => Some(e),
+            ImageError(e) => Some(e),
         }
     }
 }
@@ -46,3 +56,41 @@ impl From<PoolError> for Error {
         Error::DBPoolError(e)
     }
 }
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::IOError(e)
+    }
+}
+
+impl From<argon2::Error> for Error {
+    fn from(e: argon2::Error) -> Self {
+        Error::HashError(e)
+    }
+}
+
+impl From<image::ImageError> for Error {
+    fn from(e: image::ImageError) -> Self {
+        Error::ImageError(e)
+    }
+}
+
+// Rejects requests without a valid session
+#[derive(Debug)]
+pub struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+// Turns rejections the API raises itself into responses. Anything else is
+// left to warp.
+pub async fn handle_rejection(r: Rejection) -> Result<Box<dyn warp::Reply>, Rejection> {
+    // Checked first, since a Subsonic request is also rejected by the routes
+    // that need a session
+    if let Some(failure) = r.find::<crate::subsonic::Failure>() {
+        return Ok(Box::new(failure.response()));
+    }
+    if r.find::<Unauthorized>().is_some() {
+        return Ok(Box::new(warp::reply::with_status(warp::reply(), StatusCode::UNAUTHORIZED)));
+    }
+    Err(r)
+}
@@ -1,16 +1,19 @@
 use warp::Filter;
 
+use crate::config::Config;
 use crate::db::DB;
 use crate::handlers::albums::{
     get_album_with_id,
     get_albums,
 };
+use crate::handlers::images::get_album_cover;
 
-pub(super) fn albums_filters(db: DB)
+pub(super) fn albums_filters(db: DB, config: Config)
     -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
 {
     get_albums_filter(db.clone())
-        .or(get_album_with_id_filter(db))
+        .or(get_album_with_id_filter(db.clone()))
+        .or(get_album_cover_filter(db, config))
 }
 
 fn get_albums_filter(db: DB)
@@ -18,6 +21,7 @@ fn get_albums_filter(db: DB)
 {
     warp::path!("albums")
         .and(warp::get())
+        .and(super::auth::require_user(db.clone()))
         .and(warp::query::<super::RelationsOption>())
         .and(warp::query::<super::PaginationOptions>())
         .and(super::db_filter(db))
@@ -29,7 +33,21 @@ fn get_album_with_id_filter(db: DB)
 {
     warp::path!("albums" / i32)
         .and(warp::get())
+        .and(super::auth::require_user(db.clone()))
         .and(warp::query::<super::RelationsOption>())
         .and(super::db_filter(db))
         .and_then(get_album_with_id)
 }
+
+fn get_album_cover_filter(db: DB, config: Config)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+{
+    warp::path!("albums" / i32 / "cover")
+        .and(warp::get())
+        .and(super::auth::require_user(db.clone()))
+        .and(warp::query::<super::ImageOptions>())
+        .and(warp::header::headers_cloned())
+        .and(super::db_filter(db))
+        .and(super::config_filter(config))
+        .and_then(get_album_cover)
+}
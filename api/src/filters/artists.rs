@@ -1,16 +1,19 @@
 use warp::Filter;
 
+use crate::config::Config;
 use crate::db::DB;
 use crate::handlers::artists::{
     get_artist_with_id,
     get_artists,
 };
+use crate::handlers::images::get_artist_image;
 
-pub(super) fn artists_filters(db: DB)
+pub(super) fn artists_filters(db: DB, config: Config)
     -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
 {
     get_artists_filter(db.clone())
-        .or(get_artist_with_id_filter(db))
+        .or(get_artist_with_id_filter(db.clone()))
+        .or(get_artist_image_filter(db, config))
 }
 
 fn get_artists_filter(db: DB)
@@ -18,6 +21,7 @@ fn get_artists_filter(db: DB)
 {
     warp::path!("artists")
         .and(warp::get())
+        .and(super::auth::require_user(db.clone()))
         .and(warp::query::<super::PaginationOptions>())
         .and(super::db_filter(db))
         .and_then(get_artists)
@@ -28,7 +32,21 @@ fn get_artist_with_id_filter(db: DB)
 {
     warp::path!("artists" / i32)
         .and(warp::get())
+        .and(super::auth::require_user(db.clone()))
         .and(warp::query::<super::RelationsOption>())
         .and(super::db_filter(db))
         .and_then(get_artist_with_id)
 }
+
+fn get_artist_image_filter(db: DB, config: Config)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+{
+    warp::path!("artists" / i32 / "image")
+        .and(warp::get())
+        .and(super::auth::require_user(db.clone()))
+        .and(warp::query::<super::ImageOptions>())
+        .and(warp::header::headers_cloned())
+        .and(super::db_filter(db))
+        .and(super::config_filter(config))
+        .and_then(get_artist_image)
+}
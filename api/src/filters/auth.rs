@@ -0,0 +1,114 @@
+use warp::Filter;
+
+use crate::auth::{User, SESSION_COOKIE};
+use crate::db::DB;
+use crate::handlers::auth::{
+    authenticate,
+    login,
+    logout,
+    get_current_user,
+    create_api_token,
+    delete_api_token,
+    get_users,
+    create_user,
+};
+
+pub(super) fn auth_filters(db: DB)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+{
+    login_filter(db.clone())
+        .or(logout_filter(db.clone()))
+        .or(get_current_user_filter(db.clone()))
+        .or(create_api_token_filter(db.clone()))
+        .or(delete_api_token_filter(db.clone()))
+        .or(get_users_filter(db.clone()))
+        .or(create_user_filter(db))
+}
+
+// Extracts the logged in user, rejecting the request if there isn't one
+pub(super) fn with_user(db: DB)
+    -> impl Filter<Extract = (User, ), Error = warp::Rejection> + Clone
+{
+    warp::header::optional::<String>("authorization")
+        .and(warp::cookie::optional(SESSION_COOKIE))
+        .and(super::db_filter(db))
+        .and_then(authenticate)
+}
+
+// Like with_user, for routes that don't need to know who the user is
+pub(super) fn require_user(db: DB)
+    -> impl Filter<Extract = (), Error = warp::Rejection> + Clone
+{
+    with_user(db).map(|_: User| ()).untuple_one()
+}
+
+fn login_filter(db: DB)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+{
+    warp::path!("auth" / "login")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(super::db_filter(db))
+        .and_then(login)
+}
+
+fn logout_filter(db: DB)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+{
+    warp::path!("auth" / "logout")
+        .and(warp::post())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::cookie::optional(SESSION_COOKIE))
+        .and(super::db_filter(db))
+        .and_then(logout)
+}
+
+fn get_current_user_filter(db: DB)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+{
+    warp::path!("auth" / "me")
+        .and(warp::get())
+        .and(with_user(db))
+        .and_then(get_current_user)
+}
+
+fn create_api_token_filter(db: DB)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+{
+    warp::path!("auth" / "me" / "api-token")
+        .and(warp::post())
+        .and(with_user(db.clone()))
+        .and(super::db_filter(db))
+        .and_then(create_api_token)
+}
+
+fn delete_api_token_filter(db: DB)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+{
+    warp::path!("auth" / "me" / "api-token")
+        .and(warp::delete())
+        .and(with_user(db.clone()))
+        .and(super::db_filter(db))
+        .and_then(delete_api_token)
+}
+
+fn get_users_filter(db: DB)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+{
+    warp::path!("users")
+        .and(warp::get())
+        .and(with_user(db.clone()))
+        .and(super::db_filter(db))
+        .and_then(get_users)
+}
+
+fn create_user_filter(db: DB)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+{
+    warp::path!("users")
+        .and(warp::post())
+        .and(with_user(db.clone()))
+        .and(warp::body::json())
+        .and(super::db_filter(db))
+        .and_then(create_user)
+}
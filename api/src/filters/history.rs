@@ -0,0 +1,103 @@
+use chrono::{DateTime, Utc};
+use warp::Filter;
+
+use crate::db::DB;
+use crate::handlers::history::{
+    scrobble,
+    get_history,
+    get_top_tracks,
+    get_top_albums,
+    get_top_artists,
+};
+
+pub(super) fn history_filters(db: DB)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+{
+    scrobble_filter(db.clone())
+        .or(get_history_filter(db.clone()))
+        .or(get_top_tracks_filter(db.clone()))
+        .or(get_top_albums_filter(db.clone()))
+        .or(get_top_artists_filter(db))
+}
+
+fn scrobble_filter(db: DB)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+{
+    warp::path!("tracks" / i32 / "scrobble")
+        .and(warp::post())
+        .and(super::auth::with_user(db.clone()))
+        .and(warp::body::json())
+        .and(super::db_filter(db))
+        .and_then(scrobble)
+}
+
+fn get_history_filter(db: DB)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+{
+    warp::path!("history")
+        .and(warp::get())
+        .and(super::auth::with_user(db.clone()))
+        .and(warp::query::<WindowOptions>())
+        .and(warp::query::<super::PaginationOptions>())
+        .and(super::db_filter(db))
+        .and_then(get_history)
+}
+
+fn get_top_tracks_filter(db: DB)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+{
+    warp::path!("stats" / "top-tracks")
+        .and(warp::get())
+        .and(super::auth::with_user(db.clone()))
+        .and(warp::query::<WindowOptions>())
+        .and(warp::query::<StatsOptions>())
+        .and(super::db_filter(db))
+        .and_then(get_top_tracks)
+}
+
+fn get_top_albums_filter(db: DB)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+{
+    warp::path!("stats" / "top-albums")
+        .and(warp::get())
+        .and(super::auth::with_user(db.clone()))
+        .and(warp::query::<WindowOptions>())
+        .and(warp::query::<StatsOptions>())
+        .and(super::db_filter(db))
+        .and_then(get_top_albums)
+}
+
+fn get_top_artists_filter(db: DB)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+{
+    warp::path!("stats" / "top-artists")
+        .and(warp::get())
+        .and(super::auth::with_user(db.clone()))
+        .and(warp::query::<WindowOptions>())
+        .and(warp::query::<StatsOptions>())
+        .and(super::db_filter(db))
+        .and_then(get_top_artists)
+}
+
+// A time window as RFC 3339 timestamps, e.g. from=2020-01-01T00:00:00Z.
+// Either end can be left open.
+#[derive(Debug, Deserialize)]
+pub struct WindowOptions {
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    // Only the logged in user's plays
+    Me,
+    // Everyone's plays
+    All,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StatsOptions {
+    pub limit: Option<i64>,
+    pub scope: Option<Scope>,
+}
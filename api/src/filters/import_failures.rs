@@ -0,0 +1,32 @@
+use warp::Filter;
+
+use crate::db::DB;
+use crate::handlers::import_failures::{get_import_failures, retry_import_failure};
+
+pub(super) fn import_failures_filters(db: DB)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+{
+    get_import_failures_filter(db.clone())
+        .or(retry_import_failure_filter(db))
+}
+
+fn get_import_failures_filter(db: DB)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+{
+    warp::path!("import-failures")
+        .and(warp::get())
+        .and(super::auth::with_user(db.clone()))
+        .and(warp::query::<super::PaginationOptions>())
+        .and(super::db_filter(db))
+        .and_then(get_import_failures)
+}
+
+fn retry_import_failure_filter(db: DB)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+{
+    warp::path!("import-failures" / i32 / "retry")
+        .and(warp::post())
+        .and(super::auth::with_user(db.clone()))
+        .and(super::db_filter(db))
+        .and_then(retry_import_failure)
+}
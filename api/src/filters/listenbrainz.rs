@@ -0,0 +1,60 @@
+use warp::Filter;
+
+use crate::db::DB;
+use crate::handlers::listenbrainz::{
+    submit_listens,
+    validate_token,
+    set_listenbrainz_token,
+};
+
+// Payloads can hold up to 1000 listens
+const MAX_SUBMISSION_BYTES: u64 = 10 * 1024 * 1024;
+
+pub(super) fn listenbrainz_filters(db: DB)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+{
+    submit_listens_filter(db.clone())
+        .or(validate_token_filter(db.clone()))
+        .or(set_listenbrainz_token_filter(db))
+}
+
+// The subset of the ListenBrainz API scrobbling clients use. Clients
+// authenticate with `Authorization: Token <session token>`.
+fn submit_listens_filter(db: DB)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+{
+    warp::path!("1" / "submit-listens")
+        .and(warp::post())
+        .and(super::auth::with_user(db.clone()))
+        .and(warp::body::content_length_limit(MAX_SUBMISSION_BYTES))
+        .and(warp::body::json())
+        .and(super::db_filter(db))
+        .and_then(submit_listens)
+}
+
+fn validate_token_filter(db: DB)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+{
+    warp::path!("1" / "validate-token")
+        .and(warp::get())
+        .and(warp::query::<ValidateTokenOptions>())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(super::db_filter(db))
+        .and_then(validate_token)
+}
+
+fn set_listenbrainz_token_filter(db: DB)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+{
+    warp::path!("auth" / "me" / "listenbrainz")
+        .and(warp::put())
+        .and(super::auth::with_user(db.clone()))
+        .and(warp::body::json())
+        .and(super::db_filter(db))
+        .and_then(set_listenbrainz_token)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ValidateTokenOptions {
+    pub token: Option<String>,
+}
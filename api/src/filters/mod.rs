@@ -7,16 +7,31 @@ use warp::Filter;
 
 pub mod albums;
 pub mod artists;
+pub mod auth;
+pub mod history;
+pub mod import_failures;
+pub mod listenbrainz;
 pub mod playlists;
+pub mod search;
+pub mod subsonic;
 pub mod tracks;
 
-pub fn build(db: crate::db::DB)
+pub fn build(db: crate::db::DB, config: crate::config::Config)
     -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
 {
-    albums::albums_filters(db.clone())
-        .or(artists::artists_filters(db.clone()))
-        .or(tracks::tracks_filters(db.clone()))
-        .or(playlists::playlists_filters(db))
+    // Every route but logging in needs a user. Each filter authenticates
+    // after matching its path, so unknown paths are still a 404 rather than
+    // a 401. The Subsonic API does its own authentication.
+    auth::auth_filters(db.clone())
+        .or(playlists::playlists_filters(db.clone(), config.clone()))
+        .or(tracks::tracks_filters(db.clone(), config.clone()))
+        .or(history::history_filters(db.clone()))
+        .or(listenbrainz::listenbrainz_filters(db.clone()))
+        .or(import_failures::import_failures_filters(db.clone()))
+        .or(subsonic::subsonic_filters(db.clone(), config.clone()))
+        .or(albums::albums_filters(db.clone(), config.clone()))
+        .or(artists::artists_filters(db.clone(), config.clone()))
+        .or(search::search_filters(db))
 }
 
 fn db_filter(db: crate::db::DB)
@@ -25,6 +40,12 @@ fn db_filter(db: crate::db::DB)
     warp::any().map(move || db.clone())
 }
 
+fn config_filter(config: crate::config::Config)
+    -> impl Filter<Extract = (crate::config::Config, ), Error = std::convert::Infallible> + Clone
+{
+    warp::any().map(move || config.clone())
+}
+
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Relation {
     Albums,
@@ -78,3 +99,9 @@ pub struct PaginationOptions {
     pub limit: Option<i64>,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct ImageOptions {
+    // In pixels along the longest side
+    pub size: Option<u32>,
+}
+
@@ -1,20 +1,33 @@
 use warp::Filter;
 
+use crate::config::Config;
 use crate::db::DB;
 use crate::handlers::playlists::{
     get_playlists,
     create_playlist,
     get_playlist_with_id,
     add_to_playlist,
+    update_playlist,
+    delete_playlist,
+    remove_from_playlist,
+    move_playlist_track,
+    export_playlist,
+    import_playlist,
 };
 
-pub(super) fn playlists_filters(db: DB)
+pub(super) fn playlists_filters(db: DB, config: Config)
     -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
 {
     get_playlists_filter(db.clone())
+        .or(import_playlist_filter(db.clone(), config))
+        .or(export_playlist_filter(db.clone()))
         .or(create_playlist_filter(db.clone()))
         .or(get_playlist_with_id_filter(db.clone()))
-        .or(add_to_playlist_filter(db))
+        .or(add_to_playlist_filter(db.clone()))
+        .or(update_playlist_filter(db.clone()))
+        .or(delete_playlist_filter(db.clone()))
+        .or(remove_from_playlist_filter(db.clone()))
+        .or(move_playlist_track_filter(db))
 }
 
 fn get_playlists_filter(db: DB)
@@ -22,6 +35,7 @@ fn get_playlists_filter(db: DB)
 {
     warp::path!("playlists")
         .and(warp::get())
+        .and(super::auth::with_user(db.clone()))
         .and(super::db_filter(db))
         .and_then(get_playlists)
 }
@@ -31,6 +45,7 @@ fn create_playlist_filter(db: DB)
 {
     warp::path!("playlists")
         .and(warp::post())
+        .and(super::auth::with_user(db.clone()))
         .and(warp::body::json())
         .and(super::db_filter(db))
         .and_then(create_playlist)
@@ -41,6 +56,7 @@ fn get_playlist_with_id_filter(db: DB)
 {
     warp::path!("playlists" / i32)
         .and(warp::get())
+        .and(super::auth::with_user(db.clone()))
         .and(warp::query::<super::RelationsOption>())
         .and(super::db_filter(db))
         .and_then(get_playlist_with_id)
@@ -51,7 +67,89 @@ fn add_to_playlist_filter(db: DB)
 {
     warp::path!("playlists" / i32)
         .and(warp::post())
+        .and(super::auth::with_user(db.clone()))
         .and(warp::body::json())
         .and(super::db_filter(db))
         .and_then(add_to_playlist)
 }
+
+fn update_playlist_filter(db: DB)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+{
+    warp::path!("playlists" / i32)
+        .and(warp::patch())
+        .and(super::auth::with_user(db.clone()))
+        .and(warp::body::json())
+        .and(super::db_filter(db))
+        .and_then(update_playlist)
+}
+
+fn delete_playlist_filter(db: DB)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+{
+    warp::path!("playlists" / i32)
+        .and(warp::delete())
+        .and(super::auth::with_user(db.clone()))
+        .and(super::db_filter(db))
+        .and_then(delete_playlist)
+}
+
+fn remove_from_playlist_filter(db: DB)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+{
+    warp::path!("playlists" / i32 / "tracks" / i32)
+        .and(warp::delete())
+        .and(super::auth::with_user(db.clone()))
+        .and(super::db_filter(db))
+        .and_then(remove_from_playlist)
+}
+
+fn move_playlist_track_filter(db: DB)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+{
+    warp::path!("playlists" / i32 / "tracks" / i32)
+        .and(warp::patch())
+        .and(super::auth::with_user(db.clone()))
+        .and(warp::body::json())
+        .and(super::db_filter(db))
+        .and_then(move_playlist_track)
+}
+
+fn export_playlist_filter(db: DB)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+{
+    warp::path!("playlists" / i32 / "export")
+        .and(warp::get())
+        .and(super::auth::with_user(db.clone()))
+        .and(warp::query::<ExportOptions>())
+        .and(super::db_filter(db))
+        .and_then(export_playlist)
+}
+
+fn import_playlist_filter(db: DB, config: Config)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+{
+    warp::path!("playlists" / "import")
+        .and(warp::post())
+        .and(super::auth::with_user(db.clone()))
+        .and(warp::query::<ImportOptions>())
+        .and(warp::body::content_length_limit(MAX_IMPORT_BYTES))
+        .and(warp::body::bytes())
+        .and(super::db_filter(db))
+        .and(super::config_filter(config))
+        .and_then(import_playlist)
+}
+
+// Big enough for playlists of tens of thousands of tracks
+const MAX_IMPORT_BYTES: u64 = 16 * 1024 * 1024;
+
+#[derive(Debug, Deserialize)]
+pub struct ExportOptions {
+    pub format: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImportOptions {
+    pub format: String,
+    pub name: Option<String>,
+}
@@ -0,0 +1,86 @@
+use std::collections::BTreeSet;
+use std::fmt;
+
+use serde::{Deserialize, Deserializer};
+use serde::de::{self, Visitor};
+use warp::Filter;
+
+use crate::db::DB;
+use crate::handlers::search::search;
+
+pub(super) fn search_filters(db: DB)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+{
+    warp::path!("search")
+        .and(warp::get())
+        .and(super::auth::require_user(db.clone()))
+        .and(warp::query::<SearchOptions>())
+        .and(warp::query::<super::PaginationOptions>())
+        .and(super::db_filter(db))
+        .and_then(search)
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SearchType {
+    Artist,
+    Album,
+    Track,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SearchOptions {
+    pub q: String,
+    #[serde(rename = "type", default, deserialize_with = "deserialize_search_types")]
+    pub types: Option<BTreeSet<SearchType>>,
+}
+
+fn deserialize_search_types<'de, D>(deserializer: D) -> Result<Option<BTreeSet<SearchType>>, D::Error>
+    where
+        D: Deserializer<'de>,
+{
+    struct SearchTypesVisitor;
+
+    impl<'de> Visitor<'de> for SearchTypesVisitor {
+        type Value = Option<BTreeSet<SearchType>>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("any combination of `artist`, `album`, and `track`")
+        }
+
+        fn visit_str<E>(self, value: &str) -> Result<Option<BTreeSet<SearchType>>, E>
+        where
+            E: de::Error,
+        {
+            let mut set = BTreeSet::new();
+            for ty in value.split(",") {
+                match ty.trim() {
+                    "artist" => { set.insert(SearchType::Artist); }
+                    "album" => { set.insert(SearchType::Album); }
+                    "track" => { set.insert(SearchType::Track); }
+                    other => return Err(E::invalid_value(de::Unexpected::Str(other), &self)),
+                }
+            }
+            Ok(Some(set))
+        }
+    }
+
+    deserializer.deserialize_str(SearchTypesVisitor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn search_types() {
+        let opts: SearchOptions = serde_json::from_str(r#"{"q": "a", "type": "artist, track"}"#).unwrap();
+        assert_eq!(opts.types, Some(vec![SearchType::Artist, SearchType::Track].into_iter().collect()));
+        let opts: SearchOptions = serde_json::from_str(r#"{"q": "a"}"#).unwrap();
+        assert_eq!(opts.types, None);
+    }
+
+    #[test]
+    fn unknown_search_type_rejected() {
+        assert!(serde_json::from_str::<SearchOptions>(r#"{"q": "a", "type": "artist,playlist"}"#).is_err());
+    }
+}
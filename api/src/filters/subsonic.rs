@@ -0,0 +1,199 @@
+use warp::Filter;
+
+use crate::config::Config;
+use crate::db::DB;
+use crate::handlers::subsonic::{
+    authenticate,
+    get_open_subsonic_extensions,
+    ping,
+    get_license,
+    get_artists,
+    get_artist,
+    get_album,
+    get_song,
+    stream,
+    get_cover_art,
+    get_playlists,
+    get_playlist,
+    create_playlist,
+    update_playlist,
+    search3,
+};
+use crate::subsonic::Context;
+
+// The Subsonic API, for existing mobile clients. Every endpoint is at
+// /rest/<name> and /rest/<name>.view, takes its parameters in the query
+// string, and answers in XML unless asked for JSON with f=json.
+pub(super) fn subsonic_filters(db: DB, config: Config)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+{
+    get_open_subsonic_extensions_filter()
+        .or(ping_filter(db.clone()))
+        .or(get_license_filter(db.clone()))
+        .or(get_artists_filter(db.clone()))
+        .or(get_artist_filter(db.clone()))
+        .or(get_album_filter(db.clone()))
+        .or(get_song_filter(db.clone()))
+        .or(stream_filter(db.clone(), config.clone()))
+        .or(get_cover_art_filter(db.clone(), config))
+        .or(get_playlists_filter(db.clone()))
+        .or(get_playlist_filter(db.clone()))
+        .or(create_playlist_filter(db.clone()))
+        .or(update_playlist_filter(db.clone()))
+        .or(search3_filter(db))
+}
+
+// Matches /rest/<name> and /rest/<name>.view, by GET or POST
+fn endpoint(name: &'static str)
+    -> impl Filter<Extract = (), Error = warp::Rejection> + Clone
+{
+    warp::path("rest")
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(warp::get().or(warp::post()).unify())
+        .and_then(move |endpoint: String| async move {
+            if endpoint == name || endpoint.strip_suffix(".view") == Some(name) {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        })
+        .untuple_one()
+}
+
+// Extracts the authenticated request. Requests that can't be authenticated
+// are rejected with a Subsonic error, see error::handle_rejection.
+fn with_context(db: DB)
+    -> impl Filter<Extract = (Context, ), Error = warp::Rejection> + Clone
+{
+    warp::query::<Vec<(String, String)>>()
+        .and(super::db_filter(db))
+        .and_then(authenticate)
+}
+
+fn get_open_subsonic_extensions_filter()
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+{
+    endpoint("getOpenSubsonicExtensions")
+        .and(warp::query::<Vec<(String, String)>>())
+        .and_then(get_open_subsonic_extensions)
+}
+
+fn ping_filter(db: DB)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+{
+    endpoint("ping")
+        .and(with_context(db))
+        .and_then(ping)
+}
+
+fn get_license_filter(db: DB)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+{
+    endpoint("getLicense")
+        .and(with_context(db))
+        .and_then(get_license)
+}
+
+fn get_artists_filter(db: DB)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+{
+    endpoint("getArtists")
+        .and(with_context(db.clone()))
+        .and(super::db_filter(db))
+        .and_then(get_artists)
+}
+
+fn get_artist_filter(db: DB)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+{
+    endpoint("getArtist")
+        .and(with_context(db.clone()))
+        .and(super::db_filter(db))
+        .and_then(get_artist)
+}
+
+fn get_album_filter(db: DB)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+{
+    endpoint("getAlbum")
+        .and(with_context(db.clone()))
+        .and(super::db_filter(db))
+        .and_then(get_album)
+}
+
+fn get_song_filter(db: DB)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+{
+    endpoint("getSong")
+        .and(with_context(db.clone()))
+        .and(super::db_filter(db))
+        .and_then(get_song)
+}
+
+fn stream_filter(db: DB, config: Config)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+{
+    endpoint("stream")
+        .and(with_context(db.clone()))
+        .and(warp::header::headers_cloned())
+        .and(super::db_filter(db))
+        .and(super::config_filter(config))
+        .and_then(stream)
+}
+
+fn get_cover_art_filter(db: DB, config: Config)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+{
+    endpoint("getCoverArt")
+        .and(with_context(db.clone()))
+        .and(warp::header::headers_cloned())
+        .and(super::db_filter(db))
+        .and(super::config_filter(config))
+        .and_then(get_cover_art)
+}
+
+fn get_playlists_filter(db: DB)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+{
+    endpoint("getPlaylists")
+        .and(with_context(db.clone()))
+        .and(super::db_filter(db))
+        .and_then(get_playlists)
+}
+
+fn get_playlist_filter(db: DB)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+{
+    endpoint("getPlaylist")
+        .and(with_context(db.clone()))
+        .and(super::db_filter(db))
+        .and_then(get_playlist)
+}
+
+fn create_playlist_filter(db: DB)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+{
+    endpoint("createPlaylist")
+        .and(with_context(db.clone()))
+        .and(super::db_filter(db))
+        .and_then(create_playlist)
+}
+
+fn update_playlist_filter(db: DB)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+{
+    endpoint("updatePlaylist")
+        .and(with_context(db.clone()))
+        .and(super::db_filter(db))
+        .and_then(update_playlist)
+}
+
+fn search3_filter(db: DB)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+{
+    endpoint("search3")
+        .and(with_context(db.clone()))
+        .and(super::db_filter(db))
+        .and_then(search3)
+}
@@ -1,16 +1,17 @@
 use warp::Filter;
 
+use crate::config::Config;
 use crate::db::DB;
 use crate::handlers::tracks::{
     get_track_with_id,
     play_track,
 };
 
-pub(super) fn tracks_filters(db: DB)
+pub(super) fn tracks_filters(db: DB, config: Config)
     -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
 {
     get_track_with_id_filter(db.clone())
-        .or(play_track_filter(db))
+        .or(play_track_filter(db, config))
 }
 
 fn get_track_with_id_filter(db: DB)
@@ -18,15 +19,26 @@ fn get_track_with_id_filter(db: DB)
 {
     warp::path!("tracks" / i32)
         .and(warp::get())
+        .and(super::auth::require_user(db.clone()))
         .and(super::db_filter(db))
         .and_then(get_track_with_id)
 }
 
-fn play_track_filter(db: DB)
+fn play_track_filter(db: DB, config: Config)
     -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
 {
     warp::path!("play" / i32)
         .and(warp::get())
+        .and(super::auth::with_user(db.clone()))
+        .and(warp::query::<PlayOptions>())
+        .and(warp::header::headers_cloned())
         .and(super::db_filter(db))
+        .and(super::config_filter(config))
         .and_then(play_track)
 }
+
+#[derive(Debug, Deserialize)]
+pub struct PlayOptions {
+    pub format: Option<String>,
+    pub bitrate: Option<i64>,
+}
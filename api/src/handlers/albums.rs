@@ -3,6 +3,7 @@ use std::collections::BTreeSet;
 use crate::Error;
 use crate::db::DB;
 use crate::filters::{RelationsOption, PaginationOptions};
+use crate::images;
 
 #[derive(Serialize)]
 pub struct Album {
@@ -15,6 +16,17 @@ pub struct Album {
     pub artist: Option<super::artists::Artist>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub tracks: Option<Vec<super::tracks::Track>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub discs: Option<Vec<Disc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub credits: Option<Vec<super::credits::Credit>>,
+}
+
+// The tracks on one disc of an album, in order
+#[derive(Serialize)]
+pub struct Disc {
+    pub disc_number: i32,
+    pub track_ids: Vec<i32>,
 }
 
 // GET /albums(?page=X&limit=Y)
@@ -36,7 +48,9 @@ pub async fn get_albums(rels: RelationsOption, opts: PaginationOptions, db: DB)
     let total_pages = (count as f64 / limit as f64).ceil() as i64;
 
     let rels = rels.relations.unwrap_or(BTreeSet::new());
-    let mut select_fields = vec!["R.id", "R.mbid", "R.title", "R.artist_id", "R.image_url"];
+    let album_image = images::album_image_sql("R");
+    let artist_image = format!("{} as artist_image_url", images::artist_image_sql("A"));
+    let mut select_fields = vec!["R.id", "R.mbid", "R.title", "R.artist_id", album_image.as_str()];
     let mut joins = Vec::new();
     let mut loading_artist = false;
     for rel in rels {
@@ -47,7 +61,7 @@ pub async fn get_albums(rels: RelationsOption, opts: PaginationOptions, db: DB)
                     "A.id",
                     "A.mbid",
                     "A.name",
-                    "A.image_url as artist_image_url",
+                    artist_image.as_str(),
                 ]);
                 joins.extend_from_slice(&[
                     "INNER JOIN artist A ON A.id = R.artist_id",
@@ -78,6 +92,7 @@ pub async fn get_albums(rels: RelationsOption, opts: PaginationOptions, db: DB)
                 name: row.get(7),
                 image_url: row.get(8),
                 albums: None,
+                appears_on: None,
             })
         } else {
             None
@@ -90,10 +105,18 @@ pub async fn get_albums(rels: RelationsOption, opts: PaginationOptions, db: DB)
             image_url: row.get(4),
             artist,
             tracks: None,
+            discs: None,
+            credits: None,
         };
         albums.push(album);
     }
 
+    let ids: Vec<i32> = albums.iter().map(|a| a.id).collect();
+    let mut credits = super::credits::album_credits(&ids, &client).await?;
+    for album in &mut albums {
+        album.credits = credits.remove(&album.id);
+    }
+
     let res = super::PaginatedResponse {
         page,
         count,
@@ -108,7 +131,9 @@ pub async fn get_albums(rels: RelationsOption, opts: PaginationOptions, db: DB)
 pub async fn get_album_with_id(id: i32, rels: RelationsOption, db: DB) -> Result<impl warp::Reply, warp::Rejection> {
     let client = db.get().await?;
     let rels = rels.relations.unwrap_or(BTreeSet::new());
-    let mut select_fields = vec!["A.id", "A.mbid", "A.title", "A.artist_id", "A.image_url"];
+    let album_image = images::album_image_sql("A");
+    let artist_image = format!("{} as artist_image_url", images::artist_image_sql("C"));
+    let mut select_fields = vec!["A.id", "A.mbid", "A.title", "A.artist_id", album_image.as_str()];
     let mut joins = Vec::new();
     let mut loading_artist = false;
     let mut loading_tracks = false;
@@ -121,7 +146,7 @@ pub async fn get_album_with_id(id: i32, rels: RelationsOption, db: DB) -> Result
                     "C.id",
                     "C.mbid",
                     "C.name",
-                    "C.image_url as artist_image_url",
+                    artist_image.as_str(),
                 ]);
                 joins.extend_from_slice(&[
                     "INNER JOIN artist C ON C.id = A.artist_id",
@@ -138,9 +163,11 @@ pub async fn get_album_with_id(id: i32, rels: RelationsOption, db: DB) -> Result
                     "T.duration",
                     "T.file_location",
                     "T.album_id",
+                    "T.disc_number",
                 ]);
+                select_fields.extend_from_slice(super::tracks::REPLAYGAIN_FIELDS);
                 joins.extend_from_slice(&[
-                    "LEFT OUTER JOIN track T ON T.album_id = A.id",
+                    "LEFT OUTER JOIN track T ON T.album_id = A.id AND T.available",
                 ]);
                 loading_tracks = true;
             }
@@ -150,7 +177,8 @@ pub async fn get_album_with_id(id: i32, rels: RelationsOption, db: DB) -> Result
 
     let select_fields = select_fields.as_slice().join(", ");
     let joins = joins.as_slice().join("\n");
-    let q = format!("SELECT {} FROM album A {} WHERE A.id = $1", select_fields, joins);
+    let order = if loading_tracks { "ORDER BY T.disc_number ASC, T.position ASC" } else { "" };
+    let q = format!("SELECT {} FROM album A {} WHERE A.id = $1 {}", select_fields, joins, order);
     let stmt = client.prepare(&q).await.map_err(Error::from)?;
     let rows = client.query(&stmt, &[&id]).await.map_err(Error::from)?;
 
@@ -159,21 +187,23 @@ pub async fn get_album_with_id(id: i32, rels: RelationsOption, db: DB) -> Result
     }
 
     let mut tracks = None;
+    let mut discs = None;
 
     if loading_tracks {
         let mut tracks_vec = Vec::new();
         for row in &rows {
+            // If artist is being loaded, it will always be loaded before tracks
+            let offset = if loading_artist { 4 } else { 0 };
             // If there's no ID, no track record exists on this row, so
             // skip it.
-            if let Err(_) = row.try_get::<'_, _, i32>(9) {
+            if let Err(_) = row.try_get::<'_, _, i32>(5 + offset) {
                 continue;
             }
-            // If artist is being loaded, it will always be loaded before tracks
-            let offset = if loading_artist { 4 } else { 0 };
             let track = crate::handlers::tracks::Track {
                 id: row.get(5 + offset),
                 mbid: row.get(6 + offset),
                 title: row.get(7 + offset),
+                disc_number: row.get(13 + offset),
                 position: row.get(8 + offset),
                 bit_rate: row.get(9 + offset),
                 duration: row.get(10 + offset),
@@ -181,11 +211,31 @@ pub async fn get_album_with_id(id: i32, rels: RelationsOption, db: DB) -> Result
                 album_id: row.get(12 + offset),
                 album: None,
                 artist: None,
+                credits: None,
+                audio: None,
+                replaygain: super::tracks::ReplayGain::from_row(row, 14 + offset),
             };
             tracks_vec.push(track);
         }
 
         if !tracks_vec.is_empty() {
+            // Rows are ordered by disc, so each disc's tracks are together
+            let mut discs_vec: Vec<Disc> = Vec::new();
+            for track in &tracks_vec {
+                match discs_vec.last_mut() {
+                    Some(disc) if disc.disc_number == track.disc_number => disc.track_ids.push(track.id),
+                    _ => discs_vec.push(Disc {
+                        disc_number: track.disc_number,
+                        track_ids: vec![track.id],
+                    }),
+                }
+            }
+            let ids: Vec<i32> = tracks_vec.iter().map(|t| t.id).collect();
+            let mut credits = super::credits::track_credits(&ids, &client).await?;
+            for track in &mut tracks_vec {
+                track.credits = credits.remove(&track.id);
+            }
+            discs = Some(discs_vec);
             tracks = Some(tracks_vec);
         }
     }
@@ -198,6 +248,7 @@ pub async fn get_album_with_id(id: i32, rels: RelationsOption, db: DB) -> Result
             name: row.get(7),
             image_url: row.get(8),
             albums: None,
+            appears_on: None,
         })
     } else { None };
     let album = Album {
@@ -208,6 +259,8 @@ pub async fn get_album_with_id(id: i32, rels: RelationsOption, db: DB) -> Result
         image_url: row.get(4),
         artist,
         tracks,
+        discs,
+        credits: super::credits::album_credits(&[id], &client).await?.remove(&id),
     };
 
     Ok(warp::reply::json(&album))
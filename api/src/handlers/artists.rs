@@ -3,6 +3,7 @@ use std::collections::BTreeSet;
 use crate::Error;
 use crate::db::DB;
 use crate::filters::{RelationsOption, PaginationOptions};
+use crate::images;
 
 #[derive(Serialize)]
 pub struct Artist {
@@ -12,6 +13,9 @@ pub struct Artist {
     pub image_url: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub albums: Option<Vec<super::albums::Album>>,
+    // Albums by other artists with tracks crediting this one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub appears_on: Option<Vec<super::albums::Album>>,
 }
 
 // GET /artists(?page=X&limit=Y)
@@ -31,12 +35,12 @@ pub async fn get_artists(opts: PaginationOptions, db: DB) -> Result<impl warp::R
     };
     let offset = (page - 1) * limit as i64;
     let total_pages = (count as f64 / limit as f64).ceil() as i64;
-    let stmt = client.prepare("
-        SELECT id, mbid, name, image_url
-        FROM artist
-        ORDER BY name ASC
+    let stmt = client.prepare(&format!("
+        SELECT A.id, A.mbid, A.name, {}
+        FROM artist A
+        ORDER BY A.name ASC
         LIMIT $1 OFFSET $2
-    ").await.map_err(Error::from)?;
+    ", images::artist_image_sql("A"))).await.map_err(Error::from)?;
     let rows = client.query(&stmt, &[&limit, &offset]).await.map_err(Error::from)?;
     let mut artists = Vec::new();
 
@@ -47,6 +51,7 @@ pub async fn get_artists(opts: PaginationOptions, db: DB) -> Result<impl warp::R
             name: row.get(2),
             image_url: row.get(3),
             albums: None,
+            appears_on: None,
         };
         artists.push(artist);
     }
@@ -65,7 +70,9 @@ pub async fn get_artists(opts: PaginationOptions, db: DB) -> Result<impl warp::R
 pub async fn get_artist_with_id(id: i32, rels: RelationsOption, db: DB) -> Result<impl warp::Reply, warp::Rejection> {
     let client = db.get().await?;
     let rels = rels.relations.unwrap_or(BTreeSet::new());
-    let mut select_fields = vec!["A.id", "A.mbid", "A.name", "A.image_url"];
+    let artist_image = images::artist_image_sql("A");
+    let album_image = format!("{} as album_image_url", images::album_image_sql("R"));
+    let mut select_fields = vec!["A.id", "A.mbid", "A.name", artist_image.as_str()];
     let mut joins = Vec::new();
 
     let mut loading_albums = false;
@@ -79,10 +86,12 @@ pub async fn get_artist_with_id(id: i32, rels: RelationsOption, db: DB) -> Resul
                     "R.mbid",
                     "R.title",
                     "R.artist_id",
-                    "R.image_url as album_image_url"
+                    album_image.as_str(),
                 ]);
                 joins.extend_from_slice(&[
-                    "LEFT OUTER JOIN album R ON R.artist_id = A.id",
+                    "LEFT OUTER JOIN album R ON R.artist_id = A.id OR R.id IN (
+                        SELECT album_id FROM album_artist WHERE artist_id = A.id
+                    )",
                 ]);
                 loading_albums = true;
             }
@@ -101,6 +110,7 @@ pub async fn get_artist_with_id(id: i32, rels: RelationsOption, db: DB) -> Resul
     }
 
     let mut albums = None;
+    let mut appears_on = None;
 
     if loading_albums {
         let mut albums_vec = Vec::new();
@@ -118,6 +128,8 @@ pub async fn get_artist_with_id(id: i32, rels: RelationsOption, db: DB) -> Resul
                 image_url: row.try_get(8).ok(),
                 artist: None,
                 tracks: None,
+                discs: None,
+                credits: None,
             };
             albums_vec.push(album);
         }
@@ -125,6 +137,34 @@ pub async fn get_artist_with_id(id: i32, rels: RelationsOption, db: DB) -> Resul
         if !albums_vec.is_empty(){
             albums = Some(albums_vec);
         }
+
+        let stmt = client.prepare(&format!("
+            SELECT DISTINCT R.id, R.mbid, R.title, R.artist_id, {}
+            FROM album R
+            INNER JOIN track T ON T.album_id = R.id
+            INNER JOIN track_artist C ON C.track_id = T.id
+            WHERE C.artist_id = $1
+              AND T.available
+              AND R.artist_id <> $1
+              AND NOT EXISTS (SELECT 1 FROM album_artist B WHERE B.album_id = R.id AND B.artist_id = $1)
+            ORDER BY R.title ASC
+        ", images::album_image_sql("R"))).await.map_err(Error::from)?;
+        let rows = client.query(&stmt, &[&id]).await.map_err(Error::from)?;
+        let appears_on_vec: Vec<_> = rows.iter().map(|row| crate::handlers::albums::Album {
+            id: row.get(0),
+            mbid: row.get(1),
+            title: row.get(2),
+            artist_id: row.get(3),
+            image_url: row.get(4),
+            artist: None,
+            tracks: None,
+            discs: None,
+            credits: None,
+        }).collect();
+
+        if !appears_on_vec.is_empty() {
+            appears_on = Some(appears_on_vec);
+        }
     }
 
     let row = &rows[0];
@@ -134,6 +174,7 @@ pub async fn get_artist_with_id(id: i32, rels: RelationsOption, db: DB) -> Resul
         name: row.get(2),
         image_url: row.get(3),
         albums,
+        appears_on,
     };
 
     Ok(warp::reply::json(&artist))
@@ -0,0 +1,167 @@
+use warp::http::StatusCode;
+
+use crate::Error;
+use crate::auth::{self, User};
+use crate::db::DB;
+use crate::error::Unauthorized;
+
+#[derive(Deserialize)]
+pub struct Login {
+    username: String,
+    password: String,
+}
+
+#[derive(Serialize)]
+pub struct Session {
+    token: String,
+    user: User,
+}
+
+#[derive(Serialize)]
+pub struct ApiToken {
+    token: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NewUser {
+    username: String,
+    password: String,
+    #[serde(default)]
+    is_admin: bool,
+}
+
+// Extracts the user for the request's session, for filters::auth::with_user
+pub async fn authenticate(authorization: Option<String>, cookie: Option<String>, db: DB) -> Result<User, warp::Rejection> {
+    let token = match auth::session_token(authorization, cookie) {
+        Some(t) => t,
+        None => return Err(warp::reject::custom(Unauthorized)),
+    };
+    let client = db.get().await?;
+    match auth::user_for_token(&token, &client).await? {
+        Some(user) => Ok(user),
+        None => Err(warp::reject::custom(Unauthorized)),
+    }
+}
+
+// POST /auth/login
+pub async fn login(l: Login, db: DB) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    let client = db.get().await?;
+    let user = match auth::user_for_password(&l.username, &l.password, db.passwords(), &client).await? {
+        Some(user) => user,
+        None => return Ok(Box::new(warp::reply::with_status(warp::reply(), StatusCode::UNAUTHORIZED))),
+    };
+
+    client.execute("DELETE FROM session WHERE expires_at <= now()", &[]).await.map_err(Error::from)?;
+    let (token, token_hash) = auth::new_token();
+    let stmt = client.prepare("
+        INSERT INTO session (token_hash, user_id, expires_at)
+        VALUES ($1, $2, now() + make_interval(days => $3))
+    ").await.map_err(Error::from)?;
+    client.execute(&stmt, &[&token_hash, &user.id, &auth::SESSION_TTL_DAYS]).await.map_err(Error::from)?;
+
+    // Browsers still accept Secure cookies from http://localhost, so only
+    // other hosts need HTTPS
+    let cookie = format!(
+        "{}={}; Path=/; HttpOnly; Secure; SameSite=Strict; Max-Age={}",
+        auth::SESSION_COOKIE,
+        token,
+        auth::SESSION_TTL_DAYS as i64 * 24 * 60 * 60,
+    );
+    let res = Session {
+        token,
+        user,
+    };
+
+    Ok(Box::new(warp::reply::with_header(warp::reply::json(&res), warp::http::header::SET_COOKIE, cookie)))
+}
+
+// POST /auth/logout
+pub async fn logout(authorization: Option<String>, cookie: Option<String>, db: DB) -> Result<impl warp::Reply, warp::Rejection> {
+    if let Some(token) = auth::session_token(authorization, cookie) {
+        let client = db.get().await?;
+        let stmt = client.prepare("DELETE FROM session WHERE token_hash = $1").await.map_err(Error::from)?;
+        client.execute(&stmt, &[&auth::token_hash(&token)]).await.map_err(Error::from)?;
+    }
+
+    let cookie = format!("{}=; Path=/; HttpOnly; Secure; SameSite=Strict; Max-Age=0", auth::SESSION_COOKIE);
+    Ok(warp::reply::with_header(
+        warp::reply::with_status(warp::reply(), StatusCode::NO_CONTENT),
+        warp::http::header::SET_COOKIE,
+        cookie,
+    ))
+}
+
+// GET /auth/me
+pub async fn get_current_user(user: User) -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(warp::reply::json(&user))
+}
+
+// POST /auth/me/api-token
+// Replaces the user's API token. It's only shown here, so a lost token has to
+// be replaced rather than recovered.
+pub async fn create_api_token(user: User, db: DB) -> Result<impl warp::Reply, warp::Rejection> {
+    let client = db.get().await?;
+    let (token, token_hash) = auth::new_token();
+    let stmt = client.prepare("
+        INSERT INTO api_token (token_hash, user_id)
+        VALUES ($1, $2)
+        ON CONFLICT (user_id) DO UPDATE
+        SET token_hash = EXCLUDED.token_hash, created_at = now()
+    ").await.map_err(Error::from)?;
+    client.execute(&stmt, &[&token_hash, &user.id]).await.map_err(Error::from)?;
+
+    Ok(warp::reply::with_status(warp::reply::json(&ApiToken { token }), StatusCode::CREATED))
+}
+
+// DELETE /auth/me/api-token
+pub async fn delete_api_token(user: User, db: DB) -> Result<impl warp::Reply, warp::Rejection> {
+    let client = db.get().await?;
+    let stmt = client.prepare("DELETE FROM api_token WHERE user_id = $1").await.map_err(Error::from)?;
+    client.execute(&stmt, &[&user.id]).await.map_err(Error::from)?;
+
+    Ok(warp::reply::with_status(warp::reply(), StatusCode::NO_CONTENT))
+}
+
+// GET /users
+pub async fn get_users(user: User, db: DB) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    if !user.is_admin {
+        return Ok(Box::new(warp::reply::with_status(warp::reply(), StatusCode::FORBIDDEN)));
+    }
+
+    let client = db.get().await?;
+    let stmt = client.prepare("
+        SELECT id, username, is_admin
+        FROM \"user\"
+        ORDER BY username ASC
+    ").await.map_err(Error::from)?;
+    let rows = client.query(&stmt, &[]).await.map_err(Error::from)?;
+    let users: Vec<User> = rows.iter().map(User::from).collect();
+
+    Ok(Box::new(warp::reply::json(&users)))
+}
+
+// POST /users
+pub async fn create_user(user: User, u: NewUser, db: DB) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    if !user.is_admin {
+        return Ok(Box::new(warp::reply::with_status(warp::reply(), StatusCode::FORBIDDEN)));
+    }
+    if u.username.trim().is_empty() || !auth::is_valid_password(&u.password) {
+        return Ok(Box::new(warp::reply::with_status(warp::reply(), StatusCode::BAD_REQUEST)));
+    }
+
+    let client = db.get().await?;
+    let stmt = client.prepare("
+        INSERT INTO \"user\" (username, password_hash, is_admin)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (username) DO NOTHING
+        RETURNING id, username, is_admin
+    ").await.map_err(Error::from)?;
+    let password_hash = auth::hash_password(&u.password).await?;
+    let row = client.query_opt(&stmt, &[&u.username.trim(), &password_hash, &u.is_admin]).await.map_err(Error::from)?;
+
+    match row {
+        Some(row) => Ok(Box::new(warp::reply::with_status(warp::reply::json(&User::from(&row)), StatusCode::CREATED))),
+        None => Ok(Box::new(warp::reply::with_status(warp::reply(), StatusCode::CONFLICT))),
+    }
+}
@@ -0,0 +1,48 @@
+use std::collections::HashMap;
+
+use crate::Error;
+
+// One artist in an album or track's artist credit, e.g. "Jay-Z" with the
+// join phrase " feat. " before the next credited artist
+#[derive(Serialize)]
+pub struct Credit {
+    pub artist_id: i32,
+    pub name: String,
+    pub join_phrase: String,
+}
+
+pub async fn album_credits(ids: &[i32], client: &deadpool_postgres::Client) -> Result<HashMap<i32, Vec<Credit>>, Error> {
+    load_credits("album_artist", "album_id", ids, client).await
+}
+
+pub async fn track_credits(ids: &[i32], client: &deadpool_postgres::Client) -> Result<HashMap<i32, Vec<Credit>>, Error> {
+    load_credits("track_artist", "track_id", ids, client).await
+}
+
+async fn load_credits(table: &str, key: &str, ids: &[i32], client: &deadpool_postgres::Client)
+    -> Result<HashMap<i32, Vec<Credit>>, Error>
+{
+    let mut credits = HashMap::new();
+    if ids.is_empty() {
+        return Ok(credits);
+    }
+
+    let q = format!("
+        SELECT {0}, artist_id, credited_name, join_phrase
+        FROM {1}
+        WHERE {0} = ANY($1)
+        ORDER BY {0} ASC, position ASC
+    ", key, table);
+    let stmt = client.prepare(&q).await?;
+    let rows = client.query(&stmt, &[&ids]).await?;
+
+    for row in rows {
+        credits.entry(row.get::<_, i32>(0)).or_insert_with(Vec::new).push(Credit {
+            artist_id: row.get(1),
+            name: row.get(2),
+            join_phrase: row.get(3),
+        });
+    }
+
+    Ok(credits)
+}
@@ -0,0 +1,325 @@
+use chrono::{DateTime, Duration, Utc};
+use tokio_postgres::Row;
+use warp::http::StatusCode;
+
+use crate::Error;
+use crate::auth::User;
+use crate::db::DB;
+use crate::filters::PaginationOptions;
+use crate::images;
+use crate::listenbrainz;
+use crate::filters::history::{Scope, StatsOptions, WindowOptions};
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayEvent {
+    id: i32,
+    track_id: i32,
+    played_at: DateTime<Utc>,
+    duration_played: Option<i32>,
+    source: String,
+}
+
+impl From<&Row> for PlayEvent {
+    fn from(row: &Row) -> PlayEvent {
+        PlayEvent {
+            id: row.get(0),
+            track_id: row.get(1),
+            played_at: row.get(2),
+            duration_played: row.get(3),
+            source: row.get(4),
+        }
+    }
+}
+
+// A play with the track's details, in the same shape as a PlaylistTrack
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryEntry {
+    id: i32,
+    played_at: DateTime<Utc>,
+    duration_played: Option<i32>,
+    source: String,
+    track_id: i32,
+    title: String,
+    duration: i32,
+    album_id: i32,
+    album_title: String,
+    album_image: Option<String>,
+    artist_id: i32,
+    artist_name: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TopTrack {
+    id: i32,
+    title: String,
+    album_id: i32,
+    album_title: String,
+    album_image: Option<String>,
+    artist_id: i32,
+    artist_name: String,
+    plays: i64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TopAlbum {
+    id: i32,
+    title: String,
+    image_url: Option<String>,
+    artist_id: i32,
+    artist_name: String,
+    plays: i64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TopArtist {
+    id: i32,
+    name: String,
+    image_url: Option<String>,
+    plays: i64,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Scrobble {
+    // When the track started playing. Defaults to durationPlayed ago.
+    played_at: Option<DateTime<Utc>>,
+    // In seconds
+    duration_played: Option<i32>,
+}
+
+const PLAY_EVENT_FIELDS: &str = "id, track_id, played_at, duration_played, source";
+
+// How far apart a scrobble and a logged stream can start and still be taken
+// for the same play
+const SCROBBLE_MATCH_WINDOW_SECS: i64 = 5 * 60;
+
+const DEFAULT_TOP_LIMIT: i64 = 10;
+const MAX_TOP_LIMIT: i64 = 100;
+
+// Logs the start of a stream. Called by play_track.
+pub async fn log_stream(user: &User, track_id: i32, client: &deadpool_postgres::Client) -> Result<(), Error> {
+    let stmt = client.prepare("
+        INSERT INTO play_event (user_id, track_id, source)
+        VALUES ($1, $2, 'stream')
+    ").await?;
+    client.execute(&stmt, &[&user.id, &track_id]).await?;
+    Ok(())
+}
+
+// POST /tracks/:id/scrobble
+//
+// A scrobble for a track this user just streamed completes that stream's
+// event rather than counting a second play. Either way the play is queued to
+// be forwarded to ListenBrainz.
+pub async fn scrobble(id: i32, user: User, s: Scrobble, db: DB) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    if s.duration_played.map(|d| d < 0).unwrap_or(false) {
+        return Ok(Box::new(warp::reply::with_status(warp::reply(), StatusCode::BAD_REQUEST)));
+    }
+    let played_at = s.played_at
+        .unwrap_or_else(|| Utc::now() - Duration::seconds(s.duration_played.unwrap_or(0) as i64));
+
+    let client = db.get().await?;
+    let stmt = client.prepare("SELECT 1 FROM track WHERE id = $1").await.map_err(Error::from)?;
+    if client.query_opt(&stmt, &[&id]).await.map_err(Error::from)?.is_none() {
+        return Ok(Box::new(warp::reply::with_status(warp::reply(), StatusCode::NOT_FOUND)));
+    }
+
+    let window_start = played_at - Duration::seconds(SCROBBLE_MATCH_WINDOW_SECS);
+    let window_end = played_at + Duration::seconds(SCROBBLE_MATCH_WINDOW_SECS);
+    let stmt = client.prepare(&format!("
+        UPDATE play_event
+        SET duration_played = $3
+        WHERE id = (
+            SELECT id
+            FROM play_event
+            WHERE user_id = $1 AND track_id = $2 AND source = 'stream' AND duration_played IS NULL
+              AND played_at BETWEEN $4 AND $5
+            ORDER BY played_at DESC
+            LIMIT 1
+        )
+        RETURNING {}
+    ", PLAY_EVENT_FIELDS)).await.map_err(Error::from)?;
+    let params: &[&(dyn tokio_postgres::types::ToSql + Sync)] = &[&user.id, &id, &s.duration_played, &window_start, &window_end];
+    if let Some(row) = client.query_opt(&stmt, params).await.map_err(Error::from)? {
+        let event = PlayEvent::from(&row);
+        listenbrainz::enqueue(event.id, &client).await?;
+        return Ok(Box::new(warp::reply::json(&event)));
+    }
+
+    let stmt = client.prepare(&format!("
+        INSERT INTO play_event (user_id, track_id, played_at, duration_played, source)
+        VALUES ($1, $2, $3, $4, 'scrobble')
+        RETURNING {}
+    ", PLAY_EVENT_FIELDS)).await.map_err(Error::from)?;
+    let row = client.query_one(&stmt, &[&user.id, &id, &played_at, &s.duration_played]).await.map_err(Error::from)?;
+    let event = PlayEvent::from(&row);
+    listenbrainz::enqueue(event.id, &client).await?;
+
+    Ok(Box::new(warp::reply::with_status(warp::reply::json(&event), StatusCode::CREATED)))
+}
+
+// GET /history(?from=X&to=Y&page=X&limit=Y)
+pub async fn get_history(user: User, window: WindowOptions, opts: PaginationOptions, db: DB) -> Result<impl warp::Reply, warp::Rejection> {
+    let client = db.get().await?;
+    let stmt = client.prepare("
+        SELECT COUNT(*)
+        FROM play_event
+        WHERE user_id = $1 AND played_at >= COALESCE($2::timestamptz, '-infinity') AND played_at < COALESCE($3::timestamptz, 'infinity')
+    ").await.map_err(Error::from)?;
+    let count: i64 = client.query_one(&stmt, &[&user.id, &window.from, &window.to]).await.map_err(Error::from)?.get(0);
+    let (limit, page) = match (opts.limit.or(Some(15)), opts.page) {
+        (Some(l), Some(p)) => {
+            if count < p * l {
+                (l, ((count as f64 / l as f64).ceil() as i64).max(1))
+            } else {
+                (l, p)
+            }
+        }
+        (Some(l), None) => (l, 1),
+        _ => (15, 1),
+    };
+    let offset = (page - 1) * limit as i64;
+    let total_pages = (count as f64 / limit as f64).ceil() as i64;
+
+    let stmt = client.prepare(&format!("
+        SELECT E.id, E.played_at, E.duration_played, E.source, T.id, T.title, T.duration, R.id, R.title, {}, A.id, A.name
+        FROM play_event E
+        INNER JOIN track T ON T.id = E.track_id
+        INNER JOIN album R ON R.id = T.album_id
+        INNER JOIN artist A ON A.id = R.artist_id
+        WHERE E.user_id = $1 AND E.played_at >= COALESCE($2::timestamptz, '-infinity') AND E.played_at < COALESCE($3::timestamptz, 'infinity')
+        ORDER BY E.played_at DESC
+        LIMIT $4 OFFSET $5
+    ", images::album_image_sql("R"))).await.map_err(Error::from)?;
+    let rows = client.query(&stmt, &[&user.id, &window.from, &window.to, &limit, &offset]).await.map_err(Error::from)?;
+    let entries = rows.iter()
+        .map(|row| HistoryEntry {
+            id: row.get(0),
+            played_at: row.get(1),
+            duration_played: row.get(2),
+            source: row.get(3),
+            track_id: row.get(4),
+            title: row.get(5),
+            duration: row.get(6),
+            album_id: row.get(7),
+            album_title: row.get(8),
+            album_image: row.get(9),
+            artist_id: row.get(10),
+            artist_name: row.get(11),
+        })
+        .collect();
+
+    let res = super::PaginatedResponse {
+        page,
+        count,
+        total_pages,
+        data: entries,
+    };
+
+    Ok(warp::reply::json(&res))
+}
+
+// GET /stats/top-tracks(?from=X&to=Y&limit=Z&scope=me|all)
+pub async fn get_top_tracks(user: User, window: WindowOptions, opts: StatsOptions, db: DB) -> Result<impl warp::Reply, warp::Rejection> {
+    let rows = top_rows(&user, &window, &opts, &format!("
+        SELECT T.id, T.title, R.id, R.title, {}, A.id, A.name, COUNT(*) AS plays
+        FROM play_event E
+        INNER JOIN track T ON T.id = E.track_id
+        INNER JOIN album R ON R.id = T.album_id
+        INNER JOIN artist A ON A.id = R.artist_id
+        {{where}}
+        GROUP BY T.id, R.id, A.id
+    ", images::album_image_sql("R")), &db).await?;
+    let tracks: Vec<_> = rows.iter()
+        .map(|row| TopTrack {
+            id: row.get(0),
+            title: row.get(1),
+            album_id: row.get(2),
+            album_title: row.get(3),
+            album_image: row.get(4),
+            artist_id: row.get(5),
+            artist_name: row.get(6),
+            plays: row.get(7),
+        })
+        .collect();
+
+    Ok(warp::reply::json(&tracks))
+}
+
+// GET /stats/top-albums(?from=X&to=Y&limit=Z&scope=me|all)
+pub async fn get_top_albums(user: User, window: WindowOptions, opts: StatsOptions, db: DB) -> Result<impl warp::Reply, warp::Rejection> {
+    let rows = top_rows(&user, &window, &opts, &format!("
+        SELECT R.id, R.title, {}, A.id, A.name, COUNT(*) AS plays
+        FROM play_event E
+        INNER JOIN track T ON T.id = E.track_id
+        INNER JOIN album R ON R.id = T.album_id
+        INNER JOIN artist A ON A.id = R.artist_id
+        {{where}}
+        GROUP BY R.id, A.id
+    ", images::album_image_sql("R")), &db).await?;
+    let albums: Vec<_> = rows.iter()
+        .map(|row| TopAlbum {
+            id: row.get(0),
+            title: row.get(1),
+            image_url: row.get(2),
+            artist_id: row.get(3),
+            artist_name: row.get(4),
+            plays: row.get(5),
+        })
+        .collect();
+
+    Ok(warp::reply::json(&albums))
+}
+
+// GET /stats/top-artists(?from=X&to=Y&limit=Z&scope=me|all)
+//
+// A play counts for every artist credited on the track, so featured artists
+// are ranked too.
+pub async fn get_top_artists(user: User, window: WindowOptions, opts: StatsOptions, db: DB) -> Result<impl warp::Reply, warp::Rejection> {
+    let rows = top_rows(&user, &window, &opts, &format!("
+        SELECT A.id, A.name, {}, COUNT(*) AS plays
+        FROM play_event E
+        INNER JOIN track_artist C ON C.track_id = E.track_id
+        INNER JOIN artist A ON A.id = C.artist_id
+        {{where}}
+        GROUP BY A.id
+    ", images::artist_image_sql("A")), &db).await?;
+    let artists: Vec<_> = rows.iter()
+        .map(|row| TopArtist {
+            id: row.get(0),
+            name: row.get(1),
+            image_url: row.get(2),
+            plays: row.get(3),
+        })
+        .collect();
+
+    Ok(warp::reply::json(&artists))
+}
+
+// Runs one of the aggregate queries over the window, most played first.
+// `{where}` in the query is replaced with the window and scope conditions.
+async fn top_rows(user: &User, window: &WindowOptions, opts: &StatsOptions, query: &str, db: &DB) -> Result<Vec<Row>, Error> {
+    let client = db.get().await?;
+    let limit = opts.limit.unwrap_or(DEFAULT_TOP_LIMIT).max(1).min(MAX_TOP_LIMIT);
+    // Everyone's plays are only counted when asked for, with the user
+    // condition made a no-op so the parameters stay the same
+    let user_condition = match opts.scope.unwrap_or(Scope::Me) {
+        Scope::Me => "E.user_id = $1",
+        Scope::All => "$1::integer IS NOT NULL",
+    };
+    let q = format!(
+        "{} ORDER BY plays DESC LIMIT $4",
+        query.replace("{where}", &format!(
+            "WHERE {} AND E.played_at >= COALESCE($2::timestamptz, '-infinity') AND E.played_at < COALESCE($3::timestamptz, 'infinity')",
+            user_condition,
+        )),
+    );
+    let stmt = client.prepare(&q).await?;
+    let rows = client.query(&stmt, &[&user.id, &window.from, &window.to, &limit]).await?;
+    Ok(rows)
+}
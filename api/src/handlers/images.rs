@@ -0,0 +1,67 @@
+use warp::http::{HeaderMap, Response, StatusCode};
+
+use crate::Error;
+use crate::config::Config;
+use crate::db::DB;
+use crate::filters::ImageOptions;
+use crate::images;
+use crate::streaming;
+
+// GET /albums/:id/cover(?size=X)
+pub async fn get_album_cover(id: i32, opts: ImageOptions, headers: HeaderMap, db: DB, config: Config) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    let client = db.get().await?;
+    match image_response("album", id, opts.size, &headers, &client, &config).await? {
+        Some(res) => Ok(res),
+        None => Err(warp::reject::not_found()),
+    }
+}
+
+// GET /artists/:id/image(?size=X)
+pub async fn get_artist_image(id: i32, opts: ImageOptions, headers: HeaderMap, db: DB, config: Config) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    let client = db.get().await?;
+    match image_response("artist", id, opts.size, &headers, &client, &config).await? {
+        Some(res) => Ok(res),
+        None => Err(warp::reject::not_found()),
+    }
+}
+
+// Serves the image of row `id` of `table`, album or artist, scaled down to
+// fit `size`. Images the importer hasn't stored yet are redirected to where
+// they're hosted. Returns None if there's no image.
+pub(super) async fn image_response(
+    table: &str,
+    id: i32,
+    size: Option<u32>,
+    headers: &HeaderMap,
+    client: &deadpool_postgres::Client,
+    config: &Config,
+) -> Result<Option<Box<dyn warp::Reply>>, Error> {
+    let stmt = client.prepare(&format!("SELECT image_url, image_file FROM {} WHERE id = $1", table)).await?;
+    let row = match client.query_opt(&stmt, &[&id]).await? {
+        Some(row) => row,
+        None => return Ok(None),
+    };
+    let url: Option<String> = row.get(0);
+    let file: Option<String> = row.get(1);
+
+    if let (Some(store), Some(file)) = (config.image_store_dir.as_ref(), file) {
+        let path = match size.and_then(images::thumbnail_size) {
+            Some(size) => images::thumbnail(store, &file, size).await?,
+            None => Some(store.join(&file)),
+        };
+        if let Some(path) = path {
+            if let Some(res) = streaming::serve_file(&path, images::content_type(&path), headers).await? {
+                return Ok(Some(Box::new(res)));
+            }
+        }
+    }
+
+    Ok(url.map(|url| {
+        let res = Response::builder()
+            .status(StatusCode::FOUND)
+            .header(warp::http::header::LOCATION, url)
+            .body(Vec::new())
+            .unwrap();
+        Box::new(res) as Box<dyn warp::Reply>
+    }))
+}
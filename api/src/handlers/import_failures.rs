@@ -0,0 +1,104 @@
+use chrono::{DateTime, Utc};
+use tokio_postgres::Row;
+use warp::http::StatusCode;
+
+use crate::Error;
+use crate::auth::User;
+use crate::db::DB;
+use crate::filters::PaginationOptions;
+
+#[derive(Serialize)]
+pub struct ImportFailure {
+    id: i32,
+    file_location: String,
+    kind: String,
+    message: String,
+    tags: serde_json::Value,
+    attempts: i32,
+    retry_requested: bool,
+    first_failed_at: DateTime<Utc>,
+    last_failed_at: DateTime<Utc>,
+}
+
+impl From<&Row> for ImportFailure {
+    fn from(row: &Row) -> ImportFailure {
+        ImportFailure {
+            id: row.get(0),
+            file_location: row.get(1),
+            kind: row.get(2),
+            message: row.get(3),
+            tags: row.get(4),
+            attempts: row.get(5),
+            retry_requested: row.get(6),
+            first_failed_at: row.get(7),
+            last_failed_at: row.get(8),
+        }
+    }
+}
+
+const FIELDS: &str = "id, file_location, kind, message, tags, attempts, retry_requested, first_failed_at, last_failed_at";
+
+// GET /import-failures(?page=X&limit=Y)
+//
+// Failures show raw tags and server paths, so only admins can see them.
+pub async fn get_import_failures(user: User, opts: PaginationOptions, db: DB) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    if !user.is_admin {
+        return Ok(Box::new(warp::reply::with_status(warp::reply(), StatusCode::FORBIDDEN)));
+    }
+
+    let client = db.get().await?;
+    let count = db.count_rows("import_failure", &client).await.map_err(Error::from)?.unwrap_or(0);
+    let (limit, page) = match (opts.limit.or(Some(15)), opts.page) {
+        (Some(l), Some(p)) => {
+            if count < p * l {
+                (l, ((count as f64 / l as f64).ceil() as i64).max(1))
+            } else {
+                (l, p)
+            }
+        }
+        (Some(l), None) => (l, 1),
+        _ => (15, 1),
+    };
+    let offset = (page - 1) * limit as i64;
+    let total_pages = (count as f64 / limit as f64).ceil() as i64;
+    let stmt = client.prepare(&format!("
+        SELECT {}
+        FROM import_failure
+        ORDER BY last_failed_at DESC
+        LIMIT $1 OFFSET $2
+    ", FIELDS)).await.map_err(Error::from)?;
+    let rows = client.query(&stmt, &[&limit, &offset]).await.map_err(Error::from)?;
+
+    let res = super::PaginatedResponse {
+        page,
+        count,
+        total_pages,
+        data: rows.iter().map(ImportFailure::from).collect(),
+    };
+
+    Ok(Box::new(warp::reply::json(&res)))
+}
+
+// POST /import-failures/:id/retry
+//
+// The importer picks the file up again on its next sync, or within a minute
+// when running with --watch.
+pub async fn retry_import_failure(id: i32, user: User, db: DB) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    if !user.is_admin {
+        return Ok(Box::new(warp::reply::with_status(warp::reply(), StatusCode::FORBIDDEN)));
+    }
+
+    let client = db.get().await?;
+    let stmt = client.prepare(&format!("
+        UPDATE import_failure
+        SET retry_requested = true
+        WHERE id = $1
+        RETURNING {}
+    ", FIELDS)).await.map_err(Error::from)?;
+    let rows = client.query(&stmt, &[&id]).await.map_err(Error::from)?;
+
+    match rows.first() {
+        Some(row) => Ok(Box::new(warp::reply::json(&ImportFailure::from(row)))),
+        None => Ok(Box::new(warp::reply::with_status(warp::reply(), StatusCode::NOT_FOUND))),
+    }
+}
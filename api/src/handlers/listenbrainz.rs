@@ -0,0 +1,156 @@
+use chrono::{TimeZone, Utc};
+use warp::http::StatusCode;
+
+use crate::Error;
+use crate::auth::{self, User};
+use crate::db::DB;
+use crate::filters::listenbrainz::ValidateTokenOptions;
+use crate::listenbrainz::{self, Listen, ListenType, SubmitListens};
+
+#[derive(Serialize)]
+struct Status {
+    status: &'static str,
+}
+
+// Errors in the shape ListenBrainz clients expect
+#[derive(Serialize)]
+struct ApiError {
+    code: u16,
+    error: String,
+}
+
+#[derive(Serialize)]
+struct TokenValidity {
+    code: u16,
+    message: &'static str,
+    valid: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user_name: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ListenBrainzToken {
+    // Clears the token when absent or empty
+    token: Option<String>,
+}
+
+// POST /1/submit-listens
+//
+// Lets third-party players scrobble to us. Listens are matched to the library
+// by MusicBrainz recording id, then by title and artist. Listens that don't
+// match anything are skipped, and now playing notifications aren't recorded.
+pub async fn submit_listens(user: User, s: SubmitListens, db: DB) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    if s.listen_type == ListenType::PlayingNow {
+        return Ok(Box::new(warp::reply::json(&Status { status: "ok" })));
+    }
+    if s.listen_type == ListenType::Single && s.payload.len() != 1 {
+        return Ok(bad_request("a single listen must have exactly one listen in its payload"));
+    }
+    if s.payload.iter().any(|l| l.listened_at.is_none()) {
+        return Ok(bad_request("every listen must have listened_at"));
+    }
+
+    let client = db.get().await?;
+    for listen in &s.payload {
+        let track_id = match resolve_listen(listen, &client).await? {
+            Some(id) => id,
+            None => continue,
+        };
+        let played_at = Utc.timestamp(listen.listened_at.unwrap_or(0), 0);
+        let duration_played = listen.track_metadata.additional_info.as_ref()
+            .and_then(|i| i.duration_ms)
+            .map(|ms| (ms / 1000) as i32);
+
+        // Clients resubmit listens they aren't sure were received
+        let stmt = client.prepare("
+            INSERT INTO play_event (user_id, track_id, played_at, duration_played, source)
+            SELECT $1, $2, $3, $4, 'listenbrainz'
+            WHERE NOT EXISTS (
+                SELECT 1 FROM play_event
+                WHERE user_id = $1 AND track_id = $2 AND played_at = $3
+            )
+            RETURNING id
+        ").await.map_err(Error::from)?;
+        let row = client.query_opt(&stmt, &[&user.id, &track_id, &played_at, &duration_played]).await.map_err(Error::from)?;
+        if let Some(row) = row {
+            listenbrainz::enqueue(row.get(0), &client).await?;
+        }
+    }
+
+    Ok(Box::new(warp::reply::json(&Status { status: "ok" })))
+}
+
+// GET /1/validate-token(?token=X)
+pub async fn validate_token(opts: ValidateTokenOptions, authorization: Option<String>, db: DB) -> Result<impl warp::Reply, warp::Rejection> {
+    let token = opts.token.or_else(|| auth::session_token(authorization, None));
+    let user = match token {
+        Some(token) => {
+            let client = db.get().await?;
+            auth::user_for_token(&token, &client).await?
+        }
+        None => None,
+    };
+
+    let res = match user {
+        Some(user) => TokenValidity {
+            code: 200,
+            message: "Token valid.",
+            valid: true,
+            user_name: Some(user.username),
+        },
+        None => TokenValidity {
+            code: 200,
+            message: "Token invalid.",
+            valid: false,
+            user_name: None,
+        },
+    };
+
+    Ok(warp::reply::json(&res))
+}
+
+// PUT /auth/me/listenbrainz
+pub async fn set_listenbrainz_token(user: User, t: ListenBrainzToken, db: DB) -> Result<impl warp::Reply, warp::Rejection> {
+    let token = t.token.map(|t| t.trim().to_owned()).filter(|t| !t.is_empty());
+
+    let client = db.get().await?;
+    let stmt = client.prepare("UPDATE \"user\" SET listenbrainz_token = $2 WHERE id = $1").await.map_err(Error::from)?;
+    client.execute(&stmt, &[&user.id, &token]).await.map_err(Error::from)?;
+
+    Ok(warp::reply::with_status(warp::reply(), StatusCode::NO_CONTENT))
+}
+
+async fn resolve_listen(listen: &Listen, client: &deadpool_postgres::Client) -> Result<Option<i32>, Error> {
+    let metadata = &listen.track_metadata;
+    let recording_mbid = metadata.additional_info.as_ref().and_then(|i| i.recording_mbid.as_ref());
+    if let Some(mbid) = recording_mbid {
+        let stmt = client.prepare("SELECT id FROM track WHERE mbid = $1").await?;
+        if let Some(row) = client.query_opt(&stmt, &[&mbid]).await? {
+            return Ok(Some(row.get(0)));
+        }
+    }
+
+    // Prefer a track from the named release, then an available one
+    let stmt = client.prepare("
+        SELECT T.id
+        FROM track T
+        INNER JOIN album R ON R.id = T.album_id
+        INNER JOIN artist A ON A.id = R.artist_id
+        WHERE lower(T.title) = lower($1)
+          AND (lower(A.name) = lower($2) OR EXISTS (
+              SELECT 1 FROM track_artist C WHERE C.track_id = T.id AND lower(C.credited_name) = lower($2)
+          ))
+        ORDER BY lower(R.title) = lower($3) DESC NULLS LAST, T.available DESC, T.id ASC
+        LIMIT 1
+    ").await?;
+    let row = client.query_opt(&stmt, &[&metadata.track_name, &metadata.artist_name, &metadata.release_name]).await?;
+    Ok(row.map(|r| r.get(0)))
+}
+
+fn bad_request(error: &str) -> Box<dyn warp::Reply> {
+    let res = ApiError {
+        code: 400,
+        error: error.to_owned(),
+    };
+    Box::new(warp::reply::with_status(warp::reply::json(&res), StatusCode::BAD_REQUEST))
+}
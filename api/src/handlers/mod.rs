@@ -1,6 +1,14 @@
 pub mod albums;
 pub mod artists;
+pub mod auth;
+pub mod credits;
+pub mod history;
+pub mod images;
+pub mod import_failures;
+pub mod listenbrainz;
 pub mod playlists;
+pub mod search;
+pub mod subsonic;
 pub mod tracks;
 
 #[derive(Serialize)]
@@ -1,15 +1,27 @@
 use std::collections::BTreeSet;
+use std::path::Path;
 
+use lev::damlev;
+use tokio_postgres::types::Json;
 use warp::http::StatusCode;
 
 use crate::Error;
-use crate::db::DB;
+use crate::auth::User;
+use crate::config::Config;
+use crate::db::{escape_like, DB};
 use crate::filters::RelationsOption;
+use crate::filters::playlists::{ExportOptions, ImportOptions};
+use crate::images;
+use crate::playlist_files::{self, Entry, Format};
+use crate::rules::{self, Rules};
 
 #[derive(Serialize)]
 pub struct Playlist {
     id: i32,
     name: String,
+    // Only set for smart playlists
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rules: Option<Rules>,
     #[serde(skip_serializing_if = "Option::is_none")]
     tracks: Option<Vec<PlaylistTrack>>,
 }
@@ -31,6 +43,8 @@ pub struct PlaylistTrack {
 #[derive(Deserialize)]
 pub struct NewPlaylist {
     name: String,
+    // Makes this a smart playlist
+    rules: Option<Rules>,
 }
 
 #[derive(Deserialize)]
@@ -39,20 +53,44 @@ pub struct AddToPlaylist {
     track_id: i32,
 }
 
-pub async fn get_playlists(db: DB) -> Result<impl warp::Reply, warp::Rejection> {
+#[derive(Deserialize)]
+pub struct UpdatePlaylist {
+    name: Option<String>,
+    // Left alone when missing. Null makes the playlist a regular one.
+    #[serde(default, deserialize_with = "deserialize_some")]
+    rules: Option<Option<Rules>>,
+}
+
+// Tells a field that's null apart from one that's missing
+fn deserialize_some<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    T: serde::Deserialize<'de>,
+    D: serde::Deserializer<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+#[derive(Deserialize)]
+pub struct MoveTrack {
+    position: i32,
+}
+
+pub async fn get_playlists(user: User, db: DB) -> Result<impl warp::Reply, warp::Rejection> {
     let client = db.get().await?;
     let stmt = client.prepare("
-        SELECT id, name
+        SELECT id, name, rules
         FROM playlist
+        WHERE owner_id = $1
         ORDER BY name ASC
     ").await.map_err(Error::from)?;
-    let rows = client.query(&stmt, &[]).await.map_err(Error::from)?;
+    let rows = client.query(&stmt, &[&user.id]).await.map_err(Error::from)?;
     let mut playlists = Vec::new();
 
     for row in rows {
         let playlist = Playlist {
             id: row.get(0),
             name: row.get(1),
+            rules: row.get::<_, Option<Json<Rules>>>(2).map(|r| r.0),
             tracks: None,
         };
         playlists.push(playlist);
@@ -61,14 +99,14 @@ pub async fn get_playlists(db: DB) -> Result<impl warp::Reply, warp::Rejection>
     Ok(warp::reply::json(&playlists))
 }
 
-pub async fn create_playlist(p: NewPlaylist, db: DB) -> Result<impl warp::Reply, warp::Rejection> {
+pub async fn create_playlist(user: User, p: NewPlaylist, db: DB) -> Result<impl warp::Reply, warp::Rejection> {
     let client = db.get().await?;
     let stmt = client.prepare("
-        INSERT INTO playlist (name)
-        VALUES ($1)
-        RETURNING id, name
+        INSERT INTO playlist (name, rules, owner_id)
+        VALUES ($1, $2, $3)
+        RETURNING id, name, rules
     ").await.map_err(Error::from)?;
-    let rows = client.query(&stmt, &[&p.name]).await.map_err(Error::from)?;
+    let rows = client.query(&stmt, &[&p.name, &p.rules.as_ref().map(Json), &user.id]).await.map_err(Error::from)?;
 
     if rows.is_empty() {
         return Err(warp::reject());
@@ -78,15 +116,17 @@ pub async fn create_playlist(p: NewPlaylist, db: DB) -> Result<impl warp::Reply,
     let playlist = Playlist {
         id: row.get(0),
         name: row.get(1),
+        rules: row.get::<_, Option<Json<Rules>>>(2).map(|r| r.0),
         tracks: None,
     };
 
     Ok(warp::reply::json(&playlist))
 }
 
-pub async fn get_playlist_with_id(id: i32, rels: RelationsOption, db: DB) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+pub async fn get_playlist_with_id(id: i32, user: User, rels: RelationsOption, db: DB) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
     let rels = rels.relations.unwrap_or(BTreeSet::new());
-    let mut select_fields = vec!["P.id", "P.name"];
+    let album_image = images::album_image_sql("R");
+    let mut select_fields = vec!["P.id", "P.name", "P.rules"];
     let mut joins = Vec::new();
 
     let mut loading_tracks = false;
@@ -101,13 +141,14 @@ pub async fn get_playlist_with_id(id: i32, rels: RelationsOption, db: DB) -> Res
                     "PT.position",
                     "R.id",
                     "R.title",
-                    "R.image_url",
+                    album_image.as_str(),
                     "A.id",
                     "A.name",
                 ]);
                 joins.extend_from_slice(&[
-                    "LEFT OUTER JOIN playlist_track PT ON PT.playlist_id = P.id",
-                    "LEFT OUTER JOIN track T ON T.id = PT.track_id",
+                    // Join tracks to entries first so an unavailable track
+                    // drops its entry rather than leaving a NULL row.
+                    "LEFT OUTER JOIN (playlist_track PT INNER JOIN track T ON T.id = PT.track_id AND T.available) ON PT.playlist_id = P.id",
                     "LEFT OUTER JOIN album R ON R.id = T.album_id",
                     "LEFT OUTER JOIN artist A ON A.id = R.artist_id",
                 ]);
@@ -119,10 +160,10 @@ pub async fn get_playlist_with_id(id: i32, rels: RelationsOption, db: DB) -> Res
 
     let select_fields = select_fields.as_slice().join(", ");
     let joins = joins.as_slice().join("\n");
-    let q = format!("SELECT {} FROM playlist P {} WHERE P.id = $1", select_fields, joins);
+    let q = format!("SELECT {} FROM playlist P {} WHERE P.id = $1 AND P.owner_id = $2", select_fields, joins);
     let client = db.get().await?;
     let stmt = client.prepare(&q).await.map_err(Error::from)?;
-    let rows = client.query(&stmt, &[&id]).await.map_err(Error::from)?;
+    let rows = client.query(&stmt, &[&id, &user.id]).await.map_err(Error::from)?;
 
     if rows.is_empty() {
         return Ok(Box::new(warp::reply::with_status(warp::reply(), StatusCode::NOT_FOUND)));
@@ -134,18 +175,18 @@ pub async fn get_playlist_with_id(id: i32, rels: RelationsOption, db: DB) -> Res
         let mut playlist_tracks = Vec::new();
         for row in &rows {
             let track = PlaylistTrack {
-                id: match row.try_get(2) {
+                id: match row.try_get(3) {
                     Ok(id) => id,
                     Err(_) => break,
                 },
-                title: row.get(3),
-                duration: row.get(4),
-                position: row.get(5),
-                album_id: row.get(6),
-                album_title: row.get(7),
-                album_image: row.get(8),
-                artist_id: row.get(9),
-                artist_name: row.get(10),
+                title: row.get(4),
+                duration: row.get(5),
+                position: row.get(6),
+                album_id: row.get(7),
+                album_title: row.get(8),
+                album_image: row.get(9),
+                artist_id: row.get(10),
+                artist_name: row.get(11),
             };
             playlist_tracks.push(track);
         }
@@ -154,37 +195,39 @@ pub async fn get_playlist_with_id(id: i32, rels: RelationsOption, db: DB) -> Res
     }
 
     let row = &rows[0];
+    let rules = row.get::<_, Option<Json<Rules>>>(2).map(|r| r.0);
+    if let (true, Some(rules)) = (loading_tracks, &rules) {
+        tracks = Some(smart_playlist_tracks(rules, &client).await?);
+    }
     let playlist = Playlist {
         id: row.get(0),
         name: row.get(1),
+        rules,
         tracks,
     };
 
     Ok(Box::new(warp::reply::json(&playlist)))
 }
 
-pub async fn add_to_playlist(id: i32, t: AddToPlaylist, db: DB) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
-    let client = db.get().await?;
-    let stmt = client.prepare("
-        SELECT MAX(position) FROM playlist_track WHERE playlist_id = $1
-    ").await.map_err(Error::from)?;
-    let rows = client.query(&stmt, &[&id]).await.map_err(Error::from)?;
-
-    let next_pos = if rows.is_empty() { 1 } else { rows[0].try_get::<'_, _, i32>(0).unwrap_or(0) + 1 };
-    let stmt = client.prepare("
-        INSERT INTO playlist_track (playlist_id, track_id, position)
-        VALUES ($1, $2, $3)
-    ").await.map_err(Error::from)?;
-    client.query(&stmt, &[&id, &t.track_id, &next_pos]).await.map_err(Error::from)?;
+pub async fn add_to_playlist(id: i32, user: User, t: AddToPlaylist, db: DB) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    let mut client = db.get().await?;
+    match owned_playlist(id, &user, &client).await? {
+        None => return Ok(Box::new(warp::reply::with_status(warp::reply(), StatusCode::NOT_FOUND))),
+        Some(true) => return Ok(Box::new(warp::reply::with_status(warp::reply(), StatusCode::CONFLICT))),
+        Some(false) => {}
+    }
+    let tx = client.transaction().await.map_err(Error::from)?;
+    let position = append_track(id, t.track_id, &tx).await?;
 
-    let stmt = client.prepare("
-        SELECT T.id, T.title, T.duration, PT.position, R.id, R.title, R.image_url, A.id, A.name
+    let stmt = tx.prepare(&format!("
+        SELECT T.id, T.title, T.duration, PT.position, R.id, R.title, {}, A.id, A.name
         FROM track T
-        INNER JOIN playlist_track PT ON PT.playlist_id = $1 AND PT.track_id = T.id
+        INNER JOIN playlist_track PT ON PT.playlist_id = $1 AND PT.track_id = T.id AND PT.position = $2
         INNER JOIN album R ON R.id = T.album_id
         INNER JOIN artist A ON A.id = R.artist_id
-    ").await.map_err(Error::from)?;
-    let rows = client.query(&stmt, &[&id]).await.map_err(Error::from)?;
+    ", images::album_image_sql("R"))).await.map_err(Error::from)?;
+    let rows = tx.query(&stmt, &[&id, &position]).await.map_err(Error::from)?;
+    tx.commit().await.map_err(Error::from)?;
 
     if rows.is_empty() {
         return Ok(Box::new(warp::reply::with_status(warp::reply(), StatusCode::INTERNAL_SERVER_ERROR)));
@@ -205,3 +248,504 @@ pub async fn add_to_playlist(id: i32, t: AddToPlaylist, db: DB) -> Result<Box<dy
 
     Ok(Box::new(warp::reply::with_status(warp::reply::json(&track), StatusCode::CREATED)))
 }
+
+// PATCH /playlists/:id
+pub async fn update_playlist(id: i32, user: User, p: UpdatePlaylist, db: DB) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    let client = db.get().await?;
+    let stmt = client.prepare("
+        UPDATE playlist
+        SET name = COALESCE($2, name), rules = CASE WHEN $5 THEN $3 ELSE rules END
+        WHERE id = $1 AND owner_id = $4
+        RETURNING id, name, rules
+    ").await.map_err(Error::from)?;
+    let rules = p.rules.as_ref().and_then(|r| r.as_ref()).map(Json);
+    let rows = client.query(&stmt, &[&id, &p.name, &rules, &user.id, &p.rules.is_some()]).await.map_err(Error::from)?;
+
+    if rows.is_empty() {
+        return Ok(Box::new(warp::reply::with_status(warp::reply(), StatusCode::NOT_FOUND)));
+    }
+
+    let row = &rows[0];
+    let playlist = Playlist {
+        id: row.get(0),
+        name: row.get(1),
+        rules: row.get::<_, Option<Json<Rules>>>(2).map(|r| r.0),
+        tracks: None,
+    };
+
+    Ok(Box::new(warp::reply::json(&playlist)))
+}
+
+// DELETE /playlists/:id
+pub async fn delete_playlist(id: i32, user: User, db: DB) -> Result<impl warp::Reply, warp::Rejection> {
+    let client = db.get().await?;
+    let stmt = client.prepare("DELETE FROM playlist WHERE id = $1 AND owner_id = $2").await.map_err(Error::from)?;
+    let deleted = client.execute(&stmt, &[&id, &user.id]).await.map_err(Error::from)?;
+
+    let status = if deleted == 0 { StatusCode::NOT_FOUND } else { StatusCode::NO_CONTENT };
+    Ok(warp::reply::with_status(warp::reply(), status))
+}
+
+// DELETE /playlists/:id/tracks/:position
+pub async fn remove_from_playlist(id: i32, position: i32, user: User, db: DB) -> Result<impl warp::Reply, warp::Rejection> {
+    let mut client = db.get().await?;
+    if owned_playlist(id, &user, &client).await?.is_none() {
+        return Ok(warp::reply::with_status(warp::reply(), StatusCode::NOT_FOUND));
+    }
+    let tx = client.transaction().await.map_err(Error::from)?;
+    let stmt = tx.prepare("
+        DELETE FROM playlist_track
+        WHERE playlist_id = $1 AND position = $2
+    ").await.map_err(Error::from)?;
+    let deleted = tx.execute(&stmt, &[&id, &position]).await.map_err(Error::from)?;
+
+    if deleted == 0 {
+        return Ok(warp::reply::with_status(warp::reply(), StatusCode::NOT_FOUND));
+    }
+
+    // Close the gap by moving every later track up one
+    let max = max_position(id, &tx).await?;
+    shift_positions(id, position + 1, max, -1, &tx).await?;
+    tx.commit().await.map_err(Error::from)?;
+
+    Ok(warp::reply::with_status(warp::reply(), StatusCode::NO_CONTENT))
+}
+
+// PATCH /playlists/:id/tracks/:position
+// Moves the track at `position` to the position in the body, shifting the
+// tracks in between to make room.
+pub async fn move_playlist_track(id: i32, from: i32, user: User, m: MoveTrack, db: DB) -> Result<impl warp::Reply, warp::Rejection> {
+    let mut client = db.get().await?;
+    if owned_playlist(id, &user, &client).await?.is_none() {
+        return Ok(warp::reply::with_status(warp::reply(), StatusCode::NOT_FOUND));
+    }
+    let tx = client.transaction().await.map_err(Error::from)?;
+    let status = move_track(id, from, m.position, &tx).await?;
+    tx.commit().await.map_err(Error::from)?;
+
+    Ok(warp::reply::with_status(warp::reply(), status))
+}
+
+// Adds the track to the end of the playlist, returning its position
+async fn append_track(id: i32, track_id: i32, tx: &deadpool_postgres::Transaction<'_>) -> Result<i32, Error> {
+    let stmt = tx.prepare("
+        INSERT INTO playlist_track (playlist_id, track_id, position)
+        SELECT $1, $2, COALESCE(MAX(position), 0) + 1
+        FROM playlist_track
+        WHERE playlist_id = $1
+        RETURNING position
+    ").await?;
+    let row = tx.query_one(&stmt, &[&id, &track_id]).await?;
+    Ok(row.get(0))
+}
+
+// Moves the track at `from` to `to`, for move_playlist_track, returning the
+// status to respond with
+async fn move_track(id: i32, from: i32, to: i32, tx: &deadpool_postgres::Transaction<'_>) -> Result<StatusCode, Error> {
+    let max = max_position(id, tx).await?;
+
+    if from < 1 || from > max {
+        return Ok(StatusCode::NOT_FOUND);
+    }
+    if to < 1 || to > max {
+        return Ok(StatusCode::BAD_REQUEST);
+    }
+    if to == from {
+        return Ok(StatusCode::NO_CONTENT);
+    }
+
+    // Park the moved track at 0, which is never a real position, until its
+    // destination is free
+    let stmt = tx.prepare("
+        UPDATE playlist_track
+        SET position = 0
+        WHERE playlist_id = $1 AND position = $2
+    ").await?;
+    tx.execute(&stmt, &[&id, &from]).await?;
+
+    if to > from {
+        shift_positions(id, from + 1, to, -1, tx).await?;
+    } else {
+        shift_positions(id, to, from - 1, 1, tx).await?;
+    }
+
+    let stmt = tx.prepare("
+        UPDATE playlist_track
+        SET position = $2
+        WHERE playlist_id = $1 AND position = 0
+    ").await?;
+    tx.execute(&stmt, &[&id, &to]).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn max_position(id: i32, tx: &deadpool_postgres::Transaction<'_>) -> Result<i32, Error> {
+    let stmt = tx.prepare("
+        SELECT MAX(position) FROM playlist_track WHERE playlist_id = $1
+    ").await?;
+    let row = tx.query_one(&stmt, &[&id]).await?;
+    Ok(row.try_get::<'_, _, i32>(0).unwrap_or(0))
+}
+
+// Moves the tracks at positions `from..=to` by `by`. The primary key is
+// checked row by row, so shifting in place could collide with a neighbour
+// that hasn't moved yet. Instead the range is first negated, which can't
+// collide with anything, then flipped back to its new positions.
+async fn shift_positions(id: i32, from: i32, to: i32, by: i32, tx: &deadpool_postgres::Transaction<'_>) -> Result<(), Error> {
+    if from > to {
+        return Ok(());
+    }
+
+    let stmt = tx.prepare("
+        UPDATE playlist_track
+        SET position = -position
+        WHERE playlist_id = $1 AND position BETWEEN $2 AND $3
+    ").await?;
+    tx.execute(&stmt, &[&id, &from, &to]).await?;
+
+    let stmt = tx.prepare("
+        UPDATE playlist_track
+        SET position = -position + $2
+        WHERE playlist_id = $1 AND position < 0
+    ").await?;
+    tx.execute(&stmt, &[&id, &by]).await?;
+
+    Ok(())
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportedPlaylist {
+    playlist: Playlist,
+    unresolved: Vec<UnresolvedEntry>,
+}
+
+// An entry in an imported file that matched no track
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UnresolvedEntry {
+    // 1-based, in file order
+    index: usize,
+    location: Option<String>,
+    title: Option<String>,
+    creator: Option<String>,
+}
+
+// GET /playlists/:id/export?format=m3u8|xspf|jspf
+pub async fn export_playlist(id: i32, user: User, opts: ExportOptions, db: DB) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    let format = match opts.format.parse::<Format>() {
+        Ok(f) => f,
+        Err(_) => return Ok(Box::new(warp::reply::with_status(warp::reply(), StatusCode::BAD_REQUEST))),
+    };
+
+    let client = db.get().await?;
+    let stmt = client.prepare("SELECT name, rules FROM playlist WHERE id = $1 AND owner_id = $2").await.map_err(Error::from)?;
+    let (name, rules): (String, Option<Json<Rules>>) = match client.query_opt(&stmt, &[&id, &user.id]).await.map_err(Error::from)? {
+        Some(row) => (row.get(0), row.get(1)),
+        None => return Ok(Box::new(warp::reply::with_status(warp::reply(), StatusCode::NOT_FOUND))),
+    };
+
+    let select_fields = "T.mbid, T.title, T.duration, T.file_location, R.title, A.name";
+    let rows = match rules {
+        Some(Json(rules)) => {
+            let (q, params) = rules::build_query(&rules, select_fields);
+            let stmt = client.prepare(&q).await.map_err(Error::from)?;
+            client.query(&stmt, &rules::param_refs(&params)).await.map_err(Error::from)?
+        }
+        None => {
+            let q = format!("
+                SELECT {}
+                FROM playlist_track PT
+                INNER JOIN track T ON T.id = PT.track_id
+                INNER JOIN album R ON R.id = T.album_id
+                INNER JOIN artist A ON A.id = R.artist_id
+                WHERE PT.playlist_id = $1
+                  AND T.available
+                ORDER BY PT.position ASC
+            ", select_fields);
+            let stmt = client.prepare(&q).await.map_err(Error::from)?;
+            client.query(&stmt, &[&id]).await.map_err(Error::from)?
+        }
+    };
+    let entries: Vec<_> = rows.iter()
+        .map(|row| {
+            let mbid: String = row.get(0);
+            let file_location: String = row.get(3);
+            Entry {
+                // Relative to the music root, so the server's layout isn't
+                // given away. Importing resolves it the same way.
+                location: Some(file_location.trim_start_matches('/').to_owned()),
+                // Tracks imported from tags alone have no real MBID
+                mbid: if mbid.starts_with("local:") { None } else { Some(mbid) },
+                title: row.get(1),
+                creator: row.get(5),
+                album: row.get(4),
+                duration: row.get(2),
+            }
+        })
+        .collect();
+
+    let res = warp::http::Response::builder()
+        .status(StatusCode::OK)
+        .header(warp::http::header::CONTENT_TYPE, format.content_type())
+        .header(
+            warp::http::header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"playlist-{}.{}\"", id, format.extension()),
+        )
+        .body(playlist_files::write(format, &name, &entries))
+        .unwrap();
+
+    Ok(Box::new(res))
+}
+
+// POST /playlists/import?format=m3u8|xspf|jspf(&name=X)
+// Creates a playlist from the entries in the body that match a track. Each
+// entry is matched by file location, then MusicBrainz recording id, then
+// by title and artist.
+pub async fn import_playlist(user: User, opts: ImportOptions, body: bytes::Bytes, db: DB, config: Config) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    let format = match opts.format.parse::<Format>() {
+        Ok(f) => f,
+        Err(_) => return Ok(Box::new(warp::reply::with_status(warp::reply(), StatusCode::BAD_REQUEST))),
+    };
+    let (title, entries) = match std::str::from_utf8(&body).ok().map(|s| playlist_files::parse(format, s)) {
+        Some(Ok(parsed)) => parsed,
+        Some(Err(e)) => {
+            return Ok(Box::new(warp::reply::with_status(e.to_string(), StatusCode::BAD_REQUEST)));
+        }
+        None => return Ok(Box::new(warp::reply::with_status(warp::reply(), StatusCode::BAD_REQUEST))),
+    };
+    let name = opts.name.or(title).unwrap_or_else(|| "Imported playlist".to_owned());
+
+    let mut client = db.get().await?;
+    let tx = client.transaction().await.map_err(Error::from)?;
+
+    let mut track_ids = Vec::new();
+    let mut unresolved = Vec::new();
+    for (i, entry) in entries.into_iter().enumerate() {
+        match resolve_entry(&entry, &tx, &config).await? {
+            Some(track_id) => track_ids.push(track_id),
+            None => unresolved.push(UnresolvedEntry {
+                index: i + 1,
+                location: entry.location,
+                title: entry.title,
+                creator: entry.creator,
+            }),
+        }
+    }
+
+    let stmt = tx.prepare("
+        INSERT INTO playlist (name, owner_id)
+        VALUES ($1, $2)
+        RETURNING id, name
+    ").await.map_err(Error::from)?;
+    let row = tx.query_one(&stmt, &[&name, &user.id]).await.map_err(Error::from)?;
+    let playlist = Playlist {
+        id: row.get(0),
+        name: row.get(1),
+        rules: None,
+        tracks: None,
+    };
+
+    let stmt = tx.prepare("
+        INSERT INTO playlist_track (playlist_id, track_id, position)
+        VALUES ($1, $2, $3)
+    ").await.map_err(Error::from)?;
+    for (i, track_id) in track_ids.iter().enumerate() {
+        tx.execute(&stmt, &[&playlist.id, track_id, &(i as i32 + 1)]).await.map_err(Error::from)?;
+    }
+    tx.commit().await.map_err(Error::from)?;
+
+    let res = ImportedPlaylist {
+        playlist,
+        unresolved,
+    };
+
+    Ok(Box::new(warp::reply::with_status(warp::reply::json(&res), StatusCode::CREATED)))
+}
+
+async fn resolve_entry(entry: &Entry, tx: &deadpool_postgres::Transaction<'_>, config: &Config) -> Result<Option<i32>, Error> {
+    if let Some(location) = &entry.location {
+        // Absolute paths must be under the music root. Relative ones are
+        // assumed to be relative to it.
+        let file_location = if location.starts_with('/') {
+            config.file_location(Path::new(location))
+        } else {
+            Some(format!("/{}", location))
+        };
+        if let Some(file_location) = file_location {
+            let stmt = tx.prepare("SELECT id FROM track WHERE file_location = $1").await?;
+            if let Some(row) = tx.query_opt(&stmt, &[&file_location]).await? {
+                return Ok(Some(row.get(0)));
+            }
+        }
+    }
+
+    if let Some(mbid) = &entry.mbid {
+        let stmt = tx.prepare("SELECT id FROM track WHERE mbid = $1 ORDER BY id ASC LIMIT 1").await?;
+        if let Some(row) = tx.query_opt(&stmt, &[mbid]).await? {
+            return Ok(Some(row.get(0)));
+        }
+    }
+
+    let title = match &entry.title {
+        Some(t) => t.to_lowercase(),
+        None => return Ok(None),
+    };
+    let creator = entry.creator.as_ref().map(|c| c.to_lowercase());
+    let stmt = tx.prepare("
+        SELECT T.id, T.title, A.name
+        FROM track T
+        INNER JOIN album R ON R.id = T.album_id
+        INNER JOIN artist A ON A.id = R.artist_id
+        WHERE T.title % $1 OR T.title ILIKE $2
+        ORDER BY similarity(T.title, $1) DESC, T.id ASC
+        LIMIT 50
+    ").await?;
+    let rows = tx.query(&stmt, &[&title, &escape_like(&title)]).await?;
+
+    // Allow roughly one typo per four characters
+    let max_score = (title.chars().count() + creator.as_ref().map(|c| c.chars().count()).unwrap_or(0)) / 4;
+    let best = rows.iter()
+        .map(|row| {
+            let mut score = damlev(&row.get::<_, String>(1).to_lowercase(), &title);
+            if let Some(creator) = &creator {
+                score += damlev(&row.get::<_, String>(2).to_lowercase(), creator);
+            }
+            (score, row.get::<_, i32>(0))
+        })
+        .min_by_key(|(score, _)| *score);
+
+    Ok(best.filter(|(score, _)| *score <= max_score).map(|(_, id)| id))
+}
+
+// Smart playlists have no playlist_track rows, so their tracks are computed
+// from their rules, numbered in order
+async fn smart_playlist_tracks(rules: &Rules, client: &deadpool_postgres::Client) -> Result<Vec<PlaylistTrack>, Error> {
+    let fields = format!("T.id, T.title, T.duration, R.id, R.title, {}, A.id, A.name", images::album_image_sql("R"));
+    let (q, params) = rules::build_query(rules, &fields);
+    let stmt = client.prepare(&q).await?;
+    let rows = client.query(&stmt, &rules::param_refs(&params)).await?;
+
+    let tracks = rows.iter()
+        .enumerate()
+        .map(|(i, row)| PlaylistTrack {
+            id: row.get(0),
+            title: row.get(1),
+            duration: row.get(2),
+            position: i as i32 + 1,
+            album_id: row.get(3),
+            album_title: row.get(4),
+            album_image: row.get(5),
+            artist_id: row.get(6),
+            artist_name: row.get(7),
+        })
+        .collect();
+
+    Ok(tracks)
+}
+
+// Whether the user's playlist `id` is a smart playlist, or None if they have
+// no such playlist
+async fn owned_playlist(id: i32, user: &User, client: &deadpool_postgres::Client) -> Result<Option<bool>, Error> {
+    let stmt = client.prepare("SELECT rules IS NOT NULL FROM playlist WHERE id = $1 AND owner_id = $2").await?;
+    let row = client.query_opt(&stmt, &[&id, &user.id]).await?;
+    Ok(row.map(|r| r.get(0)))
+}
+
+#[cfg(test)]
+mod tests {
+    use deadpool_postgres::{Manager, Pool, Transaction};
+    use tokio_postgres::NoTls;
+
+    use super::*;
+
+    // Connects to the database in TEST_DATABASE_URL. Tests work on a
+    // temporary playlist_track table, which shadows the real one, in a
+    // transaction that's never committed. They're ignored by default; run
+    // them with
+    //
+    //     TEST_DATABASE_URL=postgres://... cargo test -- --ignored
+    async fn test_client() -> deadpool_postgres::Client {
+        let url = std::env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL must be set to run database tests");
+        let manager = Manager::new(url.parse().unwrap(), NoTls);
+        Pool::new(manager, 1).get().await.unwrap()
+    }
+
+    // A playlist holding tracks 10, 20, 30, 40 and 50, in that order
+    async fn create_playlist(tx: &Transaction<'_>) {
+        tx.batch_execute("
+            CREATE TEMP TABLE playlist_track (
+                playlist_id integer NOT NULL,
+                track_id integer NOT NULL,
+                position integer NOT NULL,
+                PRIMARY KEY (playlist_id, position)
+            );
+        ").await.unwrap();
+        for track_id in &[10, 20, 30, 40, 50] {
+            append_track(1, *track_id, tx).await.unwrap();
+        }
+    }
+
+    async fn track_ids(tx: &Transaction<'_>) -> Vec<i32> {
+        let rows = tx.query("SELECT track_id FROM playlist_track WHERE playlist_id = 1 ORDER BY position", &[]).await.unwrap();
+        rows.iter().map(|r| r.get(0)).collect()
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn appends_in_order() {
+        let mut client = test_client().await;
+        let tx = client.transaction().await.unwrap();
+        create_playlist(&tx).await;
+
+        assert_eq!(append_track(1, 60, &tx).await.unwrap(), 6);
+        assert_eq!(append_track(2, 60, &tx).await.unwrap(), 1);
+        assert_eq!(track_ids(&tx).await, vec![10, 20, 30, 40, 50, 60]);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn moves_track_down() {
+        let mut client = test_client().await;
+        let tx = client.transaction().await.unwrap();
+        create_playlist(&tx).await;
+
+        assert_eq!(move_track(1, 2, 4, &tx).await.unwrap(), StatusCode::NO_CONTENT);
+        assert_eq!(track_ids(&tx).await, vec![10, 30, 40, 20, 50]);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn moves_track_up() {
+        let mut client = test_client().await;
+        let tx = client.transaction().await.unwrap();
+        create_playlist(&tx).await;
+
+        assert_eq!(move_track(1, 5, 1, &tx).await.unwrap(), StatusCode::NO_CONTENT);
+        assert_eq!(track_ids(&tx).await, vec![50, 10, 20, 30, 40]);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn move_out_of_range() {
+        let mut client = test_client().await;
+        let tx = client.transaction().await.unwrap();
+        create_playlist(&tx).await;
+
+        assert_eq!(move_track(1, 6, 1, &tx).await.unwrap(), StatusCode::NOT_FOUND);
+        assert_eq!(move_track(1, 0, 1, &tx).await.unwrap(), StatusCode::NOT_FOUND);
+        assert_eq!(move_track(1, 1, 6, &tx).await.unwrap(), StatusCode::BAD_REQUEST);
+        assert_eq!(move_track(1, 1, 0, &tx).await.unwrap(), StatusCode::BAD_REQUEST);
+        assert_eq!(track_ids(&tx).await, vec![10, 20, 30, 40, 50]);
+    }
+
+    #[test]
+    fn update_rules_missing_or_null() {
+        let p: UpdatePlaylist = serde_json::from_str(r#"{"name": "a"}"#).unwrap();
+        assert!(p.rules.is_none());
+        let p: UpdatePlaylist = serde_json::from_str(r#"{"rules": null}"#).unwrap();
+        assert_eq!(p.rules, Some(None));
+        let p: UpdatePlaylist = serde_json::from_str(r#"{"rules": {"rules": []}}"#).unwrap();
+        assert!(matches!(p.rules, Some(Some(_))));
+    }
+}
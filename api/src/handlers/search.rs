@@ -0,0 +1,261 @@
+use std::collections::BTreeSet;
+
+use crate::Error;
+use crate::db::{escape_like, DB};
+use crate::filters::PaginationOptions;
+use crate::filters::search::{SearchOptions, SearchType};
+use crate::handlers::albums::Album;
+use crate::handlers::artists::Artist;
+use crate::handlers::tracks::{ReplayGain, Track, REPLAYGAIN_FIELDS};
+use crate::images;
+
+#[derive(Serialize)]
+pub struct SearchResults {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    artists: Option<super::PaginatedResponse<Artist>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    albums: Option<super::PaginatedResponse<Album>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tracks: Option<super::PaginatedResponse<Track>>,
+}
+
+// GET /search?q=X(&type=artist,album,track&page=X&limit=Y)
+pub async fn search(opts: SearchOptions, page_opts: PaginationOptions, db: DB) -> Result<impl warp::Reply, warp::Rejection> {
+    let client = db.get().await?;
+    let q = opts.q.trim();
+    let types = opts.types
+        .filter(|t| !t.is_empty())
+        .unwrap_or_else(|| {
+            let mut all = BTreeSet::new();
+            all.insert(SearchType::Artist);
+            all.insert(SearchType::Album);
+            all.insert(SearchType::Track);
+            all
+        });
+
+    let mut results = SearchResults {
+        artists: None,
+        albums: None,
+        tracks: None,
+    };
+
+    for ty in types {
+        match ty {
+            SearchType::Artist => {
+                results.artists = Some(search_artists(q, &page_opts, &client).await?);
+            }
+            SearchType::Album => {
+                results.albums = Some(search_albums(q, &page_opts, &client).await?);
+            }
+            SearchType::Track => {
+                results.tracks = Some(search_tracks(q, &page_opts, &client).await?);
+            }
+        }
+    }
+
+    Ok(warp::reply::json(&results))
+}
+
+async fn search_artists(q: &str, opts: &PaginationOptions, client: &deadpool_postgres::Client)
+    -> Result<super::PaginatedResponse<Artist>, Error>
+{
+    let count = count_matches("artist A", &match_clause("A.name"), q, client).await?;
+    let (limit, page, offset, total_pages) = paginate(count, opts);
+    let query = format!("
+        SELECT A.id, A.mbid, A.name, {}
+        FROM artist A
+        WHERE {}
+        ORDER BY {} DESC, A.name ASC
+        LIMIT $3 OFFSET $4
+    ", images::artist_image_sql("A"), match_clause("A.name"), rank_expr("A.name"));
+    let stmt = client.prepare(&query).await?;
+    let rows = client.query(&stmt, &[&q, &escape_like(q), &limit, &offset]).await?;
+    let mut artists = Vec::new();
+
+    for row in rows {
+        let artist = Artist {
+            id: row.get(0),
+            mbid: row.get(1),
+            name: row.get(2),
+            image_url: row.get(3),
+            albums: None,
+            appears_on: None,
+        };
+        artists.push(artist);
+    }
+
+    Ok(super::PaginatedResponse {
+        page,
+        count,
+        total_pages,
+        data: artists,
+    })
+}
+
+async fn search_albums(q: &str, opts: &PaginationOptions, client: &deadpool_postgres::Client)
+    -> Result<super::PaginatedResponse<Album>, Error>
+{
+    let count = count_matches("album R", &match_clause("R.title"), q, client).await?;
+    let (limit, page, offset, total_pages) = paginate(count, opts);
+    let query = format!("
+        SELECT R.id, R.mbid, R.title, R.artist_id, {}, A.id, A.mbid, A.name, {}
+        FROM album R
+        INNER JOIN artist A ON A.id = R.artist_id
+        WHERE {}
+        ORDER BY {} DESC, R.title ASC
+        LIMIT $3 OFFSET $4
+    ", images::album_image_sql("R"), images::artist_image_sql("A"), match_clause("R.title"), rank_expr("R.title"));
+    let stmt = client.prepare(&query).await?;
+    let rows = client.query(&stmt, &[&q, &escape_like(q), &limit, &offset]).await?;
+    let mut albums = Vec::new();
+
+    for row in rows {
+        let artist = Artist {
+            id: row.get(5),
+            mbid: row.get(6),
+            name: row.get(7),
+            image_url: row.get(8),
+            albums: None,
+            appears_on: None,
+        };
+        let album = Album {
+            id: row.get(0),
+            mbid: row.get(1),
+            title: row.get(2),
+            artist_id: row.get(3),
+            image_url: row.get(4),
+            artist: Some(artist),
+            tracks: None,
+            discs: None,
+            credits: None,
+        };
+        albums.push(album);
+    }
+
+    Ok(super::PaginatedResponse {
+        page,
+        count,
+        total_pages,
+        data: albums,
+    })
+}
+
+async fn search_tracks(q: &str, opts: &PaginationOptions, client: &deadpool_postgres::Client)
+    -> Result<super::PaginatedResponse<Track>, Error>
+{
+    let count = count_matches("track T", &format!("T.available AND {}", match_clause("T.title")), q, client).await?;
+    let (limit, page, offset, total_pages) = paginate(count, opts);
+    let select_fields = &[
+        "T.id",
+        "T.mbid",
+        "T.title",
+        "T.position",
+        "T.bit_rate",
+        "T.duration",
+        "T.file_location",
+        "T.album_id",
+        "R.id",
+        "R.mbid",
+        "R.title",
+        "R.artist_id",
+        images::album_image_sql("R").as_str(),
+        "A.id",
+        "A.mbid",
+        "A.name",
+        images::artist_image_sql("A").as_str(),
+        "T.disc_number",
+        REPLAYGAIN_FIELDS.join(", ").as_str(),
+    ].join(", ");
+    let query = format!("
+        SELECT {}
+        FROM track T
+        INNER JOIN album R ON R.id = T.album_id
+        INNER JOIN artist A ON A.id = R.artist_id
+        WHERE T.available AND {}
+        ORDER BY {} DESC, T.title ASC
+        LIMIT $3 OFFSET $4
+    ", select_fields, match_clause("T.title"), rank_expr("T.title"));
+    let stmt = client.prepare(&query).await?;
+    let rows = client.query(&stmt, &[&q, &escape_like(q), &limit, &offset]).await?;
+    let mut tracks = Vec::new();
+
+    for row in rows {
+        let album = Album {
+            id: row.get(8),
+            mbid: row.get(9),
+            title: row.get(10),
+            artist_id: row.get(11),
+            image_url: row.get(12),
+            artist: None,
+            tracks: None,
+            discs: None,
+            credits: None,
+        };
+        let artist = Artist {
+            id: row.get(13),
+            mbid: row.get(14),
+            name: row.get(15),
+            image_url: row.get(16),
+            albums: None,
+            appears_on: None,
+        };
+        let track = Track {
+            id: row.get(0),
+            mbid: row.get(1),
+            title: row.get(2),
+            disc_number: row.get(17),
+            position: row.get(3),
+            bit_rate: row.get(4),
+            duration: row.get(5),
+            file_location: row.get(6),
+            album_id: row.get(7),
+            album: Some(album),
+            artist: Some(artist),
+            credits: None,
+            audio: None,
+            replaygain: ReplayGain::from_row(&row, 18),
+        };
+        tracks.push(track);
+    }
+
+    Ok(super::PaginatedResponse {
+        page,
+        count,
+        total_pages,
+        data: tracks,
+    })
+}
+
+async fn count_matches(table: &str, condition: &str, q: &str, client: &deadpool_postgres::Client) -> Result<i64, Error> {
+    let query = format!("SELECT COUNT(*) FROM {} WHERE {}", table, condition);
+    let stmt = client.prepare(&query).await?;
+    let rows = client.query(&stmt, &[&q, &escape_like(q)]).await?;
+    Ok(rows.first().map(|r| r.get(0)).unwrap_or(0))
+}
+
+// A row matches if it is similar enough by trigrams (catches typos and
+// partial words), contains the query as a substring, or matches it as a
+// full-text query. All three are backed by the indexes in
+// sql/7-create-search-indexes.sql. Takes the query as $1, and again as $2
+// escaped by escape_like.
+pub(super) fn match_clause(col: &str) -> String {
+    format!(
+        "({0} % $1 OR {0} ILIKE '%' || $2 || '%' OR to_tsvector('simple', {0}) @@ plainto_tsquery('simple', $1))",
+        col,
+    )
+}
+
+pub(super) fn rank_expr(col: &str) -> String {
+    format!(
+        "(similarity({0}, $1) + ts_rank(to_tsvector('simple', {0}), plainto_tsquery('simple', $1)))",
+        col,
+    )
+}
+
+fn paginate(count: i64, opts: &PaginationOptions) -> (i64, i64, i64, i64) {
+    let limit = opts.limit.unwrap_or(15).max(1);
+    let total_pages = (count as f64 / limit as f64).ceil() as i64;
+    let page = opts.page.unwrap_or(1).min(total_pages).max(1);
+    let offset = (page - 1) * limit;
+    (limit, page, offset, total_pages)
+}
@@ -0,0 +1,672 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use tokio_postgres::Row;
+use tokio_postgres::types::Json;
+use warp::http::HeaderMap;
+
+use crate::Error;
+use crate::auth;
+use crate::config::Config;
+use crate::db::{escape_like, DB};
+use crate::filters::tracks::PlayOptions;
+use crate::rules::{self, Rules};
+use crate::streaming;
+use crate::subsonic::{self, Context, CoverArt, Element, Failure, Format, Params};
+
+// Track fields for `song`, selected from track T, album R and artist A
+const SONG_FIELDS: &str = "T.id, T.title, T.position, T.disc_number, T.bit_rate, T.duration, T.file_location, T.mbid, T.added_at, R.id, R.title, A.id, A.name, T.codec, T.size, T.sample_rate, T.bit_depth, T.channels";
+
+// Album fields for `album`. Needs the album's tracks joined as T and grouped by
+// R.id and A.id.
+const ALBUM_FIELDS: &str = "R.id, R.title, R.mbid, A.id, A.name, COUNT(T.id), COALESCE(SUM(T.duration), 0), COALESCE(MIN(T.added_at), now())";
+
+const DEFAULT_SEARCH_COUNT: i64 = 20;
+const MAX_SEARCH_COUNT: i64 = 500;
+
+// Authenticates a request for filters::subsonic, by OpenSubsonic API key (a
+// session token) or by username and password. Token authentication needs the
+// plain password, which isn't stored, so it's refused. Clients send the
+// password with every request, so successful checks are cached.
+pub async fn authenticate(params: Vec<(String, String)>, db: DB) -> Result<Context, warp::Rejection> {
+    let params = Params(params);
+    let format = Format::from_param(params.get("f"));
+    let fail = |code: i32, message: &str| warp::reject::custom(Failure {
+        format,
+        code,
+        message: message.to_owned(),
+    });
+
+    let client = db.get().await?;
+    let user = if let Some(key) = params.get("apiKey") {
+        auth::user_for_token(key, &client).await?
+    } else {
+        let username = params.get("u").ok_or_else(|| fail(subsonic::ERROR_MISSING_PARAMETER, "Required parameter is missing: u"))?;
+        let password = match (params.get("p"), params.get("t")) {
+            (Some(p), _) => decode_password(p),
+            (None, Some(_)) => return Err(fail(subsonic::ERROR_TOKEN_AUTH_UNSUPPORTED, "Token authentication is not supported, use a password or API key")),
+            (None, None) => return Err(fail(subsonic::ERROR_MISSING_PARAMETER, "Required parameter is missing: p")),
+        };
+        match password {
+            Some(p) => auth::user_for_password(username, &p, db.passwords(), &client).await?,
+            None => None,
+        }
+    };
+
+    match user {
+        Some(user) => Ok(Context {
+            user,
+            format,
+            params,
+        }),
+        None => Err(fail(subsonic::ERROR_WRONG_CREDENTIALS, "Wrong username or password")),
+    }
+}
+
+// GET /rest/getOpenSubsonicExtensions
+//
+// Clients call this before authenticating to see what they can use
+pub async fn get_open_subsonic_extensions(params: Vec<(String, String)>) -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(subsonic::extensions(Format::from_param(Params(params).get("f"))))
+}
+
+// GET /rest/ping
+pub async fn ping(ctx: Context) -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(ctx.ok(None))
+}
+
+// GET /rest/getLicense
+pub async fn get_license(ctx: Context) -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(ctx.ok(Some(Element::new("license").attr("valid", true))))
+}
+
+// GET /rest/getArtists
+//
+// Only album artists are listed, like the artists page
+pub async fn get_artists(ctx: Context, db: DB) -> Result<impl warp::Reply, warp::Rejection> {
+    let client = db.get().await?;
+    let stmt = client.prepare("
+        SELECT A.id, A.name, A.mbid, COUNT(R.id)
+        FROM artist A
+        INNER JOIN album R ON R.artist_id = A.id
+        GROUP BY A.id
+    ").await.map_err(Error::from)?;
+    let mut rows = client.query(&stmt, &[]).await.map_err(Error::from)?;
+    rows.sort_by_cached_key(|r| subsonic::sort_name(r.get(1)).to_lowercase());
+
+    let mut indexes: BTreeMap<String, Vec<Element>> = BTreeMap::new();
+    for row in &rows {
+        let name: &str = row.get(1);
+        indexes.entry(subsonic::index_name(name)).or_insert_with(Vec::new).push(artist(row));
+    }
+    let indexes = indexes.into_iter()
+        .map(|(name, artists)| Element::new("index").attr("name", name).list("artist", artists))
+        .collect();
+
+    Ok(ctx.ok(Some(
+        Element::new("artists")
+            .attr("ignoredArticles", subsonic::IGNORED_ARTICLES.join(" "))
+            .list("index", indexes)
+    )))
+}
+
+// GET /rest/getArtist?id=X
+pub async fn get_artist(ctx: Context, db: DB) -> Result<impl warp::Reply, warp::Rejection> {
+    let id: i32 = match ctx.params.parse("id") {
+        Some(id) => id,
+        None => return Ok(ctx.missing("id")),
+    };
+
+    let client = db.get().await?;
+    let stmt = client.prepare("
+        SELECT A.id, A.name, A.mbid, (SELECT COUNT(*) FROM album R WHERE R.artist_id = A.id)
+        FROM artist A
+        WHERE A.id = $1
+    ").await.map_err(Error::from)?;
+    let row = match client.query_opt(&stmt, &[&id]).await.map_err(Error::from)? {
+        Some(row) => row,
+        None => return Ok(ctx.not_found("Artist")),
+    };
+
+    // Albums they're credited on as well as their own, like the artist page
+    let q = format!("
+        SELECT {}
+        FROM album R
+        INNER JOIN artist A ON A.id = R.artist_id
+        LEFT JOIN track T ON T.album_id = R.id AND T.available
+        WHERE R.artist_id = $1 OR EXISTS (SELECT 1 FROM album_artist C WHERE C.album_id = R.id AND C.artist_id = $1)
+        GROUP BY R.id, A.id
+        ORDER BY R.title ASC
+    ", ALBUM_FIELDS);
+    let stmt = client.prepare(&q).await.map_err(Error::from)?;
+    let albums = client.query(&stmt, &[&id]).await.map_err(Error::from)?
+        .iter()
+        .map(album)
+        .collect();
+
+    Ok(ctx.ok(Some(artist(&row).list("album", albums))))
+}
+
+// GET /rest/getAlbum?id=X
+pub async fn get_album(ctx: Context, db: DB) -> Result<impl warp::Reply, warp::Rejection> {
+    let id: i32 = match ctx.params.parse("id") {
+        Some(id) => id,
+        None => return Ok(ctx.missing("id")),
+    };
+
+    let client = db.get().await?;
+    let q = format!("
+        SELECT {}
+        FROM album R
+        INNER JOIN artist A ON A.id = R.artist_id
+        LEFT JOIN track T ON T.album_id = R.id AND T.available
+        WHERE R.id = $1
+        GROUP BY R.id, A.id
+    ", ALBUM_FIELDS);
+    let stmt = client.prepare(&q).await.map_err(Error::from)?;
+    let row = match client.query_opt(&stmt, &[&id]).await.map_err(Error::from)? {
+        Some(row) => row,
+        None => return Ok(ctx.not_found("Album")),
+    };
+
+    let q = format!("
+        SELECT {}
+        FROM track T
+        INNER JOIN album R ON R.id = T.album_id
+        INNER JOIN artist A ON A.id = R.artist_id
+        WHERE R.id = $1
+          AND T.available
+        ORDER BY T.disc_number ASC, T.position ASC
+    ", SONG_FIELDS);
+    let stmt = client.prepare(&q).await.map_err(Error::from)?;
+    let songs = client.query(&stmt, &[&id]).await.map_err(Error::from)?
+        .iter()
+        .map(|r| song("song", r))
+        .collect();
+
+    Ok(ctx.ok(Some(album(&row).list("song", songs))))
+}
+
+// GET /rest/getSong?id=X
+pub async fn get_song(ctx: Context, db: DB) -> Result<impl warp::Reply, warp::Rejection> {
+    let id: i32 = match ctx.params.parse("id") {
+        Some(id) => id,
+        None => return Ok(ctx.missing("id")),
+    };
+
+    let client = db.get().await?;
+    let q = format!("
+        SELECT {}
+        FROM track T
+        INNER JOIN album R ON R.id = T.album_id
+        INNER JOIN artist A ON A.id = R.artist_id
+        WHERE T.id = $1
+          AND T.available
+    ", SONG_FIELDS);
+    let stmt = client.prepare(&q).await.map_err(Error::from)?;
+    match client.query_opt(&stmt, &[&id]).await.map_err(Error::from)? {
+        Some(row) => Ok(ctx.ok(Some(song("song", &row)))),
+        None => Ok(ctx.not_found("Song")),
+    }
+}
+
+// GET /rest/stream?id=X(&maxBitRate=Y&format=Z)
+//
+// Served by play_track. A maxBitRate below the track's own is met by
+// transcoding to MP3 when no format is asked for.
+pub async fn stream(ctx: Context, headers: HeaderMap, db: DB, config: Config) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    let id: i32 = match ctx.params.parse("id") {
+        Some(id) => id,
+        None => return Ok(Box::new(ctx.missing("id"))),
+    };
+
+    let client = db.get().await?;
+    let stmt = client.prepare("SELECT bit_rate FROM track WHERE id = $1 AND available").await.map_err(Error::from)?;
+    let bit_rate: i32 = match client.query_opt(&stmt, &[&id]).await.map_err(Error::from)? {
+        Some(row) => row.get(0),
+        None => return Ok(Box::new(ctx.not_found("Song"))),
+    };
+    drop(client);
+
+    let max_bit_rate = ctx.params.parse::<i64>("maxBitRate").filter(|b| *b > 0);
+    let format = match ctx.params.get("format") {
+        Some("raw") => None,
+        Some(f) => Some(f.to_owned()),
+        None => match max_bit_rate {
+            Some(max) if max < bit_rate as i64 => Some("mp3".to_owned()),
+            _ => None,
+        },
+    };
+    let opts = PlayOptions {
+        format,
+        bitrate: max_bit_rate,
+    };
+
+    super::tracks::play_track(id, ctx.user, opts, headers, db, config).await
+}
+
+// GET /rest/getCoverArt?id=X(&size=Y)
+pub async fn get_cover_art(ctx: Context, headers: HeaderMap, db: DB, config: Config) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    let cover = match ctx.params.get("id") {
+        Some(id) => subsonic::parse_cover_id(id),
+        None => return Ok(Box::new(ctx.missing("id"))),
+    };
+    let (table, id) = match cover {
+        Some(CoverArt::Album(id)) => ("album", id),
+        Some(CoverArt::Artist(id)) => ("artist", id),
+        None => return Ok(Box::new(ctx.not_found("Cover art"))),
+    };
+
+    let client = db.get().await?;
+    let size = ctx.params.parse("size");
+    match super::images::image_response(table, id, size, &headers, &client, &config).await? {
+        Some(res) => Ok(res),
+        None => Ok(Box::new(ctx.not_found("Cover art"))),
+    }
+}
+
+// GET /rest/getPlaylists
+pub async fn get_playlists(ctx: Context, db: DB) -> Result<impl warp::Reply, warp::Rejection> {
+    let client = db.get().await?;
+    let stmt = client.prepare("
+        SELECT id, name, rules, created_at
+        FROM playlist
+        WHERE owner_id = $1
+        ORDER BY name ASC
+    ").await.map_err(Error::from)?;
+    let rows = client.query(&stmt, &[&ctx.user.id]).await.map_err(Error::from)?;
+
+    let mut playlists = Vec::new();
+    for row in &rows {
+        let songs = playlist_songs(row, &client).await?;
+        playlists.push(playlist(&ctx, row, &songs));
+    }
+
+    Ok(ctx.ok(Some(Element::new("playlists").list("playlist", playlists))))
+}
+
+// GET /rest/getPlaylist?id=X
+pub async fn get_playlist(ctx: Context, db: DB) -> Result<impl warp::Reply, warp::Rejection> {
+    let id: i32 = match ctx.params.parse("id") {
+        Some(id) => id,
+        None => return Ok(ctx.missing("id")),
+    };
+
+    let client = db.get().await?;
+    playlist_response(&ctx, id, &client).await
+}
+
+// GET /rest/createPlaylist?name=X&songId=Y&songId=Z
+// GET /rest/createPlaylist?playlistId=X&songId=Y
+//
+// With a playlistId, replaces that playlist's tracks
+pub async fn create_playlist(ctx: Context, db: DB) -> Result<impl warp::Reply, warp::Rejection> {
+    let song_ids: Vec<i32> = ctx.params.parse_all("songId");
+    let name = ctx.params.get("name").map(str::trim).filter(|n| !n.is_empty());
+
+    let mut client = db.get().await?;
+    let tx = client.transaction().await.map_err(Error::from)?;
+    let id = match ctx.params.get("playlistId") {
+        Some(id) => {
+            let id: i32 = match id.parse() {
+                Ok(id) => id,
+                Err(_) => return Ok(ctx.not_found("Playlist")),
+            };
+            match editable_playlist(&ctx, id, &tx).await? {
+                Ok(()) => {}
+                Err(res) => return Ok(res),
+            }
+            if let Some(name) = name {
+                tx.execute("UPDATE playlist SET name = $2 WHERE id = $1", &[&id, &name]).await.map_err(Error::from)?;
+            }
+            id
+        }
+        None => {
+            let name = match name {
+                Some(n) => n,
+                None => return Ok(ctx.missing("name")),
+            };
+            let stmt = tx.prepare("
+                INSERT INTO playlist (name, owner_id)
+                VALUES ($1, $2)
+                RETURNING id
+            ").await.map_err(Error::from)?;
+            tx.query_one(&stmt, &[&name, &ctx.user.id]).await.map_err(Error::from)?.get(0)
+        }
+    };
+    set_playlist_tracks(id, &song_ids, &tx).await?;
+    tx.commit().await.map_err(Error::from)?;
+
+    playlist_response(&ctx, id, &client).await
+}
+
+// GET /rest/updatePlaylist?playlistId=X(&name=Y&songIdToAdd=Z&songIndexToRemove=N)
+//
+// Indexes to remove are zero-based positions in the playlist before the
+// update. Added songs go on the end.
+pub async fn update_playlist(ctx: Context, db: DB) -> Result<impl warp::Reply, warp::Rejection> {
+    let id: i32 = match ctx.params.get("playlistId") {
+        Some(id) => match id.parse() {
+            Ok(id) => id,
+            Err(_) => return Ok(ctx.not_found("Playlist")),
+        },
+        None => return Ok(ctx.missing("playlistId")),
+    };
+    let name = ctx.params.get("name").map(str::trim).filter(|n| !n.is_empty());
+    let to_add: Vec<i32> = ctx.params.parse_all("songIdToAdd");
+    let to_remove: BTreeSet<usize> = ctx.params.parse_all("songIndexToRemove").into_iter().collect();
+
+    let mut client = db.get().await?;
+    let tx = client.transaction().await.map_err(Error::from)?;
+    let stmt = tx.prepare("SELECT rules IS NOT NULL FROM playlist WHERE id = $1 AND owner_id = $2").await.map_err(Error::from)?;
+    let is_smart: bool = match tx.query_opt(&stmt, &[&id, &ctx.user.id]).await.map_err(Error::from)? {
+        Some(row) => row.get(0),
+        None => return Ok(ctx.not_found("Playlist")),
+    };
+
+    if let Some(name) = name {
+        tx.execute("UPDATE playlist SET name = $2 WHERE id = $1", &[&id, &name]).await.map_err(Error::from)?;
+    }
+    if !to_add.is_empty() || !to_remove.is_empty() {
+        if is_smart {
+            return Ok(ctx.error(subsonic::ERROR_NOT_AUTHORIZED, "Smart playlists can't be edited"));
+        }
+        let stmt = tx.prepare("SELECT track_id FROM playlist_track WHERE playlist_id = $1 ORDER BY position ASC").await.map_err(Error::from)?;
+        let track_ids: Vec<i32> = tx.query(&stmt, &[&id]).await.map_err(Error::from)?
+            .iter()
+            .map(|r| r.get(0))
+            .enumerate()
+            .filter(|(i, _)| !to_remove.contains(i))
+            .map(|(_, t)| t)
+            .chain(to_add)
+            .collect();
+        set_playlist_tracks(id, &track_ids, &tx).await?;
+    }
+    tx.commit().await.map_err(Error::from)?;
+
+    Ok(ctx.ok(None))
+}
+
+// GET /rest/search3?query=X(&artistCount=N&artistOffset=M&albumCount=...&songCount=...)
+//
+// Matches like /search. An empty query matches everything, which clients use
+// to sync the whole library.
+pub async fn search3(ctx: Context, db: DB) -> Result<impl warp::Reply, warp::Rejection> {
+    let q = match ctx.params.get("query") {
+        Some(q) => q.trim().trim_matches('"').trim().to_owned(),
+        None => return Ok(ctx.missing("query")),
+    };
+    let page = |name: &str| {
+        let count = ctx.params.parse::<i64>(&format!("{}Count", name)).unwrap_or(DEFAULT_SEARCH_COUNT).max(0).min(MAX_SEARCH_COUNT);
+        let offset = ctx.params.parse::<i64>(&format!("{}Offset", name)).unwrap_or(0).max(0);
+        (count, offset)
+    };
+
+    let like = escape_like(&q);
+    let client = db.get().await?;
+
+    let (count, offset) = page("artist");
+    let q_artists = format!("
+        SELECT A.id, A.name, A.mbid, (SELECT COUNT(*) FROM album R WHERE R.artist_id = A.id)
+        FROM artist A
+        WHERE ($1 = '' OR {})
+        ORDER BY {} DESC, A.name ASC, A.id ASC
+        LIMIT $3 OFFSET $4
+    ", super::search::match_clause("A.name"), super::search::rank_expr("A.name"));
+    let stmt = client.prepare(&q_artists).await.map_err(Error::from)?;
+    let artists = client.query(&stmt, &[&q, &like, &count, &offset]).await.map_err(Error::from)?
+        .iter()
+        .map(artist)
+        .collect();
+
+    let (count, offset) = page("album");
+    let q_albums = format!("
+        SELECT {}
+        FROM album R
+        INNER JOIN artist A ON A.id = R.artist_id
+        LEFT JOIN track T ON T.album_id = R.id AND T.available
+        WHERE ($1 = '' OR {})
+        GROUP BY R.id, A.id
+        ORDER BY {} DESC, R.title ASC, R.id ASC
+        LIMIT $3 OFFSET $4
+    ", ALBUM_FIELDS, super::search::match_clause("R.title"), super::search::rank_expr("R.title"));
+    let stmt = client.prepare(&q_albums).await.map_err(Error::from)?;
+    let albums = client.query(&stmt, &[&q, &like, &count, &offset]).await.map_err(Error::from)?
+        .iter()
+        .map(album)
+        .collect();
+
+    let (count, offset) = page("song");
+    let q_songs = format!("
+        SELECT {}
+        FROM track T
+        INNER JOIN album R ON R.id = T.album_id
+        INNER JOIN artist A ON A.id = R.artist_id
+        WHERE T.available AND ($1 = '' OR {})
+        ORDER BY {} DESC, T.title ASC, T.id ASC
+        LIMIT $3 OFFSET $4
+    ", SONG_FIELDS, super::search::match_clause("T.title"), super::search::rank_expr("T.title"));
+    let stmt = client.prepare(&q_songs).await.map_err(Error::from)?;
+    let songs = client.query(&stmt, &[&q, &like, &count, &offset]).await.map_err(Error::from)?
+        .iter()
+        .map(|r| song("song", r))
+        .collect();
+
+    Ok(ctx.ok(Some(
+        Element::new("searchResult3")
+            .list("artist", artists)
+            .list("album", albums)
+            .list("song", songs)
+    )))
+}
+
+// The user's playlist with its entries, or an error if they have no such
+// playlist
+async fn playlist_response(ctx: &Context, id: i32, client: &deadpool_postgres::Client) -> Result<warp::http::Response<Vec<u8>>, warp::Rejection> {
+    let stmt = client.prepare("
+        SELECT id, name, rules, created_at
+        FROM playlist
+        WHERE id = $1 AND owner_id = $2
+    ").await.map_err(Error::from)?;
+    let row = match client.query_opt(&stmt, &[&id, &ctx.user.id]).await.map_err(Error::from)? {
+        Some(row) => row,
+        None => return Ok(ctx.not_found("Playlist")),
+    };
+
+    let songs = playlist_songs(&row, client).await?;
+    let entries = songs.iter().map(|r| song("entry", r)).collect();
+    Ok(ctx.ok(Some(playlist(ctx, &row, &songs).list("entry", entries))))
+}
+
+// The SONG_FIELDS of a playlist's tracks, from a row of id, name, rules
+async fn playlist_songs(row: &Row, client: &deadpool_postgres::Client) -> Result<Vec<Row>, Error> {
+    let id: i32 = row.get(0);
+    let rows = match row.get::<_, Option<Json<Rules>>>(2) {
+        Some(Json(rules)) => {
+            let (q, params) = rules::build_query(&rules, SONG_FIELDS);
+            let stmt = client.prepare(&q).await?;
+            client.query(&stmt, &rules::param_refs(&params)).await?
+        }
+        None => {
+            let q = format!("
+                SELECT {}
+                FROM playlist_track PT
+                INNER JOIN track T ON T.id = PT.track_id
+                INNER JOIN album R ON R.id = T.album_id
+                INNER JOIN artist A ON A.id = R.artist_id
+                WHERE PT.playlist_id = $1
+                  AND T.available
+                ORDER BY PT.position ASC
+            ", SONG_FIELDS);
+            let stmt = client.prepare(&q).await?;
+            client.query(&stmt, &[&id]).await?
+        }
+    };
+    Ok(rows)
+}
+
+// Checks the playlist can have its tracks replaced, returning the error to
+// respond with if not
+async fn editable_playlist(ctx: &Context, id: i32, tx: &deadpool_postgres::Transaction<'_>) -> Result<Result<(), warp::http::Response<Vec<u8>>>, Error> {
+    let stmt = tx.prepare("SELECT rules IS NOT NULL FROM playlist WHERE id = $1 AND owner_id = $2").await?;
+    match tx.query_opt(&stmt, &[&id, &ctx.user.id]).await? {
+        Some(row) if row.get::<_, bool>(0) => Ok(Err(ctx.error(subsonic::ERROR_NOT_AUTHORIZED, "Smart playlists can't be edited"))),
+        Some(_) => Ok(Ok(())),
+        None => Ok(Err(ctx.not_found("Playlist"))),
+    }
+}
+
+// Replaces a playlist's tracks, skipping ids that aren't tracks
+async fn set_playlist_tracks(id: i32, track_ids: &[i32], tx: &deadpool_postgres::Transaction<'_>) -> Result<(), Error> {
+    tx.execute("DELETE FROM playlist_track WHERE playlist_id = $1", &[&id]).await?;
+    let stmt = tx.prepare("
+        INSERT INTO playlist_track (playlist_id, track_id, position)
+        SELECT $1, T.id, (ROW_NUMBER() OVER (ORDER BY U.ord))::integer
+        FROM unnest($2::integer[]) WITH ORDINALITY AS U(id, ord)
+        INNER JOIN track T ON T.id = U.id
+    ").await?;
+    tx.execute(&stmt, &[&id, &track_ids]).await?;
+    Ok(())
+}
+
+// From a row of id, name, rules, created_at and the playlist's songs
+fn playlist(ctx: &Context, row: &Row, songs: &[Row]) -> Element {
+    let id: i32 = row.get(0);
+    let name: String = row.get(1);
+    let created: DateTime<Utc> = row.get(3);
+    let duration: i64 = songs.iter().map(|r| r.get::<_, i32>(5) as i64).sum();
+    Element::new("playlist")
+        .attr("id", id.to_string())
+        .attr("name", name)
+        .attr("owner", ctx.user.username.as_str())
+        .attr("public", false)
+        .attr("songCount", songs.len() as i64)
+        .attr("duration", duration)
+        .attr("created", created.to_rfc3339())
+        .attr("changed", created.to_rfc3339())
+}
+
+// From a row of id, name, mbid and album count
+fn artist(row: &Row) -> Element {
+    let id: i32 = row.get(0);
+    let name: String = row.get(1);
+    let mbid: String = row.get(2);
+    let album_count: i64 = row.get(3);
+    Element::new("artist")
+        .attr("id", id.to_string())
+        .attr("name", name)
+        .attr("coverArt", subsonic::artist_cover_id(id))
+        .attr("albumCount", album_count)
+        .opt_attr("musicBrainzId", musicbrainz_id(mbid))
+}
+
+// From a row of ALBUM_FIELDS
+fn album(row: &Row) -> Element {
+    let id: i32 = row.get(0);
+    let title: String = row.get(1);
+    let mbid: String = row.get(2);
+    let artist_id: i32 = row.get(3);
+    let artist_name: String = row.get(4);
+    let song_count: i64 = row.get(5);
+    let duration: i64 = row.get(6);
+    let created: DateTime<Utc> = row.get(7);
+    Element::new("album")
+        .attr("id", id.to_string())
+        .attr("name", title)
+        .attr("artist", artist_name)
+        .attr("artistId", artist_id.to_string())
+        .attr("coverArt", subsonic::album_cover_id(id))
+        .attr("songCount", song_count)
+        .attr("duration", duration)
+        .attr("created", created.to_rfc3339())
+        .opt_attr("musicBrainzId", musicbrainz_id(mbid))
+}
+
+// From a row of SONG_FIELDS
+fn song(name: &'static str, row: &Row) -> Element {
+    let id: i32 = row.get(0);
+    let title: String = row.get(1);
+    let position: i32 = row.get(2);
+    let disc_number: i32 = row.get(3);
+    let bit_rate: i32 = row.get(4);
+    let duration: i32 = row.get(5);
+    let file_location: String = row.get(6);
+    let mbid: String = row.get(7);
+    let added_at: DateTime<Utc> = row.get(8);
+    let album_id: i32 = row.get(9);
+    let album_title: String = row.get(10);
+    let artist_id: i32 = row.get(11);
+    let artist_name: String = row.get(12);
+    let codec: Option<String> = row.get(13);
+    let size: Option<i64> = row.get(14);
+    let sample_rate: Option<i32> = row.get(15);
+    let bit_depth: Option<i32> = row.get(16);
+    let channels: Option<i32> = row.get(17);
+
+    let path = Path::new(&file_location);
+    let suffix = path.extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase())
+        .unwrap_or_default();
+    Element::new(name)
+        .attr("id", id.to_string())
+        .attr("parent", album_id.to_string())
+        .attr("isDir", false)
+        .attr("title", title)
+        .attr("album", album_title)
+        .attr("artist", artist_name)
+        .attr("track", position)
+        .attr("discNumber", disc_number)
+        .attr("coverArt", subsonic::album_cover_id(album_id))
+        .attr("contentType", streaming::content_type_for(codec.as_deref(), path))
+        .attr("suffix", suffix)
+        .attr("duration", duration)
+        .attr("bitRate", bit_rate)
+        .opt_attr("size", size)
+        .opt_attr("samplingRate", sample_rate)
+        .opt_attr("bitDepth", bit_depth)
+        .opt_attr("channelCount", channels)
+        .attr("path", file_location.trim_start_matches('/'))
+        .attr("isVideo", false)
+        .attr("created", added_at.to_rfc3339())
+        .attr("albumId", album_id.to_string())
+        .attr("artistId", artist_id.to_string())
+        .attr("type", "music")
+        .attr("mediaType", "song")
+        .opt_attr("musicBrainzId", musicbrainz_id(mbid))
+}
+
+// Surrogate ids from offline imports aren't MusicBrainz ids
+fn musicbrainz_id(mbid: String) -> Option<String> {
+    if mbid.starts_with("local:") { None } else { Some(mbid) }
+}
+
+// Passwords can be sent hex encoded, as enc:<hex>
+fn decode_password(p: &str) -> Option<String> {
+    let hex = match p.strip_prefix("enc:") {
+        Some(hex) => hex,
+        None => return Some(p.to_owned()),
+    };
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    let bytes: Option<Vec<u8>> = (0..hex.len())
+        .step_by(2)
+        .map(|i| hex.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+        .collect();
+    bytes.and_then(|b| String::from_utf8(b).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_passwords() {
+        assert_eq!(decode_password("sesame").as_deref(), Some("sesame"));
+        assert_eq!(decode_password("enc:736573616d65").as_deref(), Some("sesame"));
+        assert_eq!(decode_password("enc:7365736"), None);
+        assert_eq!(decode_password("enc:zz"), None);
+    }
+}
@@ -1,7 +1,14 @@
-use warp::http::StatusCode;
+use av::transcode::{OutputFormat, TranscodeOptions};
+use warp::http::{HeaderMap, StatusCode};
 
 use crate::Error;
+use crate::auth::User;
+use crate::config::Config;
 use crate::db::DB;
+use crate::filters::tracks::PlayOptions;
+use crate::images;
+use crate::streaming;
+use crate::transcoding::{self, TranscodeCache};
 use crate::handlers::artists::Artist;
 use crate::handlers::albums::Album;
 
@@ -10,6 +17,7 @@ pub struct Track {
     pub id: i32,
     pub mbid: String,
     pub title: String,
+    pub disc_number: i32,
     pub position: i32,
     pub bit_rate: i32,
     pub duration: i32,
@@ -19,6 +27,58 @@ pub struct Track {
     pub artist: Option<Artist>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub album: Option<Album>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub credits: Option<Vec<super::credits::Credit>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audio: Option<AudioProperties>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub replaygain: Option<ReplayGain>,
+}
+
+// The technical properties of the track's file. Only known for tracks the
+// importer has read them for, and null where FFmpeg didn't report one.
+#[derive(Serialize)]
+pub struct AudioProperties {
+    pub codec: String,
+    pub sample_rate: Option<i32>,
+    // Lossy codecs have none
+    pub bit_depth: Option<i32>,
+    pub channels: Option<i32>,
+    pub duration_ms: Option<i64>,
+    // In bytes
+    pub size: Option<i64>,
+    pub lossless: Option<bool>,
+}
+
+// Gains in dB relative to -18 LUFS, for clients to normalize volume with,
+// and peaks where 1.0 is full scale. Either tagged or measured by the
+// importer, so only missing for tracks it couldn't read and silent ones.
+#[derive(Serialize)]
+pub struct ReplayGain {
+    pub track_gain: f32,
+    pub track_peak: Option<f32>,
+    pub album_gain: Option<f32>,
+    pub album_peak: Option<f32>,
+}
+
+// Selected in this order for ReplayGain::from_row
+pub const REPLAYGAIN_FIELDS: &[&str] = &[
+    "T.replaygain_track_gain",
+    "T.replaygain_track_peak",
+    "T.replaygain_album_gain",
+    "T.replaygain_album_peak",
+];
+
+impl ReplayGain {
+    // Reads the REPLAYGAIN_FIELDS selected from column `start` on
+    pub fn from_row(row: &tokio_postgres::Row, start: usize) -> Option<ReplayGain> {
+        row.get::<_, Option<f32>>(start).map(|track_gain| ReplayGain {
+            track_gain,
+            track_peak: row.get(start + 1),
+            album_gain: row.get(start + 2),
+            album_peak: row.get(start + 3),
+        })
+    }
 }
 
 pub async fn get_track_with_id(id: i32, db: DB) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
@@ -35,11 +95,20 @@ pub async fn get_track_with_id(id: i32, db: DB) -> Result<Box<dyn warp::Reply>,
         "R.mbid",
         "R.title",
         "R.artist_id",
-        "R.image_url",
+        images::album_image_sql("R").as_str(),
         "A.id",
         "A.mbid",
         "A.name",
-        "A.image_url",
+        images::artist_image_sql("A").as_str(),
+        "T.disc_number",
+        "T.codec",
+        "T.sample_rate",
+        "T.bit_depth",
+        "T.channels",
+        "T.duration_ms",
+        "T.size",
+        "T.lossless",
+        REPLAYGAIN_FIELDS.join(", ").as_str(),
     ].join(", ");
     let client = db.get().await?;
     let q = format!("
@@ -48,6 +117,7 @@ pub async fn get_track_with_id(id: i32, db: DB) -> Result<Box<dyn warp::Reply>,
         INNER JOIN album R ON R.id = T.album_id
         INNER JOIN artist A ON A.id = R.artist_id
         WHERE T.id = $1
+          AND T.available
     ", select_fields);
     let stmt = client.prepare(&q).await.map_err(Error::from)?;
     let rows = client.query(&stmt, &[&id]).await.map_err(Error::from)?;
@@ -57,14 +127,17 @@ pub async fn get_track_with_id(id: i32, db: DB) -> Result<Box<dyn warp::Reply>,
     }
 
     let row = &rows[0];
+    let album_id: i32 = row.get(8);
     let album = Album {
-        id: row.get(8),
+        id: album_id,
         mbid: row.get(9),
         title: row.get(10),
         artist_id: row.get(11),
         image_url: row.get(12),
         artist: None,
         tracks: None,
+        discs: None,
+        credits: super::credits::album_credits(&[album_id], &client).await?.remove(&album_id),
     };
     let artist = Artist {
         id: row.get(13),
@@ -72,11 +145,13 @@ pub async fn get_track_with_id(id: i32, db: DB) -> Result<Box<dyn warp::Reply>,
         name: row.get(15),
         image_url: row.get(16),
         albums: None,
+        appears_on: None,
     };
     let track = Track {
         id: row.get(0),
         mbid: row.get(1),
         title: row.get(2),
+        disc_number: row.get(17),
         position: row.get(3),
         bit_rate: row.get(4),
         duration: row.get(5),
@@ -84,15 +159,37 @@ pub async fn get_track_with_id(id: i32, db: DB) -> Result<Box<dyn warp::Reply>,
         album_id: row.get(7),
         album: Some(album),
         artist: Some(artist),
+        credits: super::credits::track_credits(&[id], &client).await?.remove(&id),
+        audio: row.get::<_, Option<String>>(18).map(|codec| AudioProperties {
+            codec,
+            sample_rate: row.get(19),
+            bit_depth: row.get(20),
+            channels: row.get(21),
+            duration_ms: row.get(22),
+            size: row.get(23),
+            lossless: row.get(24),
+        }),
+        replaygain: ReplayGain::from_row(row, 25),
     };
 
     Ok(Box::new(warp::reply::json(&track)))
 }
 
-pub async fn play_track(id: i32, db: DB) -> Result<impl warp::Reply, warp::Rejection> {
+pub async fn play_track(id: i32, user: User, opts: PlayOptions, headers: HeaderMap, db: DB, config: Config) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    let transcode_opts = match opts.format.as_ref() {
+        Some(format) => match format.parse::<OutputFormat>() {
+            Ok(format) => Some(TranscodeOptions {
+                format,
+                bit_rate: opts.bitrate.unwrap_or(128).max(32).min(320),
+            }),
+            Err(_) => return Ok(Box::new(warp::reply::with_status(warp::reply(), StatusCode::BAD_REQUEST))),
+        },
+        None => None,
+    };
+
     let client = db.get().await?;
     let stmt = client.prepare("
-        SELECT file_location
+        SELECT file_location, codec, available
         FROM track
         WHERE id = $1
     ").await.map_err(Error::from)?;
@@ -101,8 +198,52 @@ pub async fn play_track(id: i32, db: DB) -> Result<impl warp::Reply, warp::Rejec
     if rows.len() < 1 {
         return Err(warp::reject());
     }
+    // The importer marks tracks whose file has gone as unavailable rather
+    // than deleting them, so playlists and history keep their rows.
+    if !rows[0].get::<_, bool>(2) {
+        return Ok(Box::new(warp::reply::with_status(warp::reply(), StatusCode::GONE)));
+    }
+
+    let path = config.music_path(&rows[0].get::<_, String>(0));
+    let source = match tokio::fs::metadata(&path).await {
+        Ok(md) => md,
+        Err(_) => return Ok(Box::new(warp::reply::with_status(warp::reply(), StatusCode::NOT_FOUND))),
+    };
+
+    // Players request later ranges as they seek or buffer, so only a
+    // request from the start counts as a play. Requests for missing files
+    // never get this far, so retries don't count either.
+    if streaming::is_initial_request(&headers) {
+        super::history::log_stream(&user, id, &client).await?;
+    }
+
+    let transcode_opts = match transcode_opts {
+        Some(t) => t,
+        None => {
+            let codec: Option<String> = rows[0].get(1);
+            let content_type = streaming::content_type_for(codec.as_deref(), &path);
+            return match streaming::serve_file(&path, content_type, &headers).await? {
+                Some(res) => Ok(Box::new(res)),
+                None => Ok(Box::new(warp::reply::with_status(warp::reply(), StatusCode::NOT_FOUND))),
+            };
+        }
+    };
+
+    // Finished transcodes are plain files, so they can be served with range
+    // support like the originals.
+    let cache = TranscodeCache::new(&config);
+    let cached = cache.path_for(id, &source, &transcode_opts);
+    if let Some(res) = streaming::serve_file(&cached, transcode_opts.format.content_type(), &headers).await? {
+        return Ok(Box::new(res));
+    }
+
+    let body = transcoding::transcode_to_body(path, cached, cache, transcode_opts);
+    let res = warp::http::Response::builder()
+        .status(StatusCode::OK)
+        .header(warp::http::header::CONTENT_TYPE, transcode_opts.format.content_type())
+        .header(warp::http::header::ACCEPT_RANGES, "none")
+        .body(body)
+        .unwrap();
 
-    let file_location = format!("/api/static{}", rows[0].get::<_, String>(0));
-    let redir_location = crate::encoding::encode_uri(&file_location);
-    Ok(warp::redirect::temporary(redir_location.parse::<warp::http::Uri>().unwrap()))
+    Ok(Box::new(res))
 }
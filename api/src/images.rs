@@ -0,0 +1,100 @@
+use std::path::{Path, PathBuf};
+
+use image::imageops::FilterType;
+use image::{GenericImageView, ImageFormat};
+
+use crate::Error;
+
+// Thumbnails are made at these sizes, in pixels along the longest side, and
+// requested sizes are rounded up to the next one so there are only a few per
+// image. Larger requests get the original.
+const THUMBNAIL_SIZES: [u32; 5] = [64, 128, 250, 500, 1000];
+
+// The importer keeps stored images at the top of the store, so thumbnails get
+// a directory of their own. See importer/src/images.rs.
+const THUMBNAIL_DIR: &str = "thumbnails";
+
+// The URL of the cover of album `alias` in a select, or NULL if it has none
+pub fn album_image_sql(alias: &str) -> String {
+    format!(
+        "CASE WHEN {0}.image_url IS NOT NULL OR {0}.image_file IS NOT NULL THEN '/albums/' || {0}.id || '/cover' END",
+        alias,
+    )
+}
+
+// The URL of the image of artist `alias` in a select, or NULL if it has none
+pub fn artist_image_sql(alias: &str) -> String {
+    format!(
+        "CASE WHEN {0}.image_url IS NOT NULL OR {0}.image_file IS NOT NULL THEN '/artists/' || {0}.id || '/image' END",
+        alias,
+    )
+}
+
+pub fn thumbnail_size(requested: u32) -> Option<u32> {
+    THUMBNAIL_SIZES.iter().copied().find(|&s| s >= requested)
+}
+
+pub fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("png") => "image/png",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        _ => "image/jpeg",
+    }
+}
+
+// Returns the path of stored image `file` scaled down to fit in `size`,
+// making it first if it isn't cached. Returns None if the image is missing.
+pub async fn thumbnail(store: &Path, file: &str, size: u32) -> Result<Option<PathBuf>, Error> {
+    let source = store.join(file);
+    if tokio::fs::metadata(&source).await.is_err() {
+        return Ok(None);
+    }
+    let stem = Path::new(file).file_stem().and_then(|s| s.to_str()).unwrap_or(file);
+    let path = store.join(THUMBNAIL_DIR).join(format!("{}-{}.jpg", stem, size));
+    if tokio::fs::metadata(&path).await.is_ok() {
+        return Ok(Some(path));
+    }
+
+    tokio::fs::create_dir_all(store.join(THUMBNAIL_DIR)).await?;
+    let dest = path.clone();
+    tokio::task::spawn_blocking(move || make_thumbnail(&source, &dest, size))
+        .await
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))??;
+    Ok(Some(path))
+}
+
+fn make_thumbnail(source: &Path, dest: &Path, size: u32) -> Result<(), Error> {
+    let img = image::open(source)?;
+    // Never scaled up
+    let img = if img.width() > size || img.height() > size {
+        img.resize(size, size, FilterType::Lanczos3)
+    } else {
+        img
+    };
+
+    // Written under another name first, so a request that comes in while
+    // it's being made never serves half a thumbnail. The name is unique, as
+    // concurrent requests for the same thumbnail each make their own.
+    let partial = dest.with_extension(format!("jpg.{}-{:016x}.partial", std::process::id(), rand::random::<u64>()));
+    let res = img.to_rgb8().save_with_format(&partial, ImageFormat::Jpeg)
+        .map_err(Error::from)
+        .and_then(|_| std::fs::rename(&partial, dest).map_err(Error::from));
+    if res.is_err() {
+        let _ = std::fs::remove_file(&partial);
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn thumbnail_sizes() {
+        assert_eq!(thumbnail_size(1), Some(64));
+        assert_eq!(thumbnail_size(250), Some(250));
+        assert_eq!(thumbnail_size(300), Some(500));
+        assert_eq!(thumbnail_size(1001), None);
+    }
+}
@@ -0,0 +1,346 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use reqwest::StatusCode;
+
+use crate::Error;
+use crate::config::Config;
+use crate::db::DB;
+
+// The submit-listens payload, as sent to and accepted from clients
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SubmitListens {
+    pub listen_type: ListenType,
+    pub payload: Vec<Listen>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ListenType {
+    Single,
+    Import,
+    PlayingNow,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Listen {
+    // Unix seconds. Absent for playing_now.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub listened_at: Option<i64>,
+    pub track_metadata: TrackMetadata,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TrackMetadata {
+    pub artist_name: String,
+    pub track_name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub release_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub additional_info: Option<AdditionalInfo>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct AdditionalInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recording_mbid: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub release_mbid: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub artist_mbids: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tracknumber: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub submission_client: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum SubmitError {
+    RequestError(reqwest::Error),
+    StatusError(StatusCode, String),
+}
+
+impl std::fmt::Display for SubmitError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        use SubmitError::*;
+        match self {
+            RequestError(e) => write!(fmt, "request failed: {}", e),
+            StatusError(status, body) => write!(fmt, "server responded {}: {}", status, body),
+        }
+    }
+}
+
+impl std::error::Error for SubmitError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use SubmitError::*;
+        match self {
+            RequestError(e) => Some(e),
+            StatusError(_, _) => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for SubmitError {
+    fn from(e: reqwest::Error) -> Self {
+        SubmitError::RequestError(e)
+    }
+}
+
+const SUBMISSION_CLIENT: &str = "doplr";
+
+// ListenBrainz accepts up to 1000 listens per request, but smaller batches
+// lose less to a single bad listen
+const MAX_LISTENS_PER_REQUEST: usize = 100;
+
+const QUEUE_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+// Listens that still fail after this many attempts are dropped
+const MAX_ATTEMPTS: i32 = 10;
+
+// Backoff doubles from a minute up to six hours
+const MIN_BACKOFF_SECS: i64 = 60;
+const MAX_BACKOFF_SECS: i64 = 6 * 60 * 60;
+
+pub async fn submit(http: &reqwest::Client, base_url: &str, token: &str, listens: &SubmitListens) -> Result<(), SubmitError> {
+    let res = http.post(&format!("{}/1/submit-listens", base_url.trim_end_matches('/')))
+        .header(reqwest::header::AUTHORIZATION, format!("Token {}", token))
+        .json(listens)
+        .send()
+        .await?;
+
+    if res.status().is_success() {
+        Ok(())
+    } else {
+        let status = res.status();
+        Err(SubmitError::StatusError(status, res.text().await.unwrap_or_default()))
+    }
+}
+
+// Queues a play to be forwarded, if its user has a ListenBrainz token
+pub async fn enqueue(play_event_id: i32, client: &deadpool_postgres::Client) -> Result<(), Error> {
+    let stmt = client.prepare("
+        INSERT INTO listenbrainz_queue (play_event_id)
+        SELECT E.id
+        FROM play_event E
+        INNER JOIN \"user\" U ON U.id = E.user_id
+        WHERE E.id = $1 AND U.listenbrainz_token IS NOT NULL
+        ON CONFLICT DO NOTHING
+    ").await?;
+    client.execute(&stmt, &[&play_event_id]).await?;
+    Ok(())
+}
+
+// Forwards queued listens until the process exits
+pub async fn run_queue(db: DB, config: Config) {
+    let http = reqwest::Client::new();
+    loop {
+        if let Err(e) = flush_queue(&db, &config.listenbrainz_url, &http).await {
+            eprintln!("Failed to forward listens: {}", e);
+        }
+        tokio::time::delay_for(QUEUE_POLL_INTERVAL).await;
+    }
+}
+
+struct QueuedListen {
+    play_event_id: i32,
+    listen: Listen,
+}
+
+async fn flush_queue(db: &DB, base_url: &str, http: &reqwest::Client) -> Result<(), Error> {
+    let client = db.get().await?;
+
+    // Users who removed their token since the play was queued
+    client.execute("
+        DELETE FROM listenbrainz_queue Q
+        USING play_event E, \"user\" U
+        WHERE E.id = Q.play_event_id AND U.id = E.user_id AND U.listenbrainz_token IS NULL
+    ", &[]).await?;
+
+    let stmt = client.prepare("
+        SELECT Q.play_event_id, U.listenbrainz_token, E.played_at, T.title, T.mbid, T.position, T.duration, R.title, R.mbid, A.name, A.mbid
+        FROM listenbrainz_queue Q
+        INNER JOIN play_event E ON E.id = Q.play_event_id
+        INNER JOIN \"user\" U ON U.id = E.user_id
+        INNER JOIN track T ON T.id = E.track_id
+        INNER JOIN album R ON R.id = T.album_id
+        INNER JOIN artist A ON A.id = R.artist_id
+        WHERE Q.next_attempt_at <= now() AND U.listenbrainz_token IS NOT NULL
+        ORDER BY E.played_at ASC
+        LIMIT 1000
+    ").await?;
+    let rows = client.query(&stmt, &[]).await?;
+
+    let mut by_token: BTreeMap<String, Vec<QueuedListen>> = BTreeMap::new();
+    for row in &rows {
+        let played_at: DateTime<Utc> = row.get(2);
+        let listen = Listen {
+            listened_at: Some(played_at.timestamp()),
+            track_metadata: TrackMetadata {
+                artist_name: row.get(9),
+                track_name: row.get(3),
+                release_name: row.get(7),
+                additional_info: Some(AdditionalInfo {
+                    recording_mbid: mbid(row.get(4)),
+                    release_mbid: mbid(row.get(8)),
+                    artist_mbids: mbid(row.get(10)).map(|m| vec![m]),
+                    tracknumber: row.get(5),
+                    duration_ms: Some(row.get::<_, i32>(6) as i64 * 1000),
+                    submission_client: Some(SUBMISSION_CLIENT.to_owned()),
+                }),
+            },
+        };
+        by_token.entry(row.get(1)).or_insert_with(Vec::new).push(QueuedListen {
+            play_event_id: row.get(0),
+            listen,
+        });
+    }
+
+    for (token, listens) in by_token {
+        let mut listens = listens.into_iter().peekable();
+        while listens.peek().is_some() {
+            let batch: Vec<_> = listens.by_ref().take(MAX_LISTENS_PER_REQUEST).collect();
+            let ids: Vec<i32> = batch.iter().map(|l| l.play_event_id).collect();
+            let payload = SubmitListens {
+                listen_type: if batch.len() == 1 { ListenType::Single } else { ListenType::Import },
+                payload: batch.into_iter().map(|l| l.listen).collect(),
+            };
+
+            match submit(http, base_url, &token, &payload).await {
+                Ok(()) => {
+                    client.execute("DELETE FROM listenbrainz_queue WHERE play_event_id = ANY($1)", &[&ids]).await?;
+                }
+                Err(e) => {
+                    eprintln!("Failed to submit {} listen(s): {}", ids.len(), e);
+                    record_failure(&ids, &e.to_string(), &client).await?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn record_failure(ids: &[i32], message: &str, client: &deadpool_postgres::Client) -> Result<(), Error> {
+    let stmt = client.prepare("
+        UPDATE listenbrainz_queue
+        SET attempts = attempts + 1,
+            last_error = $2,
+            next_attempt_at = now() + make_interval(secs => LEAST($3 * power(2, attempts), $4))
+        WHERE play_event_id = ANY($1)
+    ").await?;
+    client.execute(&stmt, &[&ids, &message, &(MIN_BACKOFF_SECS as f64), &(MAX_BACKOFF_SECS as f64)]).await?;
+
+    let stmt = client.prepare("
+        DELETE FROM listenbrainz_queue
+        WHERE play_event_id = ANY($1) AND attempts >= $2
+    ").await?;
+    let dropped = client.execute(&stmt, &[&ids, &MAX_ATTEMPTS]).await?;
+    if dropped > 0 {
+        eprintln!("Gave up forwarding {} listen(s) after {} attempts", dropped, MAX_ATTEMPTS);
+    }
+    Ok(())
+}
+
+// Surrogate ids from offline imports mean nothing to ListenBrainz
+fn mbid(id: String) -> Option<String> {
+    if id.starts_with("local:") { None } else { Some(id) }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::sync::{Arc, Mutex};
+
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server};
+
+    use super::*;
+
+    // Starts a stand-in ListenBrainz server that answers every request with
+    // `status`, recording each request's Authorization header and body
+    fn start_server(status: u16) -> (String, Arc<Mutex<Vec<(String, String)>>>) {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        let make_svc = make_service_fn(move |_| {
+            let recorded = recorded.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let recorded = recorded.clone();
+                    async move {
+                        let auth = req.headers().get("authorization")
+                            .and_then(|h| h.to_str().ok())
+                            .unwrap_or_default()
+                            .to_owned();
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                        recorded.lock().unwrap().push((auth, String::from_utf8_lossy(&body).into_owned()));
+                        let res = Response::builder()
+                            .status(status)
+                            .body(Body::from(r#"{"status": "ok"}"#))
+                            .unwrap();
+                        Ok::<_, Infallible>(res)
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        (url, requests)
+    }
+
+    fn listens() -> SubmitListens {
+        SubmitListens {
+            listen_type: ListenType::Single,
+            payload: vec![Listen {
+                listened_at: Some(1586000000),
+                track_metadata: TrackMetadata {
+                    artist_name: "Radiohead".to_owned(),
+                    track_name: "Karma Police".to_owned(),
+                    release_name: Some("OK Computer".to_owned()),
+                    additional_info: Some(AdditionalInfo {
+                        recording_mbid: Some("8d9a8ba7-7f7a-4a8e-9b1c-1c0d4fd2a001".to_owned()),
+                        ..AdditionalInfo::default()
+                    }),
+                },
+            }],
+        }
+    }
+
+    #[tokio::test]
+    async fn submits_listens_with_token() {
+        let (url, requests) = start_server(200);
+        submit(&reqwest::Client::new(), &url, "secret", &listens()).await.unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].0, "Token secret");
+        let body: serde_json::Value = serde_json::from_str(&requests[0].1).unwrap();
+        assert_eq!(body["listen_type"], "single");
+        assert_eq!(body["payload"][0]["listened_at"], 1586000000);
+        assert_eq!(body["payload"][0]["track_metadata"]["additional_info"]["recording_mbid"], "8d9a8ba7-7f7a-4a8e-9b1c-1c0d4fd2a001");
+        assert!(body["payload"][0]["track_metadata"]["additional_info"].get("release_mbid").is_none());
+    }
+
+    #[tokio::test]
+    async fn rejected_submission_is_an_error() {
+        let (url, _) = start_server(401);
+        match submit(&reqwest::Client::new(), &url, "wrong", &listens()).await {
+            Err(SubmitError::StatusError(status, _)) => assert_eq!(status, StatusCode::UNAUTHORIZED),
+            other => panic!("expected a status error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parses_playing_now() {
+        let listens: SubmitListens = serde_json::from_str(r#"{
+            "listen_type": "playing_now",
+            "payload": [{"track_metadata": {"artist_name": "Radiohead", "track_name": "Airbag"}}]
+        }"#).unwrap();
+        assert_eq!(listens.listen_type, ListenType::PlayingNow);
+        assert_eq!(listens.payload[0].listened_at, None);
+        assert!(listens.payload[0].track_metadata.additional_info.is_none());
+    }
+}
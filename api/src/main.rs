@@ -1,30 +1,38 @@
 #[macro_use] extern crate serde;
 
 use std::env;
+use std::net::IpAddr;
 
 use warp::Filter;
 
+mod auth;
+mod config;
 mod db;
-mod encoding;
 mod error;
 mod filters;
 mod handlers;
+mod images;
+mod listenbrainz;
+mod playlist_files;
+mod rules;
+mod streaming;
+mod subsonic;
+mod transcoding;
 
 use error::Error;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let db = db::DB::new()?;
+    let config = config::Config::from_env();
+    auth::create_initial_admin(&db).await?;
+    tokio::spawn(listenbrainz::run_queue(db.clone(), config.clone()));
 
-    let api = filters::build(db);
-    let music_root = env::var("MUSIC_DIR_ROOT").expect("MUSIC_DIR_ROOT environment variable not set");
-    let music_files = warp::path("static")
-        .and(warp::fs::dir(music_root));
-
-    let routes = api.or(music_files);
+    let routes = filters::build(db, config).recover(error::handle_rejection);
 
+    let host = env::var("API_HOST").map(|h| h.parse::<IpAddr>().expect("Failed to parse host")).unwrap_or(IpAddr::from([127, 0, 0, 1]));
     let port = env::var("API_PORT").map(|p| u16::from_str_radix(&p, 10).expect("Failed to parse port")).expect("API_PORT environment variable not set");
-    warp::serve(routes).run(([127, 0, 0, 1], port)).await;
+    warp::serve(routes).run((host, port)).await;
 
     Ok(())
 }
@@ -0,0 +1,468 @@
+use std::str::FromStr;
+
+use quick_xml::Reader;
+use quick_xml::events::Event;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    M3u8,
+    Xspf,
+    Jspf,
+}
+
+impl Format {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::M3u8 => "audio/x-mpegurl; charset=utf-8",
+            Format::Xspf => "application/xspf+xml",
+            Format::Jspf => "application/json",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::M3u8 => "m3u8",
+            Format::Xspf => "xspf",
+            Format::Jspf => "jspf",
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Format, ()> {
+        match s.to_lowercase().as_str() {
+            "m3u" | "m3u8" => Ok(Format::M3u8),
+            "xspf" => Ok(Format::Xspf),
+            "jspf" => Ok(Format::Jspf),
+            _ => Err(()),
+        }
+    }
+}
+
+// One track in a playlist file. Every field is optional since players fill
+// in as little as a bare path.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Entry {
+    // A path, either absolute or relative to the music root, or a file:// URI
+    pub location: Option<String>,
+    // A MusicBrainz recording id
+    pub mbid: Option<String>,
+    pub title: Option<String>,
+    pub creator: Option<String>,
+    pub album: Option<String>,
+    // In seconds
+    pub duration: Option<i32>,
+}
+
+#[derive(Debug)]
+pub enum ParseError {
+    XMLError(quick_xml::Error),
+    JSONError(serde_json::Error),
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        use ParseError::*;
+        match self {
+            XMLError(e) => write!(fmt, "invalid XSPF: {}", e),
+            JSONError(e) => write!(fmt, "invalid JSPF: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use ParseError::*;
+        match self {
+            XMLError(e) => Some(e),
+            JSONError(e) => Some(e),
+        }
+    }
+}
+
+const MUSICBRAINZ_RECORDING_URL: &str = "https://musicbrainz.org/recording/";
+
+pub fn write(format: Format, title: &str, entries: &[Entry]) -> String {
+    match format {
+        Format::M3u8 => write_m3u8(entries),
+        Format::Xspf => write_xspf(title, entries),
+        Format::Jspf => write_jspf(title, entries),
+    }
+}
+
+// Returns the playlist's title, if the format has one, and its entries
+pub fn parse(format: Format, s: &str) -> Result<(Option<String>, Vec<Entry>), ParseError> {
+    match format {
+        Format::M3u8 => Ok((None, parse_m3u8(s))),
+        Format::Xspf => parse_xspf(s),
+        Format::Jspf => parse_jspf(s),
+    }
+}
+
+fn write_m3u8(entries: &[Entry]) -> String {
+    let mut out = String::from("#EXTM3U\n");
+    for entry in entries {
+        let location = match &entry.location {
+            Some(l) => l,
+            None => continue,
+        };
+        let name = match (&entry.creator, &entry.title) {
+            (Some(c), Some(t)) => format!("{} - {}", c, t),
+            (None, Some(t)) => t.clone(),
+            _ => String::new(),
+        };
+        out.push_str(&format!("#EXTINF:{},{}\n{}\n", entry.duration.unwrap_or(-1), name, location));
+    }
+    out
+}
+
+fn parse_m3u8(s: &str) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    let mut pending = Entry::default();
+
+    for line in s.lines() {
+        let line = line.trim_start_matches('\u{feff}').trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(info) = line.strip_prefix("#EXTINF:") {
+            // #EXTINF:<seconds>,<artist> - <title>
+            let mut parts = info.splitn(2, ',');
+            pending.duration = parts.next()
+                .and_then(|d| d.trim().parse::<i32>().ok())
+                .filter(|d| *d >= 0);
+            if let Some(name) = parts.next() {
+                let mut name_parts = name.splitn(2, " - ");
+                match (name_parts.next(), name_parts.next()) {
+                    (Some(c), Some(t)) => {
+                        pending.creator = Some(c.trim().to_owned());
+                        pending.title = Some(t.trim().to_owned());
+                    }
+                    (Some(t), None) if !t.trim().is_empty() => pending.title = Some(t.trim().to_owned()),
+                    _ => {}
+                }
+            }
+            continue;
+        }
+        if line.starts_with('#') {
+            continue;
+        }
+        pending.location = Some(file_path(line));
+        entries.push(std::mem::take(&mut pending));
+    }
+
+    entries
+}
+
+fn write_xspf(title: &str, entries: &[Entry]) -> String {
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    out.push_str("<playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n");
+    out.push_str(&format!("  <title>{}</title>\n", escape_xml(title)));
+    out.push_str("  <trackList>\n");
+    for entry in entries {
+        out.push_str("    <track>\n");
+        let mut element = |name: &str, value: &str| {
+            out.push_str(&format!("      <{0}>{1}</{0}>\n", name, escape_xml(value)));
+        };
+        if let Some(location) = &entry.location {
+            element("location", &file_uri(location));
+        }
+        if let Some(mbid) = &entry.mbid {
+            element("identifier", &format!("{}{}", MUSICBRAINZ_RECORDING_URL, mbid));
+        }
+        if let Some(title) = &entry.title {
+            element("title", title);
+        }
+        if let Some(creator) = &entry.creator {
+            element("creator", creator);
+        }
+        if let Some(album) = &entry.album {
+            element("album", album);
+        }
+        if let Some(duration) = entry.duration {
+            element("duration", &(duration as i64 * 1000).to_string());
+        }
+        out.push_str("    </track>\n");
+    }
+    out.push_str("  </trackList>\n");
+    out.push_str("</playlist>\n");
+    out
+}
+
+fn parse_xspf(s: &str) -> Result<(Option<String>, Vec<Entry>), ParseError> {
+    let mut reader = Reader::from_str(s);
+    reader.trim_text(true);
+    let mut buf = Vec::new();
+
+    let mut title = None;
+    let mut entries = Vec::new();
+    let mut current: Option<Entry> = None;
+    // Names of the open elements, to tell a track's title from the
+    // playlist's
+    let mut path: Vec<Vec<u8>> = Vec::new();
+
+    loop {
+        match reader.read_event(&mut buf).map_err(ParseError::XMLError)? {
+            Event::Start(e) => {
+                if e.name() == b"track" {
+                    current = Some(Entry::default());
+                }
+                path.push(e.name().to_vec());
+            }
+            Event::End(e) => {
+                path.pop();
+                if e.name() == b"track" {
+                    if let Some(entry) = current.take() {
+                        entries.push(entry);
+                    }
+                }
+            }
+            Event::Text(e) => {
+                let text = e.unescape_and_decode(&reader).map_err(ParseError::XMLError)?;
+                let name = path.last().map(|n| n.as_slice()).unwrap_or(b"");
+                match current.as_mut() {
+                    Some(entry) => set_field(entry, name, text),
+                    None if name == b"title" => title = Some(text),
+                    None => {}
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+        buf.clear();
+    }
+
+    Ok((title, entries))
+}
+
+fn set_field(entry: &mut Entry, name: &[u8], text: String) {
+    match name {
+        // Only the first location and identifier are used
+        b"location" if entry.location.is_none() => entry.location = Some(uri_path(&text)),
+        b"identifier" if entry.mbid.is_none() => entry.mbid = recording_mbid(&text),
+        b"title" => entry.title = Some(text),
+        b"creator" => entry.creator = Some(text),
+        b"album" => entry.album = Some(text),
+        b"duration" => entry.duration = text.trim().parse::<i64>().ok().map(|ms| (ms / 1000) as i32),
+        _ => {}
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct JSPF {
+    playlist: JSPFPlaylist,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JSPFPlaylist {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    #[serde(default)]
+    track: Vec<JSPFTrack>,
+}
+
+// Locations and identifiers can be single strings or arrays
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl OneOrMany {
+    fn first(&self) -> Option<&String> {
+        match self {
+            OneOrMany::One(s) => Some(s),
+            OneOrMany::Many(v) => v.first(),
+        }
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct JSPFTrack {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    location: Option<OneOrMany>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    identifier: Option<OneOrMany>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    creator: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    album: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration: Option<i64>,
+}
+
+fn write_jspf(title: &str, entries: &[Entry]) -> String {
+    let track = entries.iter()
+        .map(|entry| JSPFTrack {
+            location: entry.location.as_ref().map(|l| OneOrMany::Many(vec![file_uri(l)])),
+            identifier: entry.mbid.as_ref().map(|m| OneOrMany::Many(vec![format!("{}{}", MUSICBRAINZ_RECORDING_URL, m)])),
+            title: entry.title.clone(),
+            creator: entry.creator.clone(),
+            album: entry.album.clone(),
+            duration: entry.duration.map(|d| d as i64 * 1000),
+        })
+        .collect();
+    let jspf = JSPF {
+        playlist: JSPFPlaylist {
+            title: Some(title.to_owned()),
+            track,
+        },
+    };
+    serde_json::to_string_pretty(&jspf).unwrap()
+}
+
+fn parse_jspf(s: &str) -> Result<(Option<String>, Vec<Entry>), ParseError> {
+    let jspf: JSPF = serde_json::from_str(s).map_err(ParseError::JSONError)?;
+    let entries = jspf.playlist.track.into_iter()
+        .map(|t| Entry {
+            location: t.location.as_ref().and_then(|l| l.first()).map(|l| uri_path(l)),
+            mbid: t.identifier.as_ref().and_then(|i| i.first()).and_then(|i| recording_mbid(i)),
+            title: t.title,
+            creator: t.creator,
+            album: t.album,
+            duration: t.duration.map(|ms| (ms / 1000) as i32),
+        })
+        .collect();
+    Ok((jspf.playlist.title, entries))
+}
+
+// Accepts a MusicBrainz recording URL or a bare id
+fn recording_mbid(identifier: &str) -> Option<String> {
+    let id = identifier.trim().trim_end_matches('/').rsplit('/').next()?;
+    if id.len() == 36 && id.chars().all(|c| c.is_ascii_hexdigit() || c == '-') {
+        Some(id.to_lowercase())
+    } else {
+        None
+    }
+}
+
+fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// Turns an absolute path into a file:// URI, and a relative one into a
+// relative URI reference
+fn file_uri(path: &str) -> String {
+    let mut uri = String::from(if path.starts_with('/') { "file://" } else { "" });
+    for b in path.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => uri.push(b as char),
+            _ => uri.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    uri
+}
+
+// The reverse of file_uri, for XSPF and JSPF locations, which are always
+// URIs. Other schemes are returned as is.
+fn uri_path(location: &str) -> String {
+    match location.strip_prefix("file://") {
+        Some(p) => percent_decode(p),
+        None if location.contains("://") => location.to_owned(),
+        None => percent_decode(location),
+    }
+}
+
+// Decodes file:// URIs. M3U paths are returned as is, since they needn't be
+// encoded at all.
+fn file_path(location: &str) -> String {
+    match location.strip_prefix("file://") {
+        Some(p) => percent_decode(p),
+        None => location.to_owned(),
+    }
+}
+
+fn percent_decode(encoded: &str) -> String {
+    let bytes = encoded.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+            if let Some(b) = hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                decoded.push(b);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry() -> Entry {
+        Entry {
+            location: Some("/music/Sigur Rós/Ágætis byrjun/01.flac".to_owned()),
+            mbid: Some("8d9a8ba7-7f7a-4a8e-9b1c-1c0d4fd2a001".to_owned()),
+            title: Some("Intro".to_owned()),
+            creator: Some("Sigur Rós".to_owned()),
+            album: Some("Ágætis byrjun & more".to_owned()),
+            duration: Some(97),
+        }
+    }
+
+    #[test]
+    fn m3u8_round_trip() {
+        let out = write(Format::M3u8, "Test", &[entry()]);
+        let (title, entries) = parse(Format::M3u8, &out).unwrap();
+        assert_eq!(title, None);
+        assert_eq!(entries, vec![Entry { mbid: None, album: None, ..entry() }]);
+    }
+
+    #[test]
+    fn m3u8_bare_paths() {
+        let (_, entries) = parse(Format::M3u8, "# comment\n/a.flac\r\n\nb.mp3\n").unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].location.as_deref(), Some("/a.flac"));
+        assert_eq!(entries[1].location.as_deref(), Some("b.mp3"));
+        assert_eq!(entries[1].title, None);
+    }
+
+    #[test]
+    fn xspf_round_trip() {
+        let out = write(Format::Xspf, "Mine & yours", &[entry()]);
+        assert!(out.contains("file:///music/Sigur%20R%C3%B3s/"));
+        let (title, entries) = parse(Format::Xspf, &out).unwrap();
+        assert_eq!(title.as_deref(), Some("Mine & yours"));
+        assert_eq!(entries, vec![entry()]);
+    }
+
+    #[test]
+    fn jspf_round_trip() {
+        let out = write(Format::Jspf, "Test", &[entry()]);
+        let (title, entries) = parse(Format::Jspf, &out).unwrap();
+        assert_eq!(title.as_deref(), Some("Test"));
+        assert_eq!(entries, vec![entry()]);
+    }
+
+    #[test]
+    fn relative_locations() {
+        let relative = Entry { location: Some("Sigur Rós/Ágætis byrjun/01.flac".to_owned()), ..entry() };
+        for &format in &[Format::M3u8, Format::Xspf, Format::Jspf] {
+            let out = write(format, "Test", &[relative.clone()]);
+            assert!(!out.contains("file://"));
+            let (_, entries) = parse(format, &out).unwrap();
+            assert_eq!(entries[0].location, relative.location);
+        }
+    }
+
+    #[test]
+    fn jspf_single_location() {
+        let (_, entries) = parse(Format::Jspf, r#"{"playlist": {"track": [{"location": "file:///a.flac", "title": "A"}]}}"#).unwrap();
+        assert_eq!(entries[0].location.as_deref(), Some("/a.flac"));
+        assert_eq!(entries[0].title.as_deref(), Some("A"));
+    }
+}
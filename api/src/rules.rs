@@ -0,0 +1,329 @@
+use tokio_postgres::types::ToSql;
+
+use crate::db::escape_like;
+
+// The stored definition of a smart playlist, e.g.
+// {"match": "all", "rules": [{"field": "artist", "op": "is", "value": "Radiohead"}], "sort": "most_played", "limit": 25}
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Rules {
+    #[serde(default, rename = "match")]
+    pub match_: Match,
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+    #[serde(default)]
+    pub sort: Sort,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<i64>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Match {
+    All,
+    Any,
+}
+
+impl Default for Match {
+    fn default() -> Match {
+        Match::All
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "field", rename_all = "snake_case")]
+pub enum Rule {
+    // Matches the album artist or any artist credited on the track
+    Artist { op: TextOp, value: String },
+    Album { op: TextOp, value: String },
+    Title { op: TextOp, value: String },
+    // In seconds
+    Duration { op: NumberOp, value: i32 },
+    // In kbps
+    BitRate { op: NumberOp, value: i32 },
+    // FFmpeg's codec name, e.g. "flac"
+    Codec { op: TextOp, value: String },
+    // In Hz
+    SampleRate { op: NumberOp, value: i32 },
+    BitDepth { op: NumberOp, value: i32 },
+    Lossless { value: bool },
+    PlayCount { op: NumberOp, value: i32 },
+    // `value` is a number of days
+    Added { op: DateOp, value: i32 },
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TextOp {
+    Is,
+    IsNot,
+    Contains,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum NumberOp {
+    Eq,
+    Lt,
+    Lte,
+    Gt,
+    Gte,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DateOp {
+    InLast,
+    NotInLast,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Sort {
+    // Album artist, then album, then track order
+    Artist,
+    Title,
+    // Newest first
+    Added,
+    MostPlayed,
+    Random,
+}
+
+impl Default for Sort {
+    fn default() -> Sort {
+        Sort::Artist
+    }
+}
+
+// Smart playlists can match the whole library, so they are always capped
+pub const MAX_LIMIT: i64 = 1000;
+
+impl Rules {
+    pub fn limit(&self) -> i64 {
+        self.limit.unwrap_or(MAX_LIMIT).max(0).min(MAX_LIMIT)
+    }
+}
+
+pub type Params = Vec<Box<dyn ToSql + Sync + Send>>;
+
+// Borrows the parameters in the form queries take them
+pub fn param_refs(params: &Params) -> Vec<&(dyn ToSql + Sync)> {
+    params.iter().map(|p| p.as_ref() as &(dyn ToSql + Sync)).collect()
+}
+
+// Builds a query selecting `select_fields` for every available track matching
+// the rules, in order. The query can join on the track (T), its album (R) and
+// the album's artist (A).
+pub fn build_query(rules: &Rules, select_fields: &str) -> (String, Params) {
+    let mut params: Params = Vec::new();
+    let conditions: Vec<String> = rules.rules.iter()
+        .map(|rule| condition(rule, &mut params))
+        .collect();
+
+    let mut q = format!("
+        SELECT {}
+        FROM track T
+        INNER JOIN album R ON R.id = T.album_id
+        INNER JOIN artist A ON A.id = R.artist_id
+        WHERE T.available = true
+    ", select_fields);
+
+    if !conditions.is_empty() {
+        let joiner = match rules.match_ {
+            Match::All => " AND ",
+            Match::Any => " OR ",
+        };
+        q.push_str(&format!("AND ({})\n", conditions.join(joiner)));
+    }
+
+    let order = match rules.sort {
+        Sort::Artist => "A.name ASC, R.title ASC, T.disc_number ASC, T.position ASC",
+        Sort::Title => "T.title ASC, T.id ASC",
+        Sort::Added => "T.added_at DESC, T.id ASC",
+        Sort::MostPlayed => "(SELECT COUNT(*) FROM play_event E WHERE E.track_id = T.id) DESC, T.id ASC",
+        Sort::Random => "random()",
+    };
+    q.push_str(&format!("ORDER BY {}\n", order));
+
+    params.push(Box::new(rules.limit()));
+    q.push_str(&format!("LIMIT ${}", params.len()));
+
+    (q, params)
+}
+
+fn condition(rule: &Rule, params: &mut Params) -> String {
+    match rule {
+        Rule::Artist { op, value } => {
+            let n = push_text(params, *op, value);
+            // A track isn't by an artist if any of its artists match, so
+            // "is not" is the negation of "is" rather than a per-artist test
+            let (op, negate) = match op {
+                TextOp::IsNot => (TextOp::Is, true),
+                op => (*op, false),
+            };
+            let cond = format!(
+                "({} OR EXISTS (SELECT 1 FROM track_artist C WHERE C.track_id = T.id AND {}))",
+                text_condition("A.name", op, n),
+                text_condition("C.credited_name", op, n),
+            );
+            if negate { format!("NOT {}", cond) } else { cond }
+        }
+        Rule::Album { op, value } => text_condition("R.title", *op, push_text(params, *op, value)),
+        Rule::Title { op, value } => text_condition("T.title", *op, push_text(params, *op, value)),
+        Rule::Duration { op, value } => number_condition("T.duration", *op, push(params, *value)),
+        Rule::BitRate { op, value } => number_condition("T.bit_rate", *op, push(params, *value)),
+        Rule::Codec { op, value } => text_condition("T.codec", *op, push_text(params, *op, value)),
+        Rule::SampleRate { op, value } => number_condition("T.sample_rate", *op, push(params, *value)),
+        Rule::BitDepth { op, value } => number_condition("T.bit_depth", *op, push(params, *value)),
+        Rule::Lossless { value } => format!("T.lossless = ${}", push(params, *value)),
+        Rule::PlayCount { op, value } => number_condition(PLAY_COUNT, *op, push(params, *value as i64)),
+        Rule::Added { op, value } => {
+            let n = push(params, *value);
+            match op {
+                DateOp::InLast => format!("T.added_at >= now() - make_interval(days => ${})", n),
+                DateOp::NotInLast => format!("T.added_at < now() - make_interval(days => ${})", n),
+            }
+        }
+    }
+}
+
+// A bigint, so the rule's value is widened to match
+const PLAY_COUNT: &str = "(SELECT COUNT(*) FROM play_event E WHERE E.track_id = T.id)";
+
+// Returns the placeholder number of the new parameter
+fn push<T: ToSql + Sync + Send + 'static>(params: &mut Params, value: T) -> usize {
+    params.push(Box::new(value));
+    params.len()
+}
+
+// Like push, escaping the value if it's to be matched with LIKE
+fn push_text(params: &mut Params, op: TextOp, value: &str) -> usize {
+    match op {
+        TextOp::Contains => push(params, escape_like(value)),
+        _ => push(params, value.to_owned()),
+    }
+}
+
+// Text comparisons ignore case, like search does
+fn text_condition(col: &str, op: TextOp, n: usize) -> String {
+    match op {
+        TextOp::Is => format!("lower({}) = lower(${})", col, n),
+        TextOp::IsNot => format!("lower({}) IS DISTINCT FROM lower(${})", col, n),
+        TextOp::Contains => format!("{} ILIKE '%' || ${} || '%'", col, n),
+    }
+}
+
+fn number_condition(col: &str, op: NumberOp, n: usize) -> String {
+    let op = match op {
+        NumberOp::Eq => "=",
+        NumberOp::Lt => "<",
+        NumberOp::Lte => "<=",
+        NumberOp::Gt => ">",
+        NumberOp::Gte => ">=",
+    };
+    format!("{} {} ${}", col, op, n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(json: &str) -> Rules {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn defaults() {
+        let rules = parse("{}");
+        assert_eq!(rules.match_, Match::All);
+        assert_eq!(rules.sort, Sort::Artist);
+        let (q, params) = build_query(&rules, "T.id");
+        assert!(!q.contains("AND ("));
+        assert!(q.ends_with("LIMIT $1"));
+        assert_eq!(params.len(), 1);
+    }
+
+    #[test]
+    fn all_rules_are_and_ed() {
+        let rules = parse(r#"{
+            "rules": [
+                {"field": "duration", "op": "lt", "value": 300},
+                {"field": "bit_rate", "op": "gte", "value": 320},
+                {"field": "added", "op": "in_last", "value": 30}
+            ],
+            "sort": "most_played",
+            "limit": 10
+        }"#);
+        let (q, params) = build_query(&rules, "T.id");
+        assert!(q.contains("AND (T.duration < $1 AND T.bit_rate >= $2 AND T.added_at >= now() - make_interval(days => $3))"));
+        assert!(q.contains("ORDER BY (SELECT COUNT(*) FROM play_event E WHERE E.track_id = T.id) DESC"));
+        assert!(q.ends_with("LIMIT $4"));
+        assert_eq!(params.len(), 4);
+    }
+
+    #[test]
+    fn any_rules_are_or_ed() {
+        let rules = parse(r#"{
+            "match": "any",
+            "rules": [
+                {"field": "album", "op": "is", "value": "OK Computer"},
+                {"field": "title", "op": "contains", "value": "love"}
+            ]
+        }"#);
+        let (q, _) = build_query(&rules, "T.id");
+        assert!(q.contains("AND (lower(R.title) = lower($1) OR T.title ILIKE '%' || $2 || '%')"));
+    }
+
+    #[test]
+    fn contains_matches_literally() {
+        let rules = parse(r#"{
+            "rules": [
+                {"field": "title", "op": "contains", "value": "100%_"},
+                {"field": "album", "op": "is", "value": "100%_"}
+            ]
+        }"#);
+        let (_, params) = build_query(&rules, "T.id");
+        assert_eq!(format!("{:?}", params[0]), format!("{:?}", "100\\%\\_"));
+        assert_eq!(format!("{:?}", params[1]), format!("{:?}", "100%_"));
+    }
+
+    #[test]
+    fn artist_matches_credits() {
+        let rules = parse(r#"{"rules": [{"field": "artist", "op": "is", "value": "Jay-Z"}]}"#);
+        let (q, params) = build_query(&rules, "T.id");
+        assert!(q.contains("lower(A.name) = lower($1) OR EXISTS (SELECT 1 FROM track_artist C WHERE C.track_id = T.id AND lower(C.credited_name) = lower($1))"));
+        assert_eq!(params.len(), 2);
+    }
+
+    #[test]
+    fn artist_is_not_excludes_credits() {
+        let rules = parse(r#"{"rules": [{"field": "artist", "op": "is_not", "value": "Jay-Z"}]}"#);
+        let (q, _) = build_query(&rules, "T.id");
+        assert!(q.contains("NOT (lower(A.name) = lower($1) OR EXISTS"));
+    }
+
+    #[test]
+    fn limit_is_capped() {
+        assert_eq!(parse(r#"{"limit": 1000000}"#).limit(), MAX_LIMIT);
+        assert_eq!(parse(r#"{"limit": -1}"#).limit(), 0);
+        assert_eq!(parse("{}").limit(), MAX_LIMIT);
+    }
+
+    #[test]
+    fn audio_rules() {
+        let rules = parse(r#"{
+            "rules": [
+                {"field": "lossless", "value": true},
+                {"field": "bit_depth", "op": "gte", "value": 24},
+                {"field": "codec", "op": "is_not", "value": "alac"}
+            ]
+        }"#);
+        let (q, params) = build_query(&rules, "T.id");
+        assert!(q.contains("AND (T.lossless = $1 AND T.bit_depth >= $2 AND lower(T.codec) IS DISTINCT FROM lower($3))"));
+        assert_eq!(params.len(), 4);
+    }
+
+    #[test]
+    fn unknown_field_is_rejected() {
+        assert!(serde_json::from_str::<Rules>(r#"{"rules": [{"field": "mood", "op": "is", "value": "happy"}]}"#).is_err());
+    }
+}
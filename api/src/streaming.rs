@@ -0,0 +1,318 @@
+use std::io::SeekFrom;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use hyper::Body;
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+use warp::http::{header, HeaderMap, Response, StatusCode};
+
+use crate::Error;
+
+const CHUNK_SIZE: u64 = 64 * 1024;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    // Inclusive, as in the `Content-Range` header
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn byte_count(&self) -> u64 {
+        self.end - self.start + 1
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RangeRequest {
+    Full,
+    Partial(ByteRange),
+    Unsatisfiable,
+}
+
+// Only single ranges are honored. Multi-range requests would need a
+// multipart/byteranges response, which no audio player sends, so they get
+// the whole file instead.
+pub fn parse_range(value: &str, size: u64) -> RangeRequest {
+    let spec = match value.trim().strip_prefix("bytes=") {
+        Some(spec) => spec.trim(),
+        None => return RangeRequest::Full,
+    };
+
+    if spec.contains(',') {
+        return RangeRequest::Full;
+    }
+
+    let (start, end) = match spec.find('-') {
+        Some(idx) => (spec[..idx].trim(), spec[idx + 1..].trim()),
+        None => return RangeRequest::Full,
+    };
+
+    let range = match (start.parse::<u64>(), end.parse::<u64>()) {
+        // bytes=start-end
+        (Ok(s), Ok(e)) if s <= e => ByteRange { start: s, end: e.min(size.saturating_sub(1)) },
+        // bytes=start-
+        (Ok(s), Err(_)) if end.is_empty() => ByteRange { start: s, end: size.saturating_sub(1) },
+        // bytes=-suffix
+        (Err(_), Ok(n)) if start.is_empty() => {
+            if n == 0 {
+                return RangeRequest::Unsatisfiable;
+            }
+            ByteRange { start: size.saturating_sub(n), end: size.saturating_sub(1) }
+        }
+        _ => return RangeRequest::Full,
+    };
+
+    if size == 0 || range.start >= size {
+        RangeRequest::Unsatisfiable
+    } else {
+        RangeRequest::Partial(range)
+    }
+}
+
+// Whether a request is for the whole file, rather than resuming, seeking or
+// probing. Safari asks for `bytes=0-1` before `bytes=0-`, so only an
+// open-ended range from the start counts. Parsed against the largest size,
+// only an open-ended range reaches its end.
+pub fn is_initial_request(headers: &HeaderMap) -> bool {
+    match header_str(headers, header::RANGE) {
+        Some(v) => match parse_range(v, u64::MAX) {
+            RangeRequest::Partial(range) => range.start == 0 && range.end == u64::MAX - 1,
+            _ => true,
+        },
+        None => true,
+    }
+}
+
+// Goes by the codec the importer found in the file when it's known, since
+// extensions can be wrong. FLAC and AAC come in more than one container, and
+// PCM is only told apart by its container, so those need the extension too.
+pub fn content_type_for(codec: Option<&str>, path: &Path) -> &'static str {
+    let extension = path.extension().and_then(|e| e.to_str()).map(|e| e.to_lowercase());
+    match (codec, extension.as_deref()) {
+        (Some("mp3"), _) => "audio/mpeg",
+        (Some("vorbis"), _) | (Some("opus"), _) => "audio/ogg",
+        (Some("alac"), _) => "audio/mp4",
+        (Some("flac"), Some("ogg")) | (Some("flac"), Some("oga")) => "audio/ogg",
+        (Some("flac"), _) => "audio/flac",
+        (Some("aac"), Some("aac")) => "audio/aac",
+        (Some("aac"), _) => "audio/mp4",
+        (_, Some("flac")) => "audio/flac",
+        (_, Some("mp3")) => "audio/mpeg",
+        (_, Some("ogg")) | (_, Some("oga")) | (_, Some("opus")) => "audio/ogg",
+        (_, Some("m4a")) | (_, Some("mp4")) => "audio/mp4",
+        (_, Some("aac")) => "audio/aac",
+        (_, Some("wav")) => "audio/wav",
+        (_, Some("aif")) | (_, Some("aiff")) | (_, Some("aifc")) => "audio/aiff",
+        _ => "application/octet-stream",
+    }
+}
+
+// Serves `path`, honoring `Range`, `If-Range`, `If-None-Match` and
+// `If-Modified-Since` from the request headers. Returns `None` if the file
+// does not exist.
+pub async fn serve_file(path: &Path, content_type: &str, headers: &HeaderMap) -> Result<Option<Response<Body>>, Error> {
+    let mut file = match File::open(path).await {
+        Ok(f) => f,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let md = file.metadata().await?;
+    let size = md.len();
+    let modified = md.modified().ok().map(truncate_to_secs);
+    let etag = format!("\"{:x}-{:x}\"", size, modified.map(unix_secs).unwrap_or(0));
+    let last_modified = modified.map(httpdate::fmt_http_date);
+
+    let builder = || {
+        let mut b = Response::builder()
+            .header(header::ACCEPT_RANGES, "bytes")
+            .header(header::ETAG, etag.as_str());
+        if let Some(lm) = last_modified.as_ref() {
+            b = b.header(header::LAST_MODIFIED, lm.as_str());
+        }
+        b
+    };
+
+    if is_not_modified(headers, &etag, modified) {
+        let res = builder()
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Body::empty())
+            .unwrap();
+        return Ok(Some(res));
+    }
+
+    let range = match header_str(headers, header::RANGE) {
+        Some(r) if if_range_matches(headers, &etag, last_modified.as_deref()) => parse_range(r, size),
+        _ => RangeRequest::Full,
+    };
+
+    let res = match range {
+        RangeRequest::Full => {
+            builder()
+                .status(StatusCode::OK)
+                .header(header::CONTENT_TYPE, content_type)
+                .header(header::CONTENT_LENGTH, size)
+                .body(body_from_file(file, size))
+                .unwrap()
+        }
+        RangeRequest::Partial(range) => {
+            file.seek(SeekFrom::Start(range.start)).await?;
+            builder()
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(header::CONTENT_TYPE, content_type)
+                .header(header::CONTENT_LENGTH, range.byte_count())
+                .header(header::CONTENT_RANGE, format!("bytes {}-{}/{}", range.start, range.end, size))
+                .body(body_from_file(file, range.byte_count()))
+                .unwrap()
+        }
+        RangeRequest::Unsatisfiable => {
+            builder()
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(header::CONTENT_RANGE, format!("bytes */{}", size))
+                .body(Body::empty())
+                .unwrap()
+        }
+    };
+
+    Ok(Some(res))
+}
+
+fn body_from_file(file: File, len: u64) -> Body {
+    let stream = futures::stream::try_unfold((file, len), |(mut file, remaining)| async move {
+        if remaining == 0 {
+            return Ok::<_, std::io::Error>(None);
+        }
+        let mut buf = vec![0; remaining.min(CHUNK_SIZE) as usize];
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        buf.truncate(n);
+        Ok(Some((Bytes::from(buf), (file, remaining - n as u64))))
+    });
+    Body::wrap_stream(stream)
+}
+
+fn is_not_modified(headers: &HeaderMap, etag: &str, modified: Option<SystemTime>) -> bool {
+    // If-None-Match takes precedence over If-Modified-Since when both are sent
+    if let Some(inm) = header_str(headers, header::IF_NONE_MATCH) {
+        return inm.split(',').map(str::trim).any(|t| t == "*" || t.trim_start_matches("W/") == etag);
+    }
+
+    match (header_str(headers, header::IF_MODIFIED_SINCE).and_then(|d| httpdate::parse_http_date(d).ok()), modified) {
+        (Some(since), Some(modified)) => modified <= since,
+        _ => false,
+    }
+}
+
+fn if_range_matches(headers: &HeaderMap, etag: &str, last_modified: Option<&str>) -> bool {
+    match header_str(headers, header::IF_RANGE) {
+        Some(v) => v == etag || Some(v) == last_modified,
+        None => true,
+    }
+}
+
+fn header_str(headers: &HeaderMap, name: header::HeaderName) -> Option<&str> {
+    headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+// HTTP dates only have second precision
+fn truncate_to_secs(t: SystemTime) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(unix_secs(t))
+}
+
+fn unix_secs(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn content_types() {
+        assert_eq!(content_type_for(Some("flac"), Path::new("a.flac")), "audio/flac");
+        assert_eq!(content_type_for(Some("flac"), Path::new("a.oga")), "audio/ogg");
+        assert_eq!(content_type_for(Some("opus"), Path::new("a.opus")), "audio/ogg");
+        assert_eq!(content_type_for(Some("alac"), Path::new("a.m4a")), "audio/mp4");
+        assert_eq!(content_type_for(Some("pcm_s16le"), Path::new("a.WAV")), "audio/wav");
+        assert_eq!(content_type_for(Some("pcm_s16be"), Path::new("a.aiff")), "audio/aiff");
+        assert_eq!(content_type_for(None, Path::new("a.mp3")), "audio/mpeg");
+        assert_eq!(content_type_for(None, Path::new("a")), "application/octet-stream");
+    }
+
+    fn range_headers(range: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::RANGE, range.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn initial_requests() {
+        assert!(is_initial_request(&HeaderMap::new()));
+        assert!(is_initial_request(&range_headers("bytes=0-")));
+        assert!(!is_initial_request(&range_headers("bytes=500-")));
+        assert!(!is_initial_request(&range_headers("bytes=-100")));
+    }
+
+    // Safari probes with the first two bytes before requesting the rest
+    #[test]
+    fn safari_probe_not_initial() {
+        assert!(!is_initial_request(&range_headers("bytes=0-1")));
+        assert!(is_initial_request(&range_headers("bytes=0-")));
+    }
+
+    #[test]
+    fn range_start_end() {
+        assert_eq!(RangeRequest::Partial(ByteRange { start: 0, end: 499 }), parse_range("bytes=0-499", 1000));
+    }
+
+    #[test]
+    fn range_open_ended() {
+        assert_eq!(RangeRequest::Partial(ByteRange { start: 500, end: 999 }), parse_range("bytes=500-", 1000));
+    }
+
+    #[test]
+    fn range_suffix() {
+        assert_eq!(RangeRequest::Partial(ByteRange { start: 900, end: 999 }), parse_range("bytes=-100", 1000));
+    }
+
+    #[test]
+    fn range_suffix_longer_than_file() {
+        assert_eq!(RangeRequest::Partial(ByteRange { start: 0, end: 999 }), parse_range("bytes=-5000", 1000));
+    }
+
+    #[test]
+    fn range_end_clamped_to_size() {
+        assert_eq!(RangeRequest::Partial(ByteRange { start: 10, end: 999 }), parse_range("bytes=10-5000", 1000));
+    }
+
+    #[test]
+    fn range_past_end() {
+        assert_eq!(RangeRequest::Unsatisfiable, parse_range("bytes=1000-", 1000));
+    }
+
+    #[test]
+    fn range_multiple_ignored() {
+        assert_eq!(RangeRequest::Full, parse_range("bytes=0-1,5-6", 1000));
+    }
+
+    #[test]
+    fn initial_request() {
+        let mut headers = HeaderMap::new();
+        assert!(is_initial_request(&headers));
+        headers.insert(header::RANGE, "bytes=0-".parse().unwrap());
+        assert!(is_initial_request(&headers));
+        headers.insert(header::RANGE, "bytes=5000-".parse().unwrap());
+        assert!(!is_initial_request(&headers));
+    }
+
+    #[test]
+    fn range_malformed_ignored() {
+        assert_eq!(RangeRequest::Full, parse_range("bytes=abc", 1000));
+        assert_eq!(RangeRequest::Full, parse_range("items=0-1", 1000));
+        assert_eq!(RangeRequest::Full, parse_range("bytes=10-5", 1000));
+    }
+}
@@ -0,0 +1,449 @@
+use serde_json::{Map, Value as JsonValue};
+use warp::http::{Response, StatusCode};
+
+use crate::auth::User;
+
+// The Subsonic API version implemented
+pub const API_VERSION: &str = "1.16.1";
+
+const SERVER_TYPE: &str = "doplr";
+
+const XMLNS: &str = "http://subsonic.org/restapi";
+
+// Subsonic error codes. Errors are sent with a 200 status, as clients expect.
+pub const ERROR_MISSING_PARAMETER: i32 = 10;
+pub const ERROR_WRONG_CREDENTIALS: i32 = 40;
+pub const ERROR_TOKEN_AUTH_UNSUPPORTED: i32 = 41;
+pub const ERROR_NOT_AUTHORIZED: i32 = 50;
+pub const ERROR_NOT_FOUND: i32 = 70;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Xml,
+    Json,
+}
+
+impl Format {
+    // From the `f` parameter. Anything but json is answered with XML.
+    pub fn from_param(f: Option<&str>) -> Format {
+        match f {
+            Some("json") => Format::Json,
+            _ => Format::Xml,
+        }
+    }
+}
+
+// Query parameters, which can repeat, e.g. songId=1&songId=2
+#[derive(Debug, Default)]
+pub struct Params(pub Vec<(String, String)>);
+
+impl Params {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.iter().find(|(k, _)| k == name).map(|(_, v)| v.as_str())
+    }
+
+    pub fn get_all(&self, name: &str) -> Vec<&str> {
+        self.0.iter().filter(|(k, _)| k == name).map(|(_, v)| v.as_str()).collect()
+    }
+
+    // None if the parameter is absent or isn't a T
+    pub fn parse<T: std::str::FromStr>(&self, name: &str) -> Option<T> {
+        self.get(name).and_then(|v| v.parse().ok())
+    }
+
+    pub fn parse_all<T: std::str::FromStr>(&self, name: &str) -> Vec<T> {
+        self.get_all(name).iter().filter_map(|v| v.parse().ok()).collect()
+    }
+}
+
+// An authenticated Subsonic request
+pub struct Context {
+    pub user: User,
+    pub format: Format,
+    pub params: Params,
+}
+
+impl Context {
+    pub fn ok(&self, body: Option<Element>) -> Response<Vec<u8>> {
+        respond(self.format, None, body)
+    }
+
+    pub fn error(&self, code: i32, message: &str) -> Response<Vec<u8>> {
+        respond(self.format, Some((code, message)), None)
+    }
+
+    pub fn missing(&self, name: &str) -> Response<Vec<u8>> {
+        self.error(ERROR_MISSING_PARAMETER, &format!("Required parameter is missing: {}", name))
+    }
+
+    pub fn not_found(&self, what: &str) -> Response<Vec<u8>> {
+        self.error(ERROR_NOT_FOUND, &format!("{} not found", what))
+    }
+}
+
+// Rejects requests that can't be authenticated, with the error to send back
+#[derive(Debug)]
+pub struct Failure {
+    pub format: Format,
+    pub code: i32,
+    pub message: String,
+}
+
+impl warp::reject::Reject for Failure {}
+
+impl Failure {
+    pub fn response(&self) -> Response<Vec<u8>> {
+        respond(self.format, Some((self.code, &self.message)), None)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Str(String),
+    Int(i64),
+    Bool(bool),
+}
+
+impl Value {
+    fn to_xml(&self) -> String {
+        match self {
+            Value::Str(s) => escape_xml(s),
+            Value::Int(n) => n.to_string(),
+            Value::Bool(b) => b.to_string(),
+        }
+    }
+
+    fn to_json(&self) -> JsonValue {
+        match self {
+            Value::Str(s) => JsonValue::from(s.as_str()),
+            Value::Int(n) => JsonValue::from(*n),
+            Value::Bool(b) => JsonValue::from(*b),
+        }
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Value {
+        Value::Str(s.to_owned())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Value {
+        Value::Str(s)
+    }
+}
+
+impl From<i32> for Value {
+    fn from(n: i32) -> Value {
+        Value::Int(n as i64)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(n: i64) -> Value {
+        Value::Int(n)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Value {
+        Value::Bool(b)
+    }
+}
+
+// A response element, rendered as an XML element or a JSON object. Lists are
+// JSON arrays even when they have one item, which clients rely on.
+#[derive(Clone, Debug)]
+pub struct Element {
+    name: &'static str,
+    attrs: Vec<(&'static str, Value)>,
+    children: Vec<Child>,
+}
+
+#[derive(Clone, Debug)]
+enum Child {
+    One(Element),
+    List(&'static str, Vec<Element>),
+    Values(&'static str, Vec<Value>),
+}
+
+impl Element {
+    pub fn new(name: &'static str) -> Element {
+        Element {
+            name,
+            attrs: Vec::new(),
+            children: Vec::new(),
+        }
+    }
+
+    pub fn attr<V: Into<Value>>(mut self, name: &'static str, value: V) -> Element {
+        self.attrs.push((name, value.into()));
+        self
+    }
+
+    pub fn opt_attr<V: Into<Value>>(self, name: &'static str, value: Option<V>) -> Element {
+        match value {
+            Some(v) => self.attr(name, v),
+            None => self,
+        }
+    }
+
+    pub fn child(mut self, child: Element) -> Element {
+        self.children.push(Child::One(child));
+        self
+    }
+
+    // `name` is the name of each item
+    pub fn list(mut self, name: &'static str, items: Vec<Element>) -> Element {
+        self.children.push(Child::List(name, items));
+        self
+    }
+
+    // A list of plain values, rendered as text elements in XML
+    pub fn values(mut self, name: &'static str, values: Vec<Value>) -> Element {
+        self.children.push(Child::Values(name, values));
+        self
+    }
+
+    fn write_xml(&self, out: &mut String) {
+        out.push('<');
+        out.push_str(self.name);
+        for (name, value) in &self.attrs {
+            out.push_str(&format!(" {}=\"{}\"", name, value.to_xml()));
+        }
+        if self.children.is_empty() {
+            out.push_str("/>");
+            return;
+        }
+        out.push('>');
+        for child in &self.children {
+            match child {
+                Child::One(e) => e.write_xml(out),
+                Child::List(_, items) => items.iter().for_each(|e| e.write_xml(out)),
+                Child::Values(name, values) => {
+                    for value in values {
+                        out.push_str(&format!("<{0}>{1}</{0}>", name, value.to_xml()));
+                    }
+                }
+            }
+        }
+        out.push_str(&format!("</{}>", self.name));
+    }
+
+    fn to_json(&self) -> JsonValue {
+        let mut obj = Map::new();
+        for (name, value) in &self.attrs {
+            obj.insert(name.to_string(), value.to_json());
+        }
+        for child in &self.children {
+            match child {
+                Child::One(e) => {
+                    obj.insert(e.name.to_string(), e.to_json());
+                }
+                Child::List(name, items) => {
+                    obj.insert(name.to_string(), items.iter().map(Element::to_json).collect());
+                }
+                Child::Values(name, values) => {
+                    obj.insert(name.to_string(), values.iter().map(Value::to_json).collect());
+                }
+            }
+        }
+        JsonValue::Object(obj)
+    }
+}
+
+// OpenSubsonic extensions, with the versions of each supported
+const EXTENSIONS: &[(&str, &[i64])] = &[
+    ("apiKeyAuthentication", &[1]),
+];
+
+pub fn extensions(format: Format) -> Response<Vec<u8>> {
+    let extensions = EXTENSIONS.iter()
+        .map(|(name, versions)| {
+            Element::new("openSubsonicExtensions")
+                .attr("name", *name)
+                .values("versions", versions.iter().map(|v| Value::Int(*v)).collect())
+        })
+        .collect();
+    render(format, root(None).list("openSubsonicExtensions", extensions))
+}
+
+fn respond(format: Format, error: Option<(i32, &str)>, body: Option<Element>) -> Response<Vec<u8>> {
+    let mut res = root(error);
+    if let Some(body) = body {
+        res = res.child(body);
+    }
+    render(format, res)
+}
+
+fn root(error: Option<(i32, &str)>) -> Element {
+    let root = Element::new("subsonic-response")
+        .attr("status", if error.is_some() { "failed" } else { "ok" })
+        .attr("version", API_VERSION)
+        .attr("type", SERVER_TYPE)
+        .attr("serverVersion", env!("CARGO_PKG_VERSION"))
+        .attr("openSubsonic", true);
+    match error {
+        Some((code, message)) => root.child(Element::new("error").attr("code", code).attr("message", message)),
+        None => root,
+    }
+}
+
+fn render(format: Format, mut root: Element) -> Response<Vec<u8>> {
+    let (content_type, bytes) = match format {
+        Format::Xml => {
+            let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+            // The namespace is only part of the XML rendering
+            root.attrs.insert(0, ("xmlns", Value::from(XMLNS)));
+            root.write_xml(&mut out);
+            ("text/xml; charset=utf-8", out.into_bytes())
+        }
+        Format::Json => {
+            let mut obj = Map::new();
+            obj.insert("subsonic-response".to_owned(), root.to_json());
+            ("application/json", JsonValue::Object(obj).to_string().into_bytes())
+        }
+    };
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(warp::http::header::CONTENT_TYPE, content_type)
+        .body(bytes)
+        .unwrap()
+}
+
+// Cover art ids name what the art is for, since album and artist ids overlap
+pub fn album_cover_id(album_id: i32) -> String {
+    format!("al-{}", album_id)
+}
+
+pub fn artist_cover_id(artist_id: i32) -> String {
+    format!("ar-{}", artist_id)
+}
+
+pub enum CoverArt {
+    Album(i32),
+    Artist(i32),
+}
+
+pub fn parse_cover_id(id: &str) -> Option<CoverArt> {
+    if let Some(id) = id.strip_prefix("al-") {
+        id.parse().ok().map(CoverArt::Album)
+    } else if let Some(id) = id.strip_prefix("ar-") {
+        id.parse().ok().map(CoverArt::Artist)
+    } else {
+        // Bare ids are taken to be album ids
+        id.parse().ok().map(CoverArt::Album)
+    }
+}
+
+// Articles skipped when indexing and sorting artists
+pub const IGNORED_ARTICLES: &[&str] = &["The", "El", "La", "Los", "Las", "Le", "Les"];
+
+// The name an artist is sorted by, e.g. "Beatles" for "The Beatles"
+pub fn sort_name(name: &str) -> &str {
+    for article in IGNORED_ARTICLES {
+        let prefix = name.get(..article.len());
+        if name.len() > article.len() + 1
+            && prefix.map(|p| p.eq_ignore_ascii_case(article)).unwrap_or(false)
+            && name[article.len()..].starts_with(' ')
+        {
+            return name[article.len() + 1..].trim_start();
+        }
+    }
+    name
+}
+
+// The index an artist is listed under: the first letter of their sort name,
+// or # for anything else
+pub fn index_name(name: &str) -> String {
+    match sort_name(name).chars().next() {
+        Some(c) if c.is_alphabetic() => c.to_uppercase().collect(),
+        _ => "#".to_owned(),
+    }
+}
+
+fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn body(res: Response<Vec<u8>>) -> String {
+        String::from_utf8(res.into_body()).unwrap()
+    }
+
+    fn albums() -> Element {
+        Element::new("albumList2")
+            .list("album", vec![Element::new("album").attr("id", 1).attr("name", "Kid A & Amnesiac")])
+    }
+
+    #[test]
+    fn xml_response() {
+        let xml = body(respond(Format::Xml, None, Some(albums())));
+        assert!(xml.contains("<subsonic-response xmlns=\"http://subsonic.org/restapi\" status=\"ok\" version=\"1.16.1\""));
+        assert!(xml.contains("<albumList2><album id=\"1\" name=\"Kid A &amp; Amnesiac\"/></albumList2></subsonic-response>"));
+    }
+
+    #[test]
+    fn json_lists_are_arrays() {
+        let json: JsonValue = serde_json::from_str(&body(respond(Format::Json, None, Some(albums())))).unwrap();
+        let res = &json["subsonic-response"];
+        assert_eq!(res["status"], "ok");
+        assert_eq!(res["openSubsonic"], true);
+        assert!(res.get("xmlns").is_none());
+        assert_eq!(res["albumList2"]["album"][0]["id"], 1);
+        assert_eq!(res["albumList2"]["album"][0]["name"], "Kid A & Amnesiac");
+    }
+
+    #[test]
+    fn error_response() {
+        let json: JsonValue = serde_json::from_str(&body(respond(Format::Json, Some((ERROR_NOT_FOUND, "Album not found")), None))).unwrap();
+        assert_eq!(json["subsonic-response"]["status"], "failed");
+        assert_eq!(json["subsonic-response"]["error"]["code"], 70);
+    }
+
+    #[test]
+    fn extension_versions() {
+        let xml = body(extensions(Format::Xml));
+        assert!(xml.contains("<openSubsonicExtensions name=\"apiKeyAuthentication\"><versions>1</versions></openSubsonicExtensions>"));
+        let json: JsonValue = serde_json::from_str(&body(extensions(Format::Json))).unwrap();
+        assert_eq!(json["subsonic-response"]["openSubsonicExtensions"][0]["versions"][0], 1);
+    }
+
+    #[test]
+    fn repeated_params() {
+        let params = Params(vec![
+            ("songId".to_owned(), "1".to_owned()),
+            ("songId".to_owned(), "x".to_owned()),
+            ("songId".to_owned(), "3".to_owned()),
+        ]);
+        assert_eq!(params.parse_all::<i32>("songId"), vec![1, 3]);
+        assert_eq!(params.get("songId"), Some("1"));
+        assert_eq!(params.get("name"), None);
+    }
+
+    #[test]
+    fn artist_indexes() {
+        assert_eq!(sort_name("The Beatles"), "Beatles");
+        assert_eq!(sort_name("Theatre of Tragedy"), "Theatre of Tragedy");
+        assert_eq!(sort_name("The"), "The");
+        assert_eq!(index_name("los lobos"), "L");
+        assert_eq!(index_name("The xx"), "X");
+        assert_eq!(index_name("2Pac"), "#");
+    }
+
+    #[test]
+    fn cover_ids() {
+        assert!(matches!(parse_cover_id(&album_cover_id(4)), Some(CoverArt::Album(4))));
+        assert!(matches!(parse_cover_id(&artist_cover_id(7)), Some(CoverArt::Artist(7))));
+        assert!(matches!(parse_cover_id("12"), Some(CoverArt::Album(12))));
+        assert!(parse_cover_id("pl-1").is_none());
+    }
+}
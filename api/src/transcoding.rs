@@ -0,0 +1,253 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
+
+use av::transcode::{self, TranscodeOptions};
+use bytes::Bytes;
+use futures::SinkExt;
+use futures::channel::mpsc;
+use hyper::Body;
+
+use crate::config::Config;
+
+// Number of encoded chunks buffered ahead of a slow client
+const CHANNEL_CAPACITY: usize = 16;
+
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone)]
+pub struct TranscodeCache {
+    dir: PathBuf,
+    max_bytes: u64,
+}
+
+impl TranscodeCache {
+    pub fn new(config: &Config) -> TranscodeCache {
+        TranscodeCache {
+            dir: config.transcode_cache_dir.clone(),
+            max_bytes: config.transcode_cache_max_bytes,
+        }
+    }
+
+    // Outputs are keyed on the source file's modification time and size as
+    // well as the track, so a file the importer replaces or relinks gets a
+    // new transcode rather than the old one. Stale outputs age out of the
+    // cache like any other.
+    pub fn path_for(&self, track_id: i32, source: &fs::Metadata, opts: &TranscodeOptions) -> PathBuf {
+        let modified = source.modified()
+            .ok()
+            .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        self.dir.join(format!(
+            "{}-{}-{}-{}.{}",
+            track_id, modified, source.len(), opts.bit_rate, opts.format.extension(),
+        ))
+    }
+
+    fn temp_path_for(&self, path: &Path) -> PathBuf {
+        let n = TEMP_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let mut name = path.file_name().unwrap_or_default().to_os_string();
+        name.push(format!(".{}-{}.part", std::process::id(), n));
+        self.dir.join(name)
+    }
+
+    // Removes the least recently used outputs until the cache fits within
+    // its size limit. In-progress transcodes are left alone.
+    fn evict(&self) -> io::Result<()> {
+        let mut entries = Vec::new();
+        let mut total = 0;
+
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if path.extension().map(|e| e == "part").unwrap_or(false) {
+                continue;
+            }
+            let md = entry.metadata()?;
+            if !md.is_file() {
+                continue;
+            }
+            let used = md.accessed().or_else(|_| md.modified()).unwrap_or(SystemTime::UNIX_EPOCH);
+            total += md.len();
+            entries.push((used, md.len(), path));
+        }
+
+        entries.sort_by_key(|(used, _, _)| *used);
+        for (_, size, path) in entries {
+            if total <= self.max_bytes {
+                break;
+            }
+            fs::remove_file(&path)?;
+            total -= size;
+        }
+
+        Ok(())
+    }
+}
+
+// Transcodes `source` on a blocking thread and returns a body that streams
+// the output as it is encoded. The output is written to the cache at the
+// same time, and only moved into place once the transcode succeeds.
+pub fn transcode_to_body(source: PathBuf, dest: PathBuf, cache: TranscodeCache, opts: TranscodeOptions) -> Body {
+    let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+
+    tokio::task::spawn_blocking(move || {
+        let temp = cache.temp_path_for(&dest);
+        let file = fs::create_dir_all(&cache.dir)
+            .and_then(|_| fs::File::create(&temp))
+            .map_err(|e| eprintln!("Failed to create transcode cache file {:?}: {}", temp, e))
+            .ok();
+        let mut sink = ChannelSink {
+            tx: Some(tx.clone()),
+            file,
+        };
+
+        let res = transcode::transcode(&source, &opts, &mut sink);
+        let cached = sink.file.take().map(|mut f| f.flush().is_ok()).unwrap_or(false);
+        drop(sink);
+
+        match res {
+            Ok(()) if cached => {
+                if let Err(e) = fs::rename(&temp, &dest).and_then(|_| cache.evict()) {
+                    eprintln!("Failed to update transcode cache: {}", e);
+                }
+            }
+            Ok(()) => {
+                let _ = fs::remove_file(&temp);
+            }
+            Err(e) => {
+                eprintln!("Failed to transcode {:?}: {}", source, e);
+                let _ = fs::remove_file(&temp);
+                // End the response with an error rather than a clean EOF so
+                // the client doesn't mistake a truncated file for a whole one.
+                let mut tx = tx;
+                let err = io::Error::new(io::ErrorKind::Other, e.to_string());
+                let _ = futures::executor::block_on(tx.send(Err(err)));
+            }
+        }
+    });
+
+    Body::wrap_stream(rx)
+}
+
+struct ChannelSink {
+    tx: Option<mpsc::Sender<Result<Bytes, io::Error>>>,
+    file: Option<fs::File>,
+}
+
+impl Write for ChannelSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(f) = self.file.as_mut() {
+            if let Err(e) = f.write_all(buf) {
+                eprintln!("Failed to write transcode cache file: {}", e);
+                self.file = None;
+            }
+        }
+
+        if let Some(tx) = self.tx.as_mut() {
+            let chunk = Bytes::copy_from_slice(buf);
+            if futures::executor::block_on(tx.send(Ok(chunk))).is_err() {
+                // The client went away. Keep going if the output is being
+                // cached, since it is likely to be requested again.
+                self.tx = None;
+            }
+        }
+
+        if self.tx.is_none() && self.file.is_none() {
+            Err(io::Error::new(io::ErrorKind::BrokenPipe, "client disconnected"))
+        } else {
+            Ok(buf.len())
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.file.as_mut() {
+            Some(f) => f.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use av::transcode::OutputFormat;
+
+    use super::*;
+
+    fn cache(test: &str, max_bytes: u64) -> TranscodeCache {
+        let dir = std::env::temp_dir()
+            .join(format!("doplr-transcode-test-{}-{}", std::process::id(), test));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        TranscodeCache {
+            dir,
+            max_bytes,
+        }
+    }
+
+    fn opus(bit_rate: i64) -> TranscodeOptions {
+        TranscodeOptions {
+            format: OutputFormat::Opus,
+            bit_rate,
+        }
+    }
+
+    fn entries(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir).unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn same_path_for_unchanged_source() {
+        let cache = cache("hit", u64::MAX);
+        let source = cache.dir.join("source.flac");
+        fs::write(&source, b"fLaC").unwrap();
+
+        let first = cache.path_for(1, &fs::metadata(&source).unwrap(), &opus(128));
+        let second = cache.path_for(1, &fs::metadata(&source).unwrap(), &opus(128));
+        assert_eq!(first, second);
+        assert_ne!(first, cache.path_for(1, &fs::metadata(&source).unwrap(), &opus(96)));
+        assert_ne!(first, cache.path_for(2, &fs::metadata(&source).unwrap(), &opus(128)));
+    }
+
+    #[test]
+    fn new_path_when_source_changes() {
+        let cache = cache("invalidate", u64::MAX);
+        let source = cache.dir.join("source.flac");
+        fs::write(&source, b"fLaC").unwrap();
+        let before = cache.path_for(1, &fs::metadata(&source).unwrap(), &opus(128));
+
+        fs::write(&source, b"fLaC, replaced").unwrap();
+        let after = cache.path_for(1, &fs::metadata(&source).unwrap(), &opus(128));
+        assert_ne!(before, after);
+    }
+
+    #[tokio::test]
+    async fn failed_transcode_leaves_no_partial_file() {
+        let cache = cache("partial", u64::MAX);
+        let source = cache.dir.join("source.flac");
+        fs::write(&source, b"not audio").unwrap();
+        let dest = cache.path_for(1, &fs::metadata(&source).unwrap(), &opus(128));
+
+        let body = transcode_to_body(source, dest.clone(), cache.clone(), opus(128));
+        assert!(hyper::body::to_bytes(body).await.is_err());
+        assert!(!dest.exists());
+        assert_eq!(entries(&cache.dir), vec!["source.flac"]);
+    }
+
+    #[test]
+    fn evict_skips_in_progress_transcodes() {
+        let cache = cache("evict", 0);
+        fs::write(cache.dir.join("1-0-4-128.opus"), b"done").unwrap();
+        fs::write(cache.dir.join("2-0-4-128.opus.1-0.part"), b"part").unwrap();
+
+        cache.evict().unwrap();
+        assert_eq!(entries(&cache.dir), vec!["2-0-4-128.opus.1-0.part"]);
+    }
+}
@@ -10,6 +10,7 @@ pub enum AVError {
     AVLibraryError(String),
     NullPointer(String),
     UnknownFormat,
+    IOError(std::io::Error),
 }
 
 impl std::error::Error for AVError {
@@ -18,6 +19,7 @@ impl std::error::Error for AVError {
         match self {
             EncodingError(Some(e)) => Some(e),
             PathNulByteError(e) => Some(e),
+            IOError(e) => Some(e),
             _ => None,
         }
     }
@@ -32,6 +34,7 @@ impl std::fmt::Display for AVError {
             AVLibraryError(e) => write!(fmt, "av library error: {}", e),
             NullPointer(_) => write!(fmt, "unexpected null pointer"),
             UnknownFormat => write!(fmt, "unknown format"),
+            IOError(e) => write!(fmt, "io error: {}", e),
         }
     }
 }
@@ -42,6 +45,12 @@ impl From<ffi::NulError> for AVError {
     }
 }
 
+impl From<std::io::Error> for AVError {
+    fn from(e: std::io::Error) -> Self {
+        AVError::IOError(e)
+    }
+}
+
 impl From<str::Utf8Error> for AVError {
     fn from(e: str::Utf8Error) -> Self {
         AVError::EncodingError(Some(e))
@@ -60,3 +69,12 @@ pub(super) fn av_error_to_string(code: i32) -> super::Result<Result<String, i32>
 
     Ok(Ok(utils::char_ptr_to_str(ptr)?.to_string()))
 }
+
+pub(super) fn av_library_error(code: i32) -> AVError {
+    let msg = match av_error_to_string(code) {
+        Ok(Ok(msg)) => msg,
+        Ok(Err(res)) => format!("failed to convert error code {} to string; received error code {}", code, res),
+        Err(_) => format!("failed to convert error code {} to string", code),
+    };
+    AVError::AVLibraryError(msg)
+}
@@ -0,0 +1,331 @@
+#![allow(non_snake_case)]
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+use super::error::AVError;
+use super::{error, utils};
+
+// FFmpeg defines this with a cast bindgen can't translate
+const AV_NOPTS_VALUE: i64 = i64::MIN;
+
+pub enum Format {
+    FLAC,
+    MP3,
+    Vorbis,
+    Opus,
+    // In MP4 containers, or raw ADTS streams
+    AAC,
+    ALAC,
+    // PCM, or anything else, in a WAV or AIFF container. Their tags are the
+    // container's own, whatever the codec.
+    WAV,
+    AIFF,
+}
+
+// Whether `path` starts like one of the files `Format` covers. Files are
+// recognised by their contents rather than their names, though MP4 video
+// looks the same as M4A until it's opened, where determine_format turns it
+// away.
+pub fn is_audio_file<P: AsRef<Path>>(path: P) -> bool {
+    let mut header = Vec::with_capacity(12);
+    match File::open(path).and_then(|f| f.take(12).read_to_end(&mut header)) {
+        Ok(_) => is_audio_header(&header),
+        Err(_) => false,
+    }
+}
+
+// Major brands of MP4 files that may hold audio. HEIF and AVIF images use the
+// same container, with brands like heic, mif1 and avif.
+const MP4_AUDIO_BRANDS: &[[u8; 4]] = &[*b"M4A ", *b"M4B ", *b"M4P ", *b"mp41", *b"mp42", *b"isom", *b"iso2", *b"dash"];
+
+fn is_audio_header(header: &[u8]) -> bool {
+    match header {
+        [b'f', b'L', b'a', b'C', ..] => true,
+        [b'I', b'D', b'3', ..] => true,
+        [b'O', b'g', b'g', b'S', ..] => true,
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'A', b'V', b'E', ..] => true,
+        // AIFF or AIFC
+        [b'F', b'O', b'R', b'M', _, _, _, _, b'A', b'I', b'F', _, ..] => true,
+        [_, _, _, _, b'f', b't', b'y', b'p', b0, b1, b2, b3, ..] => MP4_AUDIO_BRANDS.contains(&[*b0, *b1, *b2, *b3]),
+        // The frame sync of untagged MP3 or ADTS
+        [0xFF, b, ..] => b & 0xE0 == 0xE0,
+        _ => false,
+    }
+}
+
+// A picture stored in the file, such as a FLAC PICTURE block or an ID3 APIC
+// frame. FFmpeg exposes these as video streams with a single packet.
+pub struct Picture {
+    pub data: Vec<u8>,
+    pub mime_type: &'static str,
+}
+
+// The technical properties of a file's audio stream
+#[derive(Debug)]
+pub struct AudioProperties {
+    // FFmpeg's name for the codec, e.g. "flac" or "mp3"
+    pub codec: String,
+    pub sample_rate: i32,
+    // Lossy codecs have no bit depth of their own
+    pub bit_depth: Option<i32>,
+    pub channels: i32,
+    pub duration_ms: i64,
+    pub lossless: bool,
+}
+
+pub struct AVFormatContext {
+    ctx: *mut ffmpeg_sys::AVFormatContext,
+    path: PathBuf,
+    stream_info_read: Cell<bool>,
+}
+
+impl AVFormatContext {
+    pub fn open<P: AsRef<Path>>(p: P) -> super::Result<AVFormatContext> {
+        let path_str = p.as_ref().to_str().ok_or(AVError::EncodingError(None))?;
+        let path = CString::new(path_str)?;
+        let mut ctx = Self::alloc()?;
+
+        let fmt: *mut ffmpeg_sys::AVInputFormat = ptr::null_mut();
+        let mut opts: *mut ffmpeg_sys::AVDictionary = ptr::null_mut();
+
+        unsafe {
+            let open_res = ffmpeg_sys::avformat_open_input(&mut ctx, path.as_ptr(), fmt, &mut opts);
+            if open_res < 0 {
+                let msg = match error::av_error_to_string(open_res)? {
+                    Ok(msg) => msg,
+                    Err(res) => format!("failed to convert error code {} to string; received error code {}", open_res, res),
+                };
+                return Err(AVError::AVLibraryError(msg));
+            }
+        }
+
+        let context = AVFormatContext {
+            ctx,
+            path: p.as_ref().to_path_buf(),
+            stream_info_read: Cell::new(false),
+        };
+
+        Ok(context)
+    }
+
+    pub(super) fn find_stream_info(&self) -> super::Result<()> {
+        if !self.stream_info_read.get() {
+            let ret = unsafe { ffmpeg_sys::avformat_find_stream_info(self.ctx, ptr::null_mut()) };
+            if ret < 0 {
+                let msg = match error::av_error_to_string(ret)? {
+                    Ok(msg) => msg,
+                    Err(res) => format!("failed to convert error code {} to string; received error code {}", ret, res),
+                };
+                return Err(AVError::AVLibraryError(msg));
+            }
+            self.stream_info_read.replace(true);
+        }
+        Ok(())
+    }
+
+    fn alloc() -> super::Result<*mut ffmpeg_sys::AVFormatContext> {
+        let ctx = unsafe { ffmpeg_sys::avformat_alloc_context() };
+        if ctx == ptr::null_mut() {
+            Err(AVError::NullPointer("AVFormatContext".to_string()))
+        } else {
+            Ok(ctx)
+        }
+    }
+
+    pub fn close(self) {}
+
+    pub(super) fn as_ptr(&self) -> *mut ffmpeg_sys::AVFormatContext {
+        self.ctx
+    }
+
+    pub fn metadata<'a, 'b>(&'a self) -> super::Result<HashMap<&'b str, &'b str>> {
+        unsafe { utils::av_dict_as_hash((*self.ctx).metadata) }
+    }
+
+    pub fn determine_format(&self) -> super::Result<Format> {
+        let mut candidates = Vec::new();
+
+        unsafe {
+            self.find_stream_info()?;
+            let container = utils::char_ptr_to_str((*(*self.ctx).iformat).name)?;
+            for i in 0..(*self.ctx).nb_streams {
+                let stream = *(*self.ctx).streams.offset(i as isize);
+                let params = (*stream).codecpar;
+
+                // Videos aren't music, but cover art comes as a video stream
+                // too
+                if (*params).codec_type == ffmpeg_sys::AVMediaType_AVMEDIA_TYPE_VIDEO
+                    && (*stream).disposition & ffmpeg_sys::AV_DISPOSITION_ATTACHED_PIC as i32 == 0 {
+                    return Err(AVError::UnknownFormat);
+                }
+                if (*params).codec_type != ffmpeg_sys::AVMediaType_AVMEDIA_TYPE_AUDIO {
+                    continue;
+                }
+
+                match (container, (*params).codec_id) {
+                    ("wav", _) => candidates.push(Format::WAV),
+                    ("aiff", _) => candidates.push(Format::AIFF),
+                    (_, ffmpeg_sys::AVCodecID_AV_CODEC_ID_MP3) => candidates.push(Format::MP3),
+                    (_, ffmpeg_sys::AVCodecID_AV_CODEC_ID_FLAC) => candidates.push(Format::FLAC),
+                    (_, ffmpeg_sys::AVCodecID_AV_CODEC_ID_VORBIS) => candidates.push(Format::Vorbis),
+                    (_, ffmpeg_sys::AVCodecID_AV_CODEC_ID_OPUS) => candidates.push(Format::Opus),
+                    (_, ffmpeg_sys::AVCodecID_AV_CODEC_ID_AAC) => candidates.push(Format::AAC),
+                    (_, ffmpeg_sys::AVCodecID_AV_CODEC_ID_ALAC) => candidates.push(Format::ALAC),
+                    _ => {}
+                }
+            }
+        }
+
+        // What should be done if there are multiple audio streams?
+        if candidates.len() == 1 {
+            Ok(candidates.remove(0))
+        } else {
+            Err(AVError::UnknownFormat)
+        }
+    }
+
+    pub fn bit_rate(&self) -> i64 {
+        unsafe { (*self.ctx).bit_rate / 1000 }
+    }
+
+    // Returns the front cover attached to the file, or else the first
+    // picture attached to it
+    pub fn attached_picture(&self) -> super::Result<Option<Picture>> {
+        let mut pictures = Vec::new();
+
+        unsafe {
+            for i in 0..(*self.ctx).nb_streams {
+                let stream = *(*self.ctx).streams.offset(i as isize);
+                if (*stream).disposition & ffmpeg_sys::AV_DISPOSITION_ATTACHED_PIC as i32 == 0 {
+                    continue;
+                }
+
+                let mime_type = match (*(*stream).codecpar).codec_id {
+                    ffmpeg_sys::AVCodecID_AV_CODEC_ID_MJPEG => "image/jpeg",
+                    ffmpeg_sys::AVCodecID_AV_CODEC_ID_PNG => "image/png",
+                    _ => continue,
+                };
+                let packet = &(*stream).attached_pic;
+                if packet.data == ptr::null_mut() || packet.size <= 0 {
+                    continue;
+                }
+                let data = std::slice::from_raw_parts(packet.data, packet.size as usize).to_vec();
+
+                // FFmpeg puts the picture type in the stream's comment
+                let is_front = utils::av_dict_as_hash((*stream).metadata)?
+                    .get("comment")
+                    .map(|c| *c == "Cover (front)")
+                    .unwrap_or(false);
+                pictures.push((is_front, Picture { data, mime_type }));
+            }
+        }
+
+        let i = pictures.iter().position(|(is_front, _)| *is_front).unwrap_or(0);
+        if pictures.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(pictures.swap_remove(i).1))
+        }
+    }
+
+    pub fn duration(&self) -> i64 {
+        unsafe { (*self.ctx).duration / (ffmpeg_sys::AV_TIME_BASE as i64) }
+    }
+
+    pub fn audio_properties(&self) -> super::Result<AudioProperties> {
+        self.find_stream_info()?;
+
+        unsafe {
+            let i = ffmpeg_sys::av_find_best_stream(
+                self.ctx,
+                ffmpeg_sys::AVMediaType_AVMEDIA_TYPE_AUDIO,
+                -1,
+                -1,
+                ptr::null_mut(),
+                0,
+            );
+            if i < 0 {
+                return Err(error::av_library_error(i));
+            }
+            let stream = *(*self.ctx).streams.offset(i as isize);
+            let params = (*stream).codecpar;
+
+            let codec = utils::char_ptr_to_str(ffmpeg_sys::avcodec_get_name((*params).codec_id))?.to_string();
+            let descriptor = ffmpeg_sys::avcodec_descriptor_get((*params).codec_id);
+            let lossless = descriptor != ptr::null() && (*descriptor).props & ffmpeg_sys::AV_CODEC_PROP_LOSSLESS as i32 != 0;
+            // FLAC and ALAC report the bit depth of their decoded samples,
+            // PCM the size of its stored ones
+            let bit_depth = Some((*params).bits_per_raw_sample)
+                .filter(|b| *b > 0)
+                .or(Some((*params).bits_per_coded_sample).filter(|b| *b > 0))
+                .filter(|_| lossless);
+
+            // The container's duration is missing for some raw streams, so
+            // fall back to the stream's own
+            let duration_ms = if (*self.ctx).duration != AV_NOPTS_VALUE {
+                (*self.ctx).duration / (ffmpeg_sys::AV_TIME_BASE as i64 / 1000)
+            } else if (*stream).duration != AV_NOPTS_VALUE {
+                let tb = (*stream).time_base;
+                (*stream).duration * 1000 * tb.num as i64 / tb.den as i64
+            } else {
+                0
+            };
+
+            Ok(AudioProperties {
+                codec,
+                sample_rate: (*params).sample_rate,
+                bit_depth,
+                channels: (*params).channels,
+                duration_ms,
+                lossless,
+            })
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for AVFormatContext {
+    fn drop(&mut self) {
+        if self.ctx != ptr::null_mut() {
+            unsafe { ffmpeg_sys::avformat_close_input(&mut self.ctx); }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn audio_headers() {
+        assert!(is_audio_header(b"fLaC\0\0\0\x22"));
+        assert!(is_audio_header(b"ID3\x04\0\0"));
+        assert!(is_audio_header(b"OggS\0\x02"));
+        assert!(is_audio_header(b"RIFF\x24\x08\0\0WAVEfmt "));
+        assert!(is_audio_header(b"FORM\0\0\x08\x24AIFFCOMM"));
+        assert!(is_audio_header(b"\0\0\0\x20ftypM4A "));
+        assert!(is_audio_header(b"\0\0\0\x20ftypM4B "));
+        assert!(is_audio_header(b"\0\0\0\x1cftypmp42"));
+        assert!(is_audio_header(b"\0\0\0\x1cftypisom"));
+        assert!(is_audio_header(b"\0\0\0\x18ftypdash"));
+        assert!(!is_audio_header(b"\0\0\0\x18ftypheic"));
+        assert!(!is_audio_header(b"\0\0\0\x18ftypmif1"));
+        assert!(!is_audio_header(b"\0\0\0\x1cftypavif"));
+        assert!(!is_audio_header(b"\0\0\0\x14ftypqt  "));
+        assert!(!is_audio_header(b"\0\0\0\x20ftyp"));
+        assert!(is_audio_header(b"\xFF\xFB\x90\x64"));
+        assert!(!is_audio_header(b"\xFF\xD8\xFF\xE0"));
+        assert!(!is_audio_header(b"\x89PNG\r\n"));
+        assert!(!is_audio_header(b"RIFF\x24\x08\0\0AVI LIST"));
+        assert!(!is_audio_header(b""));
+    }
+}
@@ -1,6 +1,8 @@
 mod error;
 pub mod format;
+pub mod loudness;
 pub mod metadata;
+pub mod transcode;
 mod utils;
 
 pub use error::AVError;
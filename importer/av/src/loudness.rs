@@ -0,0 +1,409 @@
+use std::f64::consts::PI;
+use std::path::Path;
+use std::ptr;
+
+use super::error::AVError;
+use super::format::AVFormatContext;
+use super::transcode::{check, CodecContext, Frame, Packet, Resampler, AVERROR_EAGAIN, AVERROR_EOF};
+
+// EBU R128 measures loudness over 400ms blocks, overlapping by 75%
+const BLOCK_STEPS: usize = 4;
+const STEP_MS: usize = 100;
+// Silence measures as the gate itself
+pub const ABSOLUTE_GATE: f64 = -70.0;
+const RELATIVE_GATE: f64 = -10.0;
+
+// True peaks are found by oversampling 4x with a windowed sinc filter
+const OVERSAMPLING: usize = 4;
+const TAPS_PER_PHASE: usize = 12;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Loudness {
+    // Integrated loudness, in LUFS
+    pub integrated: f64,
+    // Where 1.0 is full scale
+    pub true_peak: f64,
+}
+
+// Decodes the first audio stream of `input` and measures its loudness
+pub fn analyze<P: AsRef<Path>>(input: P) -> super::Result<Loudness> {
+    let input = AVFormatContext::open(input)?;
+    unsafe { Analysis::new(&input).and_then(|mut a| a.run(input.as_ptr())) }
+}
+
+struct Analysis {
+    stream_index: i32,
+    decoder: CodecContext,
+    resampler: Resampler,
+    decoded: Frame,
+    resampled: Frame,
+    meter: Meter,
+}
+
+impl Analysis {
+    unsafe fn new(input: &AVFormatContext) -> super::Result<Analysis> {
+        input.find_stream_info()?;
+        let ictx = input.as_ptr();
+
+        let mut dec: *mut ffmpeg_sys::AVCodec = ptr::null_mut();
+        let stream_index = check(ffmpeg_sys::av_find_best_stream(
+            ictx,
+            ffmpeg_sys::AVMediaType_AVMEDIA_TYPE_AUDIO,
+            -1,
+            -1,
+            &mut dec,
+            0,
+        ))?;
+        if dec.is_null() {
+            return Err(AVError::UnknownFormat);
+        }
+
+        let decoder = CodecContext::alloc(dec)?;
+        let d = decoder.0;
+        let stream = *(*ictx).streams.offset(stream_index as isize);
+        check(ffmpeg_sys::avcodec_parameters_to_context(d, (*stream).codecpar))?;
+        if (*d).channel_layout == 0 {
+            (*d).channel_layout = ffmpeg_sys::av_get_default_channel_layout((*d).channels) as u64;
+        }
+        check(ffmpeg_sys::avcodec_open2(d, dec, ptr::null_mut()))?;
+
+        // Measured as interleaved doubles at the original rate and layout
+        let resampler = Resampler::to_format(
+            d,
+            (*d).channel_layout,
+            ffmpeg_sys::AVSampleFormat_AV_SAMPLE_FMT_DBL,
+            (*d).sample_rate,
+        )?;
+
+        Ok(Analysis {
+            stream_index,
+            decoder,
+            resampler,
+            decoded: Frame::alloc()?,
+            resampled: Frame::alloc()?,
+            meter: Meter::new((*d).channels as usize, (*d).sample_rate as u32),
+        })
+    }
+
+    unsafe fn run(&mut self, ictx: *mut ffmpeg_sys::AVFormatContext) -> super::Result<Loudness> {
+        let packet = Packet::alloc()?;
+
+        loop {
+            let ret = ffmpeg_sys::av_read_frame(ictx, packet.0);
+            if ret == AVERROR_EOF {
+                break;
+            }
+            check(ret)?;
+
+            if (*packet.0).stream_index == self.stream_index {
+                let ret = check(ffmpeg_sys::avcodec_send_packet(self.decoder.0, packet.0));
+                ffmpeg_sys::av_packet_unref(packet.0);
+                ret?;
+                self.receive_decoded()?;
+            } else {
+                ffmpeg_sys::av_packet_unref(packet.0);
+            }
+        }
+
+        check(ffmpeg_sys::avcodec_send_packet(self.decoder.0, ptr::null()))?;
+        self.receive_decoded()?;
+        while self.resample(ptr::null())? > 0 {}
+
+        Ok(self.meter.loudness())
+    }
+
+    unsafe fn receive_decoded(&mut self) -> super::Result<()> {
+        loop {
+            let ret = ffmpeg_sys::avcodec_receive_frame(self.decoder.0, self.decoded.0);
+            if ret == AVERROR_EAGAIN || ret == AVERROR_EOF {
+                return Ok(());
+            }
+            check(ret)?;
+
+            if (*self.decoded.0).channel_layout == 0 {
+                (*self.decoded.0).channel_layout = (*self.decoder.0).channel_layout;
+            }
+            let res = self.resample(self.decoded.0);
+            ffmpeg_sys::av_frame_unref(self.decoded.0);
+            res?;
+        }
+    }
+
+    // Converts `input` to doubles and meters them. A null `input` flushes the
+    // resampler. Returns the number of samples metered.
+    unsafe fn resample(&mut self, input: *const ffmpeg_sys::AVFrame) -> super::Result<i32> {
+        let d = self.decoder.0;
+        let out = self.resampled.0;
+        (*out).channel_layout = (*d).channel_layout;
+        (*out).sample_rate = (*d).sample_rate;
+        (*out).format = ffmpeg_sys::AVSampleFormat_AV_SAMPLE_FMT_DBL;
+
+        let res = check(ffmpeg_sys::swr_convert_frame(self.resampler.0, out, input));
+        let nb_samples = (*out).nb_samples;
+        if res.is_ok() && nb_samples > 0 {
+            let len = nb_samples as usize * (*d).channels as usize;
+            let samples = std::slice::from_raw_parts((*out).data[0] as *const f64, len);
+            self.meter.add(samples);
+        }
+        ffmpeg_sys::av_frame_unref(out);
+        res.map(|_| nb_samples)
+    }
+}
+
+// Measures integrated loudness and true peak as in ITU-R BS.1770-4, from
+// interleaved samples
+pub struct Meter {
+    channels: usize,
+    weights: Vec<f64>,
+    filters: Vec<KWeighting>,
+    peaks: Vec<TruePeak>,
+    step_len: usize,
+    // Weighted sums of squares over the step in progress, and the ones
+    // before it that are still part of a block
+    step: f64,
+    step_samples: usize,
+    recent_steps: Vec<f64>,
+    // Mean square of every block
+    blocks: Vec<f64>,
+}
+
+impl Meter {
+    pub fn new(channels: usize, sample_rate: u32) -> Meter {
+        // Surround channels count for more, and LFE isn't counted at all
+        let weights = (0..channels)
+            .map(|c| match (channels, c) {
+                (6, 3) => 0.0,
+                (n, c) if n > 3 && c >= 4 => 1.41,
+                _ => 1.0,
+            })
+            .collect();
+
+        Meter {
+            channels,
+            weights,
+            filters: (0..channels).map(|_| KWeighting::new(sample_rate as f64)).collect(),
+            peaks: (0..channels).map(|_| TruePeak::new(sample_rate)).collect(),
+            step_len: (sample_rate as usize * STEP_MS / 1000).max(1),
+            step: 0.0,
+            step_samples: 0,
+            recent_steps: Vec::with_capacity(BLOCK_STEPS),
+            blocks: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, samples: &[f64]) {
+        for frame in samples.chunks_exact(self.channels) {
+            for (c, &x) in frame.iter().enumerate() {
+                self.peaks[c].add(x);
+                let y = self.filters[c].process(x);
+                self.step += self.weights[c] * y * y;
+            }
+
+            self.step_samples += 1;
+            if self.step_samples == self.step_len {
+                if self.recent_steps.len() == BLOCK_STEPS {
+                    self.recent_steps.remove(0);
+                }
+                self.recent_steps.push(self.step);
+                if self.recent_steps.len() == BLOCK_STEPS {
+                    let sum: f64 = self.recent_steps.iter().sum();
+                    self.blocks.push(sum / (self.step_len * BLOCK_STEPS) as f64);
+                }
+                self.step = 0.0;
+                self.step_samples = 0;
+            }
+        }
+    }
+
+    pub fn loudness(&self) -> Loudness {
+        Loudness {
+            integrated: self.integrated(),
+            true_peak: self.peaks.iter().map(|p| p.peak).fold(0.0, f64::max),
+        }
+    }
+
+    // Silence, or anything shorter than a block, measures at the absolute
+    // gate
+    fn integrated(&self) -> f64 {
+        let gated_mean = |threshold: f64| {
+            let gated: Vec<f64> = self.blocks.iter().copied().filter(|&z| block_loudness(z) > threshold).collect();
+            if gated.is_empty() {
+                None
+            } else {
+                Some(gated.iter().sum::<f64>() / gated.len() as f64)
+            }
+        };
+
+        let relative_gate = match gated_mean(ABSOLUTE_GATE) {
+            Some(mean) => block_loudness(mean) + RELATIVE_GATE,
+            None => return ABSOLUTE_GATE,
+        };
+        gated_mean(relative_gate.max(ABSOLUTE_GATE))
+            .map(block_loudness)
+            .unwrap_or(ABSOLUTE_GATE)
+    }
+}
+
+fn block_loudness(mean_square: f64) -> f64 {
+    -0.691 + 10.0 * mean_square.max(1e-20).log10()
+}
+
+// The K-weighting pre-filter: a high shelf for the head's effect, then a
+// high pass. Coefficients are derived for any sample rate, as libebur128
+// does.
+struct KWeighting {
+    b: [f64; 5],
+    a: [f64; 5],
+    x: [f64; 5],
+    y: [f64; 5],
+}
+
+impl KWeighting {
+    fn new(sample_rate: f64) -> KWeighting {
+        let f0 = 1681.974450955533;
+        let g = 3.999843853973347;
+        let q = 0.7071752369554196;
+        let k = (PI * f0 / sample_rate).tan();
+        let vh = 10f64.powf(g / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+        let a0 = 1.0 + k / q + k * k;
+        let shelf_b = [(vh + vb * k / q + k * k) / a0, 2.0 * (k * k - vh) / a0, (vh - vb * k / q + k * k) / a0];
+        let shelf_a = [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0];
+
+        let f0 = 38.13547087602444;
+        let q = 0.5003270373238773;
+        let k = (PI * f0 / sample_rate).tan();
+        let pass_b = [1.0, -2.0, 1.0];
+        let pass_a = [1.0, 2.0 * (k * k - 1.0) / (1.0 + k / q + k * k), (1.0 - k / q + k * k) / (1.0 + k / q + k * k)];
+
+        // Both stages combined into one fourth order filter
+        KWeighting {
+            b: convolve(shelf_b, pass_b),
+            a: convolve(shelf_a, pass_a),
+            x: [0.0; 5],
+            y: [0.0; 5],
+        }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        self.x.rotate_right(1);
+        self.y.rotate_right(1);
+        self.x[0] = x;
+        let mut y = 0.0;
+        for i in 0..5 {
+            y += self.b[i] * self.x[i];
+        }
+        for i in 1..5 {
+            y -= self.a[i] * self.y[i];
+        }
+        self.y[0] = y;
+        y
+    }
+}
+
+fn convolve(p: [f64; 3], q: [f64; 3]) -> [f64; 5] {
+    let mut r = [0.0; 5];
+    for i in 0..3 {
+        for j in 0..3 {
+            r[i + j] += p[i] * q[j];
+        }
+    }
+    r
+}
+
+// Finds the peak of the signal between samples as well as at them. Rates of
+// 96kHz and up are already oversampled enough.
+struct TruePeak {
+    phases: Vec<Vec<f64>>,
+    history: Vec<f64>,
+    peak: f64,
+}
+
+impl TruePeak {
+    fn new(sample_rate: u32) -> TruePeak {
+        let factor = if sample_rate < 96000 { OVERSAMPLING } else { 1 };
+        let taps = factor * TAPS_PER_PHASE;
+        let center = (taps - 1) as f64 / 2.0;
+        let filter: Vec<f64> = (0..taps)
+            .map(|i| {
+                let t = (i as f64 - center) / factor as f64;
+                let sinc = if t == 0.0 { 1.0 } else { (PI * t).sin() / (PI * t) };
+                let window = 0.5 - 0.5 * (2.0 * PI * (i as f64 + 0.5) / taps as f64).cos();
+                sinc * window
+            })
+            .collect();
+        let phases = (0..factor)
+            .map(|p| (0..TAPS_PER_PHASE).map(|k| filter[k * factor + p]).collect())
+            .collect();
+
+        TruePeak {
+            phases,
+            history: vec![0.0; TAPS_PER_PHASE],
+            peak: 0.0,
+        }
+    }
+
+    fn add(&mut self, x: f64) {
+        self.peak = self.peak.max(x.abs());
+        if self.phases.len() == 1 {
+            return;
+        }
+
+        self.history.rotate_right(1);
+        self.history[0] = x;
+        for phase in &self.phases {
+            let y: f64 = phase.iter().zip(&self.history).map(|(h, x)| h * x).sum();
+            self.peak = self.peak.max(y.abs());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(meter: &mut Meter, channels: usize, sample_rate: u32, freq: f64, amplitude: f64, secs: usize) {
+        let samples: Vec<f64> = (0..sample_rate as usize * secs)
+            .flat_map(|i| {
+                let x = amplitude * (2.0 * PI * freq * i as f64 / sample_rate as f64).sin();
+                std::iter::repeat(x).take(channels)
+            })
+            .collect();
+        meter.add(&samples);
+    }
+
+    #[test]
+    fn stereo_sine_at_minus_23() {
+        // EBU Tech 3341, case 1
+        for &rate in &[44100, 48000] {
+            let mut meter = Meter::new(2, rate);
+            sine(&mut meter, 2, rate, 1000.0, 10f64.powf(-23.0 / 20.0), 20);
+            let loudness = meter.loudness();
+            assert!((loudness.integrated + 23.0).abs() < 0.1, "{} at {}Hz", loudness.integrated, rate);
+        }
+    }
+
+    #[test]
+    fn quiet_passages_are_gated() {
+        let mut meter = Meter::new(2, 48000);
+        sine(&mut meter, 2, 48000, 1000.0, 10f64.powf(-23.0 / 20.0), 10);
+        sine(&mut meter, 2, 48000, 1000.0, 10f64.powf(-60.0 / 20.0), 10);
+        assert!((meter.loudness().integrated + 23.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn silence() {
+        let mut meter = Meter::new(2, 44100);
+        meter.add(&vec![0.0; 44100 * 2 * 5]);
+        let loudness = meter.loudness();
+        assert_eq!(loudness.integrated, ABSOLUTE_GATE);
+        assert_eq!(loudness.true_peak, 0.0);
+    }
+
+    #[test]
+    fn true_peak_of_sine() {
+        let mut meter = Meter::new(1, 48000);
+        sine(&mut meter, 1, 48000, 997.0, 0.5, 1);
+        assert!((meter.loudness().true_peak - 0.5).abs() < 0.01);
+    }
+}
@@ -0,0 +1,410 @@
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryInto;
+use std::path::Path;
+
+use regex::Regex;
+use walkdir::WalkDir;
+
+use super::format::{self, AVFormatContext, AudioProperties, Format, Picture};
+
+pub enum MetadataKey {
+    Artist,
+    Album,
+    Disc,
+    DiscCount,
+    TrackTitle,
+    TrackNumber,
+    TrackCount,
+    TrackLength,
+    TrackGain,
+    TrackPeak,
+    AlbumGain,
+    AlbumPeak,
+}
+
+#[derive(Debug)]
+pub enum MetadataValue<'a> {
+    Artist(&'a str),
+    Album(&'a str),
+    Disc(u8),
+    DiscCount(u8),
+    TrackTitle(&'a str),
+    TrackNumber(u16),
+    TrackCount(u16),
+    TrackLength(u32),
+    // ReplayGain, in dB
+    TrackGain(f32),
+    // ReplayGain peak, where 1.0 is full scale
+    TrackPeak(f32),
+    AlbumGain(f32),
+    AlbumPeak(f32),
+}
+
+pub struct FLAC;
+pub struct MP3;
+// Vorbis comments in an Ogg container, as Vorbis and Opus have
+pub struct Ogg;
+// iTunes-style atoms
+pub struct MP4;
+// WAV INFO and AIFF chunks, or an ID3 chunk in either
+pub struct RIFF;
+
+pub trait TrackFormat {
+    fn try_get_metadata<'a, 'b>(&self, md: &'a HashMap<&'b str, &'b str>, item: MetadataKey) -> Option<MetadataValue<'b>>;
+}
+
+impl TrackFormat for FLAC {
+    fn try_get_metadata<'a, 'b>(&self, md: &'a HashMap<&'b str, &'b str>, item: MetadataKey) -> Option<MetadataValue<'b>> {
+        use MetadataKey::*;
+        match item {
+            Album => md.iter().find(|&(k, _)| k.to_lowercase() == "album").map(|(_, a)| MetadataValue::Album(a)),
+            Artist => md.iter().find(|&(k, _)| k.to_lowercase() == "artist").map(|(_, a)| MetadataValue::Artist(a)),
+            Disc => md
+                .iter()
+                .find(|&(k, _)| k.to_lowercase() == "disc")
+                .and_then(|(_, d)| u8::from_str_radix(d, 10).ok())
+                .map(|d| MetadataValue::Disc(d)),
+            DiscCount => md
+                .iter()
+                .find(|&(k, _)| k.to_lowercase() == "disctotal" || k.to_lowercase() == "totaldiscs")
+                .and_then(|(_, d)| u8::from_str_radix(d, 10).ok())
+                .map(|d| MetadataValue::DiscCount(d)),
+            TrackTitle => md.iter().find(|&(k, _)| k.to_lowercase() == "title").map(|(_, t)| MetadataValue::TrackTitle(t)),
+            TrackNumber => md
+                .iter()
+                .find(|&(k, _)| k.to_lowercase() == "track" || k.to_lowercase() == "tracknumber")
+                .and_then(|(_, t)| u16::from_str_radix(t, 10).ok())
+                .map(|t| MetadataValue::TrackNumber(t)),
+            TrackCount => md
+                .iter()
+                .find(|&(k, _)| k.to_lowercase() == "tracktotal" || k.to_lowercase() == "totaltracks")
+                .and_then(|(_, t)| u16::from_str_radix(t, 10).ok())
+                .map(|t| MetadataValue::TrackCount(t)),
+            TrackGain => find_replaygain(md, "replaygain_track_gain").map(MetadataValue::TrackGain),
+            TrackPeak => find_replaygain(md, "replaygain_track_peak").map(MetadataValue::TrackPeak),
+            AlbumGain => find_replaygain(md, "replaygain_album_gain").map(MetadataValue::AlbumGain),
+            AlbumPeak => find_replaygain(md, "replaygain_album_peak").map(MetadataValue::AlbumPeak),
+            _ => None
+        }
+    }
+}
+
+impl TrackFormat for MP3 {
+    fn try_get_metadata<'a, 'b>(&self, md: &'a HashMap<&'b str, &'b str>, item: MetadataKey) -> Option<MetadataValue<'b>> {
+        use MetadataKey::*;
+        match item {
+            Album => md.iter().find(|&(k, _)| k == &"album" || k == &"ALBUM").map(|(_, a)| MetadataValue::Album(a)),
+            Artist => md.iter().find(|&(k, _)| k == &"artist" || k == &"ARTIST").map(|(_, a)| MetadataValue::Artist(a)),
+            Disc => md
+                .iter()
+                .find(|&(k, _)| k == &"DISCNUMBER")
+                .and_then(|(_, d)| u8::from_str_radix(d, 10).ok())
+                .map(|d| MetadataValue::Disc(d)),
+            DiscCount => md
+                .iter()
+                .find(|&(k, _)| k == &"DISCTOTAL")
+                .and_then(|(_, d)| u8::from_str_radix(d, 10).ok())
+                .map(|d| MetadataValue::DiscCount(d)),
+            TrackTitle => md.iter().find(|&(k, _)| k == &"title" || k == &"TITLE").map(|(_, t)| MetadataValue::TrackTitle(t)),
+            TrackNumber => md
+                .iter()
+                .find(|&(k, _)| k == &"TRACK")
+                .and_then(|(_, t)| u16::from_str_radix(t, 10).ok())
+                .map(|t| MetadataValue::TrackNumber(t)),
+            TrackCount => md
+                .iter()
+                .find(|&(k, _)| k == &"TRACKTOTAL" || k == &"TOTALTRACKS")
+                .and_then(|(_, t)| u16::from_str_radix(t, 10).ok())
+                .map(|t| MetadataValue::TrackNumber(t)),
+            TrackGain => find_replaygain(md, "replaygain_track_gain").map(MetadataValue::TrackGain),
+            TrackPeak => find_replaygain(md, "replaygain_track_peak").map(MetadataValue::TrackPeak),
+            AlbumGain => find_replaygain(md, "replaygain_album_gain").map(MetadataValue::AlbumGain),
+            AlbumPeak => find_replaygain(md, "replaygain_album_peak").map(MetadataValue::AlbumPeak),
+            _ => None
+        }
+    }
+}
+
+impl TrackFormat for Ogg {
+    fn try_get_metadata<'a, 'b>(&self, md: &'a HashMap<&'b str, &'b str>, item: MetadataKey) -> Option<MetadataValue<'b>> {
+        use MetadataKey::*;
+        match item {
+            TrackGain => find_replaygain(md, "replaygain_track_gain")
+                .or_else(|| find_r128_gain(md, "r128_track_gain"))
+                .map(MetadataValue::TrackGain),
+            AlbumGain => find_replaygain(md, "replaygain_album_gain")
+                .or_else(|| find_r128_gain(md, "r128_album_gain"))
+                .map(MetadataValue::AlbumGain),
+            // The rest are named as in FLAC
+            _ => FLAC.try_get_metadata(md, item),
+        }
+    }
+}
+
+impl TrackFormat for MP4 {
+    fn try_get_metadata<'a, 'b>(&self, md: &'a HashMap<&'b str, &'b str>, item: MetadataKey) -> Option<MetadataValue<'b>> {
+        numbered_metadata(md, item, &["artist"])
+    }
+}
+
+impl TrackFormat for RIFF {
+    fn try_get_metadata<'a, 'b>(&self, md: &'a HashMap<&'b str, &'b str>, item: MetadataKey) -> Option<MetadataValue<'b>> {
+        // AIFF only has an author chunk
+        numbered_metadata(md, item, &["artist", "author"])
+    }
+}
+
+// FFmpeg gives MP4 atoms and RIFF chunks its generic names, with track and
+// disc numbers written as "n/total"
+fn numbered_metadata<'b>(md: &HashMap<&'b str, &'b str>, item: MetadataKey, artist_keys: &[&str]) -> Option<MetadataValue<'b>> {
+    use MetadataKey::*;
+    match item {
+        Album => find_tag(md, &["album"]).map(MetadataValue::Album),
+        Artist => find_tag(md, artist_keys).map(MetadataValue::Artist),
+        Disc => split_number(find_tag(md, &["disc"])?).0.and_then(|d| d.parse().ok()).map(MetadataValue::Disc),
+        DiscCount => split_number(find_tag(md, &["disc"])?).1.and_then(|d| d.parse().ok()).map(MetadataValue::DiscCount),
+        TrackTitle => find_tag(md, &["title"]).map(MetadataValue::TrackTitle),
+        TrackNumber => split_number(find_tag(md, &["track"])?).0.and_then(|t| t.parse().ok()).map(MetadataValue::TrackNumber),
+        TrackCount => split_number(find_tag(md, &["track"])?).1.and_then(|t| t.parse().ok()).map(MetadataValue::TrackCount),
+        TrackGain => find_replaygain(md, "replaygain_track_gain").map(MetadataValue::TrackGain),
+        TrackPeak => find_replaygain(md, "replaygain_track_peak").map(MetadataValue::TrackPeak),
+        AlbumGain => find_replaygain(md, "replaygain_album_gain").map(MetadataValue::AlbumGain),
+        AlbumPeak => find_replaygain(md, "replaygain_album_peak").map(MetadataValue::AlbumPeak),
+        _ => None
+    }
+}
+
+// The value of the first of `keys` that's present, in any case
+fn find_tag<'b>(md: &HashMap<&'b str, &'b str>, keys: &[&str]) -> Option<&'b str> {
+    keys.iter().find_map(|key| md.iter().find(|&(k, _)| k.eq_ignore_ascii_case(key)).map(|(_, v)| *v))
+}
+
+// Splits "3/12" into its number and total, either of which may be missing
+fn split_number(value: &str) -> (Option<&str>, Option<&str>) {
+    let mut parts = value.splitn(2, '/').map(str::trim).map(|p| Some(p).filter(|p| !p.is_empty()));
+    (parts.next().flatten(), parts.next().flatten())
+}
+
+// Opus gains are in 1/256 dB relative to -23 LUFS, 5 dB below ReplayGain's
+// reference
+fn find_r128_gain(md: &HashMap<&str, &str>, key: &str) -> Option<f32> {
+    let gain: i16 = find_tag(md, &[key])?.trim().parse().ok()?;
+    Some(gain as f32 / 256.0 + 5.0)
+}
+
+// ReplayGain tags are written in either case, in Vorbis comments and ID3
+// TXXX frames alike, e.g. "-6.48 dB" for gains and "0.988" for peaks
+fn find_replaygain(md: &HashMap<&str, &str>, key: &str) -> Option<f32> {
+    let value = md.iter().find(|&(k, _)| k.eq_ignore_ascii_case(key))?.1.trim();
+    let value = if value.to_ascii_lowercase().ends_with("db") {
+        &value[..value.len() - 2]
+    } else {
+        value
+    };
+    value.trim().parse().ok()
+}
+
+#[derive(Debug)]
+pub enum MediaFormat {
+    CD,
+    Digital,
+}
+
+pub struct Track<'a> {
+    ctx: AVFormatContext,
+    metadata: TrackMetadata<'a>,
+    format: Box<dyn TrackFormat>,
+}
+
+impl<'a> Track<'a> {
+    pub fn from_ctx(ctx: AVFormatContext) -> super::Result<Track<'a>> {
+        let format: Box<dyn TrackFormat> = match ctx.determine_format()? {
+            Format::FLAC => Box::new(FLAC),
+            Format::MP3 => Box::new(MP3),
+            Format::Vorbis | Format::Opus => Box::new(Ogg),
+            Format::AAC | Format::ALAC => Box::new(MP4),
+            Format::WAV | Format::AIFF => Box::new(RIFF),
+        };
+        let raw_metadata = ctx.metadata()?;
+        let metadata = TrackMetadata::from_raw_metadata(&raw_metadata, format.as_ref());
+
+        let mut track = Track {
+            ctx,
+            metadata,
+            format,
+        };
+
+        if track.metadata.track_count.is_none() {
+            track.metadata.track_count = track.guess_track_count().map(|c| MetadataValue::TrackCount(c));
+        }
+
+        Ok(track)
+    }
+
+    pub fn new<P: AsRef<Path>>(p: P) -> super::Result<Track<'a>> {
+        let ctx = AVFormatContext::open(p)?;
+        Self::from_ctx(ctx)
+    }
+
+    pub fn metadata(&self) -> &TrackMetadata<'a> {
+        &self.metadata
+    }
+
+    // Every tag in the container, as stored
+    pub fn raw_tags(&self) -> super::Result<BTreeMap<String, String>> {
+        Ok(self.ctx.metadata()?
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect())
+    }
+
+    pub fn guess_is_cd(&self) -> bool {
+        let md = self.metadata();
+        let has_disc_metadata = md.disc.is_some() || md.disc_count.is_some();
+        has_disc_metadata
+    }
+
+    pub fn guess_media_format(&self) -> Option<MediaFormat> {
+        let fmt = self.ctx.path().parent().and_then(|p| p.to_str()).and_then(|path| {
+            let reg = Regex::new(r"^.+?(?:\[(CD|WEB)(?:[\s0-9A-Z- ]*?)\]|\((CD|WEB)(?:[\s0-9A-Z- ]*?)\)).*$").unwrap();
+            match reg.captures(path) {
+                Some(caps) => {
+                    let idx = if caps.get(1).is_some() { 1 } else { 2 };
+                    match caps.get(idx).unwrap().as_str() {
+                        "CD" => Some(MediaFormat::CD),
+                        "WEB" => Some(MediaFormat::Digital),
+                        _ => None,
+                    }
+                }
+                _ => None,
+            }
+        });
+
+        if fmt.is_some() {
+            fmt
+        } else if self.guess_is_cd() {
+            Some(MediaFormat::CD)
+        } else {
+            None
+        }
+    }
+
+    pub fn bit_rate(&self) -> i64 {
+        self.ctx.bit_rate()
+    }
+
+    pub fn duration(&self) -> i64 {
+        self.ctx.duration()
+    }
+
+    pub fn audio_properties(&self) -> super::Result<AudioProperties> {
+        self.ctx.audio_properties()
+    }
+
+    pub fn path_str(&self) -> Option<&str> {
+        self.ctx.path().to_str()
+    }
+
+    pub fn path(&self) -> &Path {
+        self.ctx.path()
+    }
+
+    pub fn attached_picture(&self) -> super::Result<Option<Picture>> {
+        self.ctx.attached_picture()
+    }
+
+    pub fn guess_track_count(&self) -> Option<u16> {
+        match self.ctx.path().parent() {
+            Some(path) => {
+                WalkDir::new(path)
+                    .into_iter()
+                    .filter_map(Result::ok)
+                    .filter(|e| !e.file_type().is_dir())
+                    .filter(|e| format::is_audio_file(e.path()))
+                    .count()
+                    .try_into()
+                    .ok()
+            }
+            None => None
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct TrackMetadata<'a> {
+    pub album: Option<MetadataValue<'a>>,
+    pub artist: Option<MetadataValue<'a>>,
+    pub disc: Option<MetadataValue<'a>>,
+    pub disc_count: Option<MetadataValue<'a>>,
+    pub track_title: Option<MetadataValue<'a>>,
+    pub track_number: Option<MetadataValue<'a>>,
+    pub track_count: Option<MetadataValue<'a>>,
+    pub track_length: Option<MetadataValue<'a>>,
+    pub track_gain: Option<MetadataValue<'a>>,
+    pub track_peak: Option<MetadataValue<'a>>,
+    pub album_gain: Option<MetadataValue<'a>>,
+    pub album_peak: Option<MetadataValue<'a>>,
+}
+
+impl<'a> TrackMetadata<'a> {
+    fn from_raw_metadata(md: &HashMap<&'a str, &'a str>, f: &dyn TrackFormat) -> TrackMetadata<'a> {
+        TrackMetadata {
+            album: f.try_get_metadata(md, MetadataKey::Album),
+            artist: f.try_get_metadata(md, MetadataKey::Artist),
+            disc: f.try_get_metadata(md, MetadataKey::Disc),
+            disc_count: f.try_get_metadata(md, MetadataKey::DiscCount),
+            track_title: f.try_get_metadata(md, MetadataKey::TrackTitle),
+            track_number: f.try_get_metadata(md, MetadataKey::TrackNumber),
+            track_count: f.try_get_metadata(md, MetadataKey::TrackCount),
+            track_length: f.try_get_metadata(md, MetadataKey::TrackLength),
+            track_gain: f.try_get_metadata(md, MetadataKey::TrackGain),
+            track_peak: f.try_get_metadata(md, MetadataKey::TrackPeak),
+            album_gain: f.try_get_metadata(md, MetadataKey::AlbumGain),
+            album_peak: f.try_get_metadata(md, MetadataKey::AlbumPeak),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_numbers() {
+        assert_eq!(split_number("3/12"), (Some("3"), Some("12")));
+        assert_eq!(split_number(" 3 / 12 "), (Some("3"), Some("12")));
+        assert_eq!(split_number("3"), (Some("3"), None));
+        assert_eq!(split_number("3/"), (Some("3"), None));
+        assert_eq!(split_number("/12"), (None, Some("12")));
+        assert_eq!(split_number(""), (None, None));
+    }
+
+    #[test]
+    fn numbered_tags() {
+        let md: HashMap<&str, &str> = vec![("TRACK", "3/12"), ("disc", "/2"), ("Author", "Radiohead")].into_iter().collect();
+        assert!(matches!(numbered_metadata(&md, MetadataKey::TrackNumber, &["artist"]), Some(MetadataValue::TrackNumber(3))));
+        assert!(matches!(numbered_metadata(&md, MetadataKey::TrackCount, &["artist"]), Some(MetadataValue::TrackCount(12))));
+        assert!(numbered_metadata(&md, MetadataKey::Disc, &["artist"]).is_none());
+        assert!(matches!(numbered_metadata(&md, MetadataKey::DiscCount, &["artist"]), Some(MetadataValue::DiscCount(2))));
+        assert!(numbered_metadata(&md, MetadataKey::Artist, &["artist"]).is_none());
+        assert!(matches!(numbered_metadata(&md, MetadataKey::Artist, &["artist", "author"]), Some(MetadataValue::Artist("Radiohead"))));
+    }
+
+    #[test]
+    fn r128_gain_offset() {
+        let md: HashMap<&str, &str> = vec![("R128_TRACK_GAIN", "-1280"), ("r128_album_gain", "0"), ("r128_bad", "x")].into_iter().collect();
+        // -5 dB from -23 LUFS is 0 dB from ReplayGain's -18
+        assert_eq!(find_r128_gain(&md, "r128_track_gain"), Some(0.0));
+        assert_eq!(find_r128_gain(&md, "r128_album_gain"), Some(5.0));
+        assert_eq!(find_r128_gain(&md, "r128_bad"), None);
+        assert_eq!(find_r128_gain(&md, "r128_missing"), None);
+    }
+
+    #[test]
+    fn ogg_prefers_replaygain_tags() {
+        let md: HashMap<&str, &str> = vec![("REPLAYGAIN_TRACK_GAIN", "-6.5 dB"), ("R128_TRACK_GAIN", "256")].into_iter().collect();
+        assert!(matches!(Ogg.try_get_metadata(&md, MetadataKey::TrackGain), Some(MetadataValue::TrackGain(g)) if g == -6.5));
+        let md: HashMap<&str, &str> = vec![("R128_TRACK_GAIN", "256")].into_iter().collect();
+        assert!(matches!(Ogg.try_get_metadata(&md, MetadataKey::TrackGain), Some(MetadataValue::TrackGain(g)) if g == 6.0));
+    }
+}
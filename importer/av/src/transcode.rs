@@ -0,0 +1,524 @@
+use std::ffi::CString;
+use std::io::Write;
+use std::os::raw;
+use std::path::Path;
+use std::ptr;
+use std::str::FromStr;
+
+use super::error::{self, AVError};
+use super::format::AVFormatContext;
+
+const AVIO_BUFFER_SIZE: usize = 32 * 1024;
+// Used when the encoder accepts frames of any size
+const DEFAULT_FRAME_SIZE: i32 = 1024;
+
+// These are built from macros that bindgen can't expand
+pub(super) const AVERROR_EOF: i32 = -(0x45 | (0x4f << 8) | (0x46 << 16) | (0x20 << 24));
+pub(super) const AVERROR_EAGAIN: i32 = -(ffmpeg_sys::EAGAIN as i32);
+const AVERROR_EPIPE: i32 = -(ffmpeg_sys::EPIPE as i32);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Opus,
+    MP3,
+}
+
+impl OutputFormat {
+    fn muxer_name(&self) -> &'static str {
+        match self {
+            OutputFormat::Opus => "ogg",
+            OutputFormat::MP3 => "mp3",
+        }
+    }
+
+    fn encoder_name(&self) -> &'static str {
+        match self {
+            OutputFormat::Opus => "libopus",
+            OutputFormat::MP3 => "libmp3lame",
+        }
+    }
+
+    fn sample_rate(&self, input_rate: i32) -> i32 {
+        match self {
+            // libopus only accepts 48kHz input
+            OutputFormat::Opus => 48000,
+            OutputFormat::MP3 => match input_rate {
+                32000 | 44100 | 48000 => input_rate,
+                _ => 44100,
+            },
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Opus => "opus",
+            OutputFormat::MP3 => "mp3",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            OutputFormat::Opus => "audio/ogg",
+            OutputFormat::MP3 => "audio/mpeg",
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = AVError;
+
+    fn from_str(s: &str) -> super::Result<OutputFormat> {
+        match s.to_lowercase().as_str() {
+            "opus" => Ok(OutputFormat::Opus),
+            "mp3" => Ok(OutputFormat::MP3),
+            _ => Err(AVError::UnknownFormat),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct TranscodeOptions {
+    pub format: OutputFormat,
+    // In kbit/s
+    pub bit_rate: i64,
+}
+
+// Decodes the first audio stream of `input` and re-encodes it according to
+// `opts`, writing the muxed output to `output` as it is produced. Output is
+// written strictly sequentially, so `output` can be a pipe or socket.
+pub fn transcode<P: AsRef<Path>, W: Write>(input: P, opts: &TranscodeOptions, output: W) -> super::Result<()> {
+    let input = AVFormatContext::open(input)?;
+    let mut sink = Box::new(Sink {
+        writer: output,
+        error: None,
+    });
+
+    let res = unsafe { Pipeline::new(&input, opts, &mut sink).and_then(|mut p| p.run(input.as_ptr())) };
+
+    // A failed write surfaces from ffmpeg as a generic error; report the
+    // original one instead.
+    match sink.error.take() {
+        Some(e) => Err(AVError::IOError(e)),
+        None => res,
+    }
+}
+
+struct Sink<W: Write> {
+    writer: W,
+    error: Option<std::io::Error>,
+}
+
+unsafe extern "C" fn write_packet<W: Write>(opaque: *mut raw::c_void, buf: *mut u8, buf_size: raw::c_int) -> raw::c_int {
+    let sink = &mut *(opaque as *mut Sink<W>);
+    let data = std::slice::from_raw_parts(buf, buf_size as usize);
+    match sink.writer.write_all(data) {
+        Ok(()) => buf_size,
+        Err(e) => {
+            sink.error = Some(e);
+            AVERROR_EPIPE
+        }
+    }
+}
+
+struct Pipeline {
+    stream_index: i32,
+    decoder: CodecContext,
+    encoder: CodecContext,
+    output: OutputContext,
+    resampler: Resampler,
+    fifo: AudioFifo,
+    decoded: Frame,
+    resampled: Frame,
+    packet: Packet,
+    next_pts: i64,
+}
+
+impl Pipeline {
+    unsafe fn new<W: Write>(input: &AVFormatContext, opts: &TranscodeOptions, sink: &mut Sink<W>) -> super::Result<Pipeline> {
+        input.find_stream_info()?;
+        let ictx = input.as_ptr();
+
+        let mut dec: *mut ffmpeg_sys::AVCodec = ptr::null_mut();
+        let stream_index = check(ffmpeg_sys::av_find_best_stream(
+            ictx,
+            ffmpeg_sys::AVMediaType_AVMEDIA_TYPE_AUDIO,
+            -1,
+            -1,
+            &mut dec,
+            0,
+        ))?;
+        if dec.is_null() {
+            return Err(AVError::UnknownFormat);
+        }
+
+        let decoder = CodecContext::alloc(dec)?;
+        let d = decoder.0;
+        let in_stream = *(*ictx).streams.offset(stream_index as isize);
+        check(ffmpeg_sys::avcodec_parameters_to_context(d, (*in_stream).codecpar))?;
+        if (*d).channel_layout == 0 {
+            (*d).channel_layout = ffmpeg_sys::av_get_default_channel_layout((*d).channels) as u64;
+        }
+        check(ffmpeg_sys::avcodec_open2(d, dec, ptr::null_mut()))?;
+
+        let output = OutputContext::alloc(opts.format.muxer_name())?;
+        let octx = output.0;
+
+        let encoder_name = CString::new(opts.format.encoder_name())?;
+        let enc = ffmpeg_sys::avcodec_find_encoder_by_name(encoder_name.as_ptr());
+        if enc.is_null() {
+            return Err(AVError::AVLibraryError(format!("{} encoder not available", opts.format.encoder_name())));
+        }
+        let encoder = CodecContext::alloc(enc)?;
+        let e = encoder.0;
+        let channels = (*d).channels.min(2).max(1);
+        let sample_rate = opts.format.sample_rate((*d).sample_rate);
+        (*e).channels = channels;
+        (*e).channel_layout = ffmpeg_sys::av_get_default_channel_layout(channels) as u64;
+        (*e).sample_rate = sample_rate;
+        (*e).sample_fmt = *(*enc).sample_fmts;
+        (*e).bit_rate = opts.bit_rate * 1000;
+        (*e).time_base = ffmpeg_sys::AVRational { num: 1, den: sample_rate };
+        if (*(*octx).oformat).flags & ffmpeg_sys::AVFMT_GLOBALHEADER as i32 != 0 {
+            (*e).flags |= ffmpeg_sys::AV_CODEC_FLAG_GLOBAL_HEADER as i32;
+        }
+        check(ffmpeg_sys::avcodec_open2(e, enc, ptr::null_mut()))?;
+
+        let out_stream = ffmpeg_sys::avformat_new_stream(octx, ptr::null());
+        if out_stream.is_null() {
+            return Err(AVError::NullPointer("AVStream".to_string()));
+        }
+        check(ffmpeg_sys::avcodec_parameters_from_context((*out_stream).codecpar, e))?;
+        (*out_stream).time_base = (*e).time_base;
+
+        output.set_sink(sink)?;
+        check(ffmpeg_sys::avformat_write_header(octx, ptr::null_mut()))?;
+
+        let resampler = Resampler::new(d, e)?;
+        let fifo = AudioFifo::alloc((*e).sample_fmt, (*e).channels)?;
+
+        Ok(Pipeline {
+            stream_index,
+            decoder,
+            encoder,
+            output,
+            resampler,
+            fifo,
+            decoded: Frame::alloc()?,
+            resampled: Frame::alloc()?,
+            packet: Packet::alloc()?,
+            next_pts: 0,
+        })
+    }
+
+    unsafe fn run(&mut self, ictx: *mut ffmpeg_sys::AVFormatContext) -> super::Result<()> {
+        let input_packet = Packet::alloc()?;
+
+        loop {
+            let ret = ffmpeg_sys::av_read_frame(ictx, input_packet.0);
+            if ret == AVERROR_EOF {
+                break;
+            }
+            check(ret)?;
+
+            if (*input_packet.0).stream_index == self.stream_index {
+                let ret = check(ffmpeg_sys::avcodec_send_packet(self.decoder.0, input_packet.0));
+                ffmpeg_sys::av_packet_unref(input_packet.0);
+                ret?;
+                self.receive_decoded()?;
+            } else {
+                ffmpeg_sys::av_packet_unref(input_packet.0);
+            }
+        }
+
+        // Drain the decoder, then anything the resampler is still holding,
+        // then the fifo, then the encoder.
+        check(ffmpeg_sys::avcodec_send_packet(self.decoder.0, ptr::null()))?;
+        self.receive_decoded()?;
+        while self.resampler.delay((*self.encoder.0).sample_rate) > 0 {
+            if self.resample(ptr::null())? == 0 {
+                break;
+            }
+        }
+        self.encode_from_fifo(true)?;
+        self.encode(ptr::null())?;
+
+        check(ffmpeg_sys::av_write_trailer(self.output.0))?;
+        Ok(())
+    }
+
+    unsafe fn receive_decoded(&mut self) -> super::Result<()> {
+        loop {
+            let ret = ffmpeg_sys::avcodec_receive_frame(self.decoder.0, self.decoded.0);
+            if ret == AVERROR_EAGAIN || ret == AVERROR_EOF {
+                return Ok(());
+            }
+            check(ret)?;
+
+            // Some decoders leave the layout unset, which the resampler
+            // treats as a configuration change.
+            if (*self.decoded.0).channel_layout == 0 {
+                (*self.decoded.0).channel_layout = (*self.decoder.0).channel_layout;
+            }
+            let res = self.resample(self.decoded.0);
+            ffmpeg_sys::av_frame_unref(self.decoded.0);
+            res?;
+            self.encode_from_fifo(false)?;
+        }
+    }
+
+    // Converts `input` to the encoder's sample format, rate and layout and
+    // queues the result. A null `input` flushes the resampler. Returns the
+    // number of samples queued.
+    unsafe fn resample(&mut self, input: *const ffmpeg_sys::AVFrame) -> super::Result<i32> {
+        let out = self.resampled.0;
+        (*out).channel_layout = (*self.encoder.0).channel_layout;
+        (*out).sample_rate = (*self.encoder.0).sample_rate;
+        (*out).format = (*self.encoder.0).sample_fmt;
+
+        let res = check(ffmpeg_sys::swr_convert_frame(self.resampler.0, out, input));
+        let nb_samples = (*out).nb_samples;
+        let written = match res {
+            Ok(_) if nb_samples > 0 => {
+                check(ffmpeg_sys::av_audio_fifo_write(self.fifo.0, (*out).extended_data as *mut *mut raw::c_void, nb_samples))
+            }
+            Ok(_) => Ok(0),
+            Err(e) => Err(e),
+        };
+        ffmpeg_sys::av_frame_unref(out);
+        written
+    }
+
+    unsafe fn encode_from_fifo(&mut self, flush: bool) -> super::Result<()> {
+        let e = self.encoder.0;
+        let frame_size = if (*e).frame_size > 0 { (*e).frame_size } else { DEFAULT_FRAME_SIZE };
+
+        loop {
+            let available = ffmpeg_sys::av_audio_fifo_size(self.fifo.0);
+            if available == 0 || (available < frame_size && !flush) {
+                return Ok(());
+            }
+
+            let nb_samples = available.min(frame_size);
+            let frame = Frame::alloc()?;
+            let f = frame.0;
+            (*f).nb_samples = nb_samples;
+            (*f).channel_layout = (*e).channel_layout;
+            (*f).format = (*e).sample_fmt;
+            (*f).sample_rate = (*e).sample_rate;
+            check(ffmpeg_sys::av_frame_get_buffer(f, 0))?;
+
+            let read = ffmpeg_sys::av_audio_fifo_read(self.fifo.0, (*f).extended_data as *mut *mut raw::c_void, nb_samples);
+            if read < nb_samples {
+                return Err(AVError::AVLibraryError("short read from audio fifo".to_string()));
+            }
+
+            (*f).pts = self.next_pts;
+            self.next_pts += nb_samples as i64;
+            self.encode(f)?;
+        }
+    }
+
+    // Sends `frame` to the encoder and muxes every packet it produces. A null
+    // `frame` flushes the encoder.
+    unsafe fn encode(&mut self, frame: *const ffmpeg_sys::AVFrame) -> super::Result<()> {
+        check(ffmpeg_sys::avcodec_send_frame(self.encoder.0, frame))?;
+
+        loop {
+            let ret = ffmpeg_sys::avcodec_receive_packet(self.encoder.0, self.packet.0);
+            if ret == AVERROR_EAGAIN || ret == AVERROR_EOF {
+                return Ok(());
+            }
+            check(ret)?;
+
+            // The muxer may have picked its own time base when the header
+            // was written.
+            let out_stream = *(*self.output.0).streams;
+            (*self.packet.0).stream_index = 0;
+            ffmpeg_sys::av_packet_rescale_ts(self.packet.0, (*self.encoder.0).time_base, (*out_stream).time_base);
+            check(ffmpeg_sys::av_interleaved_write_frame(self.output.0, self.packet.0))?;
+        }
+    }
+}
+
+pub(super) fn check(ret: i32) -> super::Result<i32> {
+    if ret < 0 {
+        Err(error::av_library_error(ret))
+    } else {
+        Ok(ret)
+    }
+}
+
+pub(super) struct CodecContext(pub(super) *mut ffmpeg_sys::AVCodecContext);
+
+impl CodecContext {
+    pub(super) unsafe fn alloc(codec: *const ffmpeg_sys::AVCodec) -> super::Result<CodecContext> {
+        let ctx = ffmpeg_sys::avcodec_alloc_context3(codec);
+        if ctx.is_null() {
+            Err(AVError::NullPointer("AVCodecContext".to_string()))
+        } else {
+            Ok(CodecContext(ctx))
+        }
+    }
+}
+
+impl Drop for CodecContext {
+    fn drop(&mut self) {
+        unsafe { ffmpeg_sys::avcodec_free_context(&mut self.0); }
+    }
+}
+
+struct OutputContext(*mut ffmpeg_sys::AVFormatContext);
+
+impl OutputContext {
+    unsafe fn alloc(muxer: &str) -> super::Result<OutputContext> {
+        let muxer = CString::new(muxer)?;
+        let mut ctx = ptr::null_mut();
+        check(ffmpeg_sys::avformat_alloc_output_context2(&mut ctx, ptr::null_mut(), muxer.as_ptr(), ptr::null()))?;
+        if ctx.is_null() {
+            Err(AVError::NullPointer("AVFormatContext".to_string()))
+        } else {
+            Ok(OutputContext(ctx))
+        }
+    }
+
+    // Points the muxer at `sink` rather than a file. `sink` must outlive
+    // this context.
+    unsafe fn set_sink<W: Write>(&self, sink: &mut Sink<W>) -> super::Result<()> {
+        let buf = ffmpeg_sys::av_malloc(AVIO_BUFFER_SIZE as _) as *mut u8;
+        if buf.is_null() {
+            return Err(AVError::NullPointer("AVIOContext buffer".to_string()));
+        }
+        let pb = ffmpeg_sys::avio_alloc_context(
+            buf,
+            AVIO_BUFFER_SIZE as i32,
+            1,
+            sink as *mut Sink<W> as *mut raw::c_void,
+            None,
+            Some(write_packet::<W>),
+            None,
+        );
+        if pb.is_null() {
+            ffmpeg_sys::av_free(buf as *mut raw::c_void);
+            return Err(AVError::NullPointer("AVIOContext".to_string()));
+        }
+        (*self.0).pb = pb;
+        (*self.0).flags |= ffmpeg_sys::AVFMT_FLAG_CUSTOM_IO as i32;
+        Ok(())
+    }
+}
+
+impl Drop for OutputContext {
+    fn drop(&mut self) {
+        unsafe {
+            let pb = (*self.0).pb;
+            if !pb.is_null() {
+                // The buffer may have been reallocated by avio, so free
+                // whatever it currently points at.
+                ffmpeg_sys::av_freep(&mut (*pb).buffer as *mut *mut u8 as *mut raw::c_void);
+                ffmpeg_sys::avio_context_free(&mut (*self.0).pb);
+            }
+            ffmpeg_sys::avformat_free_context(self.0);
+        }
+    }
+}
+
+pub(super) struct Resampler(pub(super) *mut ffmpeg_sys::SwrContext);
+
+impl Resampler {
+    unsafe fn new(dec: *const ffmpeg_sys::AVCodecContext, enc: *const ffmpeg_sys::AVCodecContext) -> super::Result<Resampler> {
+        Self::to_format(dec, (*enc).channel_layout, (*enc).sample_fmt, (*enc).sample_rate)
+    }
+
+    // Converts the output of `dec` to the given layout, format and rate
+    pub(super) unsafe fn to_format(
+        dec: *const ffmpeg_sys::AVCodecContext,
+        channel_layout: u64,
+        sample_fmt: ffmpeg_sys::AVSampleFormat,
+        sample_rate: i32,
+    ) -> super::Result<Resampler> {
+        let swr = ffmpeg_sys::swr_alloc_set_opts(
+            ptr::null_mut(),
+            channel_layout as i64,
+            sample_fmt,
+            sample_rate,
+            (*dec).channel_layout as i64,
+            (*dec).sample_fmt,
+            (*dec).sample_rate,
+            0,
+            ptr::null_mut(),
+        );
+        if swr.is_null() {
+            return Err(AVError::NullPointer("SwrContext".to_string()));
+        }
+        let resampler = Resampler(swr);
+        check(ffmpeg_sys::swr_init(swr))?;
+        Ok(resampler)
+    }
+
+    unsafe fn delay(&self, sample_rate: i32) -> i64 {
+        ffmpeg_sys::swr_get_delay(self.0, sample_rate as i64)
+    }
+}
+
+impl Drop for Resampler {
+    fn drop(&mut self) {
+        unsafe { ffmpeg_sys::swr_free(&mut self.0); }
+    }
+}
+
+struct AudioFifo(*mut ffmpeg_sys::AVAudioFifo);
+
+impl AudioFifo {
+    unsafe fn alloc(sample_fmt: ffmpeg_sys::AVSampleFormat, channels: i32) -> super::Result<AudioFifo> {
+        let fifo = ffmpeg_sys::av_audio_fifo_alloc(sample_fmt, channels, 1);
+        if fifo.is_null() {
+            Err(AVError::NullPointer("AVAudioFifo".to_string()))
+        } else {
+            Ok(AudioFifo(fifo))
+        }
+    }
+}
+
+impl Drop for AudioFifo {
+    fn drop(&mut self) {
+        unsafe { ffmpeg_sys::av_audio_fifo_free(self.0); }
+    }
+}
+
+pub(super) struct Frame(pub(super) *mut ffmpeg_sys::AVFrame);
+
+impl Frame {
+    pub(super) fn alloc() -> super::Result<Frame> {
+        let frame = unsafe { ffmpeg_sys::av_frame_alloc() };
+        if frame.is_null() {
+            Err(AVError::NullPointer("AVFrame".to_string()))
+        } else {
+            Ok(Frame(frame))
+        }
+    }
+}
+
+impl Drop for Frame {
+    fn drop(&mut self) {
+        unsafe { ffmpeg_sys::av_frame_free(&mut self.0); }
+    }
+}
+
+pub(super) struct Packet(pub(super) *mut ffmpeg_sys::AVPacket);
+
+impl Packet {
+    pub(super) fn alloc() -> super::Result<Packet> {
+        let packet = unsafe { ffmpeg_sys::av_packet_alloc() };
+        if packet.is_null() {
+            Err(AVError::NullPointer("AVPacket".to_string()))
+        } else {
+            Ok(Packet(packet))
+        }
+    }
+}
+
+impl Drop for Packet {
+    fn drop(&mut self) {
+        unsafe { ffmpeg_sys::av_packet_free(&mut self.0); }
+    }
+}
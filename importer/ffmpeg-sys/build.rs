@@ -7,6 +7,7 @@ fn main() {
     println!("cargo:rustc-link-lib=avutil");
     println!("cargo:rustc-link-lib=avformat");
     println!("cargo:rustc-link-lib=avcodec");
+    println!("cargo:rustc-link-lib=swresample");
     println!("cargo:rerun-if-changed=includes.h");
 
     let out_path = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap())
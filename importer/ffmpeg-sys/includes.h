@@ -1 +1,3 @@
 #include "libavformat/avformat.h"
+#include "libavutil/audio_fifo.h"
+#include "libswresample/swresample.h"
@@ -0,0 +1,197 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+// Thumbnails the API makes from stored images. They're named after the image
+// they came from, so they're pruned along with it.
+const THUMBNAIL_DIR: &str = "thumbnails";
+
+// Anything bigger isn't cover art
+const MAX_IMAGE_BYTES: usize = 20 * 1024 * 1024;
+
+// Failed downloads are retried after an hour, doubling with each attempt up
+// to a week
+const RETRY_BACKOFF: &str = "LEAST(interval '1 hour' * power(2, image_attempts - 1), interval '7 days')";
+
+// Downloads the artwork providers link to, so it can be served without
+// reaching out to them. Images are stored under a hash of their contents,
+// which the album or artist's `image_file` refers to.
+pub struct ImageStore {
+    root: PathBuf,
+    http: reqwest::Client,
+}
+
+impl ImageStore {
+    pub fn new(root: PathBuf) -> ImageStore {
+        ImageStore {
+            root,
+            http: reqwest::Client::new(),
+        }
+    }
+
+    // Downloads the images of every album and artist that isn't stored yet.
+    // Images that can't be downloaded are retried with a backoff.
+    pub async fn store_pending(&self, client: &deadpool_postgres::Client) -> Result<()> {
+        // Artists and compilations share images, so each is fetched once
+        let mut fetched: HashMap<String, String> = HashMap::new();
+        for table in &["album", "artist"] {
+            let stmt = client.prepare(&format!("
+                SELECT id, image_url
+                FROM {}
+                WHERE image_url IS NOT NULL AND image_file IS NULL
+                  AND (image_failed_at IS NULL OR image_failed_at < now() - {})
+            ", table, RETRY_BACKOFF)).await?;
+            let rows = client.query(&stmt, &[]).await?;
+            let update_stmt = client.prepare(&format!("
+                UPDATE {}
+                SET image_file = $2, image_attempts = 0, image_failed_at = NULL
+                WHERE id = $1 AND image_url = $3
+            ", table)).await?;
+            let failed_stmt = client.prepare(&format!("
+                UPDATE {}
+                SET image_attempts = image_attempts + 1, image_failed_at = now()
+                WHERE id = $1 AND image_url = $2
+            ", table)).await?;
+
+            for row in rows {
+                let id: i32 = row.get(0);
+                let url: String = row.get(1);
+                let file = match fetched.get(&url) {
+                    Some(f) => f.clone(),
+                    None => match self.fetch(&url).await {
+                        Ok(f) => {
+                            fetched.insert(url.clone(), f.clone());
+                            f
+                        }
+                        Err(e) => {
+                            eprintln!("Failed to store image {} for {} {}: {}", url, table, id, e);
+                            client.execute(&failed_stmt, &[&id, &url]).await?;
+                            continue;
+                        }
+                    },
+                };
+                client.execute(&update_stmt, &[&id, &file, &url]).await?;
+            }
+        }
+
+        if !fetched.is_empty() {
+            println!("Stored {} image(s)", fetched.len());
+        }
+        Ok(())
+    }
+
+    // Deletes stored images, and their thumbnails, that nothing refers to
+    pub async fn prune(&self, client: &deadpool_postgres::Client) -> Result<()> {
+        let stmt = client.prepare("
+            SELECT image_file FROM album WHERE image_file IS NOT NULL
+            UNION
+            SELECT image_file FROM artist WHERE image_file IS NOT NULL
+        ").await?;
+        let referenced: HashSet<String> = client.query(&stmt, &[]).await?
+            .iter()
+            .map(|r| r.get(0))
+            .collect();
+        let stems: HashSet<&str> = referenced.iter().filter_map(|f| file_stem(f)).collect();
+
+        let mut n = 0;
+        n += remove_unreferenced(&self.root, |name| referenced.contains(name)).await?;
+        // Thumbnails are named <stem>-<size>.jpg
+        n += remove_unreferenced(&self.root.join(THUMBNAIL_DIR), |name| {
+            name.rsplitn(2, '-').nth(1).map(|stem| stems.contains(stem)).unwrap_or(false)
+        }).await?;
+        if n > 0 {
+            println!("Pruned {} stored image(s)", n);
+        }
+        Ok(())
+    }
+
+    // Downloads `url` into the store, returning the stored file's name
+    async fn fetch(&self, url: &str) -> Result<String> {
+        let res = self.http.get(url).send().await?.error_for_status()?;
+        let content_type = res.headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_owned();
+        let extension = extension_for(&content_type)
+            .ok_or_else(|| format!("unsupported content type `{}`", content_type))?;
+        if res.content_length().map(|l| l as usize > MAX_IMAGE_BYTES).unwrap_or(false) {
+            return Err("image is too large".into());
+        }
+        let bytes = res.bytes().await?;
+        if bytes.len() > MAX_IMAGE_BYTES {
+            return Err("image is too large".into());
+        }
+        self.store(&bytes, extension).await
+    }
+
+    // Adds an image to the store, returning its file name
+    pub async fn store(&self, data: &[u8], extension: &str) -> Result<String> {
+        let name = format!("{:x}.{}", Sha256::digest(data), extension);
+        let path = self.root.join(&name);
+        if tokio::fs::metadata(&path).await.is_err() {
+            tokio::fs::create_dir_all(&self.root).await?;
+            // Written under another name first, so the API never serves half
+            // an image
+            let partial = self.root.join(format!("{}.partial", name));
+            tokio::fs::write(&partial, data).await?;
+            tokio::fs::rename(&partial, &path).await?;
+        }
+        Ok(name)
+    }
+}
+
+pub fn extension_for(content_type: &str) -> Option<&'static str> {
+    match content_type.split(';').next().unwrap_or("").trim() {
+        "image/jpeg" | "image/jpg" => Some("jpg"),
+        "image/png" => Some("png"),
+        "image/gif" => Some("gif"),
+        "image/webp" => Some("webp"),
+        _ => None,
+    }
+}
+
+fn file_stem(name: &str) -> Option<&str> {
+    Path::new(name).file_stem().and_then(|s| s.to_str())
+}
+
+// Removes the files directly in `dir` that `keep` rejects, returning how many
+async fn remove_unreferenced<F: Fn(&str) -> bool>(dir: &Path, keep: F) -> Result<usize> {
+    let mut entries = match tokio::fs::read_dir(dir).await {
+        Ok(e) => e,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+    let mut n = 0;
+    while let Some(entry) = entries.next_entry().await? {
+        if !entry.file_type().await?.is_file() {
+            continue;
+        }
+        let name = entry.file_name();
+        let name = match name.to_str() {
+            Some(n) => n,
+            None => continue,
+        };
+        if !keep(name) {
+            tokio::fs::remove_file(entry.path()).await?;
+            n += 1;
+        }
+    }
+    Ok(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extensions() {
+        assert_eq!(extension_for("image/jpeg"), Some("jpg"));
+        assert_eq!(extension_for("image/png; charset=binary"), Some("png"));
+        assert_eq!(extension_for("text/html"), None);
+        assert_eq!(extension_for(""), None);
+    }
+}
@@ -0,0 +1,83 @@
+use crate::metadata::providers;
+
+#[derive(Debug)]
+pub enum ImportError {
+    NoRecordingMatch,
+    NoReleaseMatch,
+    InvalidMetadata(String),
+    InvalidPath,
+    AVError(av::AVError),
+    ProviderError(providers::ProviderError),
+    DBError(tokio_postgres::Error),
+    IOError(std::io::Error),
+}
+
+impl ImportError {
+    // Stable identifier stored in the `import_failure` table
+    pub fn kind(&self) -> &'static str {
+        use ImportError::*;
+        match self {
+            NoRecordingMatch => "no_recording_match",
+            NoReleaseMatch => "no_release_match",
+            InvalidMetadata(_) => "invalid_metadata",
+            InvalidPath => "invalid_path",
+            AVError(_) => "av_error",
+            ProviderError(_) => "provider_error",
+            DBError(_) => "database_error",
+            IOError(_) => "io_error",
+        }
+    }
+}
+
+impl std::fmt::Display for ImportError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        use ImportError::*;
+        match self {
+            NoRecordingMatch => write!(fmt, "no matching recording found"),
+            NoReleaseMatch => write!(fmt, "no matching release found"),
+            InvalidMetadata(m) => write!(fmt, "invalid metadata: {}", m),
+            InvalidPath => write!(fmt, "path is not valid UTF-8 or is outside the music root"),
+            AVError(e) => write!(fmt, "{}", e),
+            ProviderError(e) => write!(fmt, "{}", e),
+            DBError(e) => write!(fmt, "{}", e),
+            IOError(e) => write!(fmt, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ImportError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use ImportError::*;
+        match self {
+            AVError(e) => Some(e),
+            ProviderError(e) => Some(e),
+            DBError(e) => Some(e),
+            IOError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<av::AVError> for ImportError {
+    fn from(e: av::AVError) -> Self {
+        ImportError::AVError(e)
+    }
+}
+
+impl From<providers::ProviderError> for ImportError {
+    fn from(e: providers::ProviderError) -> Self {
+        ImportError::ProviderError(e)
+    }
+}
+
+impl From<tokio_postgres::Error> for ImportError {
+    fn from(e: tokio_postgres::Error) -> Self {
+        ImportError::DBError(e)
+    }
+}
+
+impl From<std::io::Error> for ImportError {
+    fn from(e: std::io::Error) -> Self {
+        ImportError::IOError(e)
+    }
+}
@@ -0,0 +1,522 @@
+use std::fs;
+
+use regex::Regex;
+use sha2::{Digest, Sha256};
+
+use av::metadata::{MetadataValue, Track as AVTrack, MediaFormat};
+use lev::damlev;
+use crate::images;
+use crate::metadata::providers::{ArtistDetails, ProviderChain};
+use crate::metadata::providers::musicbrainz::entities;
+
+use crate::models::*;
+
+mod error;
+#[cfg(test)]
+mod tests;
+
+pub use error::ImportError;
+
+type Result<T> = std::result::Result<T, ImportError>;
+
+// Prefix of the surrogate ids given to entities imported from tags alone,
+// in place of a MusicBrainz id.
+pub const LOCAL_ID_PREFIX: &str = "local:";
+
+// Image files taken to be an album's cover when they're next to its tracks,
+// by name without extension. Earlier names are preferred.
+const FOLDER_ARTWORK_NAMES: &[&str] = &["cover", "folder", "front", "album"];
+
+pub fn is_local_id(id: &str) -> bool {
+    id.starts_with(LOCAL_ID_PREFIX)
+}
+
+// Derives a surrogate id from tag values, so that every track tagged with
+// the same album ends up under one album row.
+fn local_id(parts: &[&str]) -> String {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update(part.trim().to_lowercase().as_bytes());
+        hasher.update(&[0]);
+    }
+    format!("{}{:x}", LOCAL_ID_PREFIX, hasher.finalize())
+}
+
+// The ReplayGain the file is tagged with, if any
+pub fn tagged_replaygain(track: &AVTrack) -> ReplayGain {
+    let md = track.metadata();
+    let value = |v: &Option<MetadataValue>| match *v {
+        Some(MetadataValue::TrackGain(g)) | Some(MetadataValue::TrackPeak(g))
+            | Some(MetadataValue::AlbumGain(g)) | Some(MetadataValue::AlbumPeak(g)) => Some(g),
+        _ => None,
+    };
+    ReplayGain {
+        track_gain: value(&md.track_gain),
+        track_peak: value(&md.track_peak),
+        album_gain: value(&md.album_gain),
+        album_peak: value(&md.album_peak),
+    }
+}
+
+pub struct TrackImporter<'a> {
+    providers: &'a ProviderChain,
+    track: &'a AVTrack<'a>,
+    local_artwork: bool,
+}
+
+impl<'a> TrackImporter<'a> {
+    pub fn new(providers: &'a ProviderChain, track: &'a AVTrack<'a>) -> TrackImporter<'a> {
+        TrackImporter {
+            providers,
+            track,
+            local_artwork: false,
+        }
+    }
+
+    // Uses cover art found with the file before asking providers. Only
+    // worth it when there's somewhere to keep the art, since albums only
+    // link to provider art.
+    pub fn with_local_artwork(mut self) -> TrackImporter<'a> {
+        self.local_artwork = true;
+        self
+    }
+
+    pub async fn find_match(&self) -> Result<(entities::ArtistCredit, entities::Release, entities::Recording)> {
+        let rec = self.match_to_recording().await?.ok_or(ImportError::NoRecordingMatch)?;
+        let release = match rec.releases.as_ref() {
+            Some(releases) => self.match_release(&releases),
+            _ => None,
+        }.ok_or(ImportError::NoReleaseMatch)?;
+        let artist_credit = release.artist_credit.as_ref()
+            .and_then(|ac| ac.first())
+            .cloned()
+            .ok_or_else(|| ImportError::InvalidMetadata(format!("release {} has no artist credit", release.id)))?;
+        Ok((artist_credit, release, rec))
+    }
+
+    pub fn build_track(&self, rec: &entities::Recording, release: &entities::Release, file_location: &str) -> Result<Track> {
+        let (medium, position) = self.find_release_track(release)
+            .ok_or_else(|| ImportError::InvalidMetadata(format!("release {} has no usable track number", release.id)))?;
+
+        Ok(Track {
+            mbid: rec.id.clone().ok_or_else(|| ImportError::InvalidMetadata("recording has no id".to_string()))?,
+            title: rec.title.clone().ok_or_else(|| ImportError::InvalidMetadata("recording has no title".to_string()))?,
+            disc_number: medium.position,
+            position,
+            bitrate: self.track.bit_rate(),
+            duration: self.track.duration(),
+            file_location: file_location.to_string(),
+            audio: self.track.audio_properties()?,
+        })
+    }
+
+    // Finds the medium and track number of the file on `release`. Search
+    // results only list the tracks the recording appears on, but that can be
+    // more than one, e.g. a single that is repeated on a bonus disc, so the
+    // disc and track number tags decide between them.
+    fn find_release_track<'r>(&self, release: &'r entities::Release) -> Option<(&'r entities::Medium, u16)> {
+        let md = self.track.metadata();
+        let disc = match md.disc {
+            Some(MetadataValue::Disc(d)) => Some(d as u16),
+            _ => None,
+        };
+        let number = match md.track_number {
+            Some(MetadataValue::TrackNumber(n)) => Some(n),
+            _ => None,
+        };
+
+        release.media.iter()
+            .flat_map(|m| {
+                m.track.iter()
+                    .filter_map(|t| u16::from_str_radix(&remove_alpha(&t.number), 10).ok())
+                    .map(move |n| (m, n))
+            })
+            .min_by_key(|(m, n)| {
+                let mut score = 0;
+                if disc.map(|d| d != m.position).unwrap_or(false) { score += 2; }
+                if number.map(|t| t != *n).unwrap_or(false) { score += 1; }
+                score
+            })
+    }
+
+    pub async fn build_artist(&self, artist_credit: &entities::ArtistCredit) -> Result<Artist> {
+        let artist = self.providers.artist(&artist_credit.artist.id).await?
+            .unwrap_or_else(|| ArtistDetails {
+                mbid: artist_credit.artist.id.clone(),
+                name: artist_credit.artist.name.clone(),
+                urls: Vec::new(),
+            });
+        // Artist images are nice to have, so failures are logged rather than
+        // fatal
+        let artist_image = match self.providers.artist_image(&artist).await {
+            Ok(url) => url,
+            Err(e) => {
+                eprintln!("Failed to find an image for {}: {}", artist.name, e);
+                None
+            }
+        };
+
+        Ok(Artist {
+            mbid: artist_credit.artist.id.clone(),
+            name: artist_credit.artist.name.clone(),
+            image_url: artist_image,
+        })
+    }
+
+    // The provider's artwork is looked up even when there's local art, in
+    // case the local art can't be stored
+    pub async fn build_album(&self, release: &entities::Release, artist_credit: &entities::ArtistCredit) -> Result<Album> {
+        let artwork = self.find_local_artwork();
+        let image_url = match self.providers.release_artwork(release, artist_credit).await {
+            Ok(url) => url,
+            Err(e) => {
+                eprintln!("Failed to find artwork for {}: {}", release.title, e);
+                None
+            }
+        };
+
+        Ok(Album {
+            mbid: release.id.clone(),
+            name: release.title.clone(),
+            image_url,
+            artwork,
+        })
+    }
+
+    // Finds the album's cover art in the file's directory, or else embedded
+    // in the file itself. Art that can't be read is skipped, since it's
+    // never worth failing an import over.
+    pub fn find_local_artwork(&self) -> Option<Artwork> {
+        if !self.local_artwork {
+            return None;
+        }
+        self.folder_artwork().or_else(|| self.embedded_artwork())
+    }
+
+    fn folder_artwork(&self) -> Option<Artwork> {
+        let dir = self.track.path().parent()?;
+        let (_, path, extension) = fs::read_dir(dir).ok()?
+            .filter_map(std::result::Result::ok)
+            .filter_map(|e| {
+                let path = e.path();
+                let stem = path.file_stem()?.to_str()?.to_lowercase();
+                let rank = FOLDER_ARTWORK_NAMES.iter().position(|n| *n == stem)?;
+                let extension = match path.extension()?.to_str()?.to_lowercase().as_str() {
+                    "jpg" | "jpeg" => "jpg",
+                    "png" => "png",
+                    _ => return None,
+                };
+                Some((rank, path, extension))
+            })
+            .min_by_key(|(rank, _, _)| *rank)?;
+
+        match fs::read(&path) {
+            Ok(data) => Some(Artwork { data, extension }),
+            Err(e) => {
+                eprintln!("Failed to read artwork {:?}: {}", path, e);
+                None
+            }
+        }
+    }
+
+    fn embedded_artwork(&self) -> Option<Artwork> {
+        let picture = match self.track.attached_picture() {
+            Ok(p) => p?,
+            Err(e) => {
+                eprintln!("Failed to read artwork embedded in {:?}: {}", self.track.path(), e);
+                return None;
+            }
+        };
+        Some(Artwork {
+            data: picture.data,
+            extension: images::extension_for(picture.mime_type)?,
+        })
+    }
+
+    // Builds the artist, album and track from the file's own tags, without
+    // any network lookups. The entities are given surrogate ids which are
+    // replaced once the file is matched online.
+    pub fn build_from_tags(&self, file_location: &str) -> Result<(Artist, Album, Track)> {
+        let md = self.track.metadata();
+        let artist_name = match md.artist {
+            Some(MetadataValue::Artist(a)) => a,
+            _ => return Err(ImportError::InvalidMetadata("missing artist tag".to_string())),
+        };
+        let album_title = match md.album {
+            Some(MetadataValue::Album(a)) => a,
+            _ => return Err(ImportError::InvalidMetadata("missing album tag".to_string())),
+        };
+        let title = match md.track_title {
+            Some(MetadataValue::TrackTitle(t)) => t,
+            _ => return Err(ImportError::InvalidMetadata("missing title tag".to_string())),
+        };
+        let disc = match md.disc {
+            Some(MetadataValue::Disc(d)) => d,
+            _ => 1,
+        };
+        let position = match md.track_number {
+            Some(MetadataValue::TrackNumber(n)) => n,
+            _ => 0,
+        };
+
+        let artist = Artist {
+            mbid: local_id(&[artist_name]),
+            name: artist_name.to_string(),
+            image_url: None,
+        };
+        let album = Album {
+            mbid: local_id(&[artist_name, album_title]),
+            name: album_title.to_string(),
+            image_url: None,
+            artwork: self.find_local_artwork(),
+        };
+        let track = Track {
+            mbid: local_id(&[artist_name, album_title, &disc.to_string(), &position.to_string(), title]),
+            title: title.to_string(),
+            disc_number: disc as u16,
+            position,
+            bitrate: self.track.bit_rate(),
+            duration: self.track.duration(),
+            file_location: file_location.to_string(),
+            audio: self.track.audio_properties()?,
+        };
+        Ok((artist, album, track))
+    }
+
+    pub async fn import(&self, file_location: &str) -> Result<(Artist, Album, Track)> {
+        let (artist_credit, release, rec) = self.find_match().await?;
+        self.from_entities(&rec, &release, &artist_credit, file_location).await
+    }
+
+    pub async fn from_entities(&self, rec: &entities::Recording, release: &entities::Release, artist_credit: &entities::ArtistCredit, file_location: &str) -> Result<(Artist, Album, Track)> {
+        let artist = self.build_artist(artist_credit).await?;
+        let album = self.build_album(release, artist_credit).await?;
+        let track = self.build_track(rec, release, file_location)?;
+        Ok((artist, album, track))
+    }
+
+    pub async fn search_recordings(&self) -> Result<Vec<entities::Recording>> {
+        Ok(self.providers.search_recordings(&self.track).await?.unwrap_or_default())
+    }
+
+    pub fn match_release(&self, releases: &Vec<entities::Release>) -> Option<entities::Release> {
+        if releases.is_empty() { return None; }
+        else if releases.len() == 1 { return Some(releases[0].clone()); }
+
+        let md = self.track.metadata();
+        let media_format = self.track.guess_media_format();
+        releases.iter()
+            .filter(|r| {
+                match media_format.as_ref() {
+                    Some(MediaFormat::CD) => r.media.iter().any(|m| m.format == Some("CD".to_string())),
+                    Some(MediaFormat::Digital) => r.media.iter().any(|m| m.format == Some("Digital Media".to_string())),
+                    _ => true,
+                }
+            })
+            .map(|r| {
+                let mut score = 0;
+                match &r.disambiguation {
+                    Some(d) => if d.to_lowercase().contains("clean") {
+                        score += 3;
+                    }
+                    None => {}
+                }
+
+                match md.album {
+                    Some(MetadataValue::Album(a)) => score += damlev(&r.title, a),
+                    _ => {}
+                }
+
+                match r.artist_credit.as_ref() {
+                    Some(ac) => {
+                        score += ac.iter()
+                            .map(|a| {
+                                match (a.name.as_ref(), md.artist.as_ref()) {
+                                    (Some(a1), Some(MetadataValue::Artist(a2))) => damlev(&a1, &a2),
+                                    _ => 0,
+                                }
+                            })
+                            .min()
+                            .unwrap_or(0);
+                    }
+                    None => {}
+                }
+
+                match md.track_count {
+                    Some(MetadataValue::TrackCount(c)) => {
+                        let rel_count_match = match r.track_count {
+                            Some(rc) => rc == c,
+                            None => false,
+                        };
+                        let count_match = r.media.iter().any(|m| match m.track_count {
+                            Some(mc) => c == mc,
+                            None => false,
+                        });
+                        if !count_match { score += 5 };
+                        if !rel_count_match { score += 5 };
+                    }
+                    _ => {}
+                }
+
+                match md.track_number {
+                    Some(MetadataValue::TrackNumber(n)) => {
+                        let num_match = r.media.iter().any(|m| match m.track_offset {
+                            Some(o) => (o + 1) == n,
+                            None => false,
+                        });
+                        if !num_match { score += 5 };
+                    }
+                    _ => {}
+                }
+
+                match md.disc {
+                    Some(MetadataValue::Disc(d)) => {
+                        if !r.media.iter().any(|m| m.position == d as u16) { score += 3 };
+                    }
+                    _ => {}
+                }
+
+                if r.country.is_some() && r.country != Some("US".to_string()) {
+                    score += 2;
+                }
+                //println!("{}: {:?}", score, r);
+                (score, r)
+            })
+            .min_by_key(|(score, _) | *score)
+            .map(|(_, r)| r.clone())
+    }
+
+    pub async fn match_to_recording(&self) -> Result<Option<entities::Recording>> {
+        let mut recs = self.search_recordings().await?;
+
+        if recs.is_empty() { return Ok(None); }
+        else if recs.len() == 1 { return Ok(recs.pop()); }
+
+        let md = self.track.metadata();
+        let best = recs.into_iter()
+            .map(|r| {
+                let mut score = 0;
+                match (r.title.as_ref(), md.track_title.as_ref()) {
+                    (Some(t1), Some(MetadataValue::TrackTitle(t2))) => {
+                        let n_t2 = extract_title(&t2);
+                        score += match n_t2 {
+                            Some(n_t2) => damlev(&t1, &n_t2),
+                            None => damlev(&t1, &t2),
+                        };
+                    },
+                    _ => {}
+                }
+
+                if md.artist.is_some() && r.artist_credit.len() > 0 {
+                    score += r.artist_credit.iter()
+                        .map(|a| {
+                            match (a.name.as_ref(), md.artist.as_ref()) {
+                                (Some(a1), Some(MetadataValue::Artist(a2))) => damlev(&a1, &a2),
+                                _ => 0,
+                            }
+                        })
+                        .min()
+                        .unwrap_or(0);
+                }
+
+                let media_format = self.track.guess_media_format();
+                match r.releases.as_ref() {
+                    Some(rs) => {
+                        let r_score = rs.iter()
+                            .map(|r| {
+                                let mut score = 0;
+
+                                match &r.disambiguation {
+                                    Some(d) => if d.to_lowercase().contains("clean") {
+                                        score += 3;
+                                    }
+                                    None => {}
+                                }
+
+                                match md.album {
+                                    Some(MetadataValue::Album(t2)) => score += damlev(&r.title, t2),
+                                    _ => {}
+                                }
+
+                                match media_format.as_ref() {
+                                    Some(MediaFormat::CD) => {
+                                        if !r.media.iter().any(|m| m.format == Some("CD".to_string())) {
+                                            score += 6;
+                                        }
+                                    }
+                                    Some(MediaFormat::Digital) => {
+                                        if !r.media.iter().any(|m| m.format == Some("Digital Media".to_string())) {
+                                            score += 6;
+                                        }
+                                    }
+                                    _ => {},
+                                }
+
+                                match r.artist_credit.as_ref() {
+                                    Some(ac) => {
+                                        score += ac.iter()
+                                            .map(|a| {
+                                                match (a.name.as_ref(), md.artist.as_ref()) {
+                                                    (Some(a1), Some(MetadataValue::Artist(a2))) => damlev(&a1, &a2),
+                                                    _ => 0,
+                                                }
+                                            })
+                                            .min()
+                                            .unwrap_or(0);
+                                    }
+                                    None => {}
+                                }
+
+                                match md.track_count {
+                                    Some(MetadataValue::TrackCount(c)) => {
+                                        let rel_count_match = match r.track_count {
+                                            Some(rc) => rc == c,
+                                            None => false,
+                                        };
+                                        let count_match = r.media.iter().any(|m| match m.track_count {
+                                            Some(mc) => c == mc,
+                                            None => false,
+                                        });
+                                        if !count_match { score += 5 };
+                                        if !rel_count_match { score += 5 };
+                                    }
+                                    _ => {}
+                                }
+
+                                match md.track_number {
+                                    Some(MetadataValue::TrackNumber(n)) => {
+                                        let num_match = r.media.iter().any(|m| match m.track_offset {
+                                            Some(o) => (o + 1) == n,
+                                            None => false,
+                                        });
+                                        if !num_match { score += 5 };
+                                    }
+                                    _ => {}
+                                }
+                                score
+                            })
+                            .min()
+                            .unwrap_or(0);
+                        score += r_score;
+                    }
+                    None => {}
+                }
+                //println!("{}: {:?}\n", score, r);
+                (score, r)
+            })
+            .min_by_key(|(score, _)| *score)
+            .map(|(_, r)| r);
+        Ok(best)
+    }
+}
+
+fn remove_alpha(s: &str) -> String {
+    let r = Regex::new(r"[a-zA-z]").unwrap();
+    r.replace_all(s, "").to_string()
+}
+
+fn extract_title(title: &str) -> Option<String> {
+    let reg = Regex::new(r"^(.+)\s+\(feat\.\s+.+?\)$").unwrap();
+    let caps = reg.captures(title);
+    caps.map(|c| c.get(1).unwrap().as_str().to_string())
+}
@@ -0,0 +1,169 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use av::metadata::Track as AVTrack;
+
+use super::{ImportError, TrackImporter};
+use crate::metadata::providers::{MBClient, ProviderChain, SpotifyClient};
+use crate::metadata::providers::mock::MockServer;
+use crate::metadata::providers::spotify::client::Credentials;
+
+fn provider_chain(server: &MockServer) -> ProviderChain {
+    let credentials = Credentials {
+        client_id: "id".to_string(),
+        client_secret: "secret".to_string(),
+    };
+    let mb_client = MBClient::with_base_urls(&server.url("/ws/2"), &server.url("/coverart")).unwrap();
+    let spotify_client = SpotifyClient::with_base_urls(&server.url("/spotify/v1"), &server.url("/spotify/token"), credentials);
+    ProviderChain::new(vec![Arc::new(mb_client), Arc::new(spotify_client)])
+}
+
+// Writes a FLAC file with no audio frames, which is all that matching needs:
+// the tags, and a STREAMINFO block for the duration.
+fn write_flac(test: &str, dir: &str, tags: &[(&str, &str)]) -> PathBuf {
+    let dir = std::env::temp_dir()
+        .join(format!("doplr-import-test-{}-{}", std::process::id(), test))
+        .join(dir);
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("01.flac");
+
+    let mut buf = b"fLaC".to_vec();
+
+    // STREAMINFO: 4096 sample blocks, 44.1kHz, stereo, 16 bit, 4 minutes
+    buf.extend_from_slice(&[0x00, 0x00, 0x00, 34]);
+    buf.extend_from_slice(&4096u16.to_be_bytes());
+    buf.extend_from_slice(&4096u16.to_be_bytes());
+    buf.extend_from_slice(&[0; 6]);
+    let packed = (44100u64 << 44) | (1 << 41) | (15 << 36) | (44100 * 240);
+    buf.extend_from_slice(&packed.to_be_bytes());
+    buf.extend_from_slice(&[0; 16]);
+
+    let vendor = b"doplr";
+    let mut comments = Vec::new();
+    comments.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    comments.extend_from_slice(vendor);
+    comments.extend_from_slice(&(tags.len() as u32).to_le_bytes());
+    for (k, v) in tags {
+        let tag = format!("{}={}", k, v);
+        comments.extend_from_slice(&(tag.len() as u32).to_le_bytes());
+        comments.extend_from_slice(tag.as_bytes());
+    }
+    // VORBIS_COMMENT, marked as the last metadata block
+    let len = (comments.len() as u32).to_be_bytes();
+    buf.extend_from_slice(&[0x84, len[1], len[2], len[3]]);
+    buf.extend_from_slice(&comments);
+
+    fs::write(&path, buf).unwrap();
+    path
+}
+
+#[tokio::test]
+async fn matches_studio_recording_and_us_cd_release() {
+    let server = MockServer::start(&[("/ws/2/recording", "musicbrainz/recording-search-karma-police.json")]);
+    let providers = provider_chain(&server);
+    let path = write_flac("karma-police", "Radiohead - OK Computer [CD]", &[
+        ("ARTIST", "Radiohead"),
+        ("ALBUM", "OK Computer"),
+        ("TITLE", "Karma Police"),
+        ("TRACKNUMBER", "6"),
+        ("TRACKTOTAL", "12"),
+        ("DISCNUMBER", "1"),
+    ]);
+    let track = AVTrack::new(&path).unwrap();
+    let imp = TrackImporter::new(&providers, &track);
+
+    let (artist_credit, release, rec) = imp.find_match().await.unwrap();
+    assert_eq!(rec.id.as_deref(), Some("8d9a8ba7-7f7a-4a8e-9b1c-1c0d4fd2a001"));
+    assert_eq!(release.id, "0b6b4ba0-d36f-47bd-b4ea-6a5b91842d29");
+    assert_eq!(artist_credit.artist.name, "Radiohead");
+
+    let requests = server.requests();
+    assert_eq!(requests.len(), 1);
+    assert!(requests[0].starts_with("/ws/2/recording?query="));
+    assert!(requests[0].contains("tnum%3A6"));
+}
+
+#[tokio::test]
+async fn prefers_explicit_digital_release() {
+    let server = MockServer::start(&[("/ws/2/recording", "musicbrainz/recording-search-humble.json")]);
+    let providers = provider_chain(&server);
+    let path = write_flac("humble", "Kendrick Lamar - DAMN. [WEB]", &[
+        ("ARTIST", "Kendrick Lamar"),
+        ("ALBUM", "DAMN."),
+        ("TITLE", "HUMBLE."),
+        ("TRACKNUMBER", "8"),
+        ("TRACKTOTAL", "14"),
+    ]);
+    let track = AVTrack::new(&path).unwrap();
+    let imp = TrackImporter::new(&providers, &track);
+
+    let (_, release, _) = imp.find_match().await.unwrap();
+    assert_eq!(release.id, "d3a2f2b1-4c5e-4f60-9bac-1d2e3f405b62");
+}
+
+#[tokio::test]
+async fn no_results_is_no_recording_match() {
+    let server = MockServer::start(&[("/ws/2/recording", "musicbrainz/recording-search-empty.json")]);
+    let providers = provider_chain(&server);
+    let path = write_flac("empty", "Unknown", &[
+        ("ARTIST", "Nobody"),
+        ("TITLE", "Nothing"),
+    ]);
+    let track = AVTrack::new(&path).unwrap();
+    let imp = TrackImporter::new(&providers, &track);
+
+    match imp.find_match().await {
+        Err(ImportError::NoRecordingMatch) => {}
+        other => panic!("expected NoRecordingMatch, got {:?}", other.map(|(_, r, _)| r.id)),
+    }
+}
+
+#[tokio::test]
+async fn picks_track_on_tagged_disc() {
+    let server = MockServer::start(&[("/ws/2/recording", "musicbrainz/recording-search-revolution.json")]);
+    let providers = provider_chain(&server);
+    let path = write_flac("revolution", "The Beatles - Past Masters [CD]", &[
+        ("ARTIST", "The Beatles"),
+        ("ALBUM", "Past Masters"),
+        ("TITLE", "Revolution"),
+        ("TRACKNUMBER", "7"),
+        ("TRACKTOTAL", "15"),
+        ("DISCNUMBER", "2"),
+    ]);
+    let track = AVTrack::new(&path).unwrap();
+    let imp = TrackImporter::new(&providers, &track);
+
+    let (_, release, rec) = imp.find_match().await.unwrap();
+    let track = imp.build_track(&rec, &release, "/revolution.flac").unwrap();
+    assert_eq!(track.disc_number, 2);
+    assert_eq!(track.position, 7);
+}
+
+#[tokio::test]
+async fn prefers_folder_artwork() {
+    let server = MockServer::start(&[]);
+    let providers = provider_chain(&server);
+    let path = write_flac("folder-artwork", "Boards of Canada - Geogaddi", &[
+        ("ARTIST", "Boards of Canada"),
+        ("ALBUM", "Geogaddi"),
+        ("TITLE", "Music Is Math"),
+    ]);
+    let dir = path.parent().unwrap();
+    fs::write(dir.join("folder.png"), b"folder").unwrap();
+    fs::write(dir.join("Cover.JPG"), b"cover").unwrap();
+    fs::write(dir.join("back.jpg"), b"back").unwrap();
+    let track = AVTrack::new(&path).unwrap();
+
+    let imp = TrackImporter::new(&providers, &track);
+    assert!(imp.find_local_artwork().is_none());
+
+    let imp = imp.with_local_artwork();
+    let artwork = imp.find_local_artwork().unwrap();
+    assert_eq!(artwork.data, b"cover");
+    assert_eq!(artwork.extension, "jpg");
+
+    let (_, album, _) = imp.build_from_tags("/music-is-math.flac").unwrap();
+    assert!(album.artwork.is_some());
+    assert!(server.requests().is_empty());
+}
@@ -0,0 +1,1024 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use av::format::{AudioProperties, AVFormatContext};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use deadpool_postgres::{Pool, Transaction};
+use sha2::{Digest, Sha256};
+use walkdir::WalkDir;
+
+use crate::images::ImageStore;
+use crate::import::{self, ImportError};
+use crate::metadata::providers::musicbrainz::entities;
+use crate::models::{Album, ReplayGain, Track};
+use crate::metadata::providers::ProviderChain;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+// Tracks imported offline that still can't be matched are looked up again
+// after a day, doubling with each attempt up to a month
+const LOOKUP_BACKOFF: &str = "LEAST(interval '1 day' * power(2, lookup_attempts - 1), interval '30 days')";
+
+#[derive(Default)]
+pub struct SyncOptions {
+    // Delete unavailable tracks, then any albums and artists left empty
+    pub prune: bool,
+    // Import from embedded tags only, without MusicBrainz or Spotify
+    pub offline: bool,
+}
+
+pub struct Library {
+    pool: Pool,
+    providers: ProviderChain,
+    music_root: PathBuf,
+    options: SyncOptions,
+    images: Option<ImageStore>,
+}
+
+// An artist as credited on an album or track, in order
+struct Credit {
+    artist_id: i32,
+    name: String,
+    join_phrase: String,
+}
+
+struct Credits {
+    album: Vec<Credit>,
+    track: Vec<Credit>,
+}
+
+struct DiskFile {
+    path: PathBuf,
+    location: String,
+    ctime: DateTime<Utc>,
+}
+
+impl Library {
+    pub fn new(pool: Pool, providers: ProviderChain, music_root: PathBuf, options: SyncOptions) -> Library {
+        Library {
+            pool,
+            providers,
+            music_root,
+            options,
+            images: None,
+        }
+    }
+
+    // Downloads artwork into `images` as albums and artists are imported
+    pub fn with_image_store(mut self, images: ImageStore) -> Library {
+        self.images = Some(images);
+        self
+    }
+
+    pub fn music_root(&self) -> &Path {
+        &self.music_root
+    }
+
+    pub fn pool(&self) -> &Pool {
+        &self.pool
+    }
+
+    // Brings the database in line with the music root: relinks tracks whose
+    // files were moved, marks tracks whose files are gone as unavailable, and
+    // imports every file that is new or has changed since the last sync.
+    pub async fn sync(&self) -> Result<()> {
+        let last_sync = last_sync_time(&self.pool).await?.unwrap_or(DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp(0, 0), Utc));
+
+        let walker = WalkDir::new(&self.music_root)
+            .into_iter()
+            .filter_map(std::result::Result::ok)
+            .filter(|e| !e.file_type().is_dir())
+            .filter(|e| is_audio_file(e.path()));
+
+        let mut files = Vec::new();
+        for entry in walker {
+            let md = entry.metadata()?;
+            let ndt = NaiveDateTime::from_timestamp(md.ctime(), md.ctime_nsec() as u32);
+            let location = match self.file_location(entry.path()) {
+                Some(l) => l,
+                None => continue,
+            };
+            files.push(DiskFile {
+                path: entry.into_path(),
+                location,
+                ctime: DateTime::<Utc>::from_utc(ndt, Utc),
+            });
+        }
+
+        let mut client = self.pool.get().await?;
+        let tx = client.transaction().await?;
+        let to_import = self.reconcile(&files, last_sync, &tx).await?;
+        tx.commit().await?;
+
+        for path in to_import {
+            self.import_path(path, &mut client).await?;
+        }
+
+        self.backfill_content_hashes(&client).await?;
+        self.backfill_audio_properties(&mut client).await?;
+        self.backfill_replaygain(&mut client).await?;
+
+        if !self.options.offline {
+            self.upgrade_local_tracks(&mut client).await?;
+        }
+
+        let tx = client.transaction().await?;
+        if self.options.prune {
+            prune_orphans(&tx).await?;
+        }
+        log_sync(&tx).await?;
+        tx.commit().await?;
+
+        self.store_images().await?;
+        if let Some(images) = self.images.as_ref().filter(|_| self.options.prune) {
+            images.prune(&client).await?;
+        }
+
+        Ok(())
+    }
+
+    // Downloads the artwork of albums and artists imported since the last
+    // call. Offline syncs leave it for the next online one.
+    pub async fn store_images(&self) -> Result<()> {
+        if let Some(images) = self.images.as_ref().filter(|_| !self.options.offline) {
+            let client = self.pool.get().await?;
+            images.store_pending(&client).await?;
+        }
+        Ok(())
+    }
+
+    // Imports the files whose failures were flagged for retry through the
+    // API.
+    pub async fn retry_failures(&self) -> Result<()> {
+        let mut client = self.pool.get().await?;
+        let stmt = client.prepare("SELECT file_location FROM import_failure WHERE retry_requested").await?;
+        let rows = client.query(&stmt, &[]).await?;
+
+        for row in rows {
+            let location: String = row.get(0);
+            let path = self.music_root.join(location.trim_start_matches('/'));
+            if path.exists() {
+                self.import_path(&path, &mut client).await?;
+            } else {
+                client.execute("DELETE FROM import_failure WHERE file_location = $1", &[&location]).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Imports `path` in a transaction of its own. If the import fails it is
+    // rolled back and the failure is recorded in `import_failure`, so that
+    // one bad file never holds up the rest of the library. Only errors
+    // recording the outcome are returned.
+    pub async fn import_path(&self, path: &Path, client: &mut deadpool_postgres::Client) -> Result<bool> {
+        // Measuring decodes the whole file, so it's done before the
+        // transaction is opened
+        let gain = file_replaygain(path).await;
+        let tx = client.transaction().await?;
+        match self.import_file(path, Some(gain), &tx).await {
+            Ok(()) => {
+                if let Some(location) = self.file_location(path) {
+                    tx.execute("DELETE FROM import_failure WHERE file_location = $1", &[&location]).await?;
+                }
+                tx.commit().await?;
+                Ok(true)
+            }
+            Err(e) => {
+                drop(tx);
+                eprintln!("Failed to import {:?}: {}", path, e);
+                self.record_failure(path, &e, client).await?;
+                Ok(false)
+            }
+        }
+    }
+
+    async fn record_failure(&self, path: &Path, err: &ImportError, client: &deadpool_postgres::Client) -> Result<()> {
+        let location = match self.file_location(path) {
+            Some(l) => l,
+            None => return Ok(()),
+        };
+        let tags = path.to_str()
+            .and_then(|p| av::metadata::Track::new(p).ok())
+            .and_then(|t| t.raw_tags().ok())
+            .unwrap_or_default();
+        let tags = serde_json::to_value(tags)?;
+        let stmt = client.prepare("
+            INSERT INTO import_failure (file_location, kind, message, tags, first_failed_at, last_failed_at)
+            VALUES ($1, $2, $3, $4, $5, $5)
+            ON CONFLICT (file_location) DO UPDATE
+            SET kind = EXCLUDED.kind,
+                message = EXCLUDED.message,
+                tags = EXCLUDED.tags,
+                attempts = import_failure.attempts + 1,
+                retry_requested = false,
+                last_failed_at = EXCLUDED.last_failed_at
+        ").await?;
+        client.execute(&stmt, &[&location, &err.kind(), &err.to_string(), &tags, &Utc::now()]).await?;
+        Ok(())
+    }
+
+    // Compares the tracks in the database against `files` and returns the
+    // files that still need to be imported.
+    async fn reconcile<'f>(&self, files: &'f [DiskFile], last_sync: DateTime<Utc>, tx: &Transaction<'_>) -> Result<Vec<&'f Path>> {
+        let stmt = tx.prepare("SELECT id, file_location, content_hash, available FROM track").await?;
+        let rows = tx.query(&stmt, &[]).await?;
+        let present: HashSet<&str> = files.iter().map(|f| f.location.as_str()).collect();
+
+        let mut known = HashMap::new();
+        let mut missing = HashSet::new();
+        let mut missing_by_hash = HashMap::new();
+        for row in &rows {
+            let id: i32 = row.get(0);
+            let location: String = row.get(1);
+            let hash: Option<String> = row.get(2);
+            let available: bool = row.get(3);
+            if !present.contains(location.as_str()) {
+                missing.insert(id);
+                if let Some(hash) = hash {
+                    missing_by_hash.insert(hash, id);
+                }
+            }
+            known.insert(location, (id, available));
+        }
+
+        // Files that failed before are left alone until they change or a
+        // retry is requested.
+        let stmt = tx.prepare("SELECT file_location, last_failed_at, retry_requested FROM import_failure").await?;
+        let quarantined: HashMap<String, (DateTime<Utc>, bool)> = tx.query(&stmt, &[]).await?
+            .into_iter()
+            .map(|row| (row.get(0), (row.get(1), row.get(2))))
+            .collect();
+
+        let mut to_import = Vec::new();
+        let mut now_available = Vec::new();
+        let relink_stmt = tx.prepare("UPDATE track SET file_location = $2, available = true WHERE id = $1").await?;
+
+        for file in files {
+            match known.get(&file.location) {
+                Some((id, available)) => {
+                    if !available {
+                        now_available.push(*id);
+                    }
+                    if file.ctime >= last_sync {
+                        to_import.push(file.path.as_path());
+                    }
+                }
+                None => {
+                    if let Some((failed_at, retry)) = quarantined.get(&file.location) {
+                        if !retry && file.ctime < *failed_at {
+                            continue;
+                        }
+                    }
+
+                    // A file we haven't seen at this location is either new
+                    // or one of the missing tracks, moved.
+                    if !missing_by_hash.is_empty() {
+                        let hash = hash_file(&file.path)?;
+                        if let Some(id) = missing_by_hash.remove(&hash) {
+                            tx.execute(&relink_stmt, &[&id, &file.location]).await?;
+                            missing.remove(&id);
+                            println!("Relinked track {} to {}", id, file.location);
+                            continue;
+                        }
+                    }
+                    to_import.push(file.path.as_path());
+                }
+            }
+        }
+
+        let stmt = tx.prepare("UPDATE track SET available = true WHERE id = ANY($1)").await?;
+        tx.execute(&stmt, &[&now_available]).await?;
+
+        // Tracks that are still missing may yet be relinked by recording id
+        // when their new location is imported.
+        let missing: Vec<i32> = missing.into_iter().collect();
+        let stmt = tx.prepare("UPDATE track SET available = false WHERE id = ANY($1)").await?;
+        let n = tx.execute(&stmt, &[&missing]).await?;
+        if n > 0 {
+            println!("Marked {} missing track(s) unavailable", n);
+        }
+
+        Ok(to_import)
+    }
+
+    // Matches `path` and inserts it, along with its artist and album if they
+    // are new. If a track already exists at this location it is updated in
+    // place, so playlists referencing it are kept. Its ReplayGain is left as
+    // it is without `gain`, from file_replaygain.
+    pub async fn import_file(&self, path: &Path, gain: Option<(ReplayGain, Option<f32>)>, tx: &Transaction<'_>) -> std::result::Result<(), ImportError> {
+        let file_location = self.file_location(path).ok_or(ImportError::InvalidPath)?;
+        let c = path.to_str().ok_or(ImportError::InvalidPath)?;
+        let track: av::metadata::Track<'_> = av::metadata::Track::new(c)?;
+        let mut imp = import::TrackImporter::new(&self.providers, &track);
+        if self.images.is_some() {
+            imp = imp.with_local_artwork();
+        }
+
+        let (album_id, track, credits) = if self.options.offline {
+            match self.import_from_tags(&imp, &file_location, tx).await? {
+                Some(imported) => imported,
+                None => return Ok(()),
+            }
+        } else {
+            self.import_matched(&imp, &file_location, tx).await?
+        };
+
+        let content_hash = hash_file(path)?;
+        let size = std::fs::metadata(path)?.len() as i64;
+
+        if relink_track(&track.mbid, &track.file_location, tx).await? {
+            println!("Relinked {} to {}", track.mbid, track.file_location);
+        }
+
+        let update_track_stmt = tx.prepare("
+            UPDATE track
+            SET mbid = $1, title = $2, position = $3, bit_rate = $4, duration = $5, album_id = $7, content_hash = $8, disc_number = $9, available = true
+            WHERE file_location = $6
+            RETURNING id
+        ").await?;
+        let updated = tx.query_opt(&update_track_stmt, &[&track.mbid, &track.title, &(track.position as i32), &(track.bitrate as i32), &(track.duration as i32), &track.file_location, &album_id, &content_hash, &(track.disc_number as i32)]).await?;
+        let track_id: i32 = match updated {
+            Some(row) => row.get(0),
+            None => {
+                let insert_track_stmt = tx.prepare("INSERT INTO track (mbid, title, position, bit_rate, duration, file_location, album_id, content_hash, disc_number) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9) RETURNING id").await?;
+                tx.query_one(&insert_track_stmt, &[&track.mbid, &track.title, &(track.position as i32), &(track.bitrate as i32), &(track.duration as i32), &track.file_location, &album_id, &content_hash, &(track.disc_number as i32)]).await?.get(0)
+            }
+        };
+
+        write_audio_properties(track_id, &track.audio, size, tx).await?;
+        if let Some((replaygain, loudness)) = gain {
+            write_replaygain(track_id, &replaygain, loudness, tx).await?;
+            update_album_gain(album_id, tx).await?;
+        }
+        write_credits("album_artist", "album_id", album_id, &credits.album, tx).await?;
+        write_credits("track_artist", "track_id", track_id, &credits.track, tx).await?;
+
+        println!("Imported {} from {}", track.title, track.file_location);
+
+        Ok(())
+    }
+
+    // Returns the album id, track and credits for a file matched against
+    // MusicBrainz.
+    // If the track was previously imported offline, its surrogate artist and
+    // album are upgraded in place when the matched ones aren't known yet, so
+    // their ids stay stable.
+    async fn import_matched(&self, imp: &import::TrackImporter<'_>, file_location: &str, tx: &Transaction<'_>)
+        -> std::result::Result<(i32, Track, Credits), ImportError>
+    {
+        let (ac, rel, rec) = imp.find_match().await?;
+
+        println!("{} {} {}", ac.artist.id, rel.id, rec.id.as_deref().unwrap_or(""));
+
+        let (mut existing_artist, mut existing_album) =
+            existing_artist_album(&rel.id, &ac.artist.id, tx).await?;
+        let (local_artist, local_album) = local_artist_album(file_location, tx).await?;
+
+        if existing_artist.is_none() {
+            let artist = imp.build_artist(&ac).await?;
+            let row = match local_artist {
+                Some(id) => {
+                    let stmt = tx.prepare("UPDATE artist SET mbid = $2, name = $3, image_url = $4, image_file = CASE WHEN image_url IS DISTINCT FROM $4 THEN NULL ELSE image_file END, image_attempts = CASE WHEN image_url IS DISTINCT FROM $4 THEN 0 ELSE image_attempts END WHERE id = $1 RETURNING id").await?;
+                    tx.query_one(&stmt, &[&id, &artist.mbid, &artist.name, &artist.image_url]).await?
+                }
+                None => {
+                    let stmt = tx.prepare("INSERT INTO artist (mbid, name, image_url) VALUES ($1, $2, $3) RETURNING id").await?;
+                    tx.query_one(&stmt, &[&artist.mbid, &artist.name, &artist.image_url]).await?
+                }
+            };
+            existing_artist = Some(row.get(0));
+        }
+
+        if existing_album.is_none() {
+            let album = imp.build_album(&rel, &ac).await?;
+            let image_file = self.store_artwork(&album).await;
+            // The provider's artwork is only needed if local art wasn't stored
+            let image_url = album.image_url.as_ref().filter(|_| image_file.is_none());
+            let row = match local_album {
+                Some(id) => {
+                    let stmt = tx.prepare("UPDATE album SET mbid = $2, title = $3, image_url = $4, image_file = COALESCE($6, CASE WHEN image_url IS DISTINCT FROM $4 THEN NULL ELSE image_file END), image_attempts = CASE WHEN image_url IS DISTINCT FROM $4 THEN 0 ELSE image_attempts END, artist_id = $5 WHERE id = $1 RETURNING id").await?;
+                    tx.query_one(&stmt, &[&id, &album.mbid, &album.name, &image_url, &existing_artist.as_ref().unwrap(), &image_file]).await?
+                }
+                None => {
+                    let stmt = tx.prepare("INSERT INTO album (mbid, title, image_url, artist_id, image_file) VALUES ($1, $2, $3, $4, $5) RETURNING id").await?;
+                    tx.query_one(&stmt, &[&album.mbid, &album.name, &image_url, &existing_artist.as_ref().unwrap(), &image_file]).await?
+                }
+            };
+            existing_album = Some(row.get(0));
+        }
+
+        let credits = Credits {
+            album: self.credit_artists(imp, rel.artist_credit.as_deref().unwrap_or(&[]), tx).await?,
+            track: self.credit_artists(imp, &rec.artist_credit, tx).await?,
+        };
+
+        let track = imp.build_track(&rec, &rel, file_location)?;
+        Ok((existing_album.unwrap(), track, credits))
+    }
+
+    // Resolves each credited artist to its row, importing the artists that
+    // are new, e.g. ones only featured on this track.
+    async fn credit_artists(&self, imp: &import::TrackImporter<'_>, artist_credits: &[entities::ArtistCredit], tx: &Transaction<'_>)
+        -> std::result::Result<Vec<Credit>, ImportError>
+    {
+        let select_stmt = tx.prepare("SELECT id FROM artist WHERE mbid = $1").await?;
+        let mut credits = Vec::new();
+
+        for ac in artist_credits {
+            let artist_id = match tx.query_opt(&select_stmt, &[&ac.artist.id]).await? {
+                Some(row) => row.get(0),
+                None => {
+                    let artist = imp.build_artist(ac).await?;
+                    let stmt = tx.prepare("INSERT INTO artist (mbid, name, image_url) VALUES ($1, $2, $3) RETURNING id").await?;
+                    tx.query_one(&stmt, &[&artist.mbid, &artist.name, &artist.image_url]).await?.get(0)
+                }
+            };
+            credits.push(Credit {
+                artist_id,
+                name: ac.name.clone().unwrap_or_else(|| ac.artist.name.clone()),
+                join_phrase: ac.joinphrase.clone().unwrap_or_default(),
+            });
+        }
+
+        Ok(credits)
+    }
+
+    // Returns the album id, track and credits for a file built from its tags
+    // alone, or None if the file is already matched, since an offline import
+    // should never replace a MusicBrainz match.
+    async fn import_from_tags(&self, imp: &import::TrackImporter<'_>, file_location: &str, tx: &Transaction<'_>)
+        -> std::result::Result<Option<(i32, Track, Credits)>, ImportError>
+    {
+        let stmt = tx.prepare("SELECT mbid FROM track WHERE file_location = $1").await?;
+        if let Some(row) = tx.query_opt(&stmt, &[&file_location]).await? {
+            let mbid: String = row.get(0);
+            if !import::is_local_id(&mbid) {
+                println!("Skipping {}, already matched as {}", file_location, mbid);
+                return Ok(None);
+            }
+        }
+
+        let (artist, album, track) = imp.build_from_tags(file_location)?;
+        let (mut existing_artist, mut existing_album) =
+            existing_artist_album(&album.mbid, &artist.mbid, tx).await?;
+
+        if existing_artist.is_none() {
+            let stmt = tx.prepare("INSERT INTO artist (mbid, name, image_url) VALUES ($1, $2, $3) RETURNING id").await?;
+            let row = tx.query_one(&stmt, &[&artist.mbid, &artist.name, &artist.image_url]).await?;
+            existing_artist = Some(row.get(0));
+        }
+
+        if existing_album.is_none() {
+            let image_file = self.store_artwork(&album).await;
+            let stmt = tx.prepare("INSERT INTO album (mbid, title, image_url, artist_id, image_file) VALUES ($1, $2, $3, $4, $5) RETURNING id").await?;
+            let row = tx.query_one(&stmt, &[&album.mbid, &album.name, &album.image_url, &existing_artist.as_ref().unwrap(), &image_file]).await?;
+            existing_album = Some(row.get(0));
+        }
+
+        // Tags only name one artist, so it is credited for both
+        let credit = || vec![Credit {
+            artist_id: existing_artist.unwrap(),
+            name: artist.name.clone(),
+            join_phrase: String::new(),
+        }];
+        let credits = Credits {
+            album: credit(),
+            track: credit(),
+        };
+
+        Ok(Some((existing_album.unwrap(), track, credits)))
+    }
+
+    // Hashes tracks imported before content hashes were recorded, so that
+    // reconcile can relink them if they're moved later. Tracks that are
+    // already missing can only be relinked by recording id, when their new
+    // location is imported.
+    async fn backfill_content_hashes(&self, client: &deadpool_postgres::Client) -> Result<()> {
+        let stmt = client.prepare("SELECT id, file_location FROM track WHERE content_hash IS NULL AND available").await?;
+        let rows = client.query(&stmt, &[]).await?;
+
+        let update_stmt = client.prepare("UPDATE track SET content_hash = $2 WHERE id = $1").await?;
+        for row in &rows {
+            let id: i32 = row.get(0);
+            let location: String = row.get(1);
+            let path = self.music_root.join(location.trim_start_matches('/'));
+            match hash_file(&path) {
+                Ok(hash) => { client.execute(&update_stmt, &[&id, &hash]).await?; }
+                Err(e) => eprintln!("Failed to hash {:?}: {}", path, e),
+            }
+        }
+
+        Ok(())
+    }
+
+    // Reads the audio properties of tracks imported before they were
+    // recorded, committing each track on its own. Files that can't be read
+    // are skipped until they change.
+    async fn backfill_audio_properties(&self, client: &mut deadpool_postgres::Client) -> Result<()> {
+        let stmt = client.prepare("SELECT id, file_location, audio_properties_failed_at FROM track WHERE codec IS NULL AND available").await?;
+        let rows = client.query(&stmt, &[]).await?;
+
+        let failed_stmt = client.prepare("UPDATE track SET audio_properties_failed_at = now() WHERE id = $1").await?;
+        for row in &rows {
+            let id: i32 = row.get(0);
+            let location: String = row.get(1);
+            let failed_at: Option<DateTime<Utc>> = row.get(2);
+            let path = self.music_root.join(location.trim_start_matches('/'));
+            if let Some(failed_at) = failed_at {
+                if !changed_since(&path, failed_at) {
+                    continue;
+                }
+            }
+
+            match read_audio_properties(&path) {
+                Ok((audio, size)) => {
+                    let tx = client.transaction().await?;
+                    write_audio_properties(id, &audio, size, &tx).await?;
+                    tx.commit().await?;
+                }
+                Err(e) => {
+                    eprintln!("Failed to read audio properties of {:?}: {}", path, e);
+                    client.execute(&failed_stmt, &[&id]).await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Tags or measures the ReplayGain of tracks imported before it was
+    // recorded. Each track is committed on its own, since measuring a large
+    // library takes a while. Tracks that failed to measure are skipped until
+    // their file changes.
+    async fn backfill_replaygain(&self, client: &mut deadpool_postgres::Client) -> Result<()> {
+        let stmt = client.prepare("
+            SELECT id, file_location, album_id, loudness_failed_at
+            FROM track
+            WHERE replaygain_track_gain IS NULL AND loudness IS NULL AND available
+        ").await?;
+        let rows = client.query(&stmt, &[]).await?;
+
+        for row in &rows {
+            let id: i32 = row.get(0);
+            let location: String = row.get(1);
+            let album_id: i32 = row.get(2);
+            let failed_at: Option<DateTime<Utc>> = row.get(3);
+            let path = self.music_root.join(location.trim_start_matches('/'));
+            if let Some(failed_at) = failed_at {
+                if !changed_since(&path, failed_at) {
+                    continue;
+                }
+            }
+
+            let (replaygain, loudness) = file_replaygain(&path).await;
+
+            let tx = client.transaction().await?;
+            write_replaygain(id, &replaygain, loudness, &tx).await?;
+            update_album_gain(album_id, &tx).await?;
+            tx.commit().await?;
+        }
+
+        Ok(())
+    }
+
+    // Adds the album's local artwork to the image store, returning its file
+    // name. Failures are logged rather than failing the import, and leave
+    // the album without art.
+    async fn store_artwork(&self, album: &Album) -> Option<String> {
+        let (artwork, images) = match (&album.artwork, &self.images) {
+            (Some(a), Some(i)) => (a, i),
+            _ => return None,
+        };
+        match images.store(&artwork.data, artwork.extension).await {
+            Ok(file) => Some(file),
+            Err(e) => {
+                eprintln!("Failed to store artwork for {}: {}", album.name, e);
+                None
+            }
+        }
+    }
+
+    // Tries to match every track that was imported offline. Tracks that
+    // still can't be matched keep their surrogate ids and are retried after
+    // LOOKUP_BACKOFF, or sooner if their file changes.
+    async fn upgrade_local_tracks(&self, client: &mut deadpool_postgres::Client) -> Result<()> {
+        let stmt = client.prepare(&format!("
+            SELECT file_location, last_lookup_at, last_lookup_at IS NULL OR last_lookup_at < now() - {}
+            FROM track
+            WHERE mbid LIKE $1 AND available = true
+        ", LOOKUP_BACKOFF)).await?;
+        let pattern = format!("{}%", import::LOCAL_ID_PREFIX);
+        let rows = client.query(&stmt, &[&pattern]).await?;
+        let failed_stmt = client.prepare("
+            UPDATE track
+            SET lookup_attempts = lookup_attempts + 1, last_lookup_at = now()
+            WHERE file_location = $1
+        ").await?;
+
+        for row in rows {
+            let location: String = row.get(0);
+            let last_lookup: Option<DateTime<Utc>> = row.get(1);
+            let due: bool = row.get(2);
+            let path = self.music_root.join(location.trim_start_matches('/'));
+            if !due && !last_lookup.map_or(false, |t| changed_since(&path, t)) {
+                continue;
+            }
+
+            // Already measured when it was first imported
+            let tx = client.transaction().await?;
+            match self.import_file(&path, None, &tx).await {
+                Ok(()) => tx.commit().await?,
+                Err(e) => {
+                    eprintln!("Failed to match {}: {}", location, e);
+                    drop(tx);
+                    client.execute(&failed_stmt, &[&location]).await?;
+                }
+            }
+        }
+
+        let tx = client.transaction().await?;
+        prune_local_orphans(&tx).await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    // Marks the track at `path`, or every track under it if it was a
+    // directory, as unavailable. The rows are kept so that playlists survive
+    // the file coming back.
+    pub async fn mark_unavailable(&self, path: &Path, tx: &Transaction<'_>) -> Result<u64> {
+        let location = self.file_location(path).ok_or("path is outside the music root")?;
+        let stmt = tx.prepare("
+            UPDATE track
+            SET available = false
+            WHERE file_location = $1 OR left(file_location, length($1) + 1) = $1 || '/'
+        ").await?;
+        Ok(tx.execute(&stmt, &[&location]).await?)
+    }
+
+    // Repoints the track at `from`, or every track under it if it was a
+    // directory, to `to`.
+    pub async fn move_path(&self, from: &Path, to: &Path, tx: &Transaction<'_>) -> Result<u64> {
+        let from = self.file_location(from).ok_or("path is outside the music root")?;
+        let to = self.file_location(to).ok_or("path is outside the music root")?;
+        let stmt = tx.prepare("
+            UPDATE track
+            SET file_location = $2 || substr(file_location, length($1) + 1), available = true
+            WHERE file_location = $1 OR left(file_location, length($1) + 1) = $1 || '/'
+        ").await?;
+        Ok(tx.execute(&stmt, &[&from, &to]).await?)
+    }
+
+    // Track locations are stored relative to the music root, with a leading
+    // slash.
+    pub fn file_location(&self, path: &Path) -> Option<String> {
+        let rel = path.strip_prefix(&self.music_root).ok()?;
+        Some(format!("/{}", rel.to_str()?))
+    }
+}
+
+// Files are recognised by their contents, so anything FFmpeg might read is
+// tried whatever its extension
+pub fn is_audio_file(path: &Path) -> bool {
+    av::format::is_audio_file(path)
+}
+
+// Whether the file at `path` changed after `t`, by its ctime as in sync
+fn changed_since(path: &Path, t: DateTime<Utc>) -> bool {
+    match std::fs::metadata(path) {
+        Ok(md) => {
+            let ndt = NaiveDateTime::from_timestamp(md.ctime(), md.ctime_nsec() as u32);
+            DateTime::<Utc>::from_utc(ndt, Utc) >= t
+        }
+        Err(_) => false,
+    }
+}
+
+pub fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+async fn prune_orphans(tx: &Transaction<'_>) -> Result<()> {
+    let tracks = tx.execute("DELETE FROM track WHERE available = false", &[]).await?;
+    let albums = tx.execute("
+        DELETE FROM album R
+        WHERE NOT EXISTS (SELECT 1 FROM track T WHERE T.album_id = R.id)
+    ", &[]).await?;
+    let artists = tx.execute("
+        DELETE FROM artist A
+        WHERE NOT EXISTS (SELECT 1 FROM album R WHERE R.artist_id = A.id)
+          AND NOT EXISTS (SELECT 1 FROM album_artist C WHERE C.artist_id = A.id)
+          AND NOT EXISTS (SELECT 1 FROM track_artist C WHERE C.artist_id = A.id)
+    ", &[]).await?;
+    println!("Pruned {} track(s), {} album(s) and {} artist(s)", tracks, albums, artists);
+    Ok(())
+}
+
+// A missing track with the same recording was most likely moved and
+// retagged, so it takes over the file rather than a duplicate being created.
+// If the file was already imported as another recording, the missing track
+// is merged into that row instead, so its playlist entries and plays follow
+// and the row can take its mbid. Returns whether there was such a track.
+async fn relink_track(mbid: &str, file_location: &str, tx: &Transaction<'_>)
+    -> std::result::Result<bool, tokio_postgres::Error>
+{
+    let stmt = tx.prepare("
+        SELECT M.id, T.id
+        FROM track M
+        LEFT OUTER JOIN track T ON T.file_location = $2
+        WHERE M.mbid = $1 AND M.file_location <> $2 AND M.available = false
+    ").await?;
+    let (missing_id, existing_id): (i32, Option<i32>) = match tx.query_opt(&stmt, &[&mbid, &file_location]).await? {
+        Some(row) => (row.get(0), row.get(1)),
+        None => return Ok(false),
+    };
+
+    match existing_id {
+        None => {
+            let stmt = tx.prepare("UPDATE track SET file_location = $2, available = true WHERE id = $1").await?;
+            tx.execute(&stmt, &[&missing_id, &file_location]).await?;
+        }
+        Some(existing_id) => {
+            for table in &["playlist_track", "play_event"] {
+                let stmt = tx.prepare(&format!("UPDATE {} SET track_id = $2 WHERE track_id = $1", table)).await?;
+                tx.execute(&stmt, &[&missing_id, &existing_id]).await?;
+            }
+            let stmt = tx.prepare("DELETE FROM track WHERE id = $1").await?;
+            tx.execute(&stmt, &[&missing_id]).await?;
+        }
+    }
+    Ok(true)
+}
+
+// Stores the audio properties FFmpeg reports for track `track_id`, and the
+// size of its file in bytes, clearing any earlier failure to read them
+async fn write_audio_properties(track_id: i32, audio: &AudioProperties, size: i64, tx: &Transaction<'_>)
+    -> std::result::Result<(), tokio_postgres::Error>
+{
+    let stmt = tx.prepare("
+        UPDATE track
+        SET codec = $2, sample_rate = $3, bit_depth = $4, channels = $5, duration_ms = $6, size = $7, lossless = $8,
+            audio_properties_failed_at = NULL
+        WHERE id = $1
+    ").await?;
+    tx.execute(&stmt, &[&track_id, &audio.codec, &audio.sample_rate, &audio.bit_depth, &audio.channels, &audio.duration_ms, &size, &audio.lossless]).await?;
+    Ok(())
+}
+
+fn read_audio_properties(path: &Path) -> Result<(AudioProperties, i64)> {
+    let audio = AVFormatContext::open(path)?.audio_properties()?;
+    let size = std::fs::metadata(path)?.len() as i64;
+    Ok((audio, size))
+}
+
+// The loudness ReplayGain 2.0 gains are relative to, in LUFS
+const REPLAYGAIN_REFERENCE: f32 = -18.0;
+
+// Returns the file's tagged ReplayGain if it has a track gain, otherwise
+// measures one, along with the track's loudness in LUFS. Files that can't be
+// measured are logged and left without.
+async fn file_replaygain(path: &Path) -> (ReplayGain, Option<f32>) {
+    let tagged = match av::metadata::Track::new(path) {
+        Ok(track) => import::tagged_replaygain(&track),
+        Err(e) => {
+            eprintln!("Failed to read tags of {:?}: {}", path, e);
+            ReplayGain::default()
+        }
+    };
+    if let Some(gain) = tagged.track_gain {
+        return (tagged, Some(REPLAYGAIN_REFERENCE - gain));
+    }
+
+    let input = path.to_owned();
+    let measured = tokio::task::spawn_blocking(move || av::loudness::analyze(&input))
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|r| r.map_err(|e| e.to_string()));
+    match measured {
+        Ok(l) => {
+            // Silence has no loudness to correct, and would otherwise be
+            // boosted by 52 dB
+            let audible = l.integrated > av::loudness::ABSOLUTE_GATE;
+            let replaygain = ReplayGain {
+                track_gain: Some(REPLAYGAIN_REFERENCE - l.integrated as f32).filter(|_| audible),
+                track_peak: Some(l.true_peak as f32),
+                album_gain: None,
+                album_peak: None,
+            };
+            (replaygain, Some(l.integrated as f32))
+        }
+        Err(e) => {
+            eprintln!("Failed to measure loudness of {:?}: {}", path, e);
+            (ReplayGain::default(), None)
+        }
+    }
+}
+
+// Tagged gains are kept as they are. Measured ones have no album gain until
+// update_album_gain derives it. Without a loudness, measuring failed, which
+// is recorded so backfill_replaygain can skip the track.
+async fn write_replaygain(track_id: i32, replaygain: &ReplayGain, loudness: Option<f32>, tx: &Transaction<'_>)
+    -> std::result::Result<(), tokio_postgres::Error>
+{
+    let album_tagged = replaygain.album_gain.is_some();
+    let stmt = tx.prepare("
+        UPDATE track
+        SET replaygain_track_gain = $2, replaygain_track_peak = $3, replaygain_album_gain = $4, replaygain_album_peak = $5,
+            loudness = $6, replaygain_album_tagged = $7, loudness_failed_at = CASE WHEN $6::real IS NULL THEN now() END
+        WHERE id = $1
+    ").await?;
+    tx.execute(&stmt, &[&track_id, &replaygain.track_gain, &replaygain.track_peak, &replaygain.album_gain, &replaygain.album_peak, &loudness, &album_tagged]).await?;
+    Ok(())
+}
+
+// Derives the album gain of album `id` from the loudness of its tracks,
+// weighted by duration, and its peak from the loudest of their peaks. Tracks
+// tagged with an album gain keep it.
+async fn update_album_gain(id: i32, tx: &Transaction<'_>) -> std::result::Result<(), tokio_postgres::Error> {
+    let stmt = tx.prepare("
+        WITH A AS (
+            SELECT $2 - 10 * log(SUM(duration_ms * power(10, loudness::float8 / 10)) / SUM(duration_ms)::float8) AS gain,
+                MAX(replaygain_track_peak) AS peak
+            FROM track
+            WHERE album_id = $1 AND available AND loudness > $3 AND duration_ms > 0
+        )
+        UPDATE track T
+        SET replaygain_album_gain = A.gain, replaygain_album_peak = A.peak
+        FROM A
+        WHERE T.album_id = $1 AND A.gain IS NOT NULL AND NOT T.replaygain_album_tagged
+    ").await?;
+    tx.execute(&stmt, &[&id, &(REPLAYGAIN_REFERENCE as f64), &(av::loudness::ABSOLUTE_GATE as f32)]).await?;
+    Ok(())
+}
+
+// Replaces the credits of the album or track `id`. `table` is one of the
+// credit join tables and `key` its album or track column.
+async fn write_credits(table: &str, key: &str, id: i32, credits: &[Credit], tx: &Transaction<'_>)
+    -> std::result::Result<(), tokio_postgres::Error>
+{
+    tx.execute(format!("DELETE FROM {} WHERE {} = $1", table, key).as_str(), &[&id]).await?;
+    let stmt = tx.prepare(&format!("
+        INSERT INTO {} ({}, artist_id, position, credited_name, join_phrase)
+        VALUES ($1, $2, $3, $4, $5)
+    ", table, key)).await?;
+    for (position, credit) in credits.iter().enumerate() {
+        tx.execute(&stmt, &[&id, &credit.artist_id, &(position as i32), &credit.name, &credit.join_phrase]).await?;
+    }
+    Ok(())
+}
+
+// Surrogate artists and albums mean nothing once their tracks have been
+// matched elsewhere, so they are removed whether or not pruning is enabled.
+async fn prune_local_orphans(tx: &Transaction<'_>) -> Result<()> {
+    let pattern = format!("{}%", import::LOCAL_ID_PREFIX);
+    tx.execute("
+        DELETE FROM album R
+        WHERE R.mbid LIKE $1 AND NOT EXISTS (SELECT 1 FROM track T WHERE T.album_id = R.id)
+    ", &[&pattern]).await?;
+    tx.execute("
+        DELETE FROM artist A
+        WHERE A.mbid LIKE $1
+          AND NOT EXISTS (SELECT 1 FROM album R WHERE R.artist_id = A.id)
+          AND NOT EXISTS (SELECT 1 FROM album_artist C WHERE C.artist_id = A.id)
+          AND NOT EXISTS (SELECT 1 FROM track_artist C WHERE C.artist_id = A.id)
+    ", &[&pattern]).await?;
+    Ok(())
+}
+
+async fn log_sync(tx: &Transaction<'_>) -> Result<()> {
+    let log_sync_stmt = tx.prepare("INSERT INTO sync_event (time) VALUES ($1)").await?;
+    tx.query(&log_sync_stmt, &[&Utc::now()]).await?;
+    Ok(())
+}
+
+async fn last_sync_time(pool: &Pool) -> Result<Option<DateTime<Utc>>> {
+    let client = pool.get().await?;
+    let stmt = client.prepare("SELECT MAX(time) FROM sync_event").await?;
+    let rows = client.query(&stmt, &[]).await?;
+    if rows.is_empty() {
+        Ok(None)
+    } else {
+        Ok(rows[0].try_get::<'_, _, DateTime<Utc>>(0).ok())
+    }
+}
+
+async fn existing_artist_album(album_mbid: &str, artist_mbid: &str, tx: &Transaction<'_>)
+    -> std::result::Result<(Option<i32>, Option<i32>), tokio_postgres::Error>
+{
+    // Fix this query
+    let stmt = tx.prepare("
+        SELECT A.id, B.id
+        FROM artist A
+        LEFT OUTER JOIN album B
+            ON B.mbid = $1
+        WHERE A.mbid = $2
+    ").await?;
+    let rows = tx.query(&stmt, &[&album_mbid, &artist_mbid]).await?;
+    if rows.is_empty() {
+        Ok((None, None))
+    } else {
+        Ok((rows[0].try_get::<'_, _, i32>(0).ok(),
+            rows[0].try_get::<'_, _, i32>(1).ok()))
+    }
+}
+
+// Returns the surrogate artist and album of the track at `file_location`, if
+// it was imported offline.
+async fn local_artist_album(file_location: &str, tx: &Transaction<'_>)
+    -> std::result::Result<(Option<i32>, Option<i32>), tokio_postgres::Error>
+{
+    let stmt = tx.prepare("
+        SELECT A.id, A.mbid, R.id, R.mbid
+        FROM track T
+        JOIN album R ON R.id = T.album_id
+        JOIN artist A ON A.id = R.artist_id
+        WHERE T.file_location = $1
+    ").await?;
+    match tx.query_opt(&stmt, &[&file_location]).await? {
+        Some(row) => {
+            let artist_mbid: String = row.get(1);
+            let album_mbid: String = row.get(3);
+            Ok((Some(row.get(0)).filter(|_| import::is_local_id(&artist_mbid)),
+                Some(row.get(2)).filter(|_| import::is_local_id(&album_mbid))))
+        }
+        None => Ok((None, None)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Connects to the database in TEST_DATABASE_URL. Tests create temporary
+    // tables, which shadow the real ones, in a transaction that's never
+    // committed, so nothing is left behind. They're ignored by default; run
+    // them with
+    //
+    //     TEST_DATABASE_URL=postgres://... cargo test -- --ignored
+    async fn test_client() -> deadpool_postgres::Client {
+        let url = std::env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL must be set to run database tests");
+        let manager = deadpool_postgres::Manager::new(url.parse().unwrap(), tokio_postgres::NoTls);
+        Pool::new(manager, 1).get().await.unwrap()
+    }
+
+    async fn create_tracks(tx: &Transaction<'_>) {
+        tx.batch_execute("
+            CREATE TEMP TABLE track (
+                id integer PRIMARY KEY,
+                mbid text UNIQUE NOT NULL,
+                file_location text NOT NULL,
+                available boolean NOT NULL
+            );
+            CREATE TEMP TABLE playlist_track (track_id integer NOT NULL REFERENCES track (id) ON DELETE CASCADE);
+            CREATE TEMP TABLE play_event (track_id integer NOT NULL REFERENCES track (id) ON DELETE CASCADE);
+        ").await.unwrap();
+    }
+
+    async fn track_rows(tx: &Transaction<'_>, q: &str) -> Vec<(i32, String)> {
+        tx.query(q, &[]).await.unwrap().iter().map(|r| (r.get(0), r.get(1))).collect()
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn relinks_moved_file() {
+        let mut client = test_client().await;
+        let tx = client.transaction().await.unwrap();
+        create_tracks(&tx).await;
+        tx.batch_execute("INSERT INTO track VALUES (1, 'a', '/old.flac', false)").await.unwrap();
+
+        assert!(relink_track("a", "/new.flac", &tx).await.unwrap());
+        assert_eq!(track_rows(&tx, "SELECT id, file_location FROM track").await, vec![(1, "/new.flac".to_string())]);
+        assert!(!relink_track("b", "/other.flac", &tx).await.unwrap());
+    }
+
+    // An existing file retagged as a recording whose own file was deleted
+    #[tokio::test]
+    #[ignore]
+    async fn merges_into_retagged_file() {
+        let mut client = test_client().await;
+        let tx = client.transaction().await.unwrap();
+        create_tracks(&tx).await;
+        tx.batch_execute("
+            INSERT INTO track VALUES (1, 'a', '/old.flac', false), (2, 'b', '/new.flac', true);
+            INSERT INTO playlist_track VALUES (1), (2);
+            INSERT INTO play_event VALUES (1);
+        ").await.unwrap();
+
+        assert!(relink_track("a", "/new.flac", &tx).await.unwrap());
+        assert_eq!(track_rows(&tx, "SELECT id, file_location FROM track").await, vec![(2, "/new.flac".to_string())]);
+        let playlist: Vec<i32> = tx.query("SELECT track_id FROM playlist_track", &[]).await.unwrap().iter().map(|r| r.get(0)).collect();
+        assert_eq!(playlist, vec![2, 2]);
+        let plays: Vec<i32> = tx.query("SELECT track_id FROM play_event", &[]).await.unwrap().iter().map(|r| r.get(0)).collect();
+        assert_eq!(plays, vec![2]);
+
+        // The retagged row can now take the recording's mbid
+        tx.execute("UPDATE track SET mbid = 'a' WHERE file_location = '/new.flac'", &[]).await.unwrap();
+    }
+}
@@ -1,134 +1,106 @@
 use std::env;
-use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+use std::sync::Arc;
 
-use chrono::{DateTime, NaiveDateTime, Utc};
-use tokio::time::{delay_for, Duration};
 use deadpool_postgres::{Config, Pool};
 use tokio_postgres::{NoTls};
-use walkdir::WalkDir;
 
-mod av;
+mod images;
 mod import;
+mod library;
 mod metadata;
 mod models;
-mod utils;
+mod watch;
 
-use metadata::providers::{MBClient, SpotifyClient};
+use images::ImageStore;
+use library::{Library, SyncOptions};
+use metadata::providers::{musicbrainz, spotify, MBClient, MetadataProvider, ProviderChain, ResponseCache, SpotifyClient};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let music_dir = env::var("MUSIC_DIR_ROOT").expect("MUSIC_DIR_ROOT environment variable not set");
+    let args: Vec<String> = env::args().skip(1).collect();
+    let watch = args.iter().any(|a| a == "--watch");
+    let options = SyncOptions {
+        prune: args.iter().any(|a| a == "--prune"),
+        offline: args.iter().any(|a| a == "--offline"),
+    };
 
     let pool = create_pool()?;
 
-    let mb_client = MBClient::new()?;
-    let spotify_client = SpotifyClient::new();
-
-    let last_sync = last_sync_time(pool.clone()).await?.unwrap_or(DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp(0, 0), Utc));
-
-    let walker = WalkDir::new(music_dir)
-        .into_iter()
-        .filter_map(Result::ok)
-        .filter(|e| !e.file_type().is_dir())
-        .filter(|e| match e.path().extension() {
-            Some(ext) => {
-                match ext.to_str() {
-                    Some("flac") | Some("mp3") => true,
-                    _ => false,
-                }
-            }
-            None => false,
-        });
-
-    let mut client = pool.get().await?;
-    let tx = client.transaction().await?;
-
-    for entry in walker {
-        let md = entry.metadata()?;
-        let ndt = NaiveDateTime::from_timestamp(md.ctime(), md.ctime_nsec() as u32);
-        let entry_ctime = DateTime::<Utc>::from_utc(ndt, Utc);
-
-        if entry_ctime < last_sync {
-            continue;
-        }
-
-        let c = entry.path().to_str().unwrap();
-        let track: av::metadata::Track<'_> = av::metadata::Track::new(c)?;
-        let imp = import::TrackImporter::new(mb_client.clone(), spotify_client.clone(), &track);
-        let (ac, rel, rec) = imp.find_match().await;
-
-        println!("{} {} {}", ac.artist.id, rel.id, rec.id.as_ref().unwrap());
-
-        let (mut existing_artist, mut existing_album) =
-            existing_artist_album(&rel.id, &ac.artist.id, &tx).await?;
-
-        if existing_artist.is_none() {
-            let artist = imp.build_artist(&ac).await;
-            let insert_artist_stmt = tx.prepare("INSERT INTO artist (mbid, name, image_url) VALUES ($1, $2, $3) RETURNING id").await?;
-            let row = tx.query(&insert_artist_stmt, &[&artist.mbid, &artist.name, &artist.image_url]).await?;
-            let artist_id: i32 = row[0].get(0);
-            existing_artist = Some(artist_id);
-        }
-
-        if existing_album.is_none() {
-            let album = imp.build_album(&rel, &ac).await;
-            let insert_album_stmt = tx.prepare("INSERT INTO album (mbid, title, image_url, artist_id) VALUES ($1, $2, $3, $4) RETURNING id").await?;
-            let row = tx.query(&insert_album_stmt, &[&album.mbid, &album.name, &album.image_url, &existing_artist.as_ref().unwrap()]).await?;
-            let album_id: i32 = row[0].get(0);
-            existing_album = Some(album_id);
-        }
-
-        let track = imp.build_track(&rec, &rel);
-        let insert_track_stmt = tx.prepare("INSERT INTO track (mbid, title, position, bit_rate, duration, file_location, album_id) VALUES ($1, $2, $3, $4, $5, $6, $7)").await?;
-        tx.query(&insert_track_stmt, &[&track.mbid, &track.title, &(track.position as i32), &(track.bitrate as i32), &(track.duration as i32), &track.file_location, &existing_album.as_ref().unwrap()]).await?;
-
-        println!("Imported {} / {} by {}", track.title, rel.title, ac.artist.name);
+    // Offline imports never ask a provider, so they don't need credentials
+    // for one either
+    let providers = if options.offline {
+        ProviderChain::new(Vec::new())
+    } else {
+        // --no-cache skips the provider cache entirely, for debugging matches
+        // against fresh responses
+        let cache = if args.iter().any(|a| a == "--no-cache") {
+            None
+        } else {
+            let cache = ResponseCache::new(pool.clone());
+            cache.purge_expired().await?;
+            Some(cache)
+        };
+        create_providers(cache)?
+    };
 
-        delay_for(Duration::from_secs(6)).await;
+    let mut library = Library::new(pool, providers, PathBuf::from(music_dir), options);
+    // Without an image store, artwork is linked to where providers host it
+    if let Ok(dir) = env::var("IMAGE_STORE_DIR") {
+        library = library.with_image_store(ImageStore::new(PathBuf::from(dir)));
     }
 
-    log_sync(&tx).await?;
-    tx.commit().await?;
-
-    Ok(())
-}
-
-async fn log_sync(tx: &deadpool_postgres::Transaction<'_>) -> Result<(), Box<dyn std::error::Error>> {
-    let log_sync_stmt = tx.prepare("INSERT INTO sync_event (time) VALUES ($1)").await?;
-    tx.query(&log_sync_stmt, &[&Utc::now()]).await?;
-    Ok(())
-
-}
-
-async fn last_sync_time(pool: Pool) -> Result<Option<DateTime<Utc>>, Box<dyn std::error::Error>> {
-    let client = pool.get().await?;
-    let stmt = client.prepare("SELECT MAX(time) FROM sync_event").await?;
-    let rows = client.query(&stmt, &[]).await?;
-    if rows.is_empty() {
-        Ok(None)
+    if watch {
+        watch::run(&library).await
     } else {
-        Ok(rows[0].try_get::<'_, _, DateTime<Utc>>(0).ok())
+        library.sync().await
     }
 }
 
-async fn existing_artist_album(album_mbid: &str, artist_mbid: &str, tx: &deadpool_postgres::Transaction<'_>)
-    -> Result<(Option<i32>, Option<i32>), Box<dyn std::error::Error>>
-{
-    // Fix this query
-    let stmt = tx.prepare("
-        SELECT A.id, B.id
-        FROM artist A
-        LEFT OUTER JOIN album B
-            ON B.mbid = $1
-        WHERE A.mbid = $2
-    ").await?;
-    let rows = tx.query(&stmt, &[&album_mbid, &artist_mbid]).await?;
-    if rows.is_empty() {
-        Ok((None, None))
-    } else {
-        Ok((rows[0].try_get::<'_, _, i32>(0).ok(),
-            rows[0].try_get::<'_, _, i32>(1).ok()))
+// Providers are asked in the order given in METADATA_PROVIDERS
+fn create_providers(cache: Option<ResponseCache>) -> Result<ProviderChain, Box<dyn std::error::Error>> {
+    let names = env::var("METADATA_PROVIDERS").unwrap_or_else(|_| "musicbrainz,spotify".to_string());
+    let mut providers: Vec<Arc<dyn MetadataProvider>> = Vec::new();
+
+    for name in names.split(',') {
+        match name.trim() {
+            "musicbrainz" => {
+                let mut client = match env::var("MUSICBRAINZ_URL") {
+                    Ok(url) => {
+                        let ca_url = env::var("COVERART_URL").unwrap_or_else(|_| musicbrainz::client::CA_API_BASE_URL.to_string());
+                        MBClient::with_base_urls(&url, &ca_url)?
+                    }
+                    Err(_) => MBClient::new()?,
+                };
+                if let Some(cache) = cache.clone() {
+                    client = client.with_cache(cache);
+                }
+                providers.push(Arc::new(client));
+            }
+            "spotify" => {
+                let credentials = spotify::client::Credentials {
+                    client_id: env::var("CLIENT_ID").map_err(|_| "CLIENT_ID must be set for the spotify provider")?,
+                    client_secret: env::var("CLIENT_SECRET").map_err(|_| "CLIENT_SECRET must be set for the spotify provider")?,
+                };
+                let mut client = match env::var("SPOTIFY_API_URL") {
+                    Ok(url) => {
+                        let token_url = env::var("SPOTIFY_TOKEN_URL").unwrap_or_else(|_| spotify::client::TOKEN_URL.to_string());
+                        SpotifyClient::with_base_urls(&url, &token_url, credentials)
+                    }
+                    Err(_) => SpotifyClient::new(credentials),
+                };
+                if let Some(cache) = cache.clone() {
+                    client = client.with_cache(cache);
+                }
+                providers.push(Arc::new(client));
+            }
+            "" => {}
+            other => return Err(format!("unknown metadata provider `{}`", other).into()),
+        }
     }
+
+    Ok(ProviderChain::new(providers))
 }
 
 fn create_pool() -> Result<Pool, Box<dyn std::error::Error>> {
@@ -0,0 +1,73 @@
+use chrono::{Duration, Utc};
+use deadpool_postgres::Pool;
+
+// Provider responses kept in the `provider_cache` table, keyed by request
+// URL, so reimporting a library doesn't refetch what it already has.
+//
+// The cache is only an optimisation, so database errors are logged and
+// treated as misses rather than failing the lookup.
+#[derive(Clone)]
+pub struct ResponseCache {
+    pool: Pool,
+}
+
+pub struct CachedResponse {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+impl ResponseCache {
+    pub fn new(pool: Pool) -> ResponseCache {
+        ResponseCache {
+            pool,
+        }
+    }
+
+    pub async fn get(&self, key: &str) -> Option<CachedResponse> {
+        match self.try_get(key).await {
+            Ok(res) => res,
+            Err(e) => {
+                eprintln!("Failed to read provider cache: {}", e);
+                None
+            }
+        }
+    }
+
+    pub async fn put(&self, key: &str, status: u16, body: &[u8], ttl: Duration) {
+        if let Err(e) = self.try_put(key, status, body, ttl).await {
+            eprintln!("Failed to write provider cache: {}", e);
+        }
+    }
+
+    pub async fn purge_expired(&self) -> Result<u64, Box<dyn std::error::Error>> {
+        let client = self.pool.get().await?;
+        let n = client.execute("DELETE FROM provider_cache WHERE expires_at <= $1", &[&Utc::now()]).await?;
+        Ok(n)
+    }
+
+    async fn try_get(&self, key: &str) -> Result<Option<CachedResponse>, Box<dyn std::error::Error>> {
+        let client = self.pool.get().await?;
+        let stmt = client.prepare("SELECT status, body FROM provider_cache WHERE key = $1 AND expires_at > $2").await?;
+        let row = client.query_opt(&stmt, &[&key, &Utc::now()]).await?;
+        Ok(row.map(|row| CachedResponse {
+            status: row.get::<'_, _, i32>(0) as u16,
+            body: row.get(1),
+        }))
+    }
+
+    async fn try_put(&self, key: &str, status: u16, body: &[u8], ttl: Duration) -> Result<(), Box<dyn std::error::Error>> {
+        let client = self.pool.get().await?;
+        let now = Utc::now();
+        let stmt = client.prepare("
+            INSERT INTO provider_cache (key, status, body, fetched_at, expires_at)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (key) DO UPDATE
+            SET status = EXCLUDED.status,
+                body = EXCLUDED.body,
+                fetched_at = EXCLUDED.fetched_at,
+                expires_at = EXCLUDED.expires_at
+        ").await?;
+        client.execute(&stmt, &[&key, &(status as i32), &body, &now, &(now + ttl)]).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,87 @@
+use std::fmt;
+use std::sync::Arc;
+
+use futures::future::LocalBoxFuture;
+
+use av::metadata::Track as AVTrack;
+
+use super::{ArtistDetails, MetadataProvider};
+use super::musicbrainz::entities::{ArtistCredit, Recording, Release};
+
+type Result<T> = std::result::Result<T, ProviderError>;
+
+#[derive(Debug)]
+pub struct ProviderError {
+    pub provider: &'static str,
+    pub source: Box<dyn std::error::Error>,
+}
+
+impl fmt::Display for ProviderError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}: {}", self.provider, self.source)
+    }
+}
+
+impl std::error::Error for ProviderError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.source.as_ref())
+    }
+}
+
+// Providers in order of preference. Each lookup goes to the providers in
+// turn and returns the first answer.
+#[derive(Clone)]
+pub struct ProviderChain {
+    providers: Vec<Arc<dyn MetadataProvider>>,
+}
+
+impl ProviderChain {
+    pub fn new(providers: Vec<Arc<dyn MetadataProvider>>) -> ProviderChain {
+        ProviderChain {
+            providers,
+        }
+    }
+
+    pub async fn search_recordings(&self, track: &AVTrack<'_>) -> Result<Option<Vec<Recording>>> {
+        self.first("search recordings", |p| p.search_recordings(track)).await
+    }
+
+    pub async fn artist(&self, mbid: &str) -> Result<Option<ArtistDetails>> {
+        self.first("artist lookup", |p| p.artist(mbid)).await
+    }
+
+    pub async fn artist_image(&self, artist: &ArtistDetails) -> Result<Option<String>> {
+        self.first("artist image lookup", |p| p.artist_image(artist)).await
+    }
+
+    pub async fn release_artwork(&self, release: &Release, artist_credit: &ArtistCredit) -> Result<Option<String>> {
+        self.first("artwork lookup", |p| p.release_artwork(release, artist_credit)).await
+    }
+
+    // A provider that fails is skipped rather than failing the lookup. The
+    // error is only returned if no other provider had an answer.
+    async fn first<'p, T, F>(&'p self, op: &str, f: F) -> Result<Option<T>>
+    where
+        F: Fn(&'p dyn MetadataProvider) -> LocalBoxFuture<'p, std::result::Result<Option<T>, Box<dyn std::error::Error>>>,
+    {
+        let mut error = None;
+        for provider in &self.providers {
+            match f(provider.as_ref()).await {
+                Ok(Some(res)) => return Ok(Some(res)),
+                Ok(None) => {}
+                Err(e) => {
+                    eprintln!("{} {} failed: {}", provider.name(), op, e);
+                    error = Some(ProviderError {
+                        provider: provider.name(),
+                        source: e,
+                    });
+                }
+            }
+        }
+
+        match error {
+            Some(e) => Err(e),
+            None => Ok(None),
+        }
+    }
+}
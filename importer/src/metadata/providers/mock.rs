@@ -0,0 +1,125 @@
+// A stand-in for the provider APIs that replays recorded responses from
+// tests/fixtures, so matching can be tested without the network.
+
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use hyper::{Body, Request, Response, Server, StatusCode};
+use hyper::service::{make_service_fn, service_fn};
+use tokio::sync::oneshot;
+
+struct State {
+    // Request path to fixture file, relative to tests/fixtures
+    routes: HashMap<String, String>,
+    requests: Mutex<Vec<String>>,
+    // Error statuses, and Retry-After seconds, to answer with before
+    // serving the fixture
+    failures: Mutex<HashMap<String, VecDeque<(StatusCode, Option<u64>)>>>,
+}
+
+pub struct MockServer {
+    addr: SocketAddr,
+    state: Arc<State>,
+    shutdown: Option<oneshot::Sender<()>>,
+}
+
+impl MockServer {
+    // Serves each fixture at its path. Anything else gets a 404, which is
+    // also how Cover Art Archive answers for releases it doesn't have.
+    pub fn start(routes: &[(&str, &str)]) -> MockServer {
+        let state = Arc::new(State {
+            routes: routes.iter().map(|(p, f)| (p.to_string(), f.to_string())).collect(),
+            requests: Mutex::new(Vec::new()),
+            failures: Mutex::new(HashMap::new()),
+        });
+
+        let svc_state = state.clone();
+        let make_svc = make_service_fn(move |_| {
+            let state = svc_state.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| handle(req, state.clone())))
+            }
+        });
+
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let addr = server.local_addr();
+        let (tx, rx) = oneshot::channel::<()>();
+        tokio::spawn(server.with_graceful_shutdown(async {
+            rx.await.ok();
+        }));
+
+        MockServer {
+            addr,
+            state,
+            shutdown: Some(tx),
+        }
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.addr, path)
+    }
+
+    // Answers the next request for `path` with `status` instead of its
+    // fixture. Calls queue up.
+    pub fn fail_next(&self, path: &str, status: StatusCode, retry_after: Option<u64>) {
+        self.state.failures.lock().unwrap()
+            .entry(path.to_string())
+            .or_default()
+            .push_back((status, retry_after));
+    }
+
+    // Every request received so far, as path and query
+    pub fn requests(&self) -> Vec<String> {
+        self.state.requests.lock().unwrap().clone()
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        if let Some(tx) = self.shutdown.take() {
+            let _ = tx.send(());
+        }
+    }
+}
+
+pub fn fixture_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name)
+}
+
+async fn handle(req: Request<Body>, state: Arc<State>) -> Result<Response<Body>, Infallible> {
+    let uri = req.uri();
+    let request = match uri.query() {
+        Some(q) => format!("{}?{}", uri.path(), q),
+        None => uri.path().to_string(),
+    };
+    state.requests.lock().unwrap().push(request);
+
+    let failure = state.failures.lock().unwrap()
+        .get_mut(uri.path())
+        .and_then(|f| f.pop_front());
+    if let Some((status, retry_after)) = failure {
+        let mut res = Response::builder().status(status);
+        if let Some(secs) = retry_after {
+            res = res.header("Retry-After", secs.to_string());
+        }
+        return Ok(res.body(Body::empty()).unwrap());
+    }
+
+    let fixture = state.routes.get(uri.path()).map(|f| std::fs::read(fixture_path(f)));
+    let res = match fixture {
+        Some(Ok(body)) => Response::builder()
+            .header("Content-Type", "application/json")
+            .body(Body::from(body)),
+        Some(Err(e)) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::from(e.to_string())),
+        None => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty()),
+    };
+
+    Ok(res.unwrap())
+}
@@ -1,5 +1,60 @@
+use async_trait::async_trait;
+
+use av::metadata::Track as AVTrack;
+
+pub mod cache;
+#[cfg(test)]
+pub mod mock;
 pub mod musicbrainz;
 pub mod spotify;
 
+mod chain;
+
+pub use cache::ResponseCache;
+pub use chain::{ProviderChain, ProviderError};
 pub use musicbrainz::Client as MBClient;
 pub use spotify::Client as SpotifyClient;
+
+use musicbrainz::entities::{ArtistCredit, Recording, Release};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+// What providers know about an artist beyond its credit on a release
+#[derive(Clone, Debug)]
+pub struct ArtistDetails {
+    pub mbid: String,
+    pub name: String,
+    // Links to the artist on other services, e.g. Spotify or Discogs
+    pub urls: Vec<String>,
+}
+
+// A source of metadata for the importer. Entities are described in
+// MusicBrainz terms, since that is what tracks are matched against.
+//
+// Each lookup returns Ok(None) when the provider has nothing to say, so
+// that the next provider in the chain is asked. Providers only need to
+// implement the lookups they support.
+#[async_trait(?Send)]
+pub trait MetadataProvider {
+    fn name(&self) -> &'static str;
+
+    async fn search_recordings(&self, _track: &AVTrack<'_>) -> Result<Option<Vec<Recording>>> {
+        Ok(None)
+    }
+
+    async fn artist(&self, _mbid: &str) -> Result<Option<ArtistDetails>> {
+        Ok(None)
+    }
+
+    async fn release(&self, _mbid: &str) -> Result<Option<Release>> {
+        Ok(None)
+    }
+
+    async fn artist_image(&self, _artist: &ArtistDetails) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    async fn release_artwork(&self, _release: &Release, _artist_credit: &ArtistCredit) -> Result<Option<String>> {
+        Ok(None)
+    }
+}
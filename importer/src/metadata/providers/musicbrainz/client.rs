@@ -1,18 +1,44 @@
+use std::sync::Arc;
+
 use regex::Regex;
+use reqwest::{RequestBuilder, Response, StatusCode};
 use reqwest::header::{self, HeaderMap};
 use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use tokio::time::{delay_for, Duration};
 
-use super::entities::{CoverArtImage, Recording, Relation};
+use super::entities::{CoverArtImage, Recording, Relation, Release};
+use super::rate_limit::TokenBucket;
+use crate::metadata::providers::cache::ResponseCache;
 
-const API_BASE_URL: &'static str = "http://musicbrainz.org/ws/2";
-const CA_API_BASE_URL: &'static str = "http://coverartarchive.org";
+pub const API_BASE_URL: &'static str = "http://musicbrainz.org/ws/2";
+pub const CA_API_BASE_URL: &'static str = "http://coverartarchive.org";
 const DOPLR_VERSION: &'static str = env!("CARGO_PKG_VERSION");
 
+// MusicBrainz allows an average of one request per second per client
+const RATE_LIMIT_INTERVAL: Duration = Duration::from_secs(1);
+const RATE_LIMIT_BURST: u32 = 1;
+// Retries for 503s, 429s and connection failures, with the wait doubling
+// each time unless the server says how long to wait
+const MAX_RETRIES: u32 = 5;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+// How long responses are cached. Entities rarely change once they are
+// matched, search results a little more often.
+fn lookup_ttl() -> chrono::Duration { chrono::Duration::days(30) }
+fn search_ttl() -> chrono::Duration { chrono::Duration::days(7) }
+fn not_found_ttl() -> chrono::Duration { chrono::Duration::days(1) }
+
 type Result<T> = std::result::Result<T, super::Error>;
 
 #[derive(Clone)]
 pub struct Client {
     http: reqwest::Client,
+    api_base_url: String,
+    ca_api_base_url: String,
+    limiter: Arc<TokenBucket>,
+    cache: Option<ResponseCache>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -46,51 +72,142 @@ pub struct CoverArtResponse {
 
 impl Client {
     pub fn new() -> Result<Self> {
+        Self::with_base_urls(API_BASE_URL, CA_API_BASE_URL)
+    }
+
+    // Points the client at a MusicBrainz mirror or a stand-in server
+    pub fn with_base_urls(api_base_url: &str, ca_api_base_url: &str) -> Result<Self> {
         let http = reqwest::ClientBuilder::new()
             .default_headers(Self::default_headers())
             .build()?;
 
         Ok(Client {
-            http
+            http,
+            api_base_url: api_base_url.trim_end_matches('/').to_string(),
+            ca_api_base_url: ca_api_base_url.trim_end_matches('/').to_string(),
+            limiter: Arc::new(TokenBucket::new(RATE_LIMIT_BURST, RATE_LIMIT_INTERVAL)),
+            cache: None,
         })
     }
 
+    pub fn with_cache(mut self, cache: ResponseCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
     pub async fn get_artist(&self, id: &str) -> Result<ArtistResponse> {
-        let url = format!("{}/artist/{}", API_BASE_URL, id);
-        let res = self.http.get(&url)
-            .query(&[("inc", "url-rels"), ("fmt", "json")])
-            .send()
-            .await?;
-        let buf = res.bytes().await?;
-        let res: ArtistResponse = serde_json::from_reader(buf.as_ref()).unwrap();
-        Ok(res)
+        let url = format!("{}/artist/{}", self.api_base_url, id);
+        let req = self.http.get(&url)
+            .query(&[("inc", "url-rels"), ("fmt", "json")]);
+        self.get_json(req, lookup_ttl()).await
     }
 
-    pub async fn search_recordings(&self, track: &crate::av::metadata::Track<'_>) -> Result<SearchResponse> {
+    pub async fn get_release(&self, id: &str) -> Result<Release> {
+        let url = format!("{}/release/{}", self.api_base_url, id);
+        let req = self.http.get(&url)
+            .query(&[("inc", "artist-credits recordings"), ("fmt", "json")]);
+        self.get_json(req, lookup_ttl()).await
+    }
+
+    pub async fn search_recordings(&self, track: &av::metadata::Track<'_>) -> Result<SearchResponse> {
         let q = build_query_from_track(track);
         println!("{}", q);
-        let url = API_BASE_URL.to_string() + "/recording";
-        let res = self.http.get(&url)
-            .query(&[("query", q), ("fmt", "json".to_string())])
-            .send()
-            .await?;
-        let buf = res.bytes().await?;
-        let res: SearchResponse = serde_json::from_reader(buf.as_ref()).unwrap();
-        Ok(res)
+        let url = format!("{}/recording", self.api_base_url);
+        let req = self.http.get(&url)
+            .query(&[("query", q), ("fmt", "json".to_string())]);
+        self.get_json(req, search_ttl()).await
     }
 
     pub async fn get_cover_art(&self, release_id: &str) -> Result<Option<CoverArtResponse>> {
-        let url = format!("{}/release/{}", CA_API_BASE_URL, release_id);
-        let res = self.http.get(&url)
-            .query(&[("inc", "url-rels")])
-            .send()
-            .await?;
-        if res.status().is_success() {
-            let buf = res.bytes().await?;
-            let res: CoverArtResponse = serde_json::from_reader(buf.as_ref()).unwrap();
-            Ok(Some(res))
-        } else {
-            Ok(None)
+        let url = format!("{}/release/{}", self.ca_api_base_url, release_id);
+        let req = self.http.get(&url)
+            .query(&[("inc", "url-rels")]);
+        // Cover Art Archive isn't subject to the MusicBrainz rate limit
+        let (status, buf) = self.fetch(req, false, lookup_ttl()).await?;
+        match status {
+            StatusCode::NOT_FOUND => Ok(None),
+            s if s.is_success() => Ok(Some(serde_json::from_slice(&buf)?)),
+            s => Err(super::Error::StatusError(s)),
+        }
+    }
+
+    async fn get_json<T: DeserializeOwned>(&self, req: RequestBuilder, ttl: chrono::Duration) -> Result<T> {
+        let (status, buf) = self.fetch(req, true, ttl).await?;
+        if !status.is_success() {
+            return Err(super::Error::StatusError(status));
+        }
+        Ok(serde_json::from_slice(&buf)?)
+    }
+
+    // Returns the status and body for `req`, from the cache if possible.
+    // Successful responses are cached for `ttl`, and 404s for a day so that
+    // missing cover art isn't asked for on every track of an album.
+    async fn fetch(&self, req: RequestBuilder, rate_limited: bool, ttl: chrono::Duration) -> Result<(StatusCode, Vec<u8>)> {
+        let key = req.try_clone()
+            .and_then(|r| r.build().ok())
+            .map(|r| r.url().to_string());
+
+        if let (Some(cache), Some(key)) = (self.cache.as_ref(), key.as_ref()) {
+            if let Some(hit) = cache.get(key).await {
+                if let Ok(status) = StatusCode::from_u16(hit.status) {
+                    return Ok((status, hit.body));
+                }
+            }
+        }
+
+        let res = self.send(req, rate_limited).await?;
+        let status = res.status();
+        let buf = res.bytes().await?.to_vec();
+
+        if let (Some(cache), Some(key)) = (self.cache.as_ref(), key.as_ref()) {
+            if status.is_success() {
+                cache.put(key, status.as_u16(), &buf, ttl).await;
+            } else if status == StatusCode::NOT_FOUND {
+                cache.put(key, status.as_u16(), &buf, not_found_ttl()).await;
+            }
+        }
+
+        Ok((status, buf))
+    }
+
+    // Sends `req`, retrying when the server is overloaded or unreachable.
+    // Any other response, successful or not, is returned as is.
+    async fn send(&self, req: RequestBuilder, rate_limited: bool) -> Result<Response> {
+        let mut backoff = INITIAL_BACKOFF;
+        let mut attempt = 0;
+
+        loop {
+            if rate_limited {
+                self.limiter.acquire().await;
+            }
+
+            // Only GETs without bodies are sent, so this never fails
+            let res = req.try_clone().expect("request can't be retried").send().await;
+            let wait = match res {
+                Ok(res) if is_retryable(res.status()) => {
+                    if attempt >= MAX_RETRIES {
+                        return Err(super::Error::StatusError(res.status()));
+                    }
+                    retry_after(&res).unwrap_or(backoff)
+                }
+                Ok(res) => return Ok(res),
+                Err(e) if e.is_timeout() || e.is_connect() => {
+                    if attempt >= MAX_RETRIES {
+                        return Err(e.into());
+                    }
+                    backoff
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            eprintln!("Request failed, retrying in {}s", wait.as_secs());
+            if rate_limited {
+                self.limiter.pause(wait);
+            } else {
+                delay_for(wait).await;
+            }
+            attempt += 1;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
         }
     }
 
@@ -105,7 +222,20 @@ impl Client {
     }
 }
 
-fn build_query_from_track(track: &crate::av::metadata::Track<'_>) -> String {
+fn is_retryable(status: StatusCode) -> bool {
+    status == StatusCode::SERVICE_UNAVAILABLE || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+// Only the delay-seconds form is handled, which is what MusicBrainz sends
+fn retry_after(res: &Response) -> Option<Duration> {
+    res.headers()
+        .get(header::RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .map(|secs| Duration::from_secs(secs).min(MAX_BACKOFF))
+}
+
+fn build_query_from_track(track: &av::metadata::Track<'_>) -> String {
     let md = track.metadata();
 
     let mut fields = Vec::new();
@@ -120,7 +250,7 @@ fn build_query_from_track(track: &crate::av::metadata::Track<'_>) -> String {
         .into_iter()
         .filter(|m| m.is_some())
         .filter_map(|m| {
-            use crate::av::metadata::MetadataValue::*;
+            use av::metadata::MetadataValue::*;
             match m {
                 Some(Album(a)) => Some(format!("release:{}", escape_query(&normalize_album_title(&a)))),
                 Some(Artist(a)) => Some(format!("(artist:{0} OR artistname:{0} OR creditname:{0})", escape_query(&normalize_track_title(&a)))),
@@ -157,3 +287,54 @@ fn escape_query(s: &str) -> String {
     let r = Regex::new(r"[^\p{L}\p{Nd} ]").unwrap();
     r.replace_all(s, " ").to_owned().to_string()
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::Instant;
+
+    use super::*;
+    use crate::metadata::providers::mock::MockServer;
+    use crate::metadata::providers::musicbrainz::Error;
+
+    const RADIOHEAD: &str = "a74b1b7f-71a5-4011-9441-d0b5e4122711";
+
+    fn artist_server() -> (MockServer, Client) {
+        let path = format!("/ws/2/artist/{}", RADIOHEAD);
+        let server = MockServer::start(&[(&path, "musicbrainz/artist-radiohead.json")]);
+        let client = Client::with_base_urls(&server.url("/ws/2"), &server.url("/coverart")).unwrap();
+        (server, client)
+    }
+
+    #[tokio::test]
+    async fn retries_after_service_unavailable() {
+        let (server, client) = artist_server();
+        server.fail_next(&format!("/ws/2/artist/{}", RADIOHEAD), StatusCode::SERVICE_UNAVAILABLE, Some(1));
+
+        let start = Instant::now();
+        let artist = client.get_artist(RADIOHEAD).await.unwrap();
+        assert_eq!(artist.name, "Radiohead");
+        assert_eq!(server.requests().len(), 2);
+        assert!(start.elapsed() >= Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn limits_requests_to_one_per_second() {
+        let (_server, client) = artist_server();
+
+        let start = Instant::now();
+        for _ in 0..3 {
+            client.get_artist(RADIOHEAD).await.unwrap();
+        }
+        assert!(start.elapsed() >= Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn error_status_is_an_error() {
+        let (_server, client) = artist_server();
+
+        match client.get_artist("unknown").await {
+            Err(Error::StatusError(s)) => assert_eq!(s, StatusCode::NOT_FOUND),
+            other => panic!("expected a 404, got {:?}", other.map(|a| a.name)),
+        }
+    }
+}
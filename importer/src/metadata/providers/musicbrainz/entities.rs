@@ -29,6 +29,8 @@ pub struct Artist {
 pub struct ArtistCredit {
     pub name: Option<String>,
     pub artist: Artist,
+    // Joins this credit to the next, e.g. " feat. " or " & "
+    pub joinphrase: Option<String>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -129,6 +131,8 @@ pub struct Track {
 pub struct Medium {
     pub position: u16,
     pub format: Option<String>,
+    // Searches call the tracks `track`, lookups call them `tracks`
+    #[serde(alias = "tracks")]
     pub track: Vec<Track>,
     #[serde(rename = "track-count")]
     pub track_count: Option<u16>,
@@ -2,6 +2,9 @@
 pub enum Error {
     DecodingError(serde_json::Error),
     HTTPError(reqwest::Error),
+    // The server answered with an error, or was still unavailable after
+    // every retry
+    StatusError(reqwest::StatusCode),
 }
 
 impl std::fmt::Display for Error {
@@ -10,6 +13,7 @@ impl std::fmt::Display for Error {
         match self {
             DecodingError(e) => write!(fmt, "{}", e),
             HTTPError(e) => write!(fmt, "{}", e),
+            StatusError(s) => write!(fmt, "server responded with {}", s),
         }
     }
 }
@@ -20,6 +24,7 @@ impl std::error::Error for Error {
         match self {
             DecodingError(e) => Some(e),
             HTTPError(e) => Some(e),
+            StatusError(_) => None,
         }
     }
 }
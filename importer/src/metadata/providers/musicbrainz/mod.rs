@@ -1,8 +1,10 @@
 pub mod client;
 pub mod entities;
 pub mod error;
+mod provider;
+mod rate_limit;
 
 pub use client::{Client, SearchResponse, SearchResult};
 pub use error::Error;
 
-pub use crate::utils::lev::damlev;
+pub use lev::damlev;
@@ -0,0 +1,47 @@
+use async_trait::async_trait;
+
+use av::metadata::Track as AVTrack;
+
+use super::{Client, SearchResult};
+use super::entities::{ArtistCredit, Recording, Release};
+use crate::metadata::providers::{ArtistDetails, MetadataProvider};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+#[async_trait(?Send)]
+impl MetadataProvider for Client {
+    fn name(&self) -> &'static str {
+        "musicbrainz"
+    }
+
+    async fn search_recordings(&self, track: &AVTrack<'_>) -> Result<Option<Vec<Recording>>> {
+        let res = Client::search_recordings(self, track).await?;
+        match res.results {
+            SearchResult::Recordings(r) if r.is_empty() => Ok(None),
+            SearchResult::Recordings(r) => Ok(Some(r)),
+        }
+    }
+
+    async fn artist(&self, mbid: &str) -> Result<Option<ArtistDetails>> {
+        let artist = self.get_artist(mbid).await?;
+        Ok(Some(ArtistDetails {
+            mbid: mbid.to_string(),
+            name: artist.name,
+            urls: artist.relations.into_iter().filter_map(|r| r.url).map(|u| u.resource).collect(),
+        }))
+    }
+
+    async fn release(&self, mbid: &str) -> Result<Option<Release>> {
+        Ok(Some(self.get_release(mbid).await?))
+    }
+
+    // Cover Art Archive
+    async fn release_artwork(&self, release: &Release, _artist_credit: &ArtistCredit) -> Result<Option<String>> {
+        Ok(self.get_cover_art(&release.id).await?.and_then(|cover_art| {
+            cover_art.images
+                .into_iter()
+                .find(|i| i.front)
+                .map(|i| i.image)
+        }))
+    }
+}
@@ -0,0 +1,104 @@
+use std::sync::Mutex;
+
+use tokio::time::{delay_until, Duration, Instant};
+
+// A token bucket shared by every clone of a client. Tokens are added at a
+// fixed rate up to `capacity`, and each request takes one, waiting for the
+// next token if the bucket is empty.
+pub struct TokenBucket {
+    capacity: f64,
+    interval: Duration,
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    tokens: f64,
+    refilled_at: Instant,
+    // Set when the server asks us to back off. No tokens are handed out
+    // before then.
+    paused_until: Option<Instant>,
+}
+
+impl TokenBucket {
+    // One token every `interval`, with bursts of up to `capacity` requests
+    pub fn new(capacity: u32, interval: Duration) -> TokenBucket {
+        TokenBucket {
+            capacity: capacity as f64,
+            interval,
+            state: Mutex::new(BucketState {
+                tokens: capacity as f64,
+                refilled_at: Instant::now(),
+                paused_until: None,
+            }),
+        }
+    }
+
+    pub async fn acquire(&self) {
+        loop {
+            let wait_until = {
+                let mut state = self.state.lock().unwrap();
+                let now = Instant::now();
+                match state.paused_until {
+                    Some(until) if until > now => Some(until),
+                    _ => {
+                        state.paused_until = None;
+                        let elapsed = now.saturating_duration_since(state.refilled_at);
+                        let added = elapsed.as_secs_f64() / self.interval.as_secs_f64();
+                        state.tokens = (state.tokens + added).min(self.capacity);
+                        state.refilled_at = now;
+
+                        if state.tokens >= 1.0 {
+                            state.tokens -= 1.0;
+                            None
+                        } else {
+                            let missing = 1.0 - state.tokens;
+                            Some(now + self.interval.mul_f64(missing))
+                        }
+                    }
+                }
+            };
+
+            match wait_until {
+                Some(until) => delay_until(until).await,
+                None => return,
+            }
+        }
+    }
+
+    // Holds back every request for `duration`, and empties the bucket so
+    // requests resume at the steady rate rather than in a burst.
+    pub fn pause(&self, duration: Duration) {
+        let mut state = self.state.lock().unwrap();
+        let until = Instant::now() + duration;
+        if state.paused_until.map(|u| u < until).unwrap_or(true) {
+            state.paused_until = Some(until);
+        }
+        state.tokens = 0.0;
+        state.refilled_at = until;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn waits_for_tokens_once_empty() {
+        let bucket = TokenBucket::new(1, Duration::from_millis(100));
+        let start = Instant::now();
+        bucket.acquire().await;
+        assert!(start.elapsed() < Duration::from_millis(50));
+        bucket.acquire().await;
+        bucket.acquire().await;
+        assert!(start.elapsed() >= Duration::from_millis(190));
+    }
+
+    #[tokio::test]
+    async fn pause_holds_back_requests() {
+        let bucket = TokenBucket::new(5, Duration::from_millis(10));
+        let start = Instant::now();
+        bucket.pause(Duration::from_millis(200));
+        bucket.acquire().await;
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+}
@@ -1,40 +1,191 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
 use rspotify::client::Spotify;
-use rspotify::oauth2::SpotifyClientCredentials;
 use rspotify::model::album::{FullAlbum, SimplifiedAlbum};
 use rspotify::model::artist::FullArtist;
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use tokio::sync::Mutex;
+
+use crate::metadata::providers::cache::ResponseCache;
+
+pub const API_BASE_URL: &'static str = "https://api.spotify.com/v1";
+pub const TOKEN_URL: &'static str = "https://accounts.spotify.com/api/token";
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+fn lookup_ttl() -> chrono::Duration { chrono::Duration::days(30) }
+fn search_ttl() -> chrono::Duration { chrono::Duration::days(7) }
+
+// Tokens are renewed this long before Spotify says they expire, so one
+// doesn't run out mid-request
+const TOKEN_MARGIN: Duration = Duration::from_secs(60);
+
+// A Spotify app's client credentials
+#[derive(Clone)]
+pub struct Credentials {
+    pub client_id: String,
+    pub client_secret: String,
+}
+
+#[derive(Deserialize)]
+struct Token {
+    access_token: String,
+    expires_in: u64,
+}
 
 #[derive(Clone)]
 pub struct Client {
-    client: Spotify,
+    http: reqwest::Client,
+    prefix: String,
+    token_url: String,
+    credentials: Credentials,
+    token: Arc<Mutex<Option<(String, Instant)>>>,
+    cache: Option<ResponseCache>,
 }
 
 impl Client {
-    pub fn new() -> Client {
-        let client_credentials = SpotifyClientCredentials::default().build();
-        let client = Spotify::default()
-            .client_credentials_manager(client_credentials)
-            .build();
+    pub fn new(credentials: Credentials) -> Client {
+        Self::with_base_urls(API_BASE_URL, TOKEN_URL, credentials)
+    }
 
+    // Points the client at a stand-in server. rspotify always asks
+    // accounts.spotify.com for tokens, so they're fetched here instead.
+    pub fn with_base_urls(base_url: &str, token_url: &str, credentials: Credentials) -> Client {
         Client {
-            client
+            http: reqwest::Client::new(),
+            // rspotify joins endpoints onto the prefix, so it needs the slash
+            prefix: format!("{}/", base_url.trim_end_matches('/')),
+            token_url: token_url.to_string(),
+            credentials,
+            token: Arc::new(Mutex::new(None)),
+            cache: None,
         }
     }
 
-    pub async fn get_artist(&self, id: &str) -> Result<FullArtist, Box<dyn std::error::Error>> {
-        self.client.artist(id).await.map_err(|e| e.into())
+    pub fn with_cache(mut self, cache: ResponseCache) -> Self {
+        self.cache = Some(cache);
+        self
     }
 
-    pub async fn get_album(&self, id: &str) -> Result<FullAlbum, Box<dyn std::error::Error>> {
-        self.client.album(id).await.map_err(|e| e.into())
+    pub async fn get_artist(&self, id: &str) -> Result<FullArtist> {
+        let key = format!("{}artists/{}", self.prefix, id);
+        self.cached(&key, lookup_ttl(), |s| async move { s.artist(id).await }).await
     }
 
-    pub async fn search_album(&self, album: &str, artist: &str) -> Result<Vec<SimplifiedAlbum>, Box<dyn std::error::Error>> {
+    pub async fn get_album(&self, id: &str) -> Result<FullAlbum> {
+        let key = format!("{}albums/{}", self.prefix, id);
+        self.cached(&key, lookup_ttl(), |s| async move { s.album(id).await }).await
+    }
+
+    pub async fn search_album(&self, album: &str, artist: &str) -> Result<Vec<SimplifiedAlbum>> {
         let q = format!("album:{} artist:{}", album, artist);
-        Ok(self.client.search_album(&q, 10, 0, None).await?.albums.items)
+        let key = format!("{}search?type=album&limit=10&q={}", self.prefix, q);
+        let res = self.cached(&key, search_ttl(), |s| async move { s.search_album(&q, 10, 0, None).await }).await?;
+        Ok(res.albums.items)
     }
 
-    pub async fn search_artist(&self, artist: &str) -> Result<Vec<FullArtist>, Box<dyn std::error::Error>> {
+    pub async fn search_artist(&self, artist: &str) -> Result<Vec<FullArtist>> {
         let q = format!("artist:{}", artist);
-        Ok(self.client.search_artist(&q, 10, 0, None).await?.artists.items)
+        let key = format!("{}search?type=artist&limit=10&q={}", self.prefix, q);
+        let res = self.cached(&key, search_ttl(), |s| async move { s.search_artist(&q, 10, 0, None).await }).await?;
+        Ok(res.artists.items)
+    }
+
+    // rspotify makes its own requests, so results are cached as the JSON of
+    // the models it returns, keyed by the URL it would have requested.
+    async fn cached<T, E, F, Fut>(&self, key: &str, ttl: chrono::Duration, f: F) -> Result<T>
+    where
+        T: Serialize + DeserializeOwned,
+        E: Into<Box<dyn std::error::Error>>,
+        F: FnOnce(Spotify) -> Fut,
+        Fut: Future<Output = std::result::Result<T, E>>,
+    {
+        if let Some(cache) = self.cache.as_ref() {
+            if let Some(hit) = cache.get(key).await {
+                if let Ok(res) = serde_json::from_slice(&hit.body) {
+                    return Ok(res);
+                }
+            }
+        }
+
+        let spotify = self.spotify().await?;
+        let res = f(spotify).await.map_err(|e| e.into())?;
+
+        if let Some(cache) = self.cache.as_ref() {
+            if let Ok(body) = serde_json::to_vec(&res) {
+                cache.put(key, 200, &body, ttl).await;
+            }
+        }
+
+        Ok(res)
+    }
+
+    // An rspotify client with a current token, fetching a new one if it has
+    // expired
+    async fn spotify(&self) -> Result<Spotify> {
+        let mut token = self.token.lock().await;
+        if token.as_ref().map_or(true, |(_, expires_at)| Instant::now() >= *expires_at) {
+            let res = self.http.post(&self.token_url)
+                .basic_auth(&self.credentials.client_id, Some(&self.credentials.client_secret))
+                .form(&[("grant_type", "client_credentials")])
+                .send()
+                .await?
+                .error_for_status()?;
+            let t: Token = serde_json::from_slice(&res.bytes().await?)?;
+            let expires_at = Instant::now() + Duration::from_secs(t.expires_in).checked_sub(TOKEN_MARGIN).unwrap_or_default();
+            *token = Some((t.access_token, expires_at));
+        }
+
+        let (access_token, _) = token.as_ref().unwrap();
+        Ok(Spotify::default()
+            .prefix(&self.prefix)
+            .access_token(access_token)
+            .build())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::providers::mock::MockServer;
+
+    fn client(server: &MockServer) -> Client {
+        let credentials = Credentials {
+            client_id: "id".to_string(),
+            client_secret: "secret".to_string(),
+        };
+        Client::with_base_urls(&server.url("/spotify/v1"), &server.url("/spotify/token"), credentials)
+    }
+
+    #[tokio::test]
+    async fn reuses_token_until_it_expires() {
+        let server = MockServer::start(&[
+            ("/spotify/token", "spotify/token.json"),
+            ("/spotify/v1/search", "spotify/search-artist-radiohead.json"),
+        ]);
+        let client = client(&server);
+
+        for _ in 0..2 {
+            let artists = client.search_artist("Radiohead").await.unwrap();
+            assert_eq!(artists[0].name, "Radiohead");
+        }
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[0], "/spotify/token");
+        assert!(requests[1].starts_with("/spotify/v1/search?"));
+        assert!(requests[2].starts_with("/spotify/v1/search?"));
+    }
+
+    #[tokio::test]
+    async fn token_failure_is_an_error() {
+        let server = MockServer::start(&[("/spotify/v1/search", "spotify/search-artist-radiohead.json")]);
+        let client = client(&server);
+
+        assert!(client.search_artist("Radiohead").await.is_err());
+        assert_eq!(server.requests(), vec!["/spotify/token".to_string()]);
     }
 }
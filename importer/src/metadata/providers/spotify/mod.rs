@@ -1,3 +1,4 @@
 pub mod client;
+mod provider;
 
 pub use client::Client;
@@ -0,0 +1,54 @@
+use async_trait::async_trait;
+use lev::damlev;
+use regex::Regex;
+
+use super::Client;
+use crate::metadata::providers::{ArtistDetails, MetadataProvider};
+use crate::metadata::providers::musicbrainz::entities::{ArtistCredit, Release};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+#[async_trait(?Send)]
+impl MetadataProvider for Client {
+    fn name(&self) -> &'static str {
+        "spotify"
+    }
+
+    async fn artist_image(&self, artist: &ArtistDetails) -> Result<Option<String>> {
+        let spotify_artist = match find_artist_spotify_id(&artist.urls) {
+            Some(id) => Some(self.get_artist(&id).await?),
+            None => {
+                self.search_artist(&artist.name).await?
+                    .into_iter()
+                    .map(|a| (damlev(&a.name, &artist.name), a))
+                    .min_by_key(|(s, _)| *s)
+                    .map(|(_, a)| a)
+            }
+        };
+
+        Ok(spotify_artist.and_then(|a| {
+            a.images.into_iter().max_by_key(|i| i.width.unwrap_or(0)).map(|i| i.url)
+        }))
+    }
+
+    async fn release_artwork(&self, release: &Release, artist_credit: &ArtistCredit) -> Result<Option<String>> {
+        Ok(self.search_album(&release.title, &artist_credit.artist.name).await?
+            .into_iter()
+            .map(|a| (damlev(&a.name, &release.title), a))
+            .min_by_key(|(s, _)| *s)
+            .and_then(|(_, a)| {
+                a.images
+                    .into_iter()
+                    .max_by_key(|i| i.width.unwrap_or(0))
+                    .map(|i| i.url)
+            }))
+    }
+}
+
+fn find_artist_spotify_id(urls: &[String]) -> Option<String> {
+    let reg = Regex::new(r"^https://open\.spotify\.com/artist/([a-zA-Z0-9-_]+)$").unwrap();
+    urls.iter()
+        .filter_map(|url| reg.captures(url))
+        .map(|caps| caps.get(1).unwrap().as_str().to_string())
+        .next()
+}
@@ -1,3 +1,5 @@
+use av::format::AudioProperties;
+
 #[derive(Debug)]
 pub struct Artist {
     pub mbid: String,
@@ -10,14 +12,33 @@ pub struct Album {
     pub mbid: String,
     pub name: String,
     pub image_url: Option<String>,
+    // Cover art found with the file, used in place of image_url once stored
+    pub artwork: Option<Artwork>,
+}
+
+#[derive(Debug)]
+pub struct Artwork {
+    pub data: Vec<u8>,
+    pub extension: &'static str,
 }
 
 #[derive(Debug)]
 pub struct Track {
     pub mbid: String,
+    pub disc_number: u16,
     pub position: u16,
     pub title: String,
     pub bitrate: i64,
     pub duration: i64,
     pub file_location: String,
+    pub audio: AudioProperties,
+}
+
+// Gains in dB, peaks where 1.0 is full scale
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ReplayGain {
+    pub track_gain: Option<f32>,
+    pub track_peak: Option<f32>,
+    pub album_gain: Option<f32>,
+    pub album_peak: Option<f32>,
 }
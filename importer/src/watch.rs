@@ -0,0 +1,166 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+use std::sync::mpsc as std_mpsc;
+
+use notify::{DebouncedEvent, RecursiveMode, Watcher};
+use tokio::sync::mpsc;
+use tokio::time::{timeout, Duration};
+
+use crate::library::{is_audio_file, Library};
+
+// How long a single file must be quiet before notify reports it
+const FILE_SETTLE_TIME: Duration = Duration::from_secs(2);
+// How long the whole tree must be quiet before a batch of changes is
+// imported. Copying an album in produces a burst of events, and it is much
+// better to match its tracks once they are all present.
+const BATCH_SETTLE_TIME: Duration = Duration::from_secs(10);
+// How often to check for failed imports flagged for retry while idle
+const RETRY_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Default)]
+struct PendingChanges {
+    moves: BTreeMap<PathBuf, PathBuf>,
+    removes: BTreeSet<PathBuf>,
+    upserts: BTreeSet<PathBuf>,
+    rescan: bool,
+}
+
+impl PendingChanges {
+    fn is_empty(&self) -> bool {
+        self.moves.is_empty() && self.removes.is_empty() && self.upserts.is_empty() && !self.rescan
+    }
+
+    fn add(&mut self, event: DebouncedEvent) {
+        use DebouncedEvent::*;
+        match event {
+            Create(p) | Write(p) => {
+                if p.is_dir() {
+                    // A directory moved in from outside the watched tree
+                    // only produces one event for the directory itself.
+                    self.rescan = true;
+                } else if is_audio_file(&p) {
+                    self.removes.remove(&p);
+                    self.upserts.insert(p);
+                }
+            }
+            Remove(p) => {
+                self.upserts.remove(&p);
+                self.removes.insert(p);
+            }
+            Rename(from, to) => {
+                if self.upserts.remove(&from) {
+                    // Not imported yet, so there is nothing to move
+                    if is_audio_file(&to) {
+                        self.upserts.insert(to);
+                    }
+                } else {
+                    self.removes.remove(&to);
+                    self.moves.insert(from, to);
+                }
+            }
+            Rescan => self.rescan = true,
+            Error(e, p) => eprintln!("Watch error on {:?}: {}", p, e),
+            NoticeWrite(_) | NoticeRemove(_) | Chmod(_) => {}
+        }
+    }
+}
+
+// Watches the music root and keeps the database in step with it until the
+// process is killed.
+pub async fn run(library: &Library) -> Result<(), Box<dyn std::error::Error>> {
+    // Pick up anything that changed while we weren't watching
+    library.sync().await?;
+
+    let (event_tx, event_rx) = std_mpsc::channel();
+    let mut watcher = notify::watcher(event_tx, FILE_SETTLE_TIME)?;
+    watcher.watch(library.music_root(), RecursiveMode::Recursive)?;
+
+    // notify delivers events on a blocking channel, so forward them into the
+    // runtime from a thread of their own.
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        for event in event_rx {
+            if tx.send(event).is_err() {
+                break;
+            }
+        }
+    });
+
+    println!("Watching {:?}", library.music_root());
+
+    let mut pending = PendingChanges::default();
+    loop {
+        let event = if pending.is_empty() {
+            match timeout(RETRY_POLL_INTERVAL, rx.recv()).await {
+                Ok(event) => event,
+                Err(_) => {
+                    library.retry_failures().await?;
+                    continue;
+                }
+            }
+        } else {
+            match timeout(BATCH_SETTLE_TIME, rx.recv()).await {
+                Ok(event) => event,
+                Err(_) => {
+                    apply(library, std::mem::take(&mut pending)).await?;
+                    continue;
+                }
+            }
+        };
+
+        match event {
+            Some(event) => pending.add(event),
+            None => break,
+        }
+    }
+
+    Ok(())
+}
+
+// Each change is committed on its own so that one bad file doesn't roll
+// back the rest of the batch.
+async fn apply(library: &Library, changes: PendingChanges) -> Result<(), Box<dyn std::error::Error>> {
+    if changes.rescan {
+        library.sync().await?;
+    }
+
+    let mut client = library.pool().get().await?;
+
+    for (from, to) in &changes.moves {
+        let tx = client.transaction().await?;
+        match library.move_path(from, to, &tx).await {
+            Ok(n) if n > 0 => {
+                tx.commit().await?;
+                println!("Moved {:?} to {:?}", from, to);
+            }
+            // Nothing was known at the old location, so treat it as new
+            Ok(_) if is_audio_file(to) => {
+                drop(tx);
+                library.import_path(to, &mut client).await?;
+            }
+            Ok(_) => {}
+            Err(e) => eprintln!("Failed to move {:?} to {:?}: {}", from, to, e),
+        }
+    }
+
+    for path in &changes.removes {
+        let tx = client.transaction().await?;
+        match library.mark_unavailable(path, &tx).await {
+            Ok(n) => {
+                tx.commit().await?;
+                if n > 0 {
+                    println!("Marked {} track(s) at {:?} unavailable", n, path);
+                }
+            }
+            Err(e) => eprintln!("Failed to mark {:?} unavailable: {}", path, e),
+        }
+    }
+
+    for path in &changes.upserts {
+        library.import_path(path, &mut client).await?;
+    }
+
+    library.store_images().await?;
+
+    Ok(())
+}
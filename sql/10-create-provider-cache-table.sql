@@ -0,0 +1,10 @@
+CREATE TABLE IF NOT EXISTS provider_cache (
+  key TEXT NOT NULL,
+  status integer NOT NULL,
+  body BYTEA NOT NULL,
+  fetched_at TIMESTAMP WITH TIME ZONE NOT NULL,
+  expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+  PRIMARY KEY (key)
+);
+
+CREATE INDEX IF NOT EXISTS provider_cache_expires_at_idx ON provider_cache (expires_at);
@@ -0,0 +1 @@
+ALTER TABLE track ADD COLUMN IF NOT EXISTS disc_number integer NOT NULL DEFAULT 1;
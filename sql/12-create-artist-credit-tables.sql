@@ -0,0 +1,39 @@
+CREATE TABLE IF NOT EXISTS album_artist (
+  album_id integer NOT NULL,
+  artist_id integer NOT NULL,
+  position integer NOT NULL,
+  credited_name TEXT NOT NULL,
+  join_phrase TEXT NOT NULL DEFAULT '',
+  PRIMARY KEY (album_id, position),
+  FOREIGN KEY (album_id) REFERENCES album (id) ON DELETE CASCADE,
+  FOREIGN KEY (artist_id) REFERENCES artist (id) ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS track_artist (
+  track_id integer NOT NULL,
+  artist_id integer NOT NULL,
+  position integer NOT NULL,
+  credited_name TEXT NOT NULL,
+  join_phrase TEXT NOT NULL DEFAULT '',
+  PRIMARY KEY (track_id, position),
+  FOREIGN KEY (track_id) REFERENCES track (id) ON DELETE CASCADE,
+  FOREIGN KEY (artist_id) REFERENCES artist (id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS album_artist_artist_id_idx ON album_artist (artist_id);
+CREATE INDEX IF NOT EXISTS track_artist_artist_id_idx ON track_artist (artist_id);
+
+-- Credit existing albums and tracks to their album's artist until they are
+-- reimported
+INSERT INTO album_artist (album_id, artist_id, position, credited_name)
+SELECT R.id, R.artist_id, 0, A.name
+FROM album R
+INNER JOIN artist A ON A.id = R.artist_id
+ON CONFLICT DO NOTHING;
+
+INSERT INTO track_artist (track_id, artist_id, position, credited_name)
+SELECT T.id, R.artist_id, 0, A.name
+FROM track T
+INNER JOIN album R ON R.id = T.album_id
+INNER JOIN artist A ON A.id = R.artist_id
+ON CONFLICT DO NOTHING;
@@ -0,0 +1,9 @@
+-- Smart playlists store their rules, and compute their tracks from them
+-- instead of playlist_track
+ALTER TABLE playlist ADD COLUMN IF NOT EXISTS rules JSONB;
+
+-- Tracks imported before this existed are treated as added now
+ALTER TABLE track ADD COLUMN IF NOT EXISTS added_at timestamptz NOT NULL DEFAULT now();
+ALTER TABLE track ADD COLUMN IF NOT EXISTS play_count integer NOT NULL DEFAULT 0;
+
+CREATE INDEX IF NOT EXISTS track_added_at_idx ON track (added_at);
@@ -0,0 +1,28 @@
+CREATE TABLE IF NOT EXISTS "user" (
+  id SERIAL NOT NULL,
+  username TEXT NOT NULL,
+  password_hash TEXT NOT NULL,
+  is_admin boolean NOT NULL DEFAULT false,
+  created_at timestamptz NOT NULL DEFAULT now(),
+  PRIMARY KEY (id),
+  UNIQUE (username)
+);
+
+-- Sessions are looked up by a hash of their token, so a leaked table can't
+-- be used to log in
+CREATE TABLE IF NOT EXISTS session (
+  token_hash TEXT NOT NULL,
+  user_id integer NOT NULL,
+  created_at timestamptz NOT NULL DEFAULT now(),
+  expires_at timestamptz NOT NULL,
+  PRIMARY KEY (token_hash),
+  FOREIGN KEY (user_id) REFERENCES "user" (id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS session_user_id_idx ON session (user_id);
+
+-- Existing playlists have no owner until the first admin is created, who
+-- takes them over
+ALTER TABLE playlist ADD COLUMN IF NOT EXISTS owner_id integer REFERENCES "user" (id) ON DELETE CASCADE;
+
+CREATE INDEX IF NOT EXISTS playlist_owner_id_idx ON playlist (owner_id);
@@ -0,0 +1,20 @@
+CREATE TABLE IF NOT EXISTS play_event (
+  id SERIAL NOT NULL,
+  user_id integer NOT NULL,
+  track_id integer NOT NULL,
+  played_at timestamptz NOT NULL DEFAULT now(),
+  -- Seconds of the track that were listened to, if the client reported it
+  duration_played integer,
+  -- 'stream' when logged by /play, 'scrobble' when reported by a client
+  source TEXT NOT NULL,
+  PRIMARY KEY (id),
+  FOREIGN KEY (user_id) REFERENCES "user" (id) ON DELETE CASCADE,
+  FOREIGN KEY (track_id) REFERENCES track (id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS play_event_user_id_played_at_idx ON play_event (user_id, played_at);
+CREATE INDEX IF NOT EXISTS play_event_track_id_idx ON play_event (track_id);
+CREATE INDEX IF NOT EXISTS play_event_played_at_idx ON play_event (played_at);
+
+-- Play counts are now counted from play_event
+ALTER TABLE track DROP COLUMN IF EXISTS play_count;
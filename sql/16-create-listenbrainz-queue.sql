@@ -0,0 +1,18 @@
+-- Plays submitted through /1/submit-listens are logged with source
+-- 'listenbrainz'.
+
+-- Token for forwarding the user's listens to a ListenBrainz-compatible
+-- server. Listens aren't forwarded without one.
+ALTER TABLE "user" ADD COLUMN IF NOT EXISTS listenbrainz_token TEXT;
+
+-- Listens waiting to be forwarded, or to be retried after a failure
+CREATE TABLE IF NOT EXISTS listenbrainz_queue (
+  play_event_id integer NOT NULL,
+  attempts integer NOT NULL DEFAULT 0,
+  next_attempt_at timestamptz NOT NULL DEFAULT now(),
+  last_error TEXT,
+  PRIMARY KEY (play_event_id),
+  FOREIGN KEY (play_event_id) REFERENCES play_event (id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS listenbrainz_queue_next_attempt_at_idx ON listenbrainz_queue (next_attempt_at);
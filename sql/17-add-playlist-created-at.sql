@@ -0,0 +1,3 @@
+-- Subsonic clients show when playlists were created. Existing playlists are
+-- treated as created now.
+ALTER TABLE playlist ADD COLUMN IF NOT EXISTS created_at timestamptz NOT NULL DEFAULT now();
@@ -0,0 +1,4 @@
+-- Artwork downloaded into the image store, by file name. image_url stays the
+-- provider's link, which the file is fetched from and served in place of.
+ALTER TABLE album ADD COLUMN IF NOT EXISTS image_file text;
+ALTER TABLE artist ADD COLUMN IF NOT EXISTS image_file text;
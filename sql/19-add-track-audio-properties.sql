@@ -0,0 +1,9 @@
+-- Technical properties of each track's file, as reported by FFmpeg. Tracks
+-- imported before these were added are filled in by the importer's next sync.
+ALTER TABLE track ADD COLUMN IF NOT EXISTS codec text;
+ALTER TABLE track ADD COLUMN IF NOT EXISTS sample_rate integer;
+ALTER TABLE track ADD COLUMN IF NOT EXISTS bit_depth integer;
+ALTER TABLE track ADD COLUMN IF NOT EXISTS channels integer;
+ALTER TABLE track ADD COLUMN IF NOT EXISTS duration_ms bigint;
+ALTER TABLE track ADD COLUMN IF NOT EXISTS size bigint;
+ALTER TABLE track ADD COLUMN IF NOT EXISTS lossless boolean;
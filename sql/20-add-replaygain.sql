@@ -0,0 +1,11 @@
+-- ReplayGain for client-side normalization, in dB relative to -18 LUFS, with
+-- peaks where 1.0 is full scale. Taken from the file's tags when it has them,
+-- otherwise measured by the importer as EBU R128. loudness is the track's
+-- integrated loudness in LUFS, which album gains are derived from unless the
+-- album gain was tagged too.
+ALTER TABLE track ADD COLUMN IF NOT EXISTS replaygain_track_gain real;
+ALTER TABLE track ADD COLUMN IF NOT EXISTS replaygain_track_peak real;
+ALTER TABLE track ADD COLUMN IF NOT EXISTS replaygain_album_gain real;
+ALTER TABLE track ADD COLUMN IF NOT EXISTS replaygain_album_peak real;
+ALTER TABLE track ADD COLUMN IF NOT EXISTS loudness real;
+ALTER TABLE track ADD COLUMN IF NOT EXISTS replaygain_album_tagged boolean NOT NULL DEFAULT false;
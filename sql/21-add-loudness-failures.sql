@@ -0,0 +1,3 @@
+-- When measuring the track's loudness last failed, so the importer doesn't
+-- decode it again on every sync until the file changes
+ALTER TABLE track ADD COLUMN IF NOT EXISTS loudness_failed_at TIMESTAMP WITH TIME ZONE;
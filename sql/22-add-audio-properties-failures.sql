@@ -0,0 +1,3 @@
+-- When reading the track's audio properties last failed, so the importer
+-- doesn't open it again on every sync until the file changes
+ALTER TABLE track ADD COLUMN IF NOT EXISTS audio_properties_failed_at TIMESTAMP WITH TIME ZONE;
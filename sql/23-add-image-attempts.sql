@@ -0,0 +1,6 @@
+-- Failed downloads of image_url, so the importer backs off rather than
+-- fetching a broken link on every sync. Reset when image_url changes.
+ALTER TABLE album ADD COLUMN IF NOT EXISTS image_attempts integer NOT NULL DEFAULT 0;
+ALTER TABLE album ADD COLUMN IF NOT EXISTS image_failed_at TIMESTAMP WITH TIME ZONE;
+ALTER TABLE artist ADD COLUMN IF NOT EXISTS image_attempts integer NOT NULL DEFAULT 0;
+ALTER TABLE artist ADD COLUMN IF NOT EXISTS image_failed_at TIMESTAMP WITH TIME ZONE;
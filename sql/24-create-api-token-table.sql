@@ -0,0 +1,10 @@
+-- Long-lived tokens for clients that can't log in again when a session
+-- expires, like ListenBrainz scrobblers. One per user, hashed like sessions.
+CREATE TABLE IF NOT EXISTS api_token (
+  token_hash TEXT NOT NULL,
+  user_id integer NOT NULL,
+  created_at timestamptz NOT NULL DEFAULT now(),
+  PRIMARY KEY (token_hash),
+  UNIQUE (user_id),
+  FOREIGN KEY (user_id) REFERENCES "user" (id) ON DELETE CASCADE
+);
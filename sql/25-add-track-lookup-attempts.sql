@@ -0,0 +1,4 @@
+-- Failed attempts to match tracks imported offline, so syncs back off rather
+-- than asking providers about every unmatched track each time
+ALTER TABLE track ADD COLUMN IF NOT EXISTS lookup_attempts integer NOT NULL DEFAULT 0;
+ALTER TABLE track ADD COLUMN IF NOT EXISTS last_lookup_at TIMESTAMP WITH TIME ZONE;
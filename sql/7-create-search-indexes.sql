@@ -0,0 +1,10 @@
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+
+CREATE INDEX IF NOT EXISTS artist_name_trgm_idx ON artist USING GIN (name gin_trgm_ops);
+CREATE INDEX IF NOT EXISTS artist_name_fts_idx ON artist USING GIN (to_tsvector('simple', name));
+
+CREATE INDEX IF NOT EXISTS album_title_trgm_idx ON album USING GIN (title gin_trgm_ops);
+CREATE INDEX IF NOT EXISTS album_title_fts_idx ON album USING GIN (to_tsvector('simple', title));
+
+CREATE INDEX IF NOT EXISTS track_title_trgm_idx ON track USING GIN (title gin_trgm_ops);
+CREATE INDEX IF NOT EXISTS track_title_fts_idx ON track USING GIN (to_tsvector('simple', title));
@@ -0,0 +1,4 @@
+ALTER TABLE track ADD COLUMN IF NOT EXISTS available boolean NOT NULL DEFAULT true;
+ALTER TABLE track ADD COLUMN IF NOT EXISTS content_hash TEXT;
+
+CREATE INDEX IF NOT EXISTS track_content_hash_idx ON track (content_hash);
@@ -0,0 +1,12 @@
+CREATE TABLE IF NOT EXISTS import_failure (
+  id SERIAL NOT NULL,
+  file_location TEXT UNIQUE NOT NULL,
+  kind TEXT NOT NULL,
+  message TEXT NOT NULL,
+  tags JSONB NOT NULL DEFAULT '{}',
+  attempts integer NOT NULL DEFAULT 1,
+  retry_requested boolean NOT NULL DEFAULT false,
+  first_failed_at TIMESTAMP WITH TIME ZONE NOT NULL,
+  last_failed_at TIMESTAMP WITH TIME ZONE NOT NULL,
+  PRIMARY KEY (id)
+);
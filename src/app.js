@@ -1,4 +1,4 @@
-import React from 'react'
+import React, { useEffect, useState } from 'react'
 import { Route, Switch } from 'react-router-dom'
 
 import AlbumRoute from './routes/album'
@@ -11,9 +11,26 @@ import PlaylistRoute from './routes/playlist'
 import MusicPlayer from './components/music-player'
 import Sidebar from './components/sidebar'
 
+import LoginPage from './pages/login'
 import NotFoundPage from './pages/not-found'
 
 const App = () => {
+  // undefined until the session has been checked, then null if logged out
+  const [user, setUser] = useState(undefined)
+
+  useEffect(() => {
+    fetch('/api/auth/me')
+      .then(res => res.ok ? res.json() : null)
+      .then(setUser)
+  }, [])
+
+  if (user === undefined) {
+    return null
+  }
+  if (user === null) {
+    return <LoginPage onLogin={setUser} />
+  }
+
   return (
     <div>
       <Sidebar />
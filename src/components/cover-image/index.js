@@ -4,6 +4,9 @@ import { Link } from 'react-router-dom'
 import PlayIcon from '../../icons/play.svg'
 import styles from './styles.css'
 
+// Images are served by the API, under the same prefix as its other routes
+const imageSource = (image) => image && image.startsWith('/') ? `/api${image}` : image
+
 const CoverImage = ({ link, image, imageClass = '', play }) => {
   return (
     <div className={styles.cover}>
@@ -15,7 +18,7 @@ const CoverImage = ({ link, image, imageClass = '', play }) => {
               <PlayIcon className={styles.playIcon} height={100} width={100} />
             </button>}
         </div>}
-      <img src={image} className={`${styles.image} ${imageClass}`} />
+      <img src={imageSource(image)} className={`${styles.image} ${imageClass}`} />
     </div>
   )
 }
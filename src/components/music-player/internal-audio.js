@@ -41,7 +41,7 @@ const InternalAudio = ({
       onDurationChange={() => setDuration(audioRef.current.duration)}
       onEnded={finish}
     >
-      <source src={`/api/play/${currentSong.id}`} type="audio/flac" />
+      <source src={`/api/play/${currentSong.id}`} />
     </audio>
   )
 }
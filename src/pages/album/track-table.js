@@ -24,11 +24,13 @@ const TrackTable = ({
     { Header: 'Bitrate', accessor: 'bitrate' },
     { Header: '', accessor: 'more' },
   ], [])
-  
+
+  const discCount = new Set(tracks.map(t => t.disc_number)).size
+
   const data = React.useMemo(() => (
-    tracks.map(t => ({
-      play: <PlayButton play={() => play(t.id, tracks.flatMap(it => it.position > t.position ? [it.id] : []))} />,
-      position: t.position,
+    tracks.map((t, i) => ({
+      play: <PlayButton play={() => play(t.id, tracks.slice(i + 1).map(it => it.id))} />,
+      position: discCount > 1 ? `${t.disc_number}.${t.position}` : t.position,
       title: t.title,
       duration: displayTime(t.duration),
       bitrate: t.bit_rate,
@@ -43,7 +45,7 @@ const TrackTable = ({
         </TrackMenu>
       )
     }))
-  ), [tracks])
+  ), [tracks, discCount])
 
   return <Table columns={columns} data={data} />
 }
@@ -0,0 +1,51 @@
+import React, { useState } from 'react'
+
+import styles from './styles.css'
+
+const LoginPage = ({ onLogin }) => {
+  const [username, setUsername] = useState('')
+  const [password, setPassword] = useState('')
+  const [failed, setFailed] = useState(false)
+
+  const handleSubmit = e => {
+    e.preventDefault()
+    fetch('/api/auth/login', {
+      method: 'POST',
+      headers: {
+        'Content-Type': 'application/json',
+      },
+      body: JSON.stringify({ username, password }),
+    }).then(res => {
+      if (!res.ok) {
+        setFailed(true)
+        return
+      }
+      return res.json().then(json => onLogin(json.user))
+    })
+  }
+
+  return (
+    <form className={styles.form} onSubmit={handleSubmit}>
+      <h2 className={styles.title}>Log in</h2>
+      <input
+        className={styles.input}
+        type="text"
+        placeholder="Username"
+        value={username}
+        onChange={e => setUsername(e.target.value)}
+        autoFocus
+      />
+      <input
+        className={styles.input}
+        type="password"
+        placeholder="Password"
+        value={password}
+        onChange={e => setPassword(e.target.value)}
+      />
+      {failed && <p className={styles.error}>Wrong username or password</p>}
+      <button className={styles.button} type="submit">Log in</button>
+    </form>
+  )
+}
+
+export default LoginPage
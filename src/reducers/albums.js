@@ -45,7 +45,7 @@ const getTracksForAlbum = (id) => {
   return (state) => {
     const album = state.byId.albums[id]
     if (album && album.tracks) {
-      return album.tracks.map(tid => state.byId.tracks[tid]).sort((a, b) => a.position - b.position)
+      return album.tracks.map(tid => state.byId.tracks[tid]).sort((a, b) => (a.disc_number - b.disc_number) || (a.position - b.position))
     } else {
       return []
     }